@@ -0,0 +1,231 @@
+//! Benchmarks of the paths users hit at scale: joining labels over large reader sets, normalizing
+//! big argument objects, building long traces and counting the tokens of long histories.
+//!
+//! Before benchmarking, each path is timed against a budget and the run fails if one of them goes
+//! over it. `cargo test` runs the budgets as well, along with each benchmark once, so regressions
+//! are caught without benchmarking. The budgets are an order of magnitude above the usual timings
+//! of optimized builds, and ten times that for the unoptimized builds of `cargo test`, such that
+//! slower machines do not trip them while regressions of the IFC layer, which tend to be
+//! quadratic, still do.
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestUserMessageArgs};
+use criterion::{Criterion, black_box, criterion_group};
+use gentlemen::{
+    Action, BasicPlanner, Integrity, ProductLattice, Trace,
+    fixtures::Fixture,
+    ifc::{
+        InternedEmailLabel, InternedPowerset, InverseLattice, Lattice, PowersetLattice, Universe,
+    },
+    tokens::estimate_prompt_tokens,
+    tools::{EmailLabel, MetaValue},
+};
+use serde_json::{Map, Value, json};
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const READERS: usize = 5_000;
+const ARGUMENTS: usize = 1_000;
+const TRACE_STEPS: usize = 1_000;
+const HISTORY_MESSAGES: usize = 200;
+
+// Label readable by the readers of the `universe` whose index falls in `readers`
+fn label(universe: &HashSet<String>, readers: std::ops::Range<usize>) -> EmailLabel {
+    let subset = readers.map(|i| format!("user{i}@magnet.com")).collect();
+    ProductLattice::new(
+        Integrity::trusted(),
+        InverseLattice::new(
+            PowersetLattice::new(subset, universe.clone()).expect("Invalid readers"),
+        ),
+    )
+}
+
+// Same as `label`, with the readers interned into the shared `universe`
+fn interned_label(
+    universe: &Arc<Universe<String>>,
+    readers: std::ops::Range<usize>,
+) -> InternedEmailLabel {
+    let subset = readers.map(|i| format!("user{i}@magnet.com"));
+    ProductLattice::new(
+        Integrity::trusted(),
+        InverseLattice::new(InternedPowerset::new(subset, universe).expect("Invalid readers")),
+    )
+}
+
+fn universe(size: usize) -> HashSet<String> {
+    (0..size).map(|i| format!("user{i}@magnet.com")).collect()
+}
+
+// Tool call arguments with `count` fields, in the form the model sends them
+fn big_arguments(count: usize) -> String {
+    let arguments: Map<String, Value> = (0..count)
+        .map(|i| {
+            (
+                format!("field{i}"),
+                json!({ "kind": "value", "value": format!("value of field {i}") }),
+            )
+        })
+        .collect();
+    Value::Object(arguments).to_string()
+}
+
+// Build a trace the way the labeled loop does, joining the label of each new action into the
+// label of the conversation. Labels are interned, as cloning one per step is what keeps long runs
+// over a whole organization cheap.
+fn build_trace(universe: &Arc<Universe<String>>) -> Trace<InternedEmailLabel> {
+    let mut trace: Trace<InternedEmailLabel> = Trace::default();
+    let mut conversation = interned_label(universe, 0..READERS);
+    for step in 0..TRACE_STEPS {
+        // Every tenth action reads data with fewer readers
+        if step % 10 == 0 {
+            let readers = 0..READERS - step / 10;
+            conversation = conversation
+                .join(interned_label(universe, readers))
+                .expect("Failed to join labels");
+        }
+        trace.value_mut().push(MetaValue::new(
+            Action::Finish(String::new()),
+            conversation.clone(),
+        ));
+    }
+    trace
+}
+
+// History of `messages` emails, going round the inboxes of the fixtures
+fn history(messages: usize) -> Vec<ChatCompletionRequestMessage> {
+    let emails: Vec<_> = Fixture::ALL
+        .iter()
+        .flat_map(|fixture| fixture.inbox())
+        .collect();
+    emails
+        .iter()
+        .cycle()
+        .take(messages)
+        .enumerate()
+        .map(|(i, email)| {
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(format!(
+                    "Email {i} from {}: {}",
+                    email.sender(),
+                    email.body()
+                ))
+                .build()
+                .expect("Invalid message")
+                .into()
+        })
+        .collect()
+}
+
+// Median time of running `f` a few times, failing if it goes over `budget`, or ten times that when
+// unoptimized
+fn check_budget<T>(name: &str, budget: Duration, mut f: impl FnMut() -> T) {
+    let budget = match cfg!(debug_assertions) {
+        true => budget * 10,
+        false => budget,
+    };
+    let mut timings: Vec<Duration> = (0..11)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed()
+        })
+        .collect();
+    timings.sort();
+    let median = timings[timings.len() / 2];
+    assert!(
+        median <= budget,
+        "{name} took {median:?}, over its budget of {budget:?}"
+    );
+    println!("{name}: {median:?} (budget {budget:?})");
+}
+
+fn check_budgets() {
+    let universe = universe(READERS);
+    let (a, b) = (label(&universe, 0..4_000), label(&universe, 1_000..READERS));
+    check_budget("label_join", Duration::from_millis(30), || {
+        a.clone().join(b.clone())
+    });
+    let universe = Universe::new(universe);
+    let (a, b) = (
+        interned_label(&universe, 0..4_000),
+        interned_label(&universe, 1_000..READERS),
+    );
+    check_budget("interned_label_join", Duration::from_millis(1), || {
+        a.clone().join(b.clone())
+    });
+
+    let planner = BasicPlanner::new(vec![]);
+    let arguments = big_arguments(ARGUMENTS);
+    check_budget("normalize_args", Duration::from_millis(15), || {
+        planner.normalize_args(arguments.clone())
+    });
+
+    check_budget("trace_build", Duration::from_millis(400), || {
+        build_trace(&universe)
+    });
+
+    let history = history(HISTORY_MESSAGES);
+    check_budget("history_tokens", Duration::from_millis(15), || {
+        estimate_prompt_tokens(&history, &[])
+    });
+}
+
+fn bench_label_join(c: &mut Criterion) {
+    let universe = universe(READERS);
+    let (a, b) = (label(&universe, 0..4_000), label(&universe, 1_000..READERS));
+    c.bench_function("label_join_5k_readers", |bencher| {
+        bencher.iter(|| black_box(a.clone()).join(black_box(b.clone())))
+    });
+}
+
+fn bench_interned_label_join(c: &mut Criterion) {
+    let universe = Universe::new(universe(READERS));
+    let (a, b) = (
+        interned_label(&universe, 0..4_000),
+        interned_label(&universe, 1_000..READERS),
+    );
+    c.bench_function("interned_label_join_5k_readers", |bencher| {
+        bencher.iter(|| black_box(a.clone()).join(black_box(b.clone())))
+    });
+}
+
+fn bench_normalize_args(c: &mut Criterion) {
+    let planner = BasicPlanner::new(vec![]);
+    let arguments = big_arguments(ARGUMENTS);
+    c.bench_function("normalize_args_1k_fields", |bencher| {
+        bencher.iter(|| planner.normalize_args(black_box(arguments.clone())))
+    });
+}
+
+fn bench_trace_build(c: &mut Criterion) {
+    let universe = Universe::new(universe(READERS));
+    let mut group = c.benchmark_group("trace_build_1k_steps");
+    group.sample_size(10);
+    group.bench_function("join_every_10_steps", |bencher| {
+        bencher.iter(|| build_trace(black_box(&universe)))
+    });
+    group.finish();
+}
+
+fn bench_history_tokens(c: &mut Criterion) {
+    let history = history(HISTORY_MESSAGES);
+    c.bench_function("history_tokens_200_messages", |bencher| {
+        bencher.iter(|| estimate_prompt_tokens(black_box(&history), &[]))
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_label_join,
+    bench_interned_label_join,
+    bench_normalize_args,
+    bench_trace_build,
+    bench_history_tokens
+);
+
+fn main() {
+    check_budgets();
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -0,0 +1,88 @@
+//! Compares label equality checks over a 10k-step trace when using the cached fingerprints and
+//! epochs versus walking the underlying reader sets on every step.
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use gentlemen::{
+    Action, Integrity, ProductLattice, Trace,
+    ifc::{InverseLattice, PowersetLattice},
+    tools::{EmailLabel, MetaValue},
+};
+use std::collections::HashSet;
+
+const TRACE_STEPS: usize = 10_000;
+const PRINCIPALS: usize = 500;
+
+// Build a trace where a new label is only created every 100 steps and cloned in between,
+// mimicking how the labeled loop carries the label of the conversation from step to step.
+fn build_trace() -> Trace<EmailLabel> {
+    let universe: HashSet<String> = (0..PRINCIPALS)
+        .map(|i| format!("user{i}@magnet.com"))
+        .collect();
+    let mut trace: Trace<EmailLabel> = Trace::default();
+    for step in 0..TRACE_STEPS {
+        let label = match trace.value().last() {
+            Some(previous) if step % 100 != 0 => previous.label().clone(),
+            _ => {
+                let readers = universe
+                    .iter()
+                    .take(PRINCIPALS - step / 100)
+                    .cloned()
+                    .collect();
+                ProductLattice::new(
+                    Integrity::trusted(),
+                    InverseLattice::new(
+                        PowersetLattice::new(readers, universe.clone())
+                            .expect("Invalid reader set"),
+                    ),
+                )
+            }
+        };
+        trace
+            .value_mut()
+            .push(MetaValue::new(Action::Finish(String::new()), label));
+    }
+    trace
+}
+
+// Count the steps where the label changed using the fingerprint fast path.
+fn label_changes_fingerprint(trace: &Trace<EmailLabel>) -> usize {
+    trace
+        .value()
+        .windows(2)
+        .filter(|w| w[0].label() != w[1].label())
+        .count()
+}
+
+// Count the steps where the label changed by always comparing the full sets.
+fn label_changes_full(trace: &Trace<EmailLabel>) -> usize {
+    trace
+        .value()
+        .windows(2)
+        .filter(|w| {
+            let (a, b) = (w[0].label(), w[1].label());
+            a.lattice1() != b.lattice1()
+                || a.lattice2().inner().subset() != b.lattice2().inner().subset()
+                || a.lattice2().inner().universe() != b.lattice2().inner().universe()
+        })
+        .count()
+}
+
+fn bench_trace_label_eq(c: &mut Criterion) {
+    let trace = build_trace();
+    assert_eq!(
+        label_changes_fingerprint(&trace),
+        label_changes_full(&trace)
+    );
+
+    let mut group = c.benchmark_group("trace_label_eq_10k");
+    group.sample_size(10);
+    group.bench_function("fingerprint", |b| {
+        b.iter(|| label_changes_fingerprint(black_box(&trace)))
+    });
+    group.bench_function("full_set", |b| {
+        b.iter(|| label_changes_full(black_box(&trace)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_trace_label_eq);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+//! Summarize the inbox and send the summary over Slack with the [`BasicPlanner`], which tracks no
+//! labels and checks no policy. The model is simulated, so no API key is needed:
+//!
+//! ```text
+//! cargo run --example email_summary_basic
+//! ```
+use gentlemen::{
+    BasicPlanner, Datastore, Function, PlanningLoop, mock::MockLlm,
+    simulation::SimulatedEnvironment,
+};
+use serde_json::json;
+
+async fn run() {
+    let environment = SimulatedEnvironment::new(
+        "Summarize my 3 most recent emails and send the summary to me on Slack.",
+        vec![
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "read_emails",
+                json!({ "count": { "kind": "value", "value": "3" } }),
+            ),
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "send_slack_message",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": {
+                        "kind": "value",
+                        "value": "Alice confirmed tomorrow's meeting, Charlie shared the next \
+                        steps of Project Roma and David reported on the AKS experiment.",
+                    },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            MockLlm::assistant_text("I sent you the summary of your 3 most recent emails."),
+        ],
+    );
+    let mut planning_loop = PlanningLoop::new(
+        BasicPlanner::new(vec![]),
+        environment.model(),
+        vec![
+            Function::new("read_emails".to_string()),
+            Function::new("send_slack_message".to_string()),
+        ],
+    );
+    let answer = planning_loop
+        .run(
+            environment.state(),
+            &mut Datastore::default(),
+            environment.request(),
+        )
+        .await
+        .expect("Failed to run");
+    println!("Answer: {answer}");
+
+    let sent = environment.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].channel(), "bob.sheffield@magnet.com");
+    println!("Sent to {}: {}", sent[0].channel(), sent[0].message());
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    run().await;
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn example_runs() {
+        super::run().await;
+    }
+}
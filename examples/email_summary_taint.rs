@@ -0,0 +1,76 @@
+//! Summarize the inbox with the [`TaintTrackingPlanner`], which labels every email it reads and
+//! checks every action against a policy before taking it. The summary holds no link, so the policy
+//! lets it through even though some of the emails are untrusted:
+//!
+//! ```text
+//! cargo run --example email_summary_taint
+//! ```
+use gentlemen::{
+    Datastore, MetaFunction, PlanningLoop, Policy, TaintTrackingPlanner, mock::MockLlm,
+    policy::policy_no_untrusted_url, simulation::SimulatedEnvironment,
+};
+use serde_json::json;
+
+async fn run() {
+    let environment = SimulatedEnvironment::new(
+        "Summarize my emails and send the summary to me on Slack.",
+        vec![
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "read_emails_labeled",
+                json!({ "count": { "kind": "value", "value": "5" } }),
+            ),
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": {
+                        "kind": "value",
+                        "value": "Alice confirmed tomorrow's meeting, Charlie shared the next \
+                        steps of Project Roma, David reported on the AKS experiment and two \
+                        emails look like spam.",
+                    },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            MockLlm::assistant_text("I sent you the summary of your emails."),
+        ],
+    );
+    let mut planning_loop = PlanningLoop::new(
+        TaintTrackingPlanner::new(vec![]),
+        environment.model(),
+        vec![
+            MetaFunction::new("read_emails_labeled".to_string()),
+            MetaFunction::new("send_slack_message_labeled".to_string()),
+        ],
+    );
+    let answer = planning_loop
+        .run_with_policy(
+            environment.state(),
+            &mut Datastore::default(),
+            environment.labeled_request(),
+            Policy::new(policy_no_untrusted_url),
+        )
+        .await
+        .expect("Failed to run");
+    println!("Answer: {answer}");
+
+    assert_eq!(answer, "I sent you the summary of your emails.");
+    let sent = environment.sent();
+    assert_eq!(sent.len(), 1);
+    println!("Sent to {}: {}", sent[0].channel(), sent[0].message());
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    run().await;
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn example_runs() {
+        super::run().await;
+    }
+}
@@ -0,0 +1,421 @@
+//! Backend for Anthropic's Claude models. The planners speak the chat completions format, so the
+//! conversation and the tools are converted to the Messages API on the way out and the answer,
+//! including its `tool_use` blocks, is converted back into a chat completion on the way in. Tool
+//! calls therefore reach the planners like any other tool call and end up as `Action::MakeCall`.
+use async_openai::{
+    error::{ApiError, OpenAIError},
+    types::{
+        ChatChoice, ChatCompletionMessageToolCall, ChatCompletionRequestMessage,
+        ChatCompletionResponseMessage, ChatCompletionTool, ChatCompletionToolType, CompletionUsage,
+        CreateChatCompletionResponse, FinishReason, FunctionCall, Role,
+    },
+};
+use serde_json::{Value, json};
+
+// Version of the Messages API the requests are written against
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Client of the Anthropic Messages API
+#[derive(Debug)]
+pub struct AnthropicClient {
+    client: reqwest::Client,
+    api_key: String,
+    api_base: String,
+    model: String,
+    max_tokens: u32,
+}
+
+impl AnthropicClient {
+    pub fn new(api_key: &str, model: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_key: api_key.to_string(),
+            api_base: "https://api.anthropic.com/v1".to_string(),
+            model: model.to_string(),
+            max_tokens: 500,
+        }
+    }
+
+    pub fn with_api_base(mut self, api_base: &str) -> Self {
+        self.api_base = api_base.to_string();
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn max_tokens(&self) -> u32 {
+        self.max_tokens
+    }
+
+    /// Send the conversation in `messages` to the model, advertising the given `tools`
+    pub async fn chat(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        tools: Vec<ChatCompletionTool>,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let mut request = to_anthropic_request(&messages, &tools)?;
+        request["model"] = json!(self.model);
+        request["max_tokens"] = json!(self.max_tokens);
+
+        let response = self
+            .client
+            .post(format!("{}/messages", self.api_base))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&request)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await?;
+            return Err(OpenAIError::ApiError(api_error(status.as_u16(), &body)));
+        }
+        let body: Value = response.json().await?;
+        Ok(from_anthropic_response(body))
+    }
+}
+
+/// Convert the chat completion `messages` and `tools` into the body of a Messages API request,
+/// without the model and token limit. System messages are gathered into the system prompt and
+/// consecutive messages of the same role are merged, as the API requires roles to alternate.
+pub fn to_anthropic_request(
+    messages: &[ChatCompletionRequestMessage],
+    tools: &[ChatCompletionTool],
+) -> Result<Value, OpenAIError> {
+    let mut system = vec![];
+    let mut turns: Vec<(&str, Vec<Value>)> = vec![];
+    for message in messages {
+        // The serialized form is the same for every kind of message, which spares matching on
+        // each of the content types
+        let message = serde_json::to_value(message).map_err(OpenAIError::JSONDeserialize)?;
+        let content = text(&message["content"]);
+        let (role, blocks) = match message["role"].as_str() {
+            Some("system") | Some("developer") => {
+                system.push(content);
+                continue;
+            }
+            Some("user") => {
+                // Empty text blocks are refused, which images may come without
+                let mut blocks: Vec<Value> = images(&message["content"]).collect();
+                if !content.is_empty() || blocks.is_empty() {
+                    blocks.insert(0, json!({ "type": "text", "text": content }));
+                }
+                ("user", blocks)
+            }
+            Some("assistant") => {
+                let mut blocks = vec![];
+                if !content.is_empty() {
+                    blocks.push(json!({ "type": "text", "text": content }));
+                }
+                for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+                    let arguments = tool_call["function"]["arguments"].as_str().unwrap_or("{}");
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": tool_call["id"],
+                        "name": tool_call["function"]["name"],
+                        "input": serde_json::from_str::<Value>(arguments)
+                            .map_err(OpenAIError::JSONDeserialize)?,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            Some("tool") => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": message["tool_call_id"],
+                    "content": content,
+                })],
+            ),
+            role => {
+                return Err(OpenAIError::InvalidArgument(format!(
+                    "Messages with role {role:?} are not supported by the Anthropic backend"
+                )));
+            }
+        };
+        match turns.last_mut() {
+            Some((last_role, last_blocks)) if *last_role == role => last_blocks.extend(blocks),
+            _ => turns.push((role, blocks)),
+        }
+    }
+
+    let mut request = json!({
+        "messages": turns
+            .into_iter()
+            .map(|(role, content)| json!({ "role": role, "content": content }))
+            .collect::<Vec<_>>(),
+    });
+    if !system.is_empty() {
+        request["system"] = json!(system.join("\n\n"));
+    }
+    if !tools.is_empty() {
+        request["tools"] = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "name": tool.function.name,
+                    "description": tool.function.description,
+                    "input_schema": tool
+                        .function
+                        .parameters
+                        .clone()
+                        .unwrap_or(json!({ "type": "object", "properties": {} })),
+                })
+            })
+            .collect();
+        // The planners handle a single tool call per message
+        request["tool_choice"] = json!({ "type": "auto", "disable_parallel_tool_use": true });
+    }
+    Ok(request)
+}
+
+/// Convert the body of a Messages API response into a chat completion with a single choice. Text
+/// blocks become the content of the message and `tool_use` blocks become its tool calls.
+pub fn from_anthropic_response(body: Value) -> CreateChatCompletionResponse {
+    let mut content = vec![];
+    let mut tool_calls = vec![];
+    for block in body["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => content.push(block["text"].as_str().unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(ChatCompletionMessageToolCall {
+                id: block["id"].as_str().unwrap_or_default().to_string(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: block["name"].as_str().unwrap_or_default().to_string(),
+                    arguments: block["input"].to_string(),
+                },
+            }),
+            // Thinking and other blocks carry nothing the planners could use
+            _ => {}
+        }
+    }
+    let finish_reason = match body["stop_reason"].as_str() {
+        Some("tool_use") => FinishReason::ToolCalls,
+        Some("max_tokens") => FinishReason::Length,
+        Some("refusal") => FinishReason::ContentFilter,
+        _ => FinishReason::Stop,
+    };
+    let usage = body["usage"].as_object().map(|usage| {
+        let tokens = |key| usage.get(key).and_then(Value::as_u64).unwrap_or_default() as u32;
+        CompletionUsage {
+            prompt_tokens: tokens("input_tokens"),
+            completion_tokens: tokens("output_tokens"),
+            total_tokens: tokens("input_tokens") + tokens("output_tokens"),
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    });
+
+    #[allow(deprecated)]
+    let message = ChatCompletionResponseMessage {
+        content: (!content.is_empty()).then(|| content.join("\n")),
+        refusal: None,
+        tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+        role: Role::Assistant,
+        function_call: None,
+        audio: None,
+    };
+    CreateChatCompletionResponse {
+        id: body["id"].as_str().unwrap_or_default().to_string(),
+        choices: vec![ChatChoice {
+            index: 0,
+            message,
+            finish_reason: Some(finish_reason),
+            logprobs: None,
+        }],
+        created: 0,
+        model: body["model"].as_str().unwrap_or_default().to_string(),
+        service_tier: None,
+        system_fingerprint: None,
+        object: "chat.completion".to_string(),
+        usage,
+    }
+}
+
+// Text of the serialized `content` of a message, which is either a string or an array of parts
+fn text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+// Image blocks of the images in the serialized `content` of a user message
+fn images(content: &Value) -> impl Iterator<Item = Value> {
+    content
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|part| part["image_url"]["url"].as_str())
+        .map(|url| {
+            // Data URLs embed the image, which the API takes as base64 data
+            let source = match url
+                .strip_prefix("data:")
+                .and_then(|url| url.split_once(";base64,"))
+            {
+                Some((media_type, data)) => {
+                    json!({ "type": "base64", "media_type": media_type, "data": data })
+                }
+                None => json!({ "type": "url", "url": url }),
+            };
+            json!({ "type": "image", "source": source })
+        })
+}
+
+// Read the error reported in the `body` of a request which failed with `status`. Gateways in front
+// of the API do not always answer with JSON, in which case the body is the message. The status is
+// kept as the code, such that transient failures can be told apart.
+fn api_error(status: u16, body: &str) -> ApiError {
+    let error = serde_json::from_str::<Value>(body).unwrap_or_default();
+    ApiError {
+        message: error["error"]["message"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| body.to_string()),
+        r#type: error["error"]["type"].as_str().map(str::to_string),
+        param: None,
+        code: Some(status.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
+        ChatCompletionToolArgs, FunctionObject,
+    };
+
+    #[test]
+    fn tool_calls_are_converted_to_tool_use_blocks() {
+        let tool_call = ChatCompletionMessageToolCall {
+            id: "toolu_1".to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: "read_emails".to_string(),
+                arguments: "{\"count\":5}".to_string(),
+            },
+        };
+        let messages: Vec<ChatCompletionRequestMessage> = vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content("You are an email assistant.")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content("Summarize my emails.")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .tool_calls(vec![tool_call])
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestToolMessageArgs::default()
+                .content("[]")
+                .tool_call_id("toolu_1")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content("Only the unread ones.")
+                .build()
+                .unwrap()
+                .into(),
+        ];
+        let tools = vec![
+            ChatCompletionToolArgs::default()
+                .function(FunctionObject {
+                    name: "read_emails".to_string(),
+                    description: Some("Read the latest emails".to_string()),
+                    parameters: None,
+                    strict: None,
+                })
+                .build()
+                .unwrap(),
+        ];
+
+        let request = to_anthropic_request(&messages, &tools).unwrap();
+        assert_eq!(request["system"], "You are an email assistant.");
+        let turns = request["messages"].as_array().unwrap();
+        assert_eq!(turns.len(), 3);
+        assert_eq!(turns[1]["role"], "assistant");
+        assert_eq!(
+            turns[1]["content"][0],
+            json!({ "type": "tool_use", "id": "toolu_1", "name": "read_emails", "input": { "count": 5 } })
+        );
+        // The tool result and the following user message make up a single user turn
+        assert_eq!(turns[2]["role"], "user");
+        assert_eq!(turns[2]["content"][0]["type"], "tool_result");
+        assert_eq!(turns[2]["content"][0]["tool_use_id"], "toolu_1");
+        assert_eq!(turns[2]["content"][1]["text"], "Only the unread ones.");
+        assert_eq!(request["tools"][0]["input_schema"]["type"], "object");
+    }
+
+    #[test]
+    fn images_are_converted_to_image_blocks() {
+        let request = serde_json::from_value::<ChatCompletionRequestMessage>(json!({
+            "role": "user",
+            "content": [
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=" } },
+                { "type": "image_url", "image_url": { "url": "https://magnet.com/logo.png" } },
+            ],
+        }))
+        .unwrap();
+        let request = to_anthropic_request(&[request], &[]).unwrap();
+        assert_eq!(
+            request["messages"][0]["content"],
+            json!([
+                {
+                    "type": "image",
+                    "source": { "type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo=" },
+                },
+                {
+                    "type": "image",
+                    "source": { "type": "url", "url": "https://magnet.com/logo.png" },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn tool_use_blocks_become_tool_calls() {
+        let response = from_anthropic_response(json!({
+            "id": "msg_1",
+            "model": "claude-sonnet-4-5",
+            "role": "assistant",
+            "content": [
+                { "type": "text", "text": "Let me read your emails." },
+                { "type": "tool_use", "id": "toolu_1", "name": "read_emails", "input": { "count": 5 } },
+            ],
+            "stop_reason": "tool_use",
+            "usage": { "input_tokens": 120, "output_tokens": 30 },
+        }));
+
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(
+            choice.message.content.as_deref(),
+            Some("Let me read your emails.")
+        );
+        let tool_calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].id, "toolu_1");
+        assert_eq!(tool_calls[0].function.name, "read_emails");
+        assert_eq!(tool_calls[0].function.arguments, "{\"count\":5}");
+        assert_eq!(response.usage.unwrap().total_tokens, 150);
+    }
+}
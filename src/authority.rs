@@ -0,0 +1,181 @@
+//! Principals and the authority they act with. Labels only go up as data flows, except where a
+//! principal vouches for data, raising its integrity, or releases it to more readers, lowering its
+//! confidentiality. An [`Authority`] tells which of these a principal may do, such that tools run
+//! with the authority they were given rather than minting any label they like.
+use crate::ifc::{EmailLabel, Integrity, InverseLattice, LatticeError, PowersetLattice};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Party some code acts on behalf of, such as a user, a service or the planning loop itself
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Principal(String);
+
+impl Principal {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self(name.into())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthorityError {
+    // The principal may not vouch for data
+    CannotEndorse(Principal),
+    // The principal may not release data to the reader
+    CannotDeclassify {
+        principal: Principal,
+        reader: String,
+    },
+    LatticeError(LatticeError),
+}
+
+impl From<LatticeError> for AuthorityError {
+    fn from(err: LatticeError) -> Self {
+        Self::LatticeError(err)
+    }
+}
+
+/// What a principal may do to labels besides raising them. An authority starts out with no power,
+/// which is given explicitly with [`with_endorsement`] and [`with_declassification`].
+///
+/// [`with_endorsement`]: Authority::with_endorsement
+/// [`with_declassification`]: Authority::with_declassification
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authority {
+    principal: Principal,
+    // Whether the principal may vouch for data, making it trusted
+    endorse: bool,
+    // Readers the principal may release data to
+    declassify: HashSet<String>,
+}
+
+impl Authority {
+    pub fn new(principal: Principal) -> Self {
+        Self {
+            principal,
+            endorse: false,
+            declassify: HashSet::new(),
+        }
+    }
+
+    /// Let the principal vouch for data, making it trusted
+    pub fn with_endorsement(mut self) -> Self {
+        self.endorse = true;
+        self
+    }
+
+    /// Let the principal release data to the `readers`
+    pub fn with_declassification<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        readers: I,
+    ) -> Self {
+        self.declassify.extend(readers.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn principal(&self) -> &Principal {
+        &self.principal
+    }
+
+    pub fn can_endorse(&self) -> bool {
+        self.endorse
+    }
+
+    /// The `label` made trusted, if the principal may vouch for data
+    pub fn endorse(&self, label: EmailLabel) -> Result<EmailLabel, AuthorityError> {
+        if !self.endorse {
+            return Err(AuthorityError::CannotEndorse(self.principal.clone()));
+        }
+        Ok(EmailLabel::new(
+            Integrity::trusted(),
+            label.lattice2().clone(),
+        ))
+    }
+
+    /// The `label` made readable by the `readers` as well, if the principal may release data to
+    /// each of those who could not read it already
+    pub fn declassify(
+        &self,
+        label: EmailLabel,
+        readers: HashSet<String>,
+    ) -> Result<EmailLabel, AuthorityError> {
+        let current = label.lattice2().inner();
+        if let Some(reader) = readers.iter().find(|reader| {
+            !current.subset().contains(*reader) && !self.declassify.contains(*reader)
+        }) {
+            return Err(AuthorityError::CannotDeclassify {
+                principal: self.principal.clone(),
+                reader: reader.clone(),
+            });
+        }
+        let readers =
+            PowersetLattice::new(&readers | current.subset(), current.universe().clone())?;
+        Ok(EmailLabel::new(
+            label.lattice1().clone(),
+            InverseLattice::new(readers),
+        ))
+    }
+
+    /// Label of data the principal produces itself, such as the status reported by a service,
+    /// which is readable by the whole `universe`. The data is only trusted if the principal may
+    /// vouch for it.
+    pub fn mint(&self, universe: HashSet<String>) -> EmailLabel {
+        let integrity = match self.endorse {
+            true => Integrity::trusted(),
+            false => Integrity::untrusted(),
+        };
+        EmailLabel::new(
+            integrity,
+            InverseLattice::new(PowersetLattice::top(universe)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readers(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn principals_only_do_what_their_authority_allows() {
+        let universe = readers(&["alice", "bob", "charlie"]);
+        let label = EmailLabel::new(
+            Integrity::untrusted(),
+            InverseLattice::new(
+                PowersetLattice::new(readers(&["alice"]), universe.clone()).unwrap(),
+            ),
+        );
+        let tool = Authority::new(Principal::new("read_emails"));
+        assert!(matches!(
+            tool.endorse(label.clone()),
+            Err(AuthorityError::CannotEndorse(_))
+        ));
+        assert_eq!(
+            tool.mint(universe.clone()).lattice1(),
+            &Integrity::untrusted()
+        );
+
+        let user = Authority::new(Principal::new("alice"))
+            .with_endorsement()
+            .with_declassification(["bob"]);
+        assert_eq!(
+            user.endorse(label.clone()).unwrap().lattice1(),
+            &Integrity::trusted()
+        );
+        let shared = user.declassify(label.clone(), readers(&["bob"])).unwrap();
+        assert_eq!(
+            shared.lattice2().inner().subset(),
+            &readers(&["alice", "bob"])
+        );
+        assert!(matches!(
+            user.declassify(label, readers(&["charlie"])),
+            Err(AuthorityError::CannotDeclassify { reader, .. }) if reader == "charlie"
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+//! Run a request through the planning loop, with the demo email and Slack tools.
+//!
+//! ```text
+//! gentlemen [--config <path>] <request>...
+//! ```
+//!
+//! The model is configured from the file given with `--config`, see
+//! [`LlmClient::from_config_file`], or else from the environment, see [`LlmClient::from_env`].
+use async_openai::types::{
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionTool, ChatCompletionToolArgs,
+    ChatCompletionToolType, FunctionObject,
+};
+use gentlemen::{
+    BasicPlanner, ConversationHistory, Datastore, Function, PlanningLoop,
+    openai::LlmClient,
+    prompt::{PlannerPrompt, PromptTemplate},
+    tools::variable_schema_gen,
+};
+use serde_json::{Value, json};
+use std::process::ExitCode;
+
+fn tool(name: &str, description: &str, properties: Value) -> ChatCompletionTool {
+    let required: Vec<_> = properties
+        .as_object()
+        .map(|properties| properties.keys().cloned().collect())
+        .unwrap_or_default();
+    ChatCompletionToolArgs::default()
+        .function(FunctionObject {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters: Some(variable_schema_gen(
+                json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                    "additionalProperties": false,
+                }),
+                vec![],
+            )),
+            strict: Some(true),
+        })
+        .r#type(ChatCompletionToolType::Function)
+        .build()
+        .expect("Invalid tool")
+}
+
+fn tools() -> Vec<ChatCompletionTool> {
+    vec![
+        tool(
+            "read_emails",
+            "Reading a number of {count} email from the inbox, optionally only the ones from a \
+            {sender}, about a {subject}, sent {after} or {before} a day, or {unread}",
+            json!({
+                "count": { "type": "string", "description": "The number of emails to read" },
+                "sender": {
+                    "type": ["string", "null"],
+                    "description": "Part of the address of the sender",
+                },
+                "subject": { "type": ["string", "null"], "description": "Part of the subject" },
+                "after": {
+                    "type": ["string", "null"],
+                    "description": "First day the emails were sent on, as YYYY-MM-DD",
+                },
+                "before": {
+                    "type": ["string", "null"],
+                    "description": "Last day the emails were sent on, as YYYY-MM-DD",
+                },
+                "unread": {
+                    "type": ["boolean", "null"],
+                    "description": "Whether to only read unread emails, or only read ones",
+                },
+            }),
+        ),
+        tool(
+            "send_slack_message",
+            "Sends a {message} to a slack {channel} with an optional {preview}",
+            json!({
+                "channel": { "type": "string", "description": "The channel to send to" },
+                "message": { "type": "string", "description": "The message to be sent" },
+                "preview": { "type": "string", "description": "Whether to preview links" },
+            }),
+        ),
+        tool(
+            "send_email",
+            "Sends an email with a {subject} and a {body} to the {to} addresses",
+            json!({
+                "to": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "The addresses to send the email to",
+                },
+                "subject": { "type": "string", "description": "The subject of the email" },
+                "body": { "type": "string", "description": "The body of the email" },
+            }),
+        ),
+        tool(
+            "read_calendar",
+            "Reading a number of {count} upcoming events from the calendar",
+            json!({ "count": { "type": "string", "description": "The number of events to read" } }),
+        ),
+        tool(
+            "create_event",
+            "Creates an event called {title} from {start} to {end}, inviting the {attendees}",
+            json!({
+                "title": { "type": "string", "description": "The title of the event" },
+                "start": { "type": "string", "description": "When the event starts" },
+                "end": { "type": "string", "description": "When the event ends" },
+                "attendees": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "The addresses of the people invited",
+                },
+                "description": { "type": "string", "description": "What the event is about" },
+            }),
+        ),
+        tool(
+            "web_search",
+            "Searches the web for pages about a {query}",
+            json!({ "query": { "type": "string", "description": "What to search for" } }),
+        ),
+        tool(
+            "fetch_url",
+            "Reads the page at a {url}",
+            json!({ "url": { "type": "string", "description": "The URL of the page" } }),
+        ),
+    ]
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let client = match args.iter().position(|arg| arg == "--config") {
+        Some(index) if index + 1 < args.len() => {
+            let path = args.drain(index..index + 2).nth(1).expect("Missing path");
+            LlmClient::from_config_file(path)
+        }
+        Some(_) => {
+            eprintln!("--config expects a path");
+            return ExitCode::FAILURE;
+        }
+        None => LlmClient::from_env(),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Failed to configure the model: {err:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.is_empty() {
+        eprintln!("Usage: gentlemen [--config <path>] <request>...");
+        return ExitCode::FAILURE;
+    }
+
+    let tools = tools();
+    let prompt = PlannerPrompt::new(PromptTemplate::Basic)
+        .with_tools(&tools)
+        .with_user("bob.sheffield@magnet.com")
+        .build();
+    let system = ChatCompletionRequestSystemMessageArgs::default()
+        .content(prompt)
+        .build()
+        .expect("Invalid system prompt")
+        .into();
+    let mut planning_loop = PlanningLoop::new(
+        BasicPlanner::new(tools),
+        client,
+        vec![
+            Function::new("read_emails".to_string()),
+            Function::new("send_slack_message".to_string()),
+            Function::new("send_email".to_string()),
+            Function::new("read_calendar".to_string()),
+            Function::new("create_event".to_string()),
+            Function::new("web_search".to_string()),
+            Function::new("fetch_url".to_string()),
+        ],
+    );
+    let answer = planning_loop
+        .run_from_user(
+            ConversationHistory::new(vec![system]),
+            &mut Datastore::default(),
+            &args.join(" "),
+        )
+        .await;
+    match answer {
+        Ok(answer) => {
+            println!("{answer}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("The run failed: {err:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
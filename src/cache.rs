@@ -0,0 +1,363 @@
+//! Caching of tool results, such that calling the same tool with the same arguments twice within a
+//! session does not hit the underlying service twice.
+//!
+//! Every cached result keeps the label it was produced with, and is only served to contexts whose
+//! clearance admits that label. A context which could not have seen the result in the first place
+//! falls back to calling the tool, so caching never lets data flow where it could not have flowed
+//! without it. Calls are keyed by their arguments once normalized, such that the model passing
+//! the same arguments in another order or spacing still hits the cache.
+use crate::{ifc::Lattice, tools::EmailLabel};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// When the cached results of a tool stop being valid
+#[derive(Debug, Default, Clone)]
+pub struct CachePolicy {
+    // How long results stay valid after being cached, forever if missing
+    ttl: Option<Duration>,
+    // Tools whose calls invalidate all the cached results of the tool, for example sending an
+    // email invalidates the results of reading the sent folder.
+    invalidated_by: Vec<String>,
+}
+
+impl CachePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_invalidated_by(mut self, tool: &str) -> Self {
+        self.invalidated_by.push(tool.to_string());
+        self
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    result: String,
+    label: EmailLabel,
+    cached_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    // Only the tools with a policy get cached
+    policies: HashMap<String, CachePolicy>,
+    // Cached results keyed by tool name and normalized arguments
+    entries: HashMap<(String, String), CacheEntry>,
+}
+
+// The `args` of a call as a key of the cache. JSON objects are serialized with their keys sorted,
+// while arguments which are not JSON are kept as they are.
+fn normalize(args: &str) -> String {
+    serde_json::from_str::<Value>(args).map_or_else(|_| args.to_string(), |args| args.to_string())
+}
+
+// Whether a result labeled `label` is admitted by the `clearance`, which is when joining the label
+// with the clearance does not raise the clearance
+fn admits(clearance: &EmailLabel, label: &EmailLabel) -> bool {
+    label.clone().join(clearance.clone()).as_ref() == Some(clearance)
+}
+
+/// Cache of tool results, shared by all its clones
+#[derive(Debug, Default, Clone)]
+pub struct ToolCache {
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+impl ToolCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cache the results of `tool` according to `policy`
+    pub fn with_tool(self, tool: &str, policy: CachePolicy) -> Self {
+        self.lock().policies.insert(tool.to_string(), policy);
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheInner> {
+        self.inner.lock().expect("ToolCache lock poisoned")
+    }
+
+    /// Returns the cached result of calling `tool` with `args`, if it is still valid and its label
+    /// can flow to `clearance`.
+    pub fn get(
+        &self,
+        tool: &str,
+        args: &str,
+        clearance: &EmailLabel,
+    ) -> Option<(String, EmailLabel)> {
+        let mut inner = self.lock();
+        let ttl = inner.policies.get(tool)?.ttl;
+        let key = (tool.to_string(), normalize(args));
+        let entry = inner.entries.get(&key)?;
+        if ttl.is_some_and(|ttl| entry.cached_at.elapsed() >= ttl) {
+            inner.entries.remove(&key);
+            return None;
+        }
+        admits(clearance, &entry.label).then(|| (entry.result.clone(), entry.label.clone()))
+    }
+
+    /// Cache the `result` of calling `tool` with `args`, if the tool is cached at all
+    pub fn insert(&self, tool: &str, args: &str, result: String, label: EmailLabel) {
+        let mut inner = self.lock();
+        if inner.policies.contains_key(tool) {
+            inner.entries.insert(
+                (tool.to_string(), normalize(args)),
+                CacheEntry {
+                    result,
+                    label,
+                    cached_at: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Record that `tool` was called, dropping the results of the tools it invalidates
+    pub fn called(&self, tool: &str) {
+        let mut inner = self.lock();
+        let invalidated: Vec<String> = inner
+            .policies
+            .iter()
+            .filter(|(_, policy)| policy.invalidated_by.iter().any(|name| name == tool))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in invalidated {
+            inner.entries.retain(|(cached, _), _| cached != &name);
+        }
+    }
+
+    /// Drop all the cached results of `tool`
+    pub fn invalidate(&self, tool: &str) {
+        self.lock().entries.retain(|(cached, _), _| cached != tool);
+    }
+
+    /// Drop the cached result of calling `tool` with `args`
+    pub fn invalidate_call(&self, tool: &str, args: &str) {
+        self.lock()
+            .entries
+            .remove(&(tool.to_string(), normalize(args)));
+    }
+
+    /// Drop the cached results whose label cannot flow to `label`, such as every result some
+    /// reader of `label` may no longer see once their access to the underlying data is revoked
+    pub fn invalidate_above(&self, label: &EmailLabel) {
+        self.lock()
+            .entries
+            .retain(|_, entry| admits(label, &entry.label));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Args, BasicPlanner, ConversationHistory, Datastore, Function, Integrity, Message,
+        PlanningLoop, ProductLattice, ToolError,
+        mock::MockLlm,
+        openai::LlmClient,
+        registry::{Tool, ToolEntry, ToolRegistry},
+        schema::ToolDefinition,
+        tools::{EmailAddressUniverse, INBOX, readers_label},
+    };
+    use async_openai::types::ChatCompletionTool;
+    use futures::future::BoxFuture;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Tool counting how many times it was actually called
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Tool for Counter {
+        fn name(&self) -> &str {
+            "count_emails"
+        }
+
+        fn schema(&self) -> ChatCompletionTool {
+            ToolDefinition::<Value>::new("count_emails", "Count the emails of a folder")
+                .param::<String>("folder", "The folder to count the emails of")
+                .build()
+                .unwrap()
+        }
+
+        fn execute<'a>(
+            &'a self,
+            _args: Args,
+            _datastore: &'a mut Datastore,
+        ) -> BoxFuture<'a, Result<String, ToolError>> {
+            let calls = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+            Box::pin(async move { Ok(format!("{calls} emails")) })
+        }
+    }
+
+    #[test]
+    fn cached_results_respect_clearance() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = |integrity, readers: &[&str]| {
+            ProductLattice::new(
+                integrity,
+                readers_label(
+                    readers.iter().map(|reader| reader.to_string()).collect(),
+                    universe.clone(),
+                )
+                .unwrap(),
+            )
+        };
+        let bob = label(Integrity::untrusted(), &["bob.sheffield@magnet.com"]);
+        let trusted_public = label(
+            Integrity::trusted(),
+            &["bob.sheffield@magnet.com", "alice.hudson@magnet.com"],
+        );
+
+        let cache = ToolCache::new().with_tool(
+            "read_emails_labeled",
+            CachePolicy::new().with_invalidated_by("send_slack_message_labeled"),
+        );
+        cache.insert(
+            "read_emails_labeled",
+            "{\"count\":5}",
+            "5 emails".into(),
+            bob.clone(),
+        );
+        // Tools without a policy are never cached
+        cache.insert(
+            "send_slack_message_labeled",
+            "{}",
+            "sent".into(),
+            bob.clone(),
+        );
+        assert!(
+            cache
+                .get("send_slack_message_labeled", "{}", &bob)
+                .is_none()
+        );
+
+        assert_eq!(
+            cache.get("read_emails_labeled", "{\"count\":5}", &bob),
+            Some(("5 emails".to_string(), bob.clone()))
+        );
+        assert!(
+            cache
+                .get("read_emails_labeled", "{\"count\":3}", &bob)
+                .is_none()
+        );
+        // Untrusted results readable by Bob alone cannot be served to a trusted context shared
+        // with Alice
+        assert!(
+            cache
+                .get("read_emails_labeled", "{\"count\":5}", &trusted_public)
+                .is_none()
+        );
+
+        cache.called("send_slack_message_labeled");
+        assert!(
+            cache
+                .get("read_emails_labeled", "{\"count\":5}", &bob)
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_calls_of_the_plain_loop_are_served_from_the_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let registry = ToolRegistry::new()
+            .with_tool(ToolEntry::from_tool(Counter(calls.clone())))
+            .unwrap();
+        let call = |id: &str| {
+            MockLlm::assistant_tool_call(
+                id,
+                "count_emails",
+                json!({ "folder": { "kind": "value", "value": "inbox" } }),
+            )
+        };
+        let model = LlmClient::mock(MockLlm::new(vec![
+            call("call_1"),
+            MockLlm::assistant_text("There are 1 emails."),
+        ]));
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let clearance = ProductLattice::new(
+            Integrity::trusted(),
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(registry.schemas()),
+            model,
+            Vec::<Function>::new(),
+        )
+        .with_registry(registry)
+        .with_tool_cache(
+            ToolCache::new().with_tool("count_emails", CachePolicy::new()),
+            clearance,
+        );
+
+        planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(call("call_0")),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let last_message = serde_json::to_string(requests[1].last().unwrap()).unwrap();
+        assert!(last_message.contains("1 emails"));
+    }
+
+    #[test]
+    fn calls_are_keyed_by_normalized_args_and_invalidated_by_label() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = |readers: &[&str]| {
+            ProductLattice::new(
+                Integrity::trusted(),
+                readers_label(
+                    readers.iter().map(|reader| reader.to_string()).collect(),
+                    universe.clone(),
+                )
+                .unwrap(),
+            )
+        };
+        let bob = label(&["bob.sheffield@magnet.com"]);
+        let team = label(&["bob.sheffield@magnet.com", "alice.hudson@magnet.com"]);
+        let cache = ToolCache::new()
+            .with_tool("read_emails", CachePolicy::new())
+            .with_tool("read_calendar", CachePolicy::new());
+        cache.insert(
+            "read_emails",
+            r#"{"count": 5, "folder": "inbox"}"#,
+            "5 emails".into(),
+            bob.clone(),
+        );
+        cache.insert(
+            "read_calendar",
+            r#"{"count":2}"#,
+            "2 events".into(),
+            team.clone(),
+        );
+        assert!(
+            cache
+                .get("read_emails", r#"{"folder":"inbox","count":5}"#, &bob)
+                .is_some()
+        );
+
+        // Results only Bob may read cannot flow to the team
+        cache.invalidate_above(&team);
+        assert!(
+            cache
+                .get("read_emails", r#"{"count":5,"folder":"inbox"}"#, &bob)
+                .is_none()
+        );
+        assert!(cache.get("read_calendar", r#"{"count":2}"#, &bob).is_some());
+        cache.invalidate_call("read_calendar", r#"{ "count": 2 }"#);
+        assert!(cache.get("read_calendar", r#"{"count":2}"#, &bob).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Compression of the message bodies kept in persisted state. Email-heavy sessions carry long,
+//! repetitive tool results, which compress well, while most chat messages are too short to be
+//! worth it. [`StoredText`] therefore only compresses bodies above a size threshold, and hides
+//! whether it did from its users.
+use async_openai::types::ChatCompletionRequestMessage;
+use std::{
+    borrow::Cow,
+    io::{self, Read},
+};
+
+// Bodies shorter than this are stored as is, since zstd frames have a fixed overhead
+pub const COMPRESSION_THRESHOLD: usize = 256;
+// Compression level used for stored bodies, favouring speed as state is written at every step
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug)]
+pub enum CompressionError {
+    IoError(io::Error),
+    SerdeJsonError(serde_json::Error),
+    // The decompressed body is not valid UTF-8
+    InvalidText,
+}
+
+impl From<io::Error> for CompressionError {
+    fn from(err: io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for CompressionError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::SerdeJsonError(err)
+    }
+}
+
+/// Text body which is transparently compressed with zstd when it is long enough
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredText {
+    Plain(String),
+    // Compressed bytes along with the length of the original text
+    Zstd(Vec<u8>, usize),
+}
+
+impl StoredText {
+    pub fn new(text: &str) -> Result<Self, CompressionError> {
+        if text.len() < COMPRESSION_THRESHOLD {
+            return Ok(Self::Plain(text.to_string()));
+        }
+        let bytes = zstd::encode_all(text.as_bytes(), COMPRESSION_LEVEL)?;
+        Ok(Self::Zstd(bytes, text.len()))
+    }
+
+    /// Length of the original text
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Plain(text) => text.len(),
+            Self::Zstd(_, len) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of bytes actually stored
+    pub fn stored_len(&self) -> usize {
+        match self {
+            Self::Plain(text) => text.len(),
+            Self::Zstd(bytes, _) => bytes.len(),
+        }
+    }
+
+    /// Stream the original text, decompressing it on the fly
+    pub fn reader(&self) -> Result<Box<dyn Read + '_>, CompressionError> {
+        Ok(match self {
+            Self::Plain(text) => Box::new(text.as_bytes()),
+            Self::Zstd(bytes, _) => Box::new(zstd::Decoder::new(bytes.as_slice())?),
+        })
+    }
+
+    /// Returns the original text, decompressing it if needed
+    pub fn text(&self) -> Result<Cow<'_, str>, CompressionError> {
+        match self {
+            Self::Plain(text) => Ok(Cow::Borrowed(text)),
+            Self::Zstd(_, len) => {
+                let mut text = String::with_capacity(*len);
+                self.reader()?
+                    .read_to_string(&mut text)
+                    .map_err(|err| match err.kind() {
+                        io::ErrorKind::InvalidData => CompressionError::InvalidText,
+                        _ => CompressionError::IoError(err),
+                    })?;
+                Ok(Cow::Owned(text))
+            }
+        }
+    }
+}
+
+/// Message of the conversation history as kept in persisted state
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage(StoredText);
+
+impl StoredMessage {
+    pub fn new(message: &ChatCompletionRequestMessage) -> Result<Self, CompressionError> {
+        Ok(Self(StoredText::new(&serde_json::to_string(message)?)?))
+    }
+
+    pub fn body(&self) -> &StoredText {
+        &self.0
+    }
+
+    /// Returns the original message, decompressing it if needed
+    pub fn message(&self) -> Result<ChatCompletionRequestMessage, CompressionError> {
+        Ok(serde_json::from_reader(self.0.reader()?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::INBOX;
+    use async_openai::types::ChatCompletionRequestToolMessageArgs;
+
+    #[test]
+    fn long_bodies_are_compressed_transparently() {
+        let short = StoredText::new("Hi Bob").unwrap();
+        assert_eq!(short, StoredText::Plain("Hi Bob".to_string()));
+
+        let emails = serde_json::to_string(&[INBOX, INBOX, INBOX]).unwrap();
+        let long = StoredText::new(&emails).unwrap();
+        assert!(matches!(long, StoredText::Zstd(_, _)));
+        assert_eq!(long.len(), emails.len());
+        assert!(long.stored_len() < emails.len() / 2);
+        assert_eq!(long.text().unwrap(), emails);
+
+        let message = ChatCompletionRequestToolMessageArgs::default()
+            .content(emails)
+            .tool_call_id("call_0")
+            .build()
+            .unwrap()
+            .into();
+        let stored = StoredMessage::new(&message).unwrap();
+        assert!(matches!(stored.body(), StoredText::Zstd(_, _)));
+        assert_eq!(stored.message().unwrap(), message);
+    }
+}
@@ -0,0 +1,250 @@
+//! Management of the context sent to the model. The planners append every message of a run to the
+//! conversation, such that long tasks end up sending more than the context window of the model
+//! holds. Past the [`ContextWindow`] threshold, the planning loop compacts the conversation before
+//! querying the model, either by truncating older tool results or by having the model summarize
+//! older turns.
+//!
+//! Compaction never touches the system messages and the request of the user the conversation
+//! starts with, nor splits a tool call from its result. The labels of the run are kept by the
+//! loop, not by the conversation, so a summary carries the label of the messages it replaces. The
+//! summary is written by the model from what may be untrusted tool results, so it stands in the
+//! conversation as a message of the assistant, never with the authority of the system prompt.
+use crate::tokens::estimate_prompt_tokens;
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageContent,
+        ChatCompletionRequestUserMessageArgs, ChatCompletionTool,
+    },
+};
+
+/// Instructions for the model to summarize older turns of the conversation
+pub const SUMMARY_PROMPT: &str = "Summarize the following turns of a conversation between a user, \
+    an assistant and the tools it called. Keep every fact, name, address and identifier the rest \
+    of the task may need, and leave out the wording. Answer with the summary alone.";
+
+// Marks the end of a tool result which was cut
+const TRUNCATED: &str = "... [truncated]";
+
+/// How the conversation is made to fit in the context window
+#[derive(Debug, Clone, PartialEq)]
+pub enum Compaction {
+    /// Cut every tool result but the latest down to this many characters
+    TruncateToolResults { max_chars: usize },
+    /// Have the model summarize the turns before the latest `keep_recent` messages into a single
+    /// message
+    Summarize { keep_recent: usize },
+}
+
+/// Context window of the model, past which the conversation is compacted
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextWindow {
+    max_tokens: u32,
+    compaction: Compaction,
+}
+
+impl ContextWindow {
+    /// Compact conversations estimated at more than `max_tokens` prompt tokens with `compaction`
+    pub fn new(max_tokens: u32, compaction: Compaction) -> Self {
+        Self {
+            max_tokens,
+            compaction,
+        }
+    }
+
+    pub fn max_tokens(&self) -> u32 {
+        self.max_tokens
+    }
+
+    pub fn compaction(&self) -> &Compaction {
+        &self.compaction
+    }
+
+    /// Whether sending the `messages` with the `tools` goes over the threshold
+    pub fn is_exceeded_by(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: &[ChatCompletionTool],
+    ) -> bool {
+        estimate_prompt_tokens(messages, tools) > self.max_tokens
+    }
+}
+
+/// Range of the `messages` which can be compacted, leaving out the system messages and the request
+/// they start with and the latest `keep_recent` messages. The range never ends between a tool call
+/// and its result. Returns `None` if there is nothing to compact.
+pub fn compactable(
+    messages: &[ChatCompletionRequestMessage],
+    keep_recent: usize,
+) -> Option<std::ops::Range<usize>> {
+    let system = messages
+        .iter()
+        .take_while(|message| matches!(message, ChatCompletionRequestMessage::System(_)))
+        .count();
+    let start = match messages.get(system) {
+        Some(ChatCompletionRequestMessage::User(_)) => system + 1,
+        _ => system,
+    };
+    let mut end = messages.len().saturating_sub(keep_recent);
+    // Tool results stay with the call they answer
+    while end > start
+        && matches!(
+            messages.get(end),
+            Some(ChatCompletionRequestMessage::Tool(_))
+        )
+    {
+        end -= 1;
+    }
+    (end > start).then_some(start..end)
+}
+
+/// Cut the content of every tool result of the `messages` but the latest down to `max_chars`
+/// characters, returning whether any was cut
+pub fn truncate_tool_results(
+    messages: &mut [ChatCompletionRequestMessage],
+    max_chars: usize,
+) -> bool {
+    let latest = messages
+        .iter()
+        .rposition(|message| matches!(message, ChatCompletionRequestMessage::Tool(_)));
+    let mut truncated = false;
+    for (index, message) in messages.iter_mut().enumerate() {
+        let ChatCompletionRequestMessage::Tool(message) = message else {
+            continue;
+        };
+        let ChatCompletionRequestToolMessageContent::Text(content) = &mut message.content else {
+            continue;
+        };
+        // Results cut before are not cut again
+        if Some(index) == latest
+            || content.chars().count() <= max_chars
+            || content.ends_with(TRUNCATED)
+        {
+            continue;
+        }
+        *content = format!(
+            "{}{TRUNCATED}",
+            content.chars().take(max_chars).collect::<String>()
+        );
+        truncated = true;
+    }
+    truncated
+}
+
+/// Request asking the model to summarize the `turns`
+pub fn summary_request(
+    turns: &[ChatCompletionRequestMessage],
+) -> Result<Vec<ChatCompletionRequestMessage>, OpenAIError> {
+    let turns = turns
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(OpenAIError::JSONDeserialize)?;
+    Ok(vec![
+        ChatCompletionRequestSystemMessageArgs::default()
+            .content(SUMMARY_PROMPT)
+            .build()?
+            .into(),
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(turns.join("\n"))
+            .build()?
+            .into(),
+    ])
+}
+
+/// Message of the assistant standing in the conversation for the turns the `summary` was made of
+pub fn summary_message(summary: &str) -> Result<ChatCompletionRequestMessage, OpenAIError> {
+    Ok(ChatCompletionRequestAssistantMessageArgs::default()
+        .content(format!("Summary of the earlier conversation: {summary}"))
+        .build()?
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BasicPlanner, Datastore, Function, PlanningLoop, mock::MockLlm,
+        simulation::SimulatedEnvironment,
+    };
+    use async_openai::types::ChatCompletionRequestToolMessageArgs;
+    use serde_json::json;
+
+    fn tool_result(content: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestToolMessageArgs::default()
+            .content(content)
+            .tool_call_id("call_0")
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn older_tool_results_are_truncated() {
+        let mut messages = vec![
+            tool_result("Hi Bob, see you at 10 AM."),
+            tool_result("Hi Bob!"),
+        ];
+        assert!(truncate_tool_results(&mut messages, 6));
+        let contents: Vec<_> = messages
+            .iter()
+            .map(|message| serde_json::to_value(message).unwrap()["content"].clone())
+            .collect();
+        assert_eq!(contents, [json!("Hi Bob... [truncated]"), json!("Hi Bob!")]);
+        assert!(!truncate_tool_results(&mut messages, 6));
+    }
+
+    #[tokio::test]
+    async fn older_turns_are_summarized_past_the_threshold() {
+        let read = |id| {
+            MockLlm::assistant_tool_call(
+                id,
+                "read_emails",
+                json!({ "count": { "kind": "value", "value": "1" } }),
+            )
+        };
+        let environment = SimulatedEnvironment::new(
+            "Read my latest email twice.",
+            vec![
+                read("call_0"),
+                read("call_1"),
+                MockLlm::assistant_text("Alice confirmed the meeting at 10 AM."),
+                MockLlm::assistant_text("Done."),
+            ],
+        );
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            environment.model(),
+            vec![Function::new("read_emails".to_string())],
+        )
+        .with_context_window(ContextWindow::new(
+            10,
+            Compaction::Summarize { keep_recent: 2 },
+        ));
+        let answer = planning_loop
+            .run(
+                environment.state(),
+                &mut Datastore::default(),
+                environment.request(),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "Done.");
+
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        assert_eq!(requests.len(), 4);
+        // The first read was summarized, while the request and the latest read were kept
+        let summary = serde_json::to_string(&requests[3]).unwrap();
+        assert!(
+            serde_json::to_string(&requests[2])
+                .unwrap()
+                .contains("call_0")
+        );
+        assert!(summary.contains(
+            r#"{"role":"assistant","content":"Summary of the earlier conversation: Alice confirmed"#
+        ));
+        assert!(!summary.contains("call_0"));
+        assert!(summary.contains("call_1"));
+    }
+}
@@ -0,0 +1,437 @@
+//! Files read and written by the `read_file`, `write_file` and `list_dir` tools, which only reach
+//! the files under the root of a [`Sandbox`] in the [`Datastore`].
+//!
+//! Paths are relative to the root, and may not climb out of it, whether through `..` or through a
+//! symbolic link. Each file is labeled by the longest path prefix its real location is under in
+//! the mapping of the sandbox, such as `hr/` being readable by the HR team only while `public/` is
+//! readable by anyone. A symbolic link is labeled like the file it leads to, such that a link from
+//! `public/` into `hr/` does not launder the label of what it leads to. Files under none of the
+//! prefixes are readable by anyone but untrusted, as anything may have been written to them. The
+//! same mapping tells who may read what is written, which is what [`policy_no_leaky_writes`]
+//! checks the labels of writes against.
+//!
+//! [`Datastore`]: crate::Datastore
+//! [`policy_no_leaky_writes`]: crate::policy::policy_no_leaky_writes
+use crate::{
+    Integrity, ProductLattice,
+    ifc::{InverseLattice, PowersetLattice},
+    tools::{EmailAddressUniverse, EmailLabel, INBOX, MetaValue},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt, io,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum SandboxError {
+    IoError(io::Error),
+    // The path leads out of the root of the sandbox
+    OutsideSandbox(String),
+}
+
+impl From<io::Error> for SandboxError {
+    fn from(err: io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "the file could not be accessed ({err})"),
+            Self::OutsideSandbox(path) => write!(f, "`{path}` is outside of the sandbox"),
+        }
+    }
+}
+
+/// Directory the filesystem tools are confined to, along with how its files are labeled
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+    // Labels of the files under each path prefix, relative to the root
+    labels: Vec<(PathBuf, EmailLabel)>,
+    // Addresses the labels range over
+    universe: HashSet<String>,
+}
+
+impl Sandbox {
+    /// Sandbox of the files under `root`, labeled over the addresses of the demo `INBOX`
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            labels: vec![],
+            universe: EmailAddressUniverse::new(&INBOX).into_inner(),
+        }
+    }
+
+    /// Label the files under the `prefix` with `label`, unless a longer prefix labels them
+    pub fn with_label(mut self, prefix: &str, label: EmailLabel) -> Self {
+        // Prefixes climbing out of the root would label no file
+        if let Ok(prefix) = relative(prefix) {
+            self.labels.push((prefix, label));
+        }
+        self
+    }
+
+    /// Label the files under the `prefix` as trusted and only readable by the `readers`
+    pub fn with_readers(self, prefix: &str, readers: &[&str]) -> Self {
+        let readers = readers
+            .iter()
+            .filter(|reader| self.universe.contains(**reader))
+            .map(|reader| reader.to_string())
+            .collect();
+        let readers = PowersetLattice::new(readers, self.universe.clone())
+            .expect("Readers are filtered to the universe");
+        let label = ProductLattice::new(Integrity::trusted(), InverseLattice::new(readers));
+        self.with_label(prefix, label)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Label of the file at `path`, going by the longest prefix of the mapping its real location is
+    /// under once symbolic links are followed
+    pub fn label(&self, path: &str) -> Result<EmailLabel, SandboxError> {
+        Ok(self.label_of(&self.locate(path)?))
+    }
+
+    // Label of the file at the real `path`, relative to the root
+    fn label_of(&self, path: &Path) -> EmailLabel {
+        let label = self
+            .labels
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, label)| label.clone());
+        label.unwrap_or_else(|| {
+            ProductLattice::new(
+                Integrity::untrusted(),
+                InverseLattice::new(PowersetLattice::top(self.universe.clone())),
+            )
+        })
+    }
+
+    // Real location of the file at `path` relative to the root, once symbolic links are followed,
+    // making sure that they do not lead out of the root
+    fn locate(&self, path: &str) -> Result<PathBuf, SandboxError> {
+        let full = self.root.join(relative(path)?);
+        let root = std::fs::canonicalize(&self.root)?;
+        // Files about to be written do not exist yet, so the closest directory holding them is
+        // followed instead
+        for ancestor in full.ancestors() {
+            match std::fs::canonicalize(ancestor) {
+                Ok(real) => {
+                    let rest = full.strip_prefix(ancestor).expect("Ancestors are prefixes");
+                    return match real.strip_prefix(&root) {
+                        // Joining an empty path would end the path with a separator
+                        Ok(real) if rest.as_os_str().is_empty() => Ok(real.to_path_buf()),
+                        Ok(real) => Ok(real.join(rest)),
+                        Err(_) => Err(SandboxError::OutsideSandbox(path.to_string())),
+                    };
+                }
+                // A link leading nowhere would have the write create what it leads to, wherever
+                // that is, so only missing components are skipped
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    if std::fs::symlink_metadata(ancestor).is_ok() {
+                        return Err(SandboxError::OutsideSandbox(path.to_string()));
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err(SandboxError::OutsideSandbox(path.to_string()))
+    }
+
+    // Real location of the file at `path` on disk along with its label, following the symbolic
+    // links off the runtime like `tokio::fs` does
+    async fn resolve(&self, path: &str) -> Result<(PathBuf, EmailLabel), SandboxError> {
+        let sandbox = self.clone();
+        let path = path.to_string();
+        let real = tokio::task::spawn_blocking(move || sandbox.locate(&path))
+            .await
+            .map_err(io::Error::other)??;
+        Ok((self.root.join(&real), self.label_of(&real)))
+    }
+
+    pub async fn read(&self, args: ReadFileArgs) -> Result<FileContent, SandboxError> {
+        let (real, _) = self.resolve(&args.path).await?;
+        let content = tokio::fs::read_to_string(real).await?;
+        Ok(FileContent {
+            path: args.path,
+            content,
+        })
+    }
+
+    /// Write the content of the `args`, creating the directories leading to the file if need be
+    pub async fn write(&self, args: WriteFileArgs) -> Result<FileWritten, SandboxError> {
+        let (full, _) = self.resolve(&args.path).await?;
+        if let Some(parent) = full.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&full, &args.content).await?;
+        Ok(FileWritten {
+            path: args.path,
+            bytes: args.content.len(),
+        })
+    }
+
+    pub async fn list(&self, args: ListDirArgs) -> Result<DirListing, SandboxError> {
+        let (real, _) = self.resolve(&args.path).await?;
+        let mut dir = tokio::fs::read_dir(real).await?;
+        let mut entries = vec![];
+        while let Some(entry) = dir.next_entry().await? {
+            entries.push(DirEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                dir: entry.file_type().await?.is_dir(),
+            });
+        }
+        entries.sort_by(|left, right| left.name.cmp(&right.name));
+        Ok(DirListing {
+            path: args.path,
+            entries,
+        })
+    }
+
+    pub async fn read_labeled(
+        &self,
+        args: ReadFileArgs,
+    ) -> Result<MetaValue<FileContent, EmailLabel>, SandboxError> {
+        let (_, label) = self.resolve(&args.path).await?;
+        Ok(MetaValue::new(self.read(args).await?, label))
+    }
+
+    /// Write the content of the `args`, labeling the outcome like the file written. The write
+    /// itself is only kept from leaking by the policy checking it beforehand.
+    pub async fn write_labeled(
+        &self,
+        args: WriteFileArgs,
+    ) -> Result<MetaValue<FileWritten, EmailLabel>, SandboxError> {
+        let (_, label) = self.resolve(&args.path).await?;
+        Ok(MetaValue::new(self.write(args).await?, label))
+    }
+
+    /// List the directory of the `args`. The names of its entries are labeled like the directory,
+    /// not like the files they name.
+    pub async fn list_labeled(
+        &self,
+        args: ListDirArgs,
+    ) -> Result<MetaValue<DirListing, EmailLabel>, SandboxError> {
+        let (_, label) = self.resolve(&args.path).await?;
+        Ok(MetaValue::new(self.list(args).await?, label))
+    }
+}
+
+// The `path` relative to the root of the sandbox. Absolute paths start at the root, and paths
+// climbing above it are refused.
+fn relative(path: &str) -> Result<PathBuf, SandboxError> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(SandboxError::OutsideSandbox(path.to_string()));
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// Arguments for reading a file of the sandbox
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReadFileArgs {
+    path: String,
+}
+
+impl ReadFileArgs {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileContent {
+    path: String,
+    content: String,
+}
+
+impl FileContent {
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+}
+
+/// Arguments for writing a file of the sandbox, replacing its content
+#[derive(Deserialize, Clone, Debug)]
+pub struct WriteFileArgs {
+    path: String,
+    content: String,
+}
+
+impl WriteFileArgs {
+    pub fn new(path: String, content: String) -> Self {
+        Self { path, content }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileWritten {
+    path: String,
+    bytes: usize,
+}
+
+/// Arguments for listing a directory of the sandbox, which is its root unless told otherwise
+#[derive(Deserialize, Clone, Debug)]
+pub struct ListDirArgs {
+    #[serde(default)]
+    path: String,
+}
+
+impl ListDirArgs {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DirEntry {
+    name: String,
+    dir: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DirListing {
+    path: String,
+    entries: Vec<DirEntry>,
+}
+
+impl DirListing {
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|entry| entry.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, Call, Datastore, MetaFunction, function::ToolError};
+    use serde_json::{Value, json};
+
+    #[tokio::test]
+    async fn files_are_confined_and_labeled_by_path() {
+        let root = std::env::temp_dir().join(format!("gentlemen-sandbox-{}", std::process::id()));
+        tokio::fs::create_dir_all(root.join("hr")).await.unwrap();
+        tokio::fs::write(root.join("hr/salaries.txt"), "Bob: 100")
+            .await
+            .unwrap();
+        let sandbox = Sandbox::new(&root)
+            .with_readers("hr", &["alice.hudson@magnet.com"])
+            .with_readers("hr/public", &["alice.hudson@magnet.com", "eve@evil.com"]);
+        let mut datastore = Datastore::default().with_sandbox(sandbox.clone());
+
+        let (result, label) = MetaFunction::new("read_file_labeled".to_string())
+            .call(
+                Args::new(json!({ "path": "/hr/salaries.txt" }).to_string()),
+                &mut datastore,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&result).unwrap()["content"],
+            "Bob: 100"
+        );
+        assert_eq!(
+            label.lattice2().inner().subset(),
+            &HashSet::from(["alice.hudson@magnet.com".to_string()])
+        );
+        // Unknown readers are left out, and the longest prefix wins
+        assert_eq!(
+            sandbox.label("hr/public/faq.txt").unwrap(),
+            sandbox.label("hr/x").unwrap()
+        );
+        assert_eq!(
+            sandbox
+                .label("notes.txt")
+                .unwrap()
+                .lattice2()
+                .inner()
+                .subset()
+                .len(),
+            sandbox.universe.len()
+        );
+
+        sandbox
+            .write(WriteFileArgs::new(
+                "notes/today.txt".to_string(),
+                "Call Alice".to_string(),
+            ))
+            .await
+            .unwrap();
+        let listing = sandbox.list(ListDirArgs::new(String::new())).await.unwrap();
+        assert_eq!(listing.names().collect::<Vec<_>>(), vec!["hr", "notes"]);
+        // Anything may have been written to files outside of the mapping
+        assert_eq!(
+            sandbox.label("notes/today.txt").unwrap().lattice1(),
+            &Integrity::untrusted()
+        );
+
+        for path in ["../secrets.txt", "hr/../../secrets.txt"] {
+            let escaped = sandbox.read(ReadFileArgs::new(path.to_string())).await;
+            assert!(matches!(escaped, Err(SandboxError::OutsideSandbox(_))));
+        }
+        #[cfg(unix)]
+        {
+            // Links are labeled like what they lead to
+            tokio::fs::symlink(root.join("hr"), root.join("shared"))
+                .await
+                .unwrap();
+            assert_eq!(
+                sandbox.label("shared/salaries.txt").unwrap(),
+                sandbox.label("hr/salaries.txt").unwrap()
+            );
+            tokio::fs::symlink(std::env::temp_dir(), root.join("tmp"))
+                .await
+                .unwrap();
+            let escaped = sandbox
+                .write(WriteFileArgs::new("tmp/x".to_string(), String::new()))
+                .await;
+            assert!(matches!(escaped, Err(SandboxError::OutsideSandbox(_))));
+            // Links leading nowhere are not followed by the writes either
+            let outside = std::env::temp_dir().join(format!(
+                "gentlemen-sandbox-{}-outside.txt",
+                std::process::id()
+            ));
+            tokio::fs::symlink(&outside, root.join("hr/dangling"))
+                .await
+                .unwrap();
+            let escaped = sandbox
+                .write(WriteFileArgs::new(
+                    "hr/dangling".to_string(),
+                    "Bob: 100".to_string(),
+                ))
+                .await;
+            assert!(matches!(escaped, Err(SandboxError::OutsideSandbox(_))));
+            let escaped = MetaFunction::new("write_file_labeled".to_string())
+                .call(
+                    Args::new(
+                        json!({ "path": "hr/dangling/x.txt", "content": "Bob: 100" }).to_string(),
+                    ),
+                    &mut datastore,
+                )
+                .await;
+            assert!(matches!(
+                escaped,
+                Err(ToolError::PermissionDenied(reason)) if reason.contains("outside of the sandbox")
+            ));
+            assert!(!outside.exists());
+        }
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -1,102 +1,623 @@
 use crate::Datastore;
+use crate::authority::{Authority, AuthorityError};
+use crate::files::{ListDirArgs, ReadFileArgs, Sandbox, SandboxError, WriteFileArgs};
+use crate::ifc::{Integrity, Lattice, LatticeError, MetaValue};
+use crate::mail::MailError;
+use crate::secrets::Secrets;
+use crate::store::StoreError;
 use crate::tools::{
-    EmailLabel, ReadEmailsArgs, SendSlackMessageArgs, read_emails, send_slack_message,
+    CheckUrlArgs, CreateEventArgs, EmailLabel, FetchUrlArgs, GetMessageStatusArgs,
+    ReadCalendarArgs, ReadEmailsArgs, SendEmailArgs, SendSlackMessageArgs, SummarizeArgs,
+    WebSearchArgs, check_url, check_url_labeled, create_event, create_event_labeled, fetch_url,
+    fetch_url_labeled, get_message_status, get_message_status_labeled, read_calendar,
+    read_calendar_labeled, read_emails, send_email, send_email_labeled, send_slack_message,
+    send_slack_message_labeled, sent_slack_message, sent_slack_message_labeled, service_authority,
+    summarize_labeled, web_search, web_search_labeled,
 };
+use crate::validate::{ValidationError, Validator, validate_all};
+use serde::Serialize;
+use serde_json::Value;
 use std::fmt;
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct Function(String);
+/// Labeled tools acting on the world, whose labeled results are recorded in the datastore
+pub const EFFECTS: &[&str] = &[
+    "send_slack_message_labeled",
+    "send_email_labeled",
+    "create_event_labeled",
+    "write_file_labeled",
+];
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    name: String,
+    // Secrets the function may use to reach external services, injected when the function is
+    // registered as a tool in the planning loop.
+    secrets: Option<Secrets>,
+    // Checks the arguments must pass before the function is called
+    validators: Vec<Validator>,
+}
 
 impl Function {
-    pub fn new(inner: String) -> Self {
-        Self(inner)
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            secrets: None,
+            validators: vec![],
+        }
+    }
+
+    /// Give the function access to `secrets`. Any secret the function reads is redacted from its
+    /// results.
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = Some(secrets);
+        self
     }
 
     pub fn name(&self) -> &str {
-        self.0.as_ref()
+        self.name.as_ref()
+    }
+
+    pub fn secrets(&self) -> Option<&Secrets> {
+        self.secrets.as_ref()
+    }
+
+    /// Check the arguments with `validator` before calling the function
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validators.push(validator);
+        self
+    }
+}
+
+// Functions are identified by name only, such that a function requested by the planner matches the
+// registered tool regardless of the secrets the latter holds.
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+// Scrub the secrets handed out by `secrets` from the `output` of a tool.
+fn redact(secrets: Option<&Secrets>, output: String) -> String {
+    match secrets {
+        Some(secrets) => secrets.redact(&output),
+        None => output,
+    }
+}
+
+/// Error issued by a tool which could not be called
+#[derive(Debug)]
+pub enum ToolError {
+    // No tool goes by the given name
+    UnknownTool(String),
+    // The arguments do not parse into the ones of the tool
+    InvalidArgs(serde_json::Error),
+    // The caller may not do what the call asks for, with the reason
+    PermissionDenied(String),
+    // The tool ran but failed, such as when the service it reaches is down
+    Failed(String),
+}
+
+impl From<serde_json::Error> for ToolError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::InvalidArgs(err)
+    }
+}
+
+impl From<MailError> for ToolError {
+    fn from(err: MailError) -> Self {
+        Self::Failed(err.to_string())
+    }
+}
+
+impl From<LatticeError> for ToolError {
+    fn from(err: LatticeError) -> Self {
+        Self::Failed(format!("the result could not be labeled ({err:?})"))
+    }
+}
+
+impl From<AuthorityError> for ToolError {
+    fn from(err: AuthorityError) -> Self {
+        match err {
+            AuthorityError::CannotEndorse(principal) => {
+                Self::PermissionDenied(format!("`{}` may not vouch for data", principal.name()))
+            }
+            AuthorityError::CannotDeclassify { principal, reader } => Self::PermissionDenied(
+                format!("`{}` may not release data to {reader}", principal.name()),
+            ),
+            AuthorityError::LatticeError(err) => Self::Failed(format!("{err:?}")),
+        }
+    }
+}
+
+impl fmt::Display for ToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownTool(name) => write!(f, "there is no tool called `{name}`"),
+            Self::InvalidArgs(err) => write!(f, "the arguments are invalid ({err})"),
+            Self::PermissionDenied(reason) => write!(f, "permission was denied ({reason})"),
+            Self::Failed(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl ToolError {
+    /// Tool result telling the model that its call to `function` failed, and whether calling it
+    /// again may help
+    pub fn failure_message(&self, function: &str) -> String {
+        let advice = match self {
+            Self::UnknownTool(_) => "Only call the tools you were given.".to_string(),
+            Self::InvalidArgs(_) => format!("Fix the arguments and call `{function}` again."),
+            Self::PermissionDenied(_) => format!("Do not call `{function}` like this again."),
+            Self::Failed(_) => format!("Calling `{function}` again may succeed."),
+        };
+        format!("The call to `{function}` failed because {self}. {advice}")
+    }
+}
+
+impl From<StoreError> for ToolError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::NotSanctioned(_) => Self::PermissionDenied(err.to_string()),
+            StoreError::CannotRead(_) | StoreError::UnknownHandle(_) => {
+                Self::Failed(err.to_string())
+            }
+        }
     }
 }
 
+impl From<SandboxError> for ToolError {
+    fn from(err: SandboxError) -> Self {
+        match err {
+            SandboxError::OutsideSandbox(_) => Self::PermissionDenied(err.to_string()),
+            SandboxError::IoError(_) => Self::Failed(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "slack")]
+impl From<crate::slack::SlackError> for ToolError {
+    fn from(err: crate::slack::SlackError) -> Self {
+        Self::Failed(err.to_string())
+    }
+}
+
+// Deliver the message of the `args` through the Slack client of the `datastore`, if it has one,
+// returning the id of the send
+#[cfg(feature = "slack")]
+async fn deliver(
+    datastore: &Datastore,
+    args: &SendSlackMessageArgs,
+) -> Result<Option<String>, ToolError> {
+    match datastore.slack() {
+        Some(slack) => Ok(Some(slack.send(args).await?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "slack"))]
+async fn deliver(
+    _datastore: &Datastore,
+    _args: &SendSlackMessageArgs,
+) -> Result<Option<String>, ToolError> {
+    Ok(None)
+}
+
+// Serialize the `result` of a tool for the model. Results which cannot be serialized are a fault
+// of the tool rather than of its arguments.
+fn to_output<T: Serialize>(result: &T) -> Result<String, ToolError> {
+    serde_json::to_string(result).map_err(|err| ToolError::Failed(err.to_string()))
+}
+
+// Sandbox of the `datastore` the filesystem tools are confined to
+fn sandbox(datastore: &Datastore) -> Result<&Sandbox, ToolError> {
+    datastore.sandbox().ok_or(ToolError::PermissionDenied(
+        "no directory was opened to the filesystem tools".to_string(),
+    ))
+}
+
+// Failure of fetching a `url` the web has no page at
+fn no_page(url: &str) -> ToolError {
+    ToolError::Failed(format!("there is no page at `{url}`"))
+}
+
 pub trait Call {
     type Args;
     type Output;
-    fn call(&self, args: Self::Args, _datastore: &mut Datastore) -> Self::Output;
+    /// Call the tool with `args`. Tools reaching other services await them rather than blocking
+    /// the runtime.
+    fn call(
+        &self,
+        args: Self::Args,
+        datastore: &mut Datastore,
+    ) -> impl Future<Output = Result<Self::Output, ToolError>> + Send;
+    /// Check the semantics of `args` before they are passed to `call`
+    fn validate(&self, _args: &Self::Args) -> Result<(), ValidationError> {
+        Ok(())
+    }
 }
 
 impl Call for Function {
     type Args = Args;
     type Output = String;
+
+    fn validate(&self, args: &Self::Args) -> Result<(), ValidationError> {
+        validate_all(&self.validators, args)
+    }
+
     // A function reads from and writes to a global datastore. This allows for interaction between
     // tools and capture side effects through update to the datastore.
     // Currently in this model we return an updated datastore.
-    fn call(&self, args: Self::Args, _datastore: &mut Datastore) -> Self::Output {
-        match self.0.as_str() {
+    async fn call(&self, args: Self::Args, datastore: &mut Datastore) -> Result<String, ToolError> {
+        let result = match self.name.as_str() {
             "read_emails" => {
                 // Convert args to desired type
-                let args: ReadEmailsArgs = serde_json::from_str(&args.0).unwrap();
-                let result = read_emails(args);
-                println!("{result:?}");
-                serde_json::to_string(&result).unwrap()
+                let args: ReadEmailsArgs = serde_json::from_str(args.value())?;
+                let result = match datastore.mailbox() {
+                    Some(mailbox) => mailbox.read(args).await?,
+                    None => read_emails(args),
+                };
+                to_output(&result)?
             }
             "send_slack_message" => {
-                let args: SendSlackMessageArgs = serde_json::from_str(&args.0).unwrap();
-                let result = send_slack_message(args);
-                println!("{result:?}");
-                serde_json::to_string(&result).unwrap()
+                let args: SendSlackMessageArgs = serde_json::from_str(args.value())?;
+                let result = match deliver(datastore, &args).await? {
+                    Some(send_id) => sent_slack_message(send_id),
+                    None => send_slack_message(args),
+                };
+                to_output(&result)?
+            }
+            "send_email" => {
+                let args: SendEmailArgs = serde_json::from_str(args.value())?;
+                to_output(&send_email(args))?
+            }
+            "get_message_status" => {
+                let args: GetMessageStatusArgs = serde_json::from_str(args.value())?;
+                let result = get_message_status(args);
+                to_output(&result)?
+            }
+            "check_url" => {
+                let args: CheckUrlArgs = serde_json::from_str(args.value())?;
+                let result = check_url(args);
+                to_output(&result)?
+            }
+            "read_calendar" => {
+                let args: ReadCalendarArgs = serde_json::from_str(args.value())?;
+                let result = read_calendar(args);
+                to_output(&result)?
+            }
+            "create_event" => {
+                let args: CreateEventArgs = serde_json::from_str(args.value())?;
+                let result = create_event(args);
+                to_output(&result)?
+            }
+            "web_search" => {
+                let args: WebSearchArgs = serde_json::from_str(args.value())?;
+                let result = web_search(args);
+                to_output(&result)?
+            }
+            "fetch_url" => {
+                let args: FetchUrlArgs = serde_json::from_str(args.value())?;
+                let url = args.url().to_string();
+                let page = fetch_url(args).ok_or_else(|| no_page(&url))?;
+                to_output(&page)?
+            }
+            "read_file" => {
+                let args: ReadFileArgs = serde_json::from_str(args.value())?;
+                let result = sandbox(datastore)?.read(args).await?;
+                to_output(&result)?
+            }
+            "write_file" => {
+                let args: WriteFileArgs = serde_json::from_str(args.value())?;
+                let result = sandbox(datastore)?.write(args).await?;
+                to_output(&result)?
+            }
+            "list_dir" => {
+                let args: ListDirArgs = serde_json::from_str(args.value())?;
+                let result = sandbox(datastore)?.list(args).await?;
+                to_output(&result)?
+            }
+            name => return Err(ToolError::UnknownTool(name.to_string())),
+        };
+        // Redact before the result gets anywhere near the logs or the conversation
+        let result = redact(self.secrets(), result);
+        println!("{result}");
+        Ok(result)
+    }
+}
+
+/// How the label of a tool's result is derived from the label of the inputs of the call, which
+/// the loop dispatching the call applies after the tool ran. Labels are only ever lowered through
+/// the authority the call runs with.
+#[derive(Debug, Clone, Default)]
+pub enum LabelPropagation {
+    /// Keep the label the tool gives its result
+    #[default]
+    Tool,
+    /// Vouch for the result as far as the authority of the call goes, for tools reporting what the
+    /// service they reach produces itself, such as the status of a send
+    Endorsed,
+    /// Label the result with the join of the labels of the inputs, for tools which only pass
+    /// their inputs along
+    JoinOfInputs,
+    /// Give every result the same label, regardless of the inputs
+    Constant(EmailLabel),
+    /// Derive the label from that of the inputs and the one the tool gave its result
+    Custom(fn(&EmailLabel, EmailLabel) -> Option<EmailLabel>),
+}
+
+impl LabelPropagation {
+    /// Propagation of the built-in tool called `name`
+    pub fn of(name: &str) -> Self {
+        match name {
+            "send_slack_message_labeled"
+            | "send_email_labeled"
+            | "get_message_status_labeled"
+            | "check_url_labeled"
+            | "create_event_labeled" => Self::Endorsed,
+            _ => Self::Tool,
+        }
+    }
+
+    /// Label of a result the tool labeled `output`, given the label of the `inputs` of the call
+    /// and the `authority` it ran with. Returns `None` if the labels cannot be combined.
+    pub fn propagate(
+        &self,
+        inputs: &EmailLabel,
+        output: EmailLabel,
+        authority: &Authority,
+    ) -> Option<EmailLabel> {
+        match self {
+            Self::Tool => Some(output),
+            Self::Endorsed => Some(authority.endorse(output.clone()).unwrap_or(output)),
+            Self::JoinOfInputs => derive(inputs.clone(), output, authority),
+            Self::Constant(label) => derive(label.clone(), output, authority),
+            Self::Custom(derive_label) => {
+                derive(derive_label(inputs, output.clone())?, output, authority)
             }
-            _ => panic!("{:?}", self.0),
         }
     }
 }
 
+// Label `derived` for a result the tool labeled `output`. The result keeps the restrictions of
+// `output` unless the `authority` may lift them, by vouching for the result or by releasing it to
+// the readers of `derived`.
+fn derive(derived: EmailLabel, output: EmailLabel, authority: &Authority) -> Option<EmailLabel> {
+    let label = derived.clone().join(output)?;
+    let label = match derived.lattice1() == &Integrity::trusted() {
+        true => authority.endorse(label.clone()).unwrap_or(label),
+        false => label,
+    };
+    let readers = derived.lattice2().inner().subset().clone();
+    Some(
+        authority
+            .declassify(label.clone(), readers)
+            .unwrap_or(label),
+    )
+}
+
 /// Similar with `Function` but we return the result of the function call along with the `Label` of
 /// the result
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub struct MetaFunction {
     name: String,
+    // Secrets the function may use to reach external services
+    secrets: Option<Secrets>,
+    // Checks the arguments must pass before the function is called
+    validators: Vec<Validator>,
+    // How the label of the result is derived from the labels of the inputs
+    propagation: LabelPropagation,
+    // Authority granted to the function itself, which it runs with instead of the one of the loop
+    authority: Option<Authority>,
+}
+
+impl PartialEq for MetaFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
 }
 
 impl Call for MetaFunction {
     type Args = Args;
     type Output = (String, EmailLabel);
+
+    fn validate(&self, args: &Self::Args) -> Result<(), ValidationError> {
+        validate_all(&self.validators, args)
+    }
+
     // A function reads from and writes to a global datastore. This allows for interaction between
     // tools and capture side effects through update to the datastore.
-    // Currently in this model we return an updated datastore.
-    fn call(&self, args: Self::Args, _datastore: &mut Datastore) -> Self::Output {
-        match self.name.as_ref() {
+    // The results of the calls acting on the world are kept in its store, labeled.
+    async fn call(
+        &self,
+        args: Self::Args,
+        datastore: &mut Datastore,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        self.call_with_authority(args, datastore, &service_authority())
+            .await
+    }
+}
+
+impl MetaFunction {
+    /// Call the function with the `authority` it runs with, such as to dereference quarantined
+    /// results. What the function produces itself is labeled untrusted, until its
+    /// [`LabelPropagation`] vouches for it through the authority. Functions granted an authority
+    /// of their own run with it instead.
+    pub async fn call_with_authority(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        authority: &Authority,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        self.dispatch(args, datastore, authority, None).await
+    }
+
+    /// Call the function with the `authority` it runs with, given the label of the `inputs` of the
+    /// call. Functions repeating their inputs in their results, such as `check_url_labeled`, keep
+    /// them readable only by those who may read the inputs.
+    pub async fn call_with_inputs(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        authority: &Authority,
+        inputs: &EmailLabel,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        self.dispatch(args, datastore, authority, Some(inputs))
+            .await
+    }
+
+    async fn dispatch(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        authority: &Authority,
+        inputs: Option<&EmailLabel>,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        let authority = self.authority.as_ref().unwrap_or(authority);
+        let (result, label) = match self.name.as_ref() {
             "read_emails_labeled" => {
                 // Convert args to desired type
-                let args: ReadEmailsArgs = serde_json::from_str(&args.0).unwrap();
-                let (value, label) = crate::tools::read_emails_labeled(args, &crate::tools::INBOX)
-                    .into_inner()
-                    .into_raw_parts();
-                let value = value
-                    .into_iter()
-                    .map(|mv| format!("{:?}", mv.value()))
-                    .collect::<Vec<_>>();
-                (serde_json::to_string(&value).unwrap(), label)
+                let args: ReadEmailsArgs = serde_json::from_str(args.value())?;
+                let emails = match datastore.mailbox() {
+                    Some(mailbox) => mailbox.read_labeled(args).await?,
+                    None => crate::tools::read_emails_labeled(args, &crate::tools::INBOX),
+                };
+                let (value, label) = emails.into_inner().into_raw_parts();
+                let value = value.iter().map(|mv| mv.value()).collect::<Vec<_>>();
+                (to_output(&value)?, label)
             }
             "send_slack_message_labeled" => {
                 // Convert args to desired type
-                let args: SendSlackMessageArgs = serde_json::from_str(&args.0).unwrap();
-
-                let (value, label) = crate::tools::send_slack_message_labeled(args)
-                    .into_inner()
-                    .into_raw_parts();
+                let args: SendSlackMessageArgs = serde_json::from_str(args.value())?;
+                let sent = match deliver(datastore, &args).await? {
+                    Some(send_id) => sent_slack_message_labeled(send_id),
+                    None => send_slack_message_labeled(args),
+                };
+                datastore.record_send(sent.send_id().to_string());
+                let (value, label) = sent.into_inner().into_raw_parts();
 
-                (serde_json::to_string(&value).unwrap(), label)
+                (to_output(&value)?, label)
+            }
+            "send_email_labeled" => {
+                let args: SendEmailArgs = serde_json::from_str(args.value())?;
+                let sent = send_email_labeled(args);
+                datastore.record_send(sent.send_id().to_string());
+                sent.into_inner().into_raw_parts()
+            }
+            "get_message_status_labeled" => {
+                let args: GetMessageStatusArgs = serde_json::from_str(args.value())?;
+                get_message_status_labeled(args).into_raw_parts()
+            }
+            "check_url_labeled" => {
+                let args: CheckUrlArgs = serde_json::from_str(args.value())?;
+                check_url_labeled(args, inputs).into_raw_parts()
+            }
+            "read_calendar_labeled" => {
+                let args: ReadCalendarArgs = serde_json::from_str(args.value())?;
+                let (value, label) = read_calendar_labeled(args)?.into_raw_parts();
+                let value = value.iter().map(|mv| mv.value()).collect::<Vec<_>>();
+                (to_output(&value)?, label)
+            }
+            "create_event_labeled" => {
+                let args: CreateEventArgs = serde_json::from_str(args.value())?;
+                let (value, label) = create_event_labeled(args)?.into_raw_parts();
+                (to_output(&value)?, label)
+            }
+            // Results of the web are untrusted whatever the authority of the call
+            "web_search_labeled" => {
+                let args: WebSearchArgs = serde_json::from_str(args.value())?;
+                let (value, label) = web_search_labeled(args).into_raw_parts();
+                (to_output(&value)?, label)
             }
-            _ => {
-                println!("Trying to call function {:#?}", self.name);
-                todo!()
+            "fetch_url_labeled" => {
+                let args: FetchUrlArgs = serde_json::from_str(args.value())?;
+                let url = args.url().to_string();
+                let page = fetch_url_labeled(args).ok_or_else(|| no_page(&url))?;
+                let (value, label) = page.into_raw_parts();
+                (to_output(&value)?, label)
             }
+            // Only the handle of the result is passed, which the summarizer dereferences with the
+            // authority of the loop
+            "summarize_labeled" => {
+                let args: SummarizeArgs = serde_json::from_str(args.value())?;
+                summarize_labeled(args, datastore.quarantine(), authority)?.into_raw_parts()
+            }
+            "read_file_labeled" => {
+                let args: ReadFileArgs = serde_json::from_str(args.value())?;
+                let file = sandbox(datastore)?.read_labeled(args).await?;
+                let (value, label) = file.into_raw_parts();
+                (to_output(&value)?, label)
+            }
+            "write_file_labeled" => {
+                let args: WriteFileArgs = serde_json::from_str(args.value())?;
+                let written = sandbox(datastore)?.write_labeled(args).await?;
+                let (value, label) = written.into_raw_parts();
+                (to_output(&value)?, label)
+            }
+            "list_dir_labeled" => {
+                let args: ListDirArgs = serde_json::from_str(args.value())?;
+                let listing = sandbox(datastore)?.list_labeled(args).await?;
+                let (value, label) = listing.into_raw_parts();
+                (to_output(&value)?, label)
+            }
+            name => return Err(ToolError::UnknownTool(name.to_string())),
+        };
+        let result = redact(self.secrets(), result);
+        if EFFECTS.contains(&self.name()) {
+            let value = serde_json::from_str(&result).unwrap_or(Value::String(result.clone()));
+            datastore.record_effect(self.name(), MetaValue::new(value, label.clone()));
         }
+        Ok((result, label))
     }
 }
 
 impl MetaFunction {
     pub fn new(name: String) -> Self {
-        Self { name }
+        Self {
+            propagation: LabelPropagation::of(&name),
+            name,
+            secrets: None,
+            validators: vec![],
+            authority: None,
+        }
+    }
+
+    /// Give the function access to `secrets`. Any secret the function reads is redacted from its
+    /// results.
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = Some(secrets);
+        self
+    }
+
+    pub fn secrets(&self) -> Option<&Secrets> {
+        self.secrets.as_ref()
+    }
+
+    /// Check the arguments with `validator` before calling the function
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validators.push(validator);
+        self
+    }
+
+    /// Derive the label of the function's results with `propagation` instead of keeping the label
+    /// the function gives them
+    pub fn with_label_propagation(mut self, propagation: LabelPropagation) -> Self {
+        self.propagation = propagation;
+        self
+    }
+
+    pub fn label_propagation(&self) -> &LabelPropagation {
+        &self.propagation
+    }
+
+    /// Run the function with its own `authority` rather than the one of the loop calling it, such
+    /// as to let only the tool reporting the statuses of a trusted service vouch for them
+    pub fn with_authority(mut self, authority: Authority) -> Self {
+        self.authority = Some(authority);
+        self
+    }
+
+    pub fn authority(&self) -> Option<&Authority> {
+        self.authority.as_ref()
     }
 
     pub fn name(&self) -> &str {
@@ -104,8 +625,23 @@ impl MetaFunction {
     }
 }
 
+/// Arguments of a function call, as the JSON object serialized by the model
 #[derive(Clone, Debug)]
-pub struct Args(pub String);
+pub struct Args(String);
+
+impl Args {
+    pub fn new(args: String) -> Self {
+        Self(args)
+    }
+
+    pub fn value(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
 
 #[derive(Clone)]
 pub enum Arg {
@@ -1,4 +1,18 @@
-use std::{cmp::Ordering, collections::HashSet, hash::Hash};
+use crate::sealed::Sealed;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Number, Value};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+    },
+};
+
+// Source of unique epochs for freshly constructed powerset labels.
+static POWERSET_EPOCH: AtomicU64 = AtomicU64::new(0);
 
 pub trait Lattice: PartialOrd + Sized + Clone + std::fmt::Debug {
     /// Returns the least upper bound between `self` and `other` values
@@ -7,7 +21,57 @@ pub trait Lattice: PartialOrd + Sized + Clone + std::fmt::Debug {
     fn meet(self, other: Self) -> Option<Self>;
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+/// Lattice with a least and a greatest value, such that labels can be combined starting from
+/// nothing
+pub trait BoundedLattice: Lattice {
+    /// Returns the greatest value of the lattice, which every value flows to
+    fn top() -> Self;
+    /// Returns the least value of the lattice, which flows to every value
+    fn bottom() -> Self;
+
+    /// Returns the join of all the `values`, which is the bottom of the lattice if there are none
+    fn join_all<I: IntoIterator<Item = Self>>(values: I) -> Option<Self> {
+        values.into_iter().try_fold(Self::bottom(), Self::join)
+    }
+
+    /// Returns the meet of all the `values`, which is the top of the lattice if there are none
+    fn meet_all<I: IntoIterator<Item = Self>>(values: I) -> Option<Self> {
+        values.into_iter().try_fold(Self::top(), Self::meet)
+    }
+}
+
+// Join of 2 values of a totally ordered lattice, which is the greatest of them
+fn total_join<T: PartialOrd>(a: T, b: T) -> T {
+    if a <= b { b } else { a }
+}
+
+// Meet of 2 values of a totally ordered lattice, which is the least of them
+fn total_meet<T: PartialOrd>(a: T, b: T) -> T {
+    if a <= b { a } else { b }
+}
+
+/// A cheap 64-bit summary of a label. Labels with different fingerprints are never equal, which
+/// lets equality and ordering checks on hot paths (e.g. every step of a long trace) skip walking
+/// the underlying sets.
+pub trait Fingerprint: Sealed {
+    fn fingerprint(&self) -> u64;
+}
+
+// Mix two fingerprints into one such that the order of the operands matters.
+fn combine(a: u64, b: u64) -> u64 {
+    a.rotate_left(5).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ b
+}
+
+// Order independent hash of a set, built by summing the hashes of its elements.
+fn set_fingerprint<T: Hash>(set: &HashSet<T>) -> u64 {
+    set.iter().fold(0u64, |acc, item| {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        acc.wrapping_add(hasher.finish())
+    })
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
 pub enum Confidentiality {
     // Public information
     Low = 0,
@@ -17,11 +81,29 @@ pub enum Confidentiality {
 
 impl Lattice for Confidentiality {
     fn join(self, other: Self) -> Option<Self> {
-        Some(if self <= other { other } else { self })
+        Some(total_join(self, other))
     }
 
     fn meet(self, other: Self) -> Option<Self> {
-        Some(if self <= other { self } else { other })
+        Some(total_meet(self, other))
+    }
+}
+
+impl BoundedLattice for Confidentiality {
+    fn top() -> Self {
+        Self::High
+    }
+
+    fn bottom() -> Self {
+        Self::Low
+    }
+}
+
+impl Sealed for Confidentiality {}
+
+impl Fingerprint for Confidentiality {
+    fn fingerprint(&self) -> u64 {
+        self.clone() as u64
     }
 }
 
@@ -35,7 +117,131 @@ impl Confidentiality {
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+/// Ordered names of the classification levels of an organization, from the least to the most
+/// restricted, such as `Public < Internal < Confidential < Secret`
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct ClassificationScale(Vec<String>);
+
+// Scales read back are checked like the ones built with `new`
+impl TryFrom<Vec<String>> for ClassificationScale {
+    type Error = &'static str;
+
+    fn try_from(levels: Vec<String>) -> Result<Self, Self::Error> {
+        Self::new(levels).ok_or("A scale takes at least one level, each named once")
+    }
+}
+
+impl ClassificationScale {
+    /// Scale of the `levels`, from the least to the most restricted. Returns `None` if there are
+    /// no levels or some level is named twice.
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(levels: I) -> Option<Self> {
+        let levels: Vec<String> = levels.into_iter().map(Into::into).collect();
+        let unique: HashSet<&String> = levels.iter().collect();
+        (!levels.is_empty() && unique.len() == levels.len()).then_some(Self(levels))
+    }
+
+    /// The `Public < Internal < Confidential < Secret` scale
+    pub fn standard() -> Self {
+        Self(
+            ["Public", "Internal", "Confidential", "Secret"]
+                .map(String::from)
+                .to_vec(),
+        )
+    }
+
+    pub fn levels(&self) -> &[String] {
+        &self.0
+    }
+
+    /// Classification at the level named `name`, if the scale has one
+    pub fn level(&self, name: &str) -> Option<Classification> {
+        let rank = self.0.iter().position(|level| level == name)?;
+        Some(Classification {
+            rank,
+            scale: self.clone(),
+        })
+    }
+
+    /// The least restricted classification of the scale
+    pub fn bottom(&self) -> Classification {
+        Classification {
+            rank: 0,
+            scale: self.clone(),
+        }
+    }
+
+    /// The most restricted classification of the scale
+    pub fn top(&self) -> Classification {
+        Classification {
+            rank: self.0.len() - 1,
+            scale: self.clone(),
+        }
+    }
+}
+
+/// Confidentiality level on a [`ClassificationScale`], for organizations with more tiers than
+/// [`Confidentiality`] has. Classifications on different scales are not comparable.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+#[serde(try_from = "ClassificationParts")]
+pub struct Classification {
+    rank: usize,
+    scale: ClassificationScale,
+}
+
+// Classification as it is read back, before its rank is checked against its scale
+#[derive(Deserialize)]
+struct ClassificationParts {
+    rank: usize,
+    scale: ClassificationScale,
+}
+
+impl TryFrom<ClassificationParts> for Classification {
+    type Error = &'static str;
+
+    fn try_from(parts: ClassificationParts) -> Result<Self, Self::Error> {
+        match parts.rank < parts.scale.0.len() {
+            true => Ok(Self {
+                rank: parts.rank,
+                scale: parts.scale,
+            }),
+            false => Err("The rank is not on the scale"),
+        }
+    }
+}
+
+impl Classification {
+    /// Name of the level of the classification
+    pub fn name(&self) -> &str {
+        &self.scale.0[self.rank]
+    }
+
+    pub fn scale(&self) -> &ClassificationScale {
+        &self.scale
+    }
+}
+
+impl PartialOrd for Classification {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self.scale == other.scale).then(|| self.rank.cmp(&other.rank))
+    }
+}
+
+impl Lattice for Classification {
+    /// Returns the more restricted of the 2 classifications, if they are on the same scale
+    fn join(self, other: Self) -> Option<Self> {
+        self.partial_cmp(&other)?;
+        Some(total_join(self, other))
+    }
+
+    /// Returns the less restricted of the 2 classifications, if they are on the same scale
+    fn meet(self, other: Self) -> Option<Self> {
+        self.partial_cmp(&other)?;
+        Some(total_meet(self, other))
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
 pub enum Integrity {
     // High integrity
     Trusted = 0,
@@ -43,23 +249,59 @@ pub enum Integrity {
     Untrusted = 1,
 }
 
+// `None` is the bottom, below every label, like the derived order has it
 impl<L: Lattice> Lattice for Option<L> {
     fn join(self, other: Self) -> Option<Self> {
-        Some(self.and(other))
+        match (self, other) {
+            (Some(left), Some(right)) => left.join(right).map(Some),
+            (left, right) => Some(left.or(right)),
+        }
     }
 
     fn meet(self, other: Self) -> Option<Self> {
-        Some(self.or(other))
+        match (self, other) {
+            (Some(left), Some(right)) => left.meet(right).map(Some),
+            _ => Some(None),
+        }
     }
 }
 
 impl Lattice for Integrity {
     fn join(self, other: Self) -> Option<Self> {
-        Some(if self <= other { other } else { self })
+        Some(total_join(self, other))
     }
 
     fn meet(self, other: Self) -> Option<Self> {
-        Some(if self <= other { self } else { other })
+        Some(total_meet(self, other))
+    }
+}
+
+impl BoundedLattice for Integrity {
+    fn top() -> Self {
+        Self::Untrusted
+    }
+
+    fn bottom() -> Self {
+        Self::Trusted
+    }
+}
+
+impl Sealed for Integrity {}
+
+impl Fingerprint for Integrity {
+    fn fingerprint(&self) -> u64 {
+        self.clone() as u64
+    }
+}
+
+impl<L: Fingerprint> Sealed for Option<L> {}
+
+impl<L: Fingerprint> Fingerprint for Option<L> {
+    fn fingerprint(&self) -> u64 {
+        match self {
+            Some(inner) => combine(1, inner.fingerprint()),
+            None => 0,
+        }
     }
 }
 
@@ -74,7 +316,7 @@ impl Integrity {
 }
 
 // Information lattice corresponding to the product of 2 other lattices
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ProductLattice<A: Lattice, B: Lattice> {
     lattice1: A,
     lattice2: B,
@@ -84,17 +326,12 @@ impl<A: Lattice, B: Lattice> PartialOrd for ProductLattice<A, B> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let ord1 = self.lattice1.partial_cmp(&other.lattice1)?;
         let ord2 = self.lattice2.partial_cmp(&other.lattice2)?;
-        if ord1 == ord2 {
-            // If the 2 are equal, we return the result
-            Some(ord1)
-        // If at least one is smaller than the other and the other is equal, we return `Less`
-        } else if ord1 == Ordering::Less && ord2 == Ordering::Equal
-            || ord1 == Ordering::Equal && ord2 == Ordering::Less
-        {
-            Some(Ordering::Less)
-        // Otherwise greater
-        } else {
-            Some(Ordering::Greater)
+        match (ord1, ord2) {
+            // Equal components leave the order to the other component
+            (Ordering::Equal, ord) | (ord, Ordering::Equal) => Some(ord),
+            (ord1, ord2) if ord1 == ord2 => Some(ord1),
+            // One component is smaller while the other is greater, so neither flows to the other
+            _ => None,
         }
     }
 }
@@ -117,6 +354,16 @@ impl<A: Lattice, B: Lattice> Lattice for ProductLattice<A, B> {
     }
 }
 
+impl<A: BoundedLattice, B: BoundedLattice> BoundedLattice for ProductLattice<A, B> {
+    fn top() -> Self {
+        Self::new(A::top(), B::top())
+    }
+
+    fn bottom() -> Self {
+        Self::new(A::bottom(), B::bottom())
+    }
+}
+
 impl<A: Lattice, B: Lattice> ProductLattice<A, B> {
     pub fn new(lattice1: A, lattice2: B) -> Self {
         Self { lattice1, lattice2 }
@@ -131,11 +378,26 @@ impl<A: Lattice, B: Lattice> ProductLattice<A, B> {
     }
 }
 
+impl<A: Lattice + Fingerprint, B: Lattice + Fingerprint> Sealed for ProductLattice<A, B> {}
+
+impl<A: Lattice + Fingerprint, B: Lattice + Fingerprint> Fingerprint for ProductLattice<A, B> {
+    fn fingerprint(&self) -> u64 {
+        combine(self.lattice1.fingerprint(), self.lattice2.fingerprint())
+    }
+}
+
 /// Powerset lattice ordered by subset inclusion
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub struct PowersetLattice<T: Eq + Hash> {
     subset: HashSet<T>,
     universe: HashSet<T>,
+    // Cached fingerprints of the `subset` and `universe` sets. The sets can only be set through
+    // `new`, which is also the only place where these are computed, so they never go stale.
+    subset_fingerprint: u64,
+    universe_fingerprint: u64,
+    // Unique id handed out by `new` and shared by all the clones of this value. Since the sets
+    // cannot be mutated after construction, 2 values with the same epoch are always equal.
+    epoch: u64,
 }
 
 impl<T: Eq + Hash> PowersetLattice<T> {
@@ -144,33 +406,142 @@ impl<T: Eq + Hash> PowersetLattice<T> {
             return Err(LatticeError::SubsetNotInUniverse);
         }
 
-        Ok(Self { subset, universe })
+        let subset_fingerprint = set_fingerprint(&subset);
+        let universe_fingerprint = set_fingerprint(&universe);
+
+        Ok(Self {
+            subset,
+            universe,
+            subset_fingerprint,
+            universe_fingerprint,
+            epoch: POWERSET_EPOCH.fetch_add(1, AtomicOrdering::Relaxed),
+        })
+    }
+
+    /// Returns the greatest value of the lattice over `universe`, which is the whole universe.
+    /// Powersets are only bounded once their universe is known.
+    pub fn top(universe: HashSet<T>) -> Self
+    where
+        T: Clone,
+    {
+        Self::new(universe.clone(), universe).expect("The universe is a subset of itself")
+    }
+
+    /// Returns the least value of the lattice over `universe`, which is the empty set
+    pub fn bottom(universe: HashSet<T>) -> Self {
+        Self::new(HashSet::new(), universe).expect("The empty set is a subset of any universe")
+    }
+
+    pub fn subset(&self) -> &HashSet<T> {
+        &self.subset
+    }
+
+    pub fn universe(&self) -> &HashSet<T> {
+        &self.universe
+    }
+
+    // Returns true if the 2 subsets are equal, only walking the sets when the fingerprints and
+    // the lengths match.
+    fn subset_eq(&self, other: &Self) -> bool {
+        self.epoch == other.epoch
+            || self.subset_fingerprint == other.subset_fingerprint
+                && self.subset.len() == other.subset.len()
+                && self.subset == other.subset
+    }
+
+    // Returns true if the 2 values are sets of the same universe, such that they can be compared
+    fn universe_eq(&self, other: &Self) -> bool {
+        self.epoch == other.epoch
+            || self.universe_fingerprint == other.universe_fingerprint
+                && self.universe == other.universe
+    }
+}
+
+// The sets alone describe the lattice, the fingerprints and the epoch are recomputed by `new`
+// when deserializing
+#[derive(Serialize, Deserialize)]
+struct PowersetSets<S> {
+    subset: S,
+    universe: S,
+}
+
+// Elements of the `set` in order
+fn sorted<T: Ord>(set: &HashSet<T>) -> Vec<&T> {
+    let mut items: Vec<_> = set.iter().collect();
+    items.sort();
+    items
+}
+
+// The sets are written in order, such that a label is always written the same way
+impl<T: Eq + Hash + Ord + Serialize> Serialize for PowersetLattice<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PowersetSets {
+            subset: sorted(&self.subset),
+            universe: sorted(&self.universe),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Eq + Hash + Deserialize<'de>> Deserialize<'de> for PowersetLattice<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let sets = PowersetSets::<HashSet<T>>::deserialize(deserializer)?;
+        Self::new(sets.subset, sets.universe)
+            .map_err(|err| serde::de::Error::custom(format!("{err:?}")))
+    }
+}
+
+impl<T: Eq + Hash> PartialEq for PowersetLattice<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.epoch == other.epoch || self.subset_eq(other) && self.universe_eq(other)
     }
 }
 
 impl<T: Eq + Hash> PartialOrd for PowersetLattice<T> {
+    /// Orders the sets by inclusion. Sets of different universes, and sets neither of which
+    /// includes the other, are not comparable.
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.subset == other.subset {
+        if !self.universe_eq(other) {
+            None
+        } else if self.subset_eq(other) {
             Some(Ordering::Equal)
         } else if self.subset.is_subset(&other.subset) {
             Some(Ordering::Less)
-        } else {
+        } else if other.subset.is_subset(&self.subset) {
             Some(Ordering::Greater)
+        } else {
+            None
         }
     }
 }
 
+impl<T: Eq + Hash> Sealed for PowersetLattice<T> {}
+
+impl<T: Eq + Hash> Fingerprint for PowersetLattice<T> {
+    fn fingerprint(&self) -> u64 {
+        combine(self.subset_fingerprint, self.universe_fingerprint)
+    }
+}
+
 impl<T: Eq + Hash + Clone + std::fmt::Debug> Lattice for PowersetLattice<T> {
-    /// Returns the least upper bound between `self` and `other` values
+    /// Returns the least upper bound between `self` and `other` values, if they are sets of the
+    /// same universe
     fn join(self, other: Self) -> Option<Self> {
+        if !self.universe_eq(&other) {
+            return None;
+        }
         // Union of the 2 subsets
         let subset = &self.subset | &other.subset;
 
         Self::new(subset, self.universe).ok()
     }
 
-    /// Returns the greatest lower bound between `self` and `other` values
+    /// Returns the greatest lower bound between `self` and `other` values, if they are sets of the
+    /// same universe
     fn meet(self, other: Self) -> Option<Self> {
+        if !self.universe_eq(&other) {
+            return None;
+        }
         // Intersection of the 2 subsets
         let subset = &self.subset & &other.subset;
 
@@ -179,7 +550,7 @@ impl<T: Eq + Hash + Clone + std::fmt::Debug> Lattice for PowersetLattice<T> {
 }
 
 // Information lattice which inverses the order of operations
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct InverseLattice<T: Lattice> {
     inner: T,
 }
@@ -188,6 +559,18 @@ impl<T: Lattice> InverseLattice<T> {
     pub fn new(inner: T) -> Self {
         Self { inner }
     }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Lattice + Fingerprint> Sealed for InverseLattice<T> {}
+
+impl<T: Lattice + Fingerprint> Fingerprint for InverseLattice<T> {
+    fn fingerprint(&self) -> u64 {
+        !self.inner.fingerprint()
+    }
 }
 
 impl<T: Lattice> PartialOrd for InverseLattice<T> {
@@ -196,6 +579,16 @@ impl<T: Lattice> PartialOrd for InverseLattice<T> {
     }
 }
 
+impl<T: BoundedLattice> BoundedLattice for InverseLattice<T> {
+    fn top() -> Self {
+        Self::new(T::bottom())
+    }
+
+    fn bottom() -> Self {
+        Self::new(T::top())
+    }
+}
+
 impl<T: Lattice> Lattice for InverseLattice<T> {
     fn join(self, other: Self) -> Option<Self> {
         Some(Self::new(self.inner.meet(other.inner)?))
@@ -206,6 +599,450 @@ impl<T: Lattice> Lattice for InverseLattice<T> {
     }
 }
 
+/// Members of a powerset universe, numbered in order such that the subsets of the universe can be
+/// held as bitsets. Labels share their universe behind an `Arc`, so cloning or joining them never
+/// copies the members.
+#[derive(Debug)]
+pub struct Universe<T> {
+    // Members in order, the index of a member being its bit in the subsets
+    members: Vec<T>,
+    index: HashMap<T, usize>,
+}
+
+impl<T: Ord + Hash + Clone> Universe<T> {
+    pub fn new<I: IntoIterator<Item = T>>(members: I) -> Arc<Self> {
+        let members: Vec<T> = members
+            .into_iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let index = members
+            .iter()
+            .enumerate()
+            .map(|(bit, member)| (member.clone(), bit))
+            .collect();
+        Arc::new(Self { members, index })
+    }
+}
+
+// Universes of strings handed out by `Universe::shared` which some label still holds
+static SHARED_UNIVERSES: Mutex<Vec<Weak<Universe<String>>>> = Mutex::new(Vec::new());
+
+impl Universe<String> {
+    /// Universe holding the `members`, which is the very universe handed out for the same members
+    /// before as long as a label still holds it. Labels interned over and over into the same
+    /// members then share their universe, and are joined without comparing members.
+    pub fn shared(members: &HashSet<String>) -> Arc<Self> {
+        let mut shared = SHARED_UNIVERSES
+            .lock()
+            .expect("Poisoned shared universes lock");
+        shared.retain(|universe| universe.strong_count() > 0);
+        let found = shared.iter().filter_map(Weak::upgrade).find(|universe| {
+            universe.len() == members.len()
+                && members
+                    .iter()
+                    .all(|member| universe.index.contains_key(member))
+        });
+        if let Some(universe) = found {
+            return universe;
+        }
+        let universe = Self::new(members.iter().cloned());
+        shared.push(Arc::downgrade(&universe));
+        universe
+    }
+}
+
+impl<T> Universe<T> {
+    pub fn members(&self) -> &[T] {
+        &self.members
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    // Number of words in the bitset of a subset
+    fn words(&self) -> usize {
+        self.members.len().div_ceil(u64::BITS as usize)
+    }
+}
+
+// Members are kept in order, so universes holding the same members are equal member by member
+impl<T: PartialEq> PartialEq for Universe<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.members == other.members
+    }
+}
+
+/// Powerset lattice over a shared [`Universe`], holding its subset as a bitset. Joins and meets
+/// are done a word at a time and clones only bump reference counts, which keeps labels with many
+/// readers cheap to carry along long traces.
+#[derive(Debug, Clone)]
+pub struct InternedPowerset<T> {
+    universe: Arc<Universe<T>>,
+    subset: Arc<[u64]>,
+}
+
+impl<T: Ord + Hash + Clone> InternedPowerset<T> {
+    pub fn new<I: IntoIterator<Item = T>>(
+        subset: I,
+        universe: &Arc<Universe<T>>,
+    ) -> Result<Self, LatticeError> {
+        let mut bits = vec![0u64; universe.words()];
+        for member in subset {
+            let bit = *universe
+                .index
+                .get(&member)
+                .ok_or(LatticeError::SubsetNotInUniverse)?;
+            bits[bit / u64::BITS as usize] |= 1 << (bit % u64::BITS as usize);
+        }
+        Ok(Self {
+            universe: universe.clone(),
+            subset: bits.into(),
+        })
+    }
+
+    /// Returns the greatest value of the lattice over `universe`, which is the whole universe
+    pub fn top(universe: &Arc<Universe<T>>) -> Self {
+        Self::new(universe.members.iter().cloned(), universe)
+            .expect("The universe is a subset of itself")
+    }
+
+    /// Returns the least value of the lattice over `universe`, which is the empty set
+    pub fn bottom(universe: &Arc<Universe<T>>) -> Self {
+        Self::new([], universe).expect("The empty set is a subset of any universe")
+    }
+
+    /// Interns the `powerset` into `universe`, which has to hold the same members as the universe
+    /// of the `powerset`
+    pub fn intern(
+        powerset: &PowersetLattice<T>,
+        universe: &Arc<Universe<T>>,
+    ) -> Result<Self, LatticeError> {
+        if powerset.universe().len() != universe.len()
+            || !powerset
+                .universe()
+                .iter()
+                .all(|member| universe.index.contains_key(member))
+        {
+            return Err(LatticeError::SubsetNotInUniverse);
+        }
+        Self::new(powerset.subset().iter().cloned(), universe)
+    }
+
+    pub fn to_powerset(&self) -> PowersetLattice<T> {
+        PowersetLattice::new(
+            self.members().cloned().collect(),
+            self.universe.members.iter().cloned().collect(),
+        )
+        .expect("The subset is drawn from the universe")
+    }
+
+    pub fn contains(&self, member: &T) -> bool {
+        self.universe
+            .index
+            .get(member)
+            .is_some_and(|bit| self.has_bit(*bit))
+    }
+}
+
+impl<T> InternedPowerset<T> {
+    pub fn universe(&self) -> &Arc<Universe<T>> {
+        &self.universe
+    }
+
+    /// Members of the subset, in the order of the universe
+    pub fn members(&self) -> impl Iterator<Item = &T> {
+        self.universe
+            .members
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.has_bit(*bit))
+            .map(|(_, member)| member)
+    }
+
+    pub fn len(&self) -> usize {
+        self.subset
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subset.iter().all(|word| *word == 0)
+    }
+
+    fn has_bit(&self, bit: usize) -> bool {
+        self.subset[bit / u64::BITS as usize] & 1 << (bit % u64::BITS as usize) != 0
+    }
+
+    // Returns true if the 2 values are sets of the same universe, which is usually the very same
+    // shared universe
+    fn universe_eq(&self, other: &Self) -> bool
+    where
+        T: PartialEq,
+    {
+        Arc::ptr_eq(&self.universe, &other.universe) || self.universe == other.universe
+    }
+
+    // Combine the bitsets of the 2 values word by word, if they are sets of the same universe
+    fn combine(self, other: Self, op: impl Fn(u64, u64) -> u64) -> Option<Self>
+    where
+        T: PartialEq,
+    {
+        if !self.universe_eq(&other) {
+            return None;
+        }
+        let subset = self
+            .subset
+            .iter()
+            .zip(other.subset.iter())
+            .map(|(a, b)| op(*a, *b))
+            .collect();
+        Some(Self {
+            universe: self.universe,
+            subset,
+        })
+    }
+}
+
+impl<T: PartialEq> PartialEq for InternedPowerset<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.universe_eq(other) && self.subset == other.subset
+    }
+}
+
+impl<T: PartialEq> PartialOrd for InternedPowerset<T> {
+    /// Orders the sets by inclusion. Sets of different universes, and sets neither of which
+    /// includes the other, are not comparable.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if !self.universe_eq(other) {
+            return None;
+        }
+        let words = self.subset.iter().zip(other.subset.iter());
+        let less = words.clone().all(|(a, b)| a & !b == 0);
+        let greater = words.into_iter().all(|(a, b)| b & !a == 0);
+        match (less, greater) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+}
+
+impl<T: PartialEq + Clone + std::fmt::Debug> Lattice for InternedPowerset<T> {
+    /// Returns the union of the 2 sets, if they are sets of the same universe
+    fn join(self, other: Self) -> Option<Self> {
+        self.combine(other, |a, b| a | b)
+    }
+
+    /// Returns the intersection of the 2 sets, if they are sets of the same universe
+    fn meet(self, other: Self) -> Option<Self> {
+        self.combine(other, |a, b| a & b)
+    }
+}
+
+// Written the same way as a `PowersetLattice`, such that either can read labels written by the
+// other
+impl<T: Serialize> Serialize for InternedPowerset<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PowersetSets {
+            subset: self.members().collect::<Vec<_>>(),
+            universe: self.universe.members.iter().collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+// Every label read gets a universe of its own, which labels joined with it then share
+impl<'de, T: Ord + Hash + Clone + Deserialize<'de>> Deserialize<'de> for InternedPowerset<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let sets = PowersetSets::<Vec<T>>::deserialize(deserializer)?;
+        Self::new(sets.subset, &Universe::new(sets.universe))
+            .map_err(|err| serde::de::Error::custom(format!("{err:?}")))
+    }
+}
+
+/// Disjunction of principals, which any one of them satisfies. The empty disjunction is never
+/// satisfied.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Serialize, Deserialize)]
+pub struct Disjunction(BTreeSet<String>);
+
+impl Disjunction {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(principals: I) -> Self {
+        Self(principals.into_iter().map(Into::into).collect())
+    }
+
+    pub fn principals(&self) -> &BTreeSet<String> {
+        &self.0
+    }
+
+    // Whether satisfying `self` always satisfies `other`, which is when `other` allows every
+    // principal `self` does
+    fn implies(&self, other: &Self) -> bool {
+        self.0.is_subset(&other.0)
+    }
+}
+
+/// Conjunction of disjunctions of principals, in conjunctive normal form. The empty conjunction
+/// is always satisfied, while a conjunction holding the empty disjunction never is.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct Cnf(BTreeSet<Disjunction>);
+
+impl Cnf {
+    /// The formula every set of principals satisfies
+    pub fn truth() -> Self {
+        Self(BTreeSet::new())
+    }
+
+    /// The formula no set of principals satisfies
+    pub fn falsity() -> Self {
+        Self(BTreeSet::from([Disjunction::new::<_, String>([])]))
+    }
+
+    /// The formula only satisfied by `principal`
+    pub fn principal<S: Into<String>>(principal: S) -> Self {
+        Self::any_of([principal])
+    }
+
+    /// The formula satisfied by any one of the `principals`
+    pub fn any_of<I: IntoIterator<Item = S>, S: Into<String>>(principals: I) -> Self {
+        Self(BTreeSet::from([Disjunction::new(principals)])).reduced()
+    }
+
+    pub fn clauses(&self) -> &BTreeSet<Disjunction> {
+        &self.0
+    }
+
+    /// Conjunction of `self` and `other`, which takes satisfying both
+    pub fn and(self, other: Self) -> Self {
+        Self(&self.0 | &other.0).reduced()
+    }
+
+    /// Disjunction of `self` and `other`, which takes satisfying either
+    pub fn or(self, other: Self) -> Self {
+        let clauses = self
+            .0
+            .iter()
+            .flat_map(|a| other.0.iter().map(move |b| Disjunction(&a.0 | &b.0)))
+            .collect();
+        Self(clauses).reduced()
+    }
+
+    /// Whether every set of principals satisfying `self` also satisfies `other`
+    pub fn implies(&self, other: &Self) -> bool {
+        other
+            .0
+            .iter()
+            .all(|clause| self.0.iter().any(|own| own.implies(clause)))
+    }
+
+    /// Whether the `principals` together satisfy the formula
+    pub fn is_satisfied_by(&self, principals: &[&str]) -> bool {
+        self.0.iter().all(|clause| {
+            principals
+                .iter()
+                .any(|principal| clause.0.contains(*principal))
+        })
+    }
+
+    // Drop the clauses implied by other clauses, such that equivalent formulas are equal
+    fn reduced(self) -> Self {
+        let clauses = self
+            .0
+            .iter()
+            .filter(|clause| {
+                !self
+                    .0
+                    .iter()
+                    .any(|other| other != *clause && other.implies(clause))
+            })
+            .cloned()
+            .collect();
+        Self(clauses)
+    }
+}
+
+/// Disjunction category label, made of a secrecy and an integrity formula over principals. The
+/// secrecy formula tells who may read the data, such that `Alice OR Bob` lets either of them
+/// read it, while `Alice AND Bob` takes both of them together. The integrity formula tells who
+/// vouches for the data. Data flows to contexts at least as secret and vouched for by at most the
+/// principals vouching for the data.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct DcLabel {
+    secrecy: Cnf,
+    integrity: Cnf,
+}
+
+impl DcLabel {
+    pub fn new(secrecy: Cnf, integrity: Cnf) -> Self {
+        Self { secrecy, integrity }
+    }
+
+    pub fn secrecy(&self) -> &Cnf {
+        &self.secrecy
+    }
+
+    pub fn integrity(&self) -> &Cnf {
+        &self.integrity
+    }
+
+    /// Whether the `principals` together are allowed to read data with this label
+    pub fn can_be_read_by(&self, principals: &[&str]) -> bool {
+        self.secrecy.is_satisfied_by(principals)
+    }
+
+    // Whether data labeled with `self` may flow to `other`
+    fn flows_to(&self, other: &Self) -> bool {
+        other.secrecy.implies(&self.secrecy) && self.integrity.implies(&other.integrity)
+    }
+}
+
+impl PartialOrd for DcLabel {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.flows_to(other), other.flows_to(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+}
+
+impl Lattice for DcLabel {
+    /// Returns the least upper bound between `self` and `other` values, which is as secret as both
+    /// and only vouched for by what vouches for either
+    fn join(self, other: Self) -> Option<Self> {
+        Some(Self::new(
+            self.secrecy.and(other.secrecy),
+            self.integrity.or(other.integrity),
+        ))
+    }
+
+    /// Returns the greatest lower bound between `self` and `other` values
+    fn meet(self, other: Self) -> Option<Self> {
+        Some(Self::new(
+            self.secrecy.or(other.secrecy),
+            self.integrity.and(other.integrity),
+        ))
+    }
+}
+
+impl BoundedLattice for DcLabel {
+    fn top() -> Self {
+        Self::new(Cnf::falsity(), Cnf::truth())
+    }
+
+    fn bottom() -> Self {
+        Self::new(Cnf::truth(), Cnf::falsity())
+    }
+}
+
 #[derive(Debug)]
 pub enum LatticeError {
     SubsetNotInUniverse,
@@ -215,3 +1052,487 @@ pub enum LatticeError {
 }
 
 pub type Label = ProductLattice<Confidentiality, Integrity>;
+
+/// The [`EmailLabel`] is a product lattice of the integrity label and the confidentiality label
+pub type EmailLabel = ProductLattice<Integrity, InverseLattice<PowersetLattice<String>>>;
+
+/// [`EmailLabel`] whose readers are interned, for runs carrying labels with many readers
+pub type InternedEmailLabel = ProductLattice<Integrity, InverseLattice<InternedPowerset<String>>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetaValue<T: std::fmt::Debug, L: Lattice> {
+    value: T,
+    label: L,
+}
+
+impl<T: std::fmt::Debug, L: Lattice> MetaValue<T, L> {
+    pub fn new(value: T, label: L) -> Self {
+        Self { value, label }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn label(&self) -> &L {
+        &self.label
+    }
+
+    pub fn into_raw_parts(self) -> (T, L) {
+        (self.value, self.label)
+    }
+
+    pub fn raw_parts(&self) -> (&T, &L) {
+        (&self.value, &self.label)
+    }
+}
+
+/// JSON value with a label at any of its nodes, such as a list of tool results which each carry
+/// the label of where they came from. A label applies to its node and to everything below it, so
+/// an unlabeled node carries the labels of the nodes above it. Reading one field of the value only
+/// taints the reader with the labels of that field, while the value as a whole carries the join of
+/// all its labels.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabeledValue<L: Lattice> {
+    node: LabeledNode<L>,
+    label: Option<L>,
+}
+
+/// Node of a [`LabeledValue`], mirroring the variants of a JSON value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum LabeledNode<L: Lattice> {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<LabeledValue<L>>),
+    Object(BTreeMap<String, LabeledValue<L>>),
+}
+
+impl<L: Lattice> LabeledValue<L> {
+    /// Label the `value` as a whole with `label`, leaving the nodes below it unlabeled
+    pub fn new(value: Value, label: Option<L>) -> Self {
+        let node = match value {
+            Value::Null => LabeledNode::Null,
+            Value::Bool(value) => LabeledNode::Bool(value),
+            Value::Number(value) => LabeledNode::Number(value),
+            Value::String(value) => LabeledNode::String(value),
+            Value::Array(values) => LabeledNode::Array(
+                values
+                    .into_iter()
+                    .map(|value| Self::new(value, None))
+                    .collect(),
+            ),
+            Value::Object(fields) => LabeledNode::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (name, Self::new(value, None)))
+                    .collect(),
+            ),
+        };
+        Self { node, label }
+    }
+
+    /// Array of the `values`, which keep their own labels
+    pub fn array(values: Vec<Self>) -> Self {
+        Self {
+            node: LabeledNode::Array(values),
+            label: None,
+        }
+    }
+
+    /// Object of the `fields`, which keep their own labels
+    pub fn object(fields: BTreeMap<String, Self>) -> Self {
+        Self {
+            node: LabeledNode::Object(fields),
+            label: None,
+        }
+    }
+
+    pub fn node(&self) -> &LabeledNode<L> {
+        &self.node
+    }
+
+    /// Label of this node alone, without the labels of the nodes below it
+    pub fn label(&self) -> Option<&L> {
+        self.label.as_ref()
+    }
+
+    /// Join `label` into the label of this node, which taints everything below it
+    pub fn taint(mut self, label: L) -> Result<Self, LatticeError> {
+        self.label = Some(match self.label {
+            Some(own) => own.join(label).ok_or(LatticeError::LabelJoinFailed)?,
+            None => label,
+        });
+        Ok(self)
+    }
+
+    /// The value without its labels
+    pub fn to_value(&self) -> Value {
+        match &self.node {
+            LabeledNode::Null => Value::Null,
+            LabeledNode::Bool(value) => Value::Bool(*value),
+            LabeledNode::Number(value) => Value::Number(value.clone()),
+            LabeledNode::String(value) => Value::String(value.clone()),
+            LabeledNode::Array(values) => Value::Array(values.iter().map(Self::to_value).collect()),
+            LabeledNode::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.to_value()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Join of all the labels of the value, which is the label of the value as a whole. Returns
+    /// `None` if no node is labeled.
+    pub fn joined_label(&self) -> Result<Option<L>, LatticeError> {
+        let children: Box<dyn Iterator<Item = &Self>> = match &self.node {
+            LabeledNode::Array(values) => Box::new(values.iter()),
+            LabeledNode::Object(fields) => Box::new(fields.values()),
+            _ => Box::new(std::iter::empty()),
+        };
+        let mut joined = self.label.clone();
+        for child in children {
+            joined = match (joined, child.joined_label()?) {
+                (Some(joined), Some(label)) => {
+                    Some(joined.join(label).ok_or(LatticeError::LabelJoinFailed)?)
+                }
+                (joined, label) => joined.or(label),
+            };
+        }
+        Ok(joined)
+    }
+
+    /// The node at the JSON `pointer`, such as `/emails/0/body`, tainted with the labels of the
+    /// nodes above it. Returns `None` if there is no such node.
+    pub fn pointer(&self, pointer: &str) -> Result<Option<Self>, LatticeError> {
+        if pointer.is_empty() {
+            return Ok(Some(self.clone()));
+        }
+        let Some(path) = pointer.strip_prefix('/') else {
+            return Ok(None);
+        };
+        let mut node = self;
+        let mut above = vec![];
+        for token in path.split('/') {
+            let token = token.replace("~1", "/").replace("~0", "~");
+            let child = match &node.node {
+                LabeledNode::Array(values) => {
+                    token.parse::<usize>().ok().and_then(|i| values.get(i))
+                }
+                LabeledNode::Object(fields) => fields.get(&token),
+                _ => None,
+            };
+            let Some(child) = child else {
+                return Ok(None);
+            };
+            above.extend(node.label.clone());
+            node = child;
+        }
+        above
+            .into_iter()
+            .try_fold(node.clone(), Self::taint)
+            .map(Some)
+    }
+}
+
+impl<L: Lattice> From<MetaValue<Value, L>> for LabeledValue<L> {
+    fn from(value: MetaValue<Value, L>) -> Self {
+        let (value, label) = value.into_raw_parts();
+        Self::new(value, Some(label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{option, prelude::*, test_runner::TestCaseError};
+
+    fn readers(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn powerset_fingerprint_matches_equality() {
+        let universe = readers(&["alice", "bob", "charlie"]);
+        let a = PowersetLattice::new(readers(&["alice", "bob"]), universe.clone()).unwrap();
+        let b = PowersetLattice::new(readers(&["bob", "alice"]), universe.clone()).unwrap();
+        let c = PowersetLattice::new(readers(&["alice"]), universe).unwrap();
+
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_eq!(a, b);
+        assert_ne!(a.fingerprint(), c.fingerprint());
+        assert_ne!(a, c);
+        assert_eq!(c.partial_cmp(&a), Some(Ordering::Less));
+    }
+
+    #[test]
+    fn labels_lie_between_the_bounds() {
+        let label = Label::new(Confidentiality::high(), Integrity::trusted());
+        assert!(Label::bottom() <= label);
+        assert!(label <= Label::top());
+        assert_eq!(
+            Label::join_all([
+                label.clone(),
+                Label::new(Confidentiality::low(), Integrity::untrusted())
+            ]),
+            Some(Label::top())
+        );
+        assert_eq!(Label::join_all([]), Some(Label::bottom()));
+        // Inverted lattices swap their bounds
+        assert_eq!(
+            InverseLattice::<Integrity>::top().inner(),
+            &Integrity::trusted()
+        );
+
+        let universe = readers(&["alice", "bob"]);
+        let alice = PowersetLattice::new(readers(&["alice"]), universe.clone()).unwrap();
+        assert!(PowersetLattice::bottom(universe.clone()) < alice);
+        assert!(alice < PowersetLattice::top(universe));
+    }
+
+    #[test]
+    fn labels_are_written_the_same_way() {
+        let readers = PowersetLattice::new(
+            readers(&["charlie", "alice", "bob"]),
+            readers(&["bob", "charlie", "dave", "alice"]),
+        )
+        .unwrap();
+        let label = EmailLabel::new(Integrity::untrusted(), InverseLattice::new(readers));
+        let json = serde_json::to_string(&label).unwrap();
+        assert_eq!(
+            json,
+            r#"{"lattice1":"Untrusted","lattice2":{"inner":{"subset":["alice","bob","charlie"],"universe":["alice","bob","charlie","dave"]}}}"#
+        );
+        let restored: EmailLabel = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, label);
+        assert_eq!(restored.fingerprint(), label.fingerprint());
+    }
+
+    #[test]
+    fn fields_only_carry_their_own_labels_and_those_above() {
+        let email = |body: &str, integrity| {
+            LabeledValue::new(serde_json::json!({ "body": body }), Some(integrity))
+        };
+        let inbox = LabeledValue::object(BTreeMap::from([(
+            "emails".to_string(),
+            LabeledValue::array(vec![
+                email("See you at 10 AM.", Integrity::trusted()),
+                email("Click https://fides.github.io", Integrity::untrusted()),
+            ]),
+        )]));
+        assert_eq!(inbox.joined_label().unwrap(), Some(Integrity::untrusted()));
+        let first = inbox.pointer("/emails/0/body").unwrap().unwrap();
+        assert_eq!(first.to_value(), "See you at 10 AM.");
+        assert_eq!(first.label(), Some(&Integrity::trusted()));
+        assert!(inbox.pointer("/emails/2").unwrap().is_none());
+
+        // Labels above a field taint it
+        let inbox = inbox.taint(Integrity::untrusted()).unwrap();
+        let first = inbox.pointer("/emails/0").unwrap().unwrap();
+        assert_eq!(first.joined_label().unwrap(), Some(Integrity::untrusted()));
+    }
+
+    #[test]
+    fn either_reader_can_read_disjunctions() {
+        let shared = DcLabel::new(Cnf::any_of(["alice", "bob"]), Cnf::principal("alice"));
+        assert!(shared.can_be_read_by(&["alice"]));
+        assert!(shared.can_be_read_by(&["bob"]));
+        assert!(!shared.can_be_read_by(&["charlie"]));
+
+        // Joining with data only Bob can read leaves Bob alone, vouched for by nobody in particular
+        let bobs = DcLabel::new(Cnf::principal("bob"), Cnf::principal("bob"));
+        let joined = shared.clone().join(bobs.clone()).unwrap();
+        assert_eq!(joined.secrecy(), &Cnf::principal("bob"));
+        assert_eq!(joined.integrity(), &Cnf::any_of(["alice", "bob"]));
+        assert!(shared < joined && bobs < joined);
+        assert_eq!(shared.partial_cmp(&bobs), None);
+
+        assert!(DcLabel::bottom() <= shared && shared <= DcLabel::top());
+        assert!(!DcLabel::top().can_be_read_by(&["alice", "bob"]));
+    }
+
+    #[test]
+    fn classifications_follow_their_scale() {
+        let scale = ClassificationScale::standard();
+        let internal = scale.level("Internal").unwrap();
+        let secret = scale.level("Secret").unwrap();
+        assert!(internal < secret && secret == scale.top());
+        assert_eq!(
+            internal.clone().join(secret.clone()).unwrap().name(),
+            "Secret"
+        );
+        assert_eq!(
+            internal.clone().meet(scale.bottom()).unwrap().name(),
+            "Public"
+        );
+
+        // Other scales do not compare
+        let other = ClassificationScale::new(["Green", "Amber", "Red"]).unwrap();
+        assert_eq!(internal.partial_cmp(&other.bottom()), None);
+        assert!(internal.clone().join(other.top()).is_none());
+        assert!(ClassificationScale::new(["Red", "Red"]).is_none());
+
+        // Classifications slot into products like any lattice
+        let label = ProductLattice::new(internal, Integrity::trusted());
+        let joined = label
+            .join(ProductLattice::new(secret, Integrity::untrusted()))
+            .unwrap();
+        assert_eq!(joined.lattice1().name(), "Secret");
+        assert_eq!(joined.lattice2(), &Integrity::untrusted());
+    }
+
+    #[test]
+    fn joined_label_fingerprint_is_recomputed() {
+        let universe = readers(&["alice", "bob", "charlie"]);
+        let a = PowersetLattice::new(readers(&["alice"]), universe.clone()).unwrap();
+        let b = PowersetLattice::new(readers(&["bob"]), universe.clone()).unwrap();
+        let expected = PowersetLattice::new(readers(&["alice", "bob"]), universe).unwrap();
+
+        let joined = a.join(b).unwrap();
+        assert_eq!(joined.fingerprint(), expected.fingerprint());
+        assert_eq!(joined, expected);
+    }
+
+    #[test]
+    fn interned_powersets_agree_with_powersets() {
+        let universe = Universe::new((0..100).map(|i| format!("user{i}")));
+        let readers = |range: std::ops::Range<usize>| range.map(|i| format!("user{i}"));
+        let a = InternedPowerset::new(readers(0..70), &universe).unwrap();
+        let b = InternedPowerset::new(readers(50..100), &universe).unwrap();
+
+        let joined = a.clone().join(b.clone()).unwrap();
+        assert_eq!(joined.len(), 100);
+        assert_eq!(joined, InternedPowerset::top(&universe));
+        assert!(Arc::ptr_eq(joined.universe(), &universe));
+        let met = a.clone().meet(b.clone()).unwrap();
+        assert_eq!(
+            met.to_powerset(),
+            a.to_powerset().meet(b.to_powerset()).unwrap()
+        );
+        assert!(met.contains(&"user60".to_string()) && !met.contains(&"user0".to_string()));
+        assert_eq!(a.partial_cmp(&b), None);
+
+        // Both are written the same way, and read back into either
+        let written = serde_json::to_value(&met).unwrap();
+        assert_eq!(written, serde_json::to_value(met.to_powerset()).unwrap());
+        let read: InternedPowerset<String> = serde_json::from_value(written).unwrap();
+        assert_eq!(read, met);
+        assert!(InternedPowerset::new(["mallory".to_string()], &universe).is_err());
+
+        // Labels interned into the same members share their universe
+        let members = (0..100).map(|i| format!("user{i}")).collect();
+        assert!(Arc::ptr_eq(
+            &Universe::shared(&members),
+            &Universe::shared(&members)
+        ));
+    }
+
+    // Check the lattice laws on `a`, `b` and `c`, along with the order agreeing with the join
+    fn check_laws<L: Lattice>(a: L, b: L, c: L) -> Result<(), TestCaseError> {
+        let join = |x: &L, y: &L| x.clone().join(y.clone()).unwrap();
+        let meet = |x: &L, y: &L| x.clone().meet(y.clone()).unwrap();
+        prop_assert_eq!(join(&a, &b), join(&b, &a));
+        prop_assert_eq!(meet(&a, &b), meet(&b, &a));
+        prop_assert_eq!(join(&join(&a, &b), &c), join(&a, &join(&b, &c)));
+        prop_assert_eq!(meet(&meet(&a, &b), &c), meet(&a, &meet(&b, &c)));
+        prop_assert_eq!(join(&a, &meet(&a, &b)), a.clone());
+        prop_assert_eq!(meet(&a, &join(&a, &b)), a.clone());
+        prop_assert_eq!(a <= b, join(&a, &b) == b);
+        let joined = join(&a, &b);
+        prop_assert_eq!(a.partial_cmp(&b).is_none(), joined != a && joined != b);
+        Ok(())
+    }
+
+    const PRINCIPALS: [&str; 4] = ["alice", "bob", "charlie", "david"];
+
+    fn integrity() -> impl Strategy<Value = Integrity> {
+        any::<bool>().prop_map(|trusted| match trusted {
+            true => Integrity::trusted(),
+            false => Integrity::untrusted(),
+        })
+    }
+
+    fn confidentiality() -> impl Strategy<Value = Confidentiality> {
+        any::<bool>().prop_map(|high| match high {
+            true => Confidentiality::high(),
+            false => Confidentiality::low(),
+        })
+    }
+
+    fn powerset() -> impl Strategy<Value = PowersetLattice<String>> {
+        (0usize..1 << PRINCIPALS.len()).prop_map(|members| {
+            let subset = PRINCIPALS
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| members & 1 << bit != 0)
+                .map(|(_, name)| name.to_string())
+                .collect();
+            PowersetLattice::new(subset, readers(&PRINCIPALS)).unwrap()
+        })
+    }
+
+    fn interned_powerset() -> impl Strategy<Value = InternedPowerset<String>> {
+        powerset().prop_map(|powerset| {
+            let universe = Universe::new(powerset.universe().iter().cloned());
+            InternedPowerset::intern(&powerset, &universe).unwrap()
+        })
+    }
+
+    fn email_label() -> impl Strategy<Value = EmailLabel> {
+        (integrity(), powerset()).prop_map(|(integrity, readers)| {
+            EmailLabel::new(integrity, InverseLattice::new(readers))
+        })
+    }
+
+    fn classification() -> impl Strategy<Value = Classification> {
+        let scale = ClassificationScale::standard();
+        (0..scale.levels().len()).prop_map(move |rank| scale.level(&scale.levels()[rank]).unwrap())
+    }
+
+    fn cnf() -> impl Strategy<Value = Cnf> {
+        let clause = prop::collection::vec(prop::sample::select(&PRINCIPALS[..3]), 0..3);
+        prop::collection::vec(clause, 0..3).prop_map(|clauses| {
+            clauses
+                .into_iter()
+                .fold(Cnf::truth(), |cnf, clause| cnf.and(Cnf::any_of(clause)))
+        })
+    }
+
+    fn dc_label() -> impl Strategy<Value = DcLabel> {
+        (cnf(), cnf()).prop_map(|(secrecy, integrity)| DcLabel::new(secrecy, integrity))
+    }
+
+    proptest! {
+        #[test]
+        fn lattices_follow_the_lattice_laws(
+            integrities in [integrity(), integrity(), integrity()],
+            confidentialities in [confidentiality(), confidentiality(), confidentiality()],
+            powersets in [powerset(), powerset(), powerset()],
+            interned in [interned_powerset(), interned_powerset(), interned_powerset()],
+            email_labels in [email_label(), email_label(), email_label()],
+            classifications in [classification(), classification(), classification()],
+            dc_labels in [dc_label(), dc_label(), dc_label()],
+            optional in [option::of(email_label()), option::of(email_label()), option::of(email_label())],
+        ) {
+            let [a, b, c] = integrities;
+            check_laws(a, b, c)?;
+            let [a, b, c] = confidentialities;
+            check_laws(a, b, c)?;
+            let [a, b, c] = powersets;
+            check_laws(a, b, c)?;
+            let [a, b, c] = interned;
+            check_laws(a, b, c)?;
+            let [a, b, c] = email_labels;
+            check_laws(a, b, c)?;
+            let [a, b, c] = classifications;
+            check_laws(a, b, c)?;
+            let [a, b, c] = dc_labels;
+            check_laws(a, b, c)?;
+            let [a, b, c] = optional;
+            check_laws(a, b, c)?;
+        }
+    }
+}
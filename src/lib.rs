@@ -1,47 +1,993 @@
+//! Planners for LLM agents, guarded by information flow control.
+//!
+//! The crate is split into features, such that embedding only part of it does not pull in the
+//! rest of its dependencies:
+//!
+//! - `ifc`: the lattices labeling data, and labeled values
+//! - `openai-backend`: the clients of the model APIs, along with async-openai and tokio
+//! - `demo-tools`: the email and Slack tools the planners are demonstrated with
+//! - `planners`: the planners and the planning loop, which call the demo tools
+//! - `telemetry`: the trace sinks, the log observer and the job queue streaming to them
+//! - `cli`: the `gentlemen` binary, running a request through the planning loop
+//! - `imap`: reading live emails from IMAP servers, along with rustls
+//! - `slack`: delivering the messages of the Slack tools through the Slack Web API
+//!
+//! The [`Plan`] trait is always available. All the features but `cli` are enabled by default.
+#[cfg(feature = "openai-backend")]
+pub mod anthropic;
+#[cfg(feature = "ifc")]
+pub mod authority;
+#[cfg(feature = "planners")]
+pub mod cache;
+#[cfg(feature = "planners")]
+pub mod compression;
+#[cfg(feature = "planners")]
+pub mod context;
+#[cfg(feature = "planners")]
+pub mod files;
+#[cfg(feature = "demo-tools")]
+pub mod fixtures;
+#[cfg(feature = "planners")]
 pub mod function;
+#[cfg(feature = "ifc")]
 pub mod ifc;
+#[cfg(feature = "planners")]
+pub mod injection;
+pub mod locale;
+#[cfg(feature = "planners")]
+pub mod mail;
+#[cfg(feature = "planners")]
 mod message;
+#[cfg(feature = "demo-tools")]
+pub mod mime;
+#[cfg(feature = "openai-backend")]
+pub mod mock;
+#[cfg(feature = "openai-backend")]
 pub mod openai;
+#[cfg(feature = "planners")]
 mod plan;
+#[cfg(feature = "planners")]
+pub mod prompt;
+#[cfg(feature = "planners")]
+pub mod quorum;
+#[cfg(feature = "planners")]
+pub mod quota;
+#[cfg(feature = "ifc")]
+pub mod redact;
+#[cfg(feature = "planners")]
+pub mod registry;
+#[cfg(feature = "planners")]
+pub mod remote;
+#[cfg(feature = "openai-backend")]
+pub mod response_cache;
+#[cfg(feature = "openai-backend")]
+pub mod retry;
+#[cfg(feature = "planners")]
+pub mod schema;
+#[cfg(feature = "ifc")]
+mod sealed;
+pub mod secrets;
+#[cfg(feature = "planners")]
+pub mod simulation;
+#[cfg(feature = "slack")]
+pub mod slack;
+#[cfg(feature = "planners")]
 mod state;
+#[cfg(feature = "ifc")]
+pub mod store;
+#[cfg(feature = "openai-backend")]
+pub mod tokens;
+#[cfg(feature = "demo-tools")]
 pub mod tools;
+#[cfg(feature = "planners")]
+pub mod validate;
 
-pub use function::{Args, Call, Function, MetaFunction};
+#[cfg(feature = "planners")]
+pub use function::{Args, Call, Function, LabelPropagation, MetaFunction, ToolError};
+#[cfg(feature = "ifc")]
 pub use ifc::{Confidentiality, Integrity, Label, ProductLattice};
-pub use message::{LabeledMessage, Message};
+#[cfg(feature = "planners")]
+pub use message::{Image, LabeledMessage, Message};
+#[cfg(feature = "opa")]
+pub use plan::opa;
+#[cfg(feature = "planners")]
 pub use plan::{
-    BasicPlanner, Plan, PlanningLoop, Policy, TaintTrackingPlanner, Trace, VarPlanner, policy,
+    ApprovalGate, AuditLog, BasicPlanner, Fallback, FewShotPlanner, FinishCriteria,
+    FinishingPlanner, Honeypot, InternedFunction, LabeledTool, LoopCheckpoint, LoopMessage,
+    Middleware, MiddlewarePlanner, PlanningLoop, Policy, PolicyCheck, PolicySet, QuarantinePlanner,
+    RunStep, RunTrace, Sequenced, Session, Shadowed, TaintLabel, TaintTrackingPlanner, Trace,
+    UpfrontPlanner, VarPlanner, ViolationHandler, WithRetries, approval, audit, checkpoint,
+    combinators, dag, differential, few_shot, finish, honeypot, middleware, observer, policy,
+    quarantine, recovery, repair, replay, rules, sanitize, session, upfront,
 };
+#[cfg(feature = "telemetry")]
+pub use plan::{JobQueue, Orchestrator, jobs, orchestrator, sink};
+#[cfg(feature = "planners")]
 pub use state::{ConversationHistory, LabeledConversationHistory, LabeledState, State};
 
 // use plan::Variable;
+#[cfg(feature = "planners")]
 use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionTool};
+#[cfg(feature = "planners")]
+use files::Sandbox;
+#[cfg(feature = "planners")]
+use injection::InjectionFlag;
+#[cfg(feature = "planners")]
+use mail::Mailbox;
+#[cfg(feature = "planners")]
+use quota::Quotas;
+#[cfg(feature = "planners")]
+use std::fmt;
+#[cfg(feature = "planners")]
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "planners")]
+use store::{LabeledStore, Quarantine};
+
+/// Enables a state passing planner which is plugged into the `PlanningLoop`
+pub trait Plan<S, M> {
+    /// The type of action returned by one call of the `plan` function
+    type Action;
+    type Error: std::fmt::Debug;
+    /// Take and process a previous known `state` and the current `message` and returns a new state
+    /// which contains the previous message and an action to be taken by the caller.
+    fn plan(&mut self, state: S, message: M) -> Result<(S, Self::Action), Self::Error>;
+
+    /// Memory the planner keeps on top of the state, saved in checkpoints such that a resumed run
+    /// remembers it. Planners working off the state alone keep none.
+    fn memory(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Restore the `memory` saved in a checkpoint
+    fn restore_memory(&mut self, _memory: serde_json::Value) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+}
+
+// Boxed planners plan like the planner they hold, such that planners of different types can be
+// combined
+impl<S, M, P: Plan<S, M> + ?Sized> Plan<S, M> for Box<P> {
+    type Action = P::Action;
+    type Error = P::Error;
+
+    fn plan(&mut self, state: S, message: M) -> Result<(S, Self::Action), Self::Error> {
+        (**self).plan(state, message)
+    }
+
+    fn memory(&self) -> Option<serde_json::Value> {
+        (**self).memory()
+    }
+
+    fn restore_memory(&mut self, memory: serde_json::Value) -> Result<(), serde_json::Error> {
+        (**self).restore_memory(memory)
+    }
+}
+
+/// Data the tools and the planning loop keep between calls
+#[cfg(feature = "planners")]
+#[derive(Debug, Default, Clone)]
+pub struct Datastore {
+    quotas: Option<Quotas>,
+    // Mailbox the email tools read, instead of the demo inbox
+    mailbox: Option<Mailbox>,
+    // Client the Slack tools deliver their messages through
+    #[cfg(feature = "slack")]
+    slack: Option<slack::SlackClient>,
+    // Directory the filesystem tools are confined to
+    sandbox: Option<Sandbox>,
+    // Tool results the injection detector flagged during the runs
+    injections: Vec<InjectionFlag>,
+    // Labeled entries the tools read and write, including the effects of their calls
+    store: LabeledStore<ifc::EmailLabel>,
+    // Effects recorded so far, numbering the next one
+    effects: usize,
+    // Id of the send the last call to a sending tool made, until the loop verifies it
+    sent: Option<String>,
+    // Untrusted tool results the conversation only sees the handles of
+    quarantine: Quarantine,
+    // Run the store and the effects belong to, if any
+    namespace: Option<String>,
+    // Entries of the other runs, set aside until they are entered again
+    parked: HashMap<Option<String>, Namespace>,
+}
+
+#[cfg(feature = "planners")]
+impl Datastore {
+    /// Enforce the `quotas` in every loop run with this datastore
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    pub fn quotas(&self) -> Option<&Quotas> {
+        self.quotas.as_ref()
+    }
+
+    /// Have the email tools read the emails of the `mailbox`
+    pub fn with_mailbox(mut self, mailbox: Mailbox) -> Self {
+        self.mailbox = Some(mailbox);
+        self
+    }
+
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        self.mailbox.as_ref()
+    }
+
+    /// Have the Slack tools deliver their messages through the Slack `client`
+    #[cfg(feature = "slack")]
+    pub fn with_slack(mut self, client: slack::SlackClient) -> Self {
+        self.slack = Some(client);
+        self
+    }
+
+    #[cfg(feature = "slack")]
+    pub fn slack(&self) -> Option<&slack::SlackClient> {
+        self.slack.as_ref()
+    }
+
+    /// Confine the filesystem tools to the `sandbox`, without which they refuse to run
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    pub fn sandbox(&self) -> Option<&Sandbox> {
+        self.sandbox.as_ref()
+    }
+
+    /// Tool results the injection detector of the loop flagged, which policies can act upon
+    pub fn injections(&self) -> &[InjectionFlag] {
+        &self.injections
+    }
+
+    pub(crate) fn flag_injection(&mut self, flag: InjectionFlag) {
+        self.injections.push(flag);
+    }
+
+    /// Labeled entries the tools and the application keep between calls
+    pub fn store(&self) -> &LabeledStore<ifc::EmailLabel> {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut LabeledStore<ifc::EmailLabel> {
+        &mut self.store
+    }
 
-pub struct Datastore;
+    // Keep the labeled `result` of a call to `tool` which acted on the world, under the key
+    // `<tool>/<n>` for the n-th effect recorded, which is returned
+    pub(crate) fn record_effect(
+        &mut self,
+        tool: &str,
+        result: ifc::MetaValue<serde_json::Value, ifc::EmailLabel>,
+    ) -> String {
+        let key = format!("{tool}/{}", self.effects);
+        self.effects += 1;
+        self.store.put(&key, result);
+        key
+    }
 
+    // Keep the id of a send a tool just made, such that the loop can verify the send without
+    // looking for the id in what the tool returned
+    pub(crate) fn record_send(&mut self, send_id: String) {
+        self.sent = Some(send_id);
+    }
+
+    // Id of the send the last call made, if any, which is forgotten once taken
+    pub(crate) fn take_send(&mut self) -> Option<String> {
+        self.sent.take()
+    }
+
+    /// Untrusted tool results held out of the conversation
+    pub fn quarantine(&self) -> &Quarantine {
+        &self.quarantine
+    }
+
+    pub fn quarantine_mut(&mut self) -> &mut Quarantine {
+        &mut self.quarantine
+    }
+
+    /// Let the tool called `tool` read the results held in quarantine, in every namespace
+    pub fn with_sanctioned_tool(mut self, tool: &str) -> Self {
+        self.quarantine.sanction(tool);
+        self
+    }
+
+    /// Snapshot of the entries of the store, which [`rollback`] restores
+    ///
+    /// [`rollback`]: Self::rollback
+    pub fn snapshot(&self) -> DatastoreSnapshot {
+        DatastoreSnapshot {
+            store: self.store.clone(),
+            effects: self.effects,
+        }
+    }
+
+    /// Undo every write to the store since the `snapshot` was taken. What the tools did outside of
+    /// the datastore, such as messages delivered through Slack, stays done.
+    pub fn rollback(&mut self, snapshot: DatastoreSnapshot) {
+        self.store = snapshot.store;
+        self.effects = snapshot.effects;
+    }
+
+    /// Apply the writes which a `branch` of this datastore, cloned when the `base` snapshot was
+    /// taken, made to its store since, such as those of tool calls running side by side on clones
+    /// of the datastore. The effects the branch recorded are numbered after the ones recorded here,
+    /// such that branches never overwrite each other's effects.
+    pub fn merge(&mut self, branch: Datastore, base: &DatastoreSnapshot) {
+        let recorded = base.effects..branch.effects;
+        let deleted: Vec<_> = base
+            .store
+            .keys()
+            .filter(|key| !branch.store.contains(key))
+            .map(str::to_string)
+            .collect();
+        for key in deleted {
+            self.store.delete(&key);
+        }
+        let mut effects = vec![];
+        for (key, entry) in branch.store.into_entries() {
+            let previous = base.store.entry(&key);
+            if previous.is_some_and(|previous| {
+                previous.value() == entry.value() && previous.label() == entry.label()
+            }) {
+                continue;
+            }
+            // Effects are kept under `<tool>/<n>`, with `n` numbering them in the branch
+            let effect = key
+                .rsplit_once('/')
+                .and_then(|(tool, n)| Some((tool.to_string(), n.parse::<usize>().ok()?)))
+                .filter(|(_, n)| previous.is_none() && recorded.contains(n));
+            match effect {
+                Some((tool, n)) => effects.push((n, tool, entry)),
+                None => {
+                    self.store.put(&key, entry);
+                }
+            }
+        }
+        effects.sort_by_key(|(n, _, _)| *n);
+        for (_, tool, entry) in effects {
+            self.record_effect(&tool, entry);
+        }
+    }
+
+    /// Keep the entries of the run `run_id` apart from those of every other run, such that loops
+    /// taking turns on the datastore do not read each other's variables and intermediate results.
+    /// The entries of the run left are set aside until it is entered again.
+    pub fn enter(&mut self, run_id: &str) {
+        self.switch(Some(run_id.to_string()));
+    }
+
+    /// Go back to the entries shared by the runs which did not enter a namespace of their own
+    pub fn leave(&mut self) {
+        self.switch(None);
+    }
+
+    /// Run whose namespace the store holds, if any
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Runs which have a namespace in the datastore, the entered one included
+    pub fn namespaces(&self) -> Vec<&str> {
+        self.parked
+            .keys()
+            .chain([&self.namespace])
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Drop the namespaces which were left at least `ttl` ago, returning the runs they belonged
+    /// to. The entered namespace is never dropped.
+    pub fn collect_expired(&mut self, ttl: Duration) -> Vec<String> {
+        let expired: Vec<_> = self
+            .parked
+            .iter()
+            .filter_map(|(run_id, namespace)| {
+                let run_id = run_id.as_ref()?;
+                (namespace.left.elapsed() >= ttl).then(|| run_id.clone())
+            })
+            .collect();
+        for run_id in expired.iter() {
+            self.parked.remove(&Some(run_id.clone()));
+        }
+        expired
+    }
+
+    // Set the entries of the current namespace aside and take up those of `namespace`
+    fn switch(&mut self, namespace: Option<String>) {
+        if namespace == self.namespace {
+            return;
+        }
+        let mut entered = self.parked.remove(&namespace).unwrap_or_default();
+        // Tools are sanctioned to read the quarantine of every run
+        for tool in self.quarantine.sanctioned() {
+            entered.quarantine.sanction(tool);
+        }
+        let left = Namespace {
+            store: std::mem::replace(&mut self.store, entered.store),
+            effects: std::mem::replace(&mut self.effects, entered.effects),
+            quarantine: std::mem::replace(&mut self.quarantine, entered.quarantine),
+            left: Instant::now(),
+        };
+        let run_id = std::mem::replace(&mut self.namespace, namespace);
+        self.parked.insert(run_id, left);
+    }
+
+    /// Make the tool calls of `calls` as one transaction, rolling back their writes to the store
+    /// if any of them fails
+    pub async fn transaction<T, E>(
+        &mut self,
+        calls: impl AsyncFnOnce(&mut Self) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let snapshot = self.snapshot();
+        let result = calls(self).await;
+        if result.is_err() {
+            self.rollback(snapshot);
+        }
+        result
+    }
+}
+
+// Entries of a run set aside by the datastore, along with when they were left
+#[cfg(feature = "planners")]
 #[derive(Debug, Clone)]
+struct Namespace {
+    store: LabeledStore<ifc::EmailLabel>,
+    effects: usize,
+    quarantine: Quarantine,
+    left: Instant,
+}
+
+#[cfg(feature = "planners")]
+impl Default for Namespace {
+    fn default() -> Self {
+        Self {
+            store: LabeledStore::default(),
+            effects: 0,
+            quarantine: Quarantine::default(),
+            left: Instant::now(),
+        }
+    }
+}
+
+/// Entries of a [`Datastore`] at some point, to roll back to
+#[cfg(feature = "planners")]
+#[derive(Debug, Clone)]
+pub struct DatastoreSnapshot {
+    store: LabeledStore<ifc::EmailLabel>,
+    effects: usize,
+}
+
+#[cfg(feature = "planners")]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Action {
-    // Query the model with a specific conversation history and available tools
+    // Query the model with a specific conversation history and available tools, both shared with
+    // the planner which took the action
     Query(
         ConversationHistory<ChatCompletionRequestMessage>,
-        Vec<ChatCompletionTool>,
+        Arc<Vec<ChatCompletionTool>>,
     ),
     // Call a `Tool` with `Args`
     MakeCall(Function, Args, String),
     // Finish the conversation and respond to the user.
     Finish(String),
+    // Take an action defined by the application, such as pausing or escalating to a human
+    Custom(Box<dyn CustomAction>),
+}
+
+/// How the planning loop carries on after a [`CustomAction`]
+#[cfg(feature = "planners")]
+#[derive(Debug)]
+pub enum CustomOutcome {
+    // Pass the message to the planner and keep going
+    Continue(Message),
+    // Finish the run with the given answer
+    Finish(String),
+}
+
+/// Action defined by the application, for planners which need more than querying the model and
+/// calling tools. Custom actions are checked against the policy like any other action before the
+/// loop takes them.
+#[cfg(feature = "planners")]
+pub trait CustomAction: fmt::Debug + Send + Sync {
+    /// Name of the action, used to report it in traces and refusals
+    fn name(&self) -> &str;
+    /// Take the action, returning how the run carries on
+    fn execute(&self, datastore: &mut Datastore) -> CustomOutcome;
+    /// Clone the action behind the box
+    fn clone_box(&self) -> Box<dyn CustomAction>;
 }
 
+#[cfg(feature = "planners")]
+impl Clone for Box<dyn CustomAction> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Whether completing a task needs the model to see the results of the tools it calls
+#[cfg(feature = "planners")]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TaskType {
     DataDependent,
     DataIndependent,
 }
 
+#[cfg(feature = "planners")]
 pub struct Task {
     _query: String,
     _tools: Vec<Function>,
     _datastores: Vec<Datastore>,
 }
 
-#[cfg(test)]
-mod tests {}
+#[cfg(all(test, feature = "planners"))]
+mod tests {
+    use super::*;
+    use crate::{mock::MockLlm, openai::LlmClient};
+
+    // Hands the conversation over to a human
+    #[derive(Debug, Clone)]
+    struct Escalate;
+
+    impl CustomAction for Escalate {
+        fn name(&self) -> &str {
+            "escalate"
+        }
+
+        fn execute(&self, _datastore: &mut Datastore) -> CustomOutcome {
+            CustomOutcome::Finish("A human will get back to you.".to_string())
+        }
+
+        fn clone_box(&self) -> Box<dyn CustomAction> {
+            Box::new(self.clone())
+        }
+    }
+
+    // Escalates every message it is given
+    struct EscalatingPlanner;
+
+    impl Plan<State, Message> for EscalatingPlanner {
+        type Action = Action;
+        type Error = ();
+
+        fn plan(&mut self, state: State, _message: Message) -> Result<(State, Action), ()> {
+            Ok((state, Action::Custom(Box::new(Escalate))))
+        }
+    }
+
+    // Keeps thinking about the message it is given, without ever finishing
+    #[derive(Debug, Clone)]
+    struct Ponder;
+
+    impl CustomAction for Ponder {
+        fn name(&self) -> &str {
+            "ponder"
+        }
+
+        fn execute(&self, _datastore: &mut Datastore) -> CustomOutcome {
+            CustomOutcome::Continue(Message::Chat(MockLlm::assistant_text("Let me think.")))
+        }
+
+        fn clone_box(&self) -> Box<dyn CustomAction> {
+            Box::new(self.clone())
+        }
+    }
+
+    struct PonderingPlanner;
+
+    impl Plan<State, Message> for PonderingPlanner {
+        type Action = Action;
+        type Error = ();
+
+        fn plan(&mut self, state: State, _message: Message) -> Result<(State, Action), ()> {
+            Ok((state, Action::Custom(Box::new(Ponder))))
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_which_never_finish_are_cut_short() {
+        let run = |planning_loop: PlanningLoop<State, Message, Function, PonderingPlanner>| async {
+            let mut planning_loop = planning_loop;
+            planning_loop
+                .run(
+                    ConversationHistory::new(vec![]),
+                    &mut Datastore::default(),
+                    Message::Chat(MockLlm::assistant_text("What is the meaning of life?")),
+                )
+                .await
+        };
+        let new = || {
+            PlanningLoop::new(
+                PonderingPlanner,
+                LlmClient::mock(MockLlm::new(vec![])),
+                vec![],
+            )
+        };
+
+        let Err(plan::PlanError::IterationLimit { limit, trace }) =
+            run(new().with_max_iterations(5)).await
+        else {
+            panic!("Expected the iteration limit to be reached");
+        };
+        assert_eq!(limit, 5);
+        assert_eq!(trace.len(), 5);
+
+        let limit = std::time::Duration::from_millis(20);
+        let Err(plan::PlanError::Timeout { trace, .. }) = run(new().with_timeout(limit)).await
+        else {
+            panic!("Expected the run to time out");
+        };
+        assert!(!trace.is_empty());
+    }
+
+    #[test]
+    fn queries_share_the_conversation_and_tools_of_the_planner() {
+        let mut planner = BasicPlanner::new(vec![]);
+        let mut request = MockLlm::assistant_text("Read my latest email.");
+        request.role = async_openai::types::Role::User;
+        let (state, action) = planner
+            .plan(State::default(), Message::Chat(request))
+            .expect("Failed to plan");
+        let Action::Query(conv_history, tools) = &action else {
+            panic!("Expected the model to be queried");
+        };
+        assert!(std::ptr::eq(conv_history.messages(), state.messages()));
+
+        // The conversation is only copied once it changes, leaving the query as it was
+        let (state, action) = planner
+            .plan(
+                state,
+                Message::Chat(MockLlm::assistant_text("Nothing new.")),
+            )
+            .expect("Failed to plan");
+        assert_eq!(conv_history.messages().len(), 1);
+        assert_eq!(state.messages().len(), 2);
+        let Action::Finish(_) = action else {
+            panic!("Expected the run to finish");
+        };
+        let (_, next) = planner
+            .plan(
+                state,
+                Message::ToolResult(String::new(), "call_0".to_string()),
+            )
+            .expect("Failed to plan");
+        let Action::Query(_, next_tools) = next else {
+            panic!("Expected the model to be queried");
+        };
+        assert!(Arc::ptr_eq(tools, &next_tools));
+    }
+
+    #[tokio::test]
+    async fn runs_are_traced_and_can_be_replayed() {
+        let run = |model| async move {
+            let mut planning_loop = PlanningLoop::new(
+                BasicPlanner::new(vec![]),
+                LlmClient::mock(model),
+                vec![Function::new("read_emails".to_string())],
+            );
+            let mut request = MockLlm::assistant_text("Read my latest email.");
+            request.role = async_openai::types::Role::User;
+            planning_loop
+                .run_traced(
+                    ConversationHistory::new(vec![]),
+                    &mut Datastore::default(),
+                    Message::Chat(request),
+                )
+                .await
+        };
+        let (answer, trace) = run(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "read_emails",
+                serde_json::json!({ "count": { "kind": "value", "value": "1" } }),
+            ),
+            MockLlm::assistant_text("Alice confirmed the meeting."),
+        ]))
+        .await;
+        let answer = answer.expect("Failed to run");
+
+        let steps = trace.steps();
+        assert!(matches!(
+            steps.iter().map(|step| &step.action).collect::<Vec<_>>()[..],
+            [
+                Action::Query(..),
+                Action::MakeCall(..),
+                Action::Query(..),
+                Action::Finish(_)
+            ]
+        ));
+        assert!(matches!(
+            &steps[1].outcome,
+            Some(Message::ToolResult(_, id)) if id == "call_0"
+        ));
+        assert!(
+            steps
+                .windows(2)
+                .all(|steps| steps[0].taken_at <= steps[1].taken_at)
+        );
+
+        let (replayed, _) = run(MockLlm::new(trace.transcript())).await;
+        assert_eq!(replayed.expect("Failed to replay"), answer);
+    }
+
+    #[tokio::test]
+    async fn custom_actions_are_taken_by_the_loop() {
+        let mut planning_loop = PlanningLoop::new(
+            EscalatingPlanner,
+            LlmClient::mock(MockLlm::new(vec![])),
+            vec![],
+        );
+        let answer = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(MockLlm::assistant_text("I want to talk to a human.")),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "A human will get back to you.");
+    }
+
+    #[tokio::test]
+    async fn answers_are_streamed() {
+        let deltas = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let streamed = deltas.clone();
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+                "You have no new emails.",
+            )])),
+            vec![],
+        )
+        .with_answer_stream(move |delta| streamed.lock().unwrap().push(delta.to_string()));
+        let mut request = MockLlm::assistant_text("Any new emails?");
+        request.role = async_openai::types::Role::User;
+        let answer = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(*deltas.lock().unwrap(), [answer]);
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct EmailSummary {
+        sender: String,
+        subject: String,
+        urgent: bool,
+    }
+
+    #[tokio::test]
+    async fn final_answers_are_parsed_into_their_output_schema() {
+        let output = schema::OutputSchema::<EmailSummary>::new("email_summary", "Latest email")
+            .field::<String>("sender", "Address of the sender")
+            .field::<String>("subject", "Subject of the email")
+            .field::<bool>("urgent", "Whether the email needs an answer today");
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            LlmClient::mock(MockLlm::new(vec![
+                MockLlm::assistant_text(
+                    r#"{"sender": "alice@magnet.com", "subject": "Meeting", "urgent": true}"#,
+                ),
+                MockLlm::assistant_text("Alice wrote about the meeting."),
+            ])),
+            vec![],
+        );
+        let summary = planning_loop
+            .run_structured(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::user("Summarize my latest email."),
+                &output,
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(summary.sender, "alice@magnet.com");
+        assert_eq!(summary.subject, "Meeting");
+        assert!(summary.urgent);
+        // The format is only asked for during the run
+        assert!(planning_loop.model().response_format().is_none());
+
+        let answer = planning_loop
+            .run_structured(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::user("Summarize my latest email."),
+                &output,
+            )
+            .await;
+        assert!(matches!(
+            answer,
+            Err(plan::PlanError::InvalidOutput { answer, .. }) if answer == "Alice wrote about the meeting."
+        ));
+
+        // Fields the output needs cannot be left out
+        let missing = schema::OutputSchema::<EmailSummary>::new("email_summary", "")
+            .field::<String>("sender", "");
+        assert!(matches!(
+            missing.build(),
+            Err(schema::SchemaError::OutOfSync(_))
+        ));
+    }
+    #[tokio::test]
+    async fn runs_stop_before_going_over_their_token_budget() {
+        let run = |budget| async move {
+            let mut planning_loop = PlanningLoop::new(
+                BasicPlanner::new(vec![]),
+                LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+                    "You have no new emails.",
+                )])),
+                vec![],
+            )
+            .with_token_budget(budget);
+            let mut request = MockLlm::assistant_text("Any new emails?");
+            request.role = async_openai::types::Role::User;
+            let answer = planning_loop
+                .run(
+                    ConversationHistory::new(vec![]),
+                    &mut Datastore::default(),
+                    Message::Chat(request),
+                )
+                .await;
+            let requests = planning_loop.model().as_mock().unwrap().requests().len();
+            (answer, requests)
+        };
+
+        let (answer, requests) = run(10).await;
+        assert!(matches!(
+            answer,
+            Err(plan::PlanError::BudgetExceeded { budget: 10, .. })
+        ));
+        // The request was priced before being sent
+        assert_eq!(requests, 0);
+        assert_eq!(run(1000).await.0.unwrap(), "You have no new emails.");
+
+        // The prompt fits, but not along with the longest answer the model may give
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            LlmClient::anthropic("key", "claude"),
+            vec![],
+        )
+        .with_token_budget(100);
+        let mut request = MockLlm::assistant_text("Any new emails?");
+        request.role = async_openai::types::Role::User;
+        let answer = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request),
+            )
+            .await;
+        assert!(matches!(
+            answer,
+            Err(plan::PlanError::BudgetExceeded { budget: 100, needed }) if needed > 500
+        ));
+    }
+
+    #[tokio::test]
+    async fn quotas_are_enforced_across_the_loops_of_a_user() {
+        let store = std::sync::Arc::new(quota::QuotaStore::in_memory());
+        let run = |tokens| {
+            let quotas = Quotas::new(store.clone())
+                .with_scope("user:alice", quota::QuotaLimits::new().with_tokens(tokens));
+            async move {
+                let mut planning_loop = PlanningLoop::new(
+                    BasicPlanner::new(vec![]),
+                    LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+                        "You have no new emails.",
+                    )])),
+                    vec![],
+                );
+                let mut request = MockLlm::assistant_text("Any new emails?");
+                request.role = async_openai::types::Role::User;
+                planning_loop
+                    .run(
+                        ConversationHistory::new(vec![]),
+                        &mut Datastore::default().with_quotas(quotas),
+                        Message::Chat(request),
+                    )
+                    .await
+            }
+        };
+
+        assert!(run(1000).await.is_ok());
+        let spent = store.usage("user:alice").tokens;
+        assert!(spent > 0);
+        // Another loop of the same user starts from what the first one spent
+        let Err(plan::PlanError::QuotaExceeded(exceeded)) = run(spent + 1).await else {
+            panic!("Expected the quota to be exceeded");
+        };
+        assert_eq!(exceeded.used, spent);
+    }
+
+    #[tokio::test]
+    async fn effects_of_labeled_tools_are_kept_in_the_datastore() {
+        let mut datastore = Datastore::default();
+        let args = r#"{"to": "alice.hudson@magnet.com", "subject": "Hi", "body": "See you"}"#;
+        let (_, label) = MetaFunction::new("send_email_labeled".to_string())
+            .call(Args::new(args.to_string()), &mut datastore)
+            .await
+            .unwrap();
+        // Reading tools leave no trace
+        MetaFunction::new("read_emails_labeled".to_string())
+            .call(Args::new(r#"{"count": 1}"#.to_string()), &mut datastore)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            datastore.store().keys().collect::<Vec<_>>(),
+            ["send_email_labeled/0"]
+        );
+        let sent = datastore
+            .store()
+            .get("send_email_labeled/0", &label)
+            .unwrap()
+            .unwrap();
+        assert!(sent.value().as_str().unwrap().starts_with("Email sent!"));
+        assert_eq!(sent.label(), &label);
+    }
+
+    #[tokio::test]
+    async fn failed_transactions_roll_back_their_writes() {
+        let send = MetaFunction::new("send_email_labeled".to_string());
+        let args = |to: &str| {
+            Args::new(format!(
+                r#"{{"to": "{to}", "subject": "Hi", "body": "See you"}}"#
+            ))
+        };
+        let mut datastore = Datastore::default();
+        datastore
+            .transaction(async |datastore| {
+                send.call(args("alice.hudson@magnet.com"), datastore).await
+            })
+            .await
+            .unwrap();
+        // The second send is missing its arguments, after the first one went through
+        let failed = datastore
+            .transaction(async |datastore| {
+                send.call(args("alice.hudson@magnet.com"), datastore)
+                    .await?;
+                send.call(Args::new("{}".to_string()), datastore).await
+            })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(
+            datastore.store().keys().collect::<Vec<_>>(),
+            ["send_email_labeled/0"]
+        );
+    }
+
+    #[test]
+    fn runs_keep_their_entries_apart() {
+        let universe = tools::EmailAddressUniverse::new(&tools::INBOX).into_inner();
+        let label = tools::service_authority().mint(universe);
+        let entry = |text: &str| ifc::MetaValue::new(serde_json::json!(text), label.clone());
+        let mut datastore = Datastore::default();
+        datastore.enter("run-1");
+        datastore.store_mut().put("draft", entry("Meeting at 10"));
+        datastore.enter("run-2");
+        assert!(!datastore.store().contains("draft"));
+        datastore.store_mut().put("draft", entry("Lunch at noon"));
+        datastore.enter("run-1");
+        assert_eq!(datastore.namespace(), Some("run-1"));
+        let draft = datastore.store().get("draft", &label);
+        assert_eq!(draft.unwrap().unwrap().value(), "Meeting at 10");
+
+        let mut namespaces = datastore.namespaces();
+        namespaces.sort();
+        assert_eq!(namespaces, ["run-1", "run-2"]);
+        // Only the runs left behind expire, while the shared entries are never collected
+        assert_eq!(datastore.collect_expired(Duration::ZERO), ["run-2"]);
+        assert_eq!(datastore.namespaces(), ["run-1"]);
+        assert!(datastore.store().contains("draft"));
+    }
+}
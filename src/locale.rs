@@ -0,0 +1,213 @@
+//! Language and regional conventions of the user, used to tell the model how to phrase and format
+//! its answers, and to notice messages which are not written the way the user would write them.
+use std::fmt;
+
+// Languages writing the family name before the given name
+const FAMILY_NAME_FIRST: [&str; 5] = ["hu", "ja", "ko", "vi", "zh"];
+
+// Most frequent short words of the languages `detect_language` knows about. They are frequent
+// enough to show up in any sentence and rarely used by the other languages.
+const STOPWORDS: [(&str, &[&str]); 7] = [
+    (
+        "en",
+        &[
+            "the", "and", "is", "are", "you", "to", "of", "with", "for", "this",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "est", "vous", "des", "avec", "pour", "une",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "sie", "mit", "nicht", "ein", "für",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "usted", "con", "para", "una", "por",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "gli", "e", "è", "sono", "con", "per", "una", "che", "non",
+        ],
+    ),
+    (
+        "ro",
+        &[
+            "și", "este", "sunt", "cu", "pentru", "o", "nu", "că", "din", "vă",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "os", "e", "é", "você", "com", "para", "uma", "não", "do",
+        ],
+    ),
+];
+
+/// Language and optional region of the user, as in the `en-US` language tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locale {
+    language: String,
+    region: Option<String>,
+}
+
+impl Locale {
+    pub fn new(language: &str, region: Option<&str>) -> Self {
+        Self {
+            language: language.to_lowercase(),
+            region: region.map(str::to_uppercase),
+        }
+    }
+
+    /// Parse a language tag such as `en`, `en-US` or `pt_BR`
+    pub fn parse(tag: &str) -> Self {
+        let mut parts = tag.split(['-', '_']);
+        let language = parts.next().unwrap_or_default();
+        Self::new(language, parts.next())
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// English name of the language, falling back to its code for unknown languages
+    pub fn language_name(&self) -> &str {
+        match self.language.as_str() {
+            "en" => "English",
+            "fr" => "French",
+            "de" => "German",
+            "es" => "Spanish",
+            "it" => "Italian",
+            "ro" => "Romanian",
+            "pt" => "Portuguese",
+            "hu" => "Hungarian",
+            "ja" => "Japanese",
+            "ko" => "Korean",
+            "vi" => "Vietnamese",
+            "zh" => "Chinese",
+            language => language,
+        }
+    }
+
+    /// Format the date the way it is usually written in this locale
+    pub fn format_date(&self, year: u32, month: u32, day: u32) -> String {
+        match (self.language.as_str(), self.region()) {
+            ("en", Some("US")) => format!("{month:02}/{day:02}/{year}"),
+            ("de" | "ro", _) => format!("{day:02}.{month:02}.{year}"),
+            ("en" | "fr" | "es" | "it" | "pt", _) => format!("{day:02}/{month:02}/{year}"),
+            _ => format!("{year}-{month:02}-{day:02}"),
+        }
+    }
+
+    /// Format a full name the way it is usually written in this locale
+    pub fn format_name(&self, given: &str, family: &str) -> String {
+        if FAMILY_NAME_FIRST.contains(&self.language.as_str()) {
+            format!("{family} {given}")
+        } else {
+            format!("{given} {family}")
+        }
+    }
+
+    /// Instruction telling the model how to phrase and format its answers for this locale
+    pub fn instruction(&self) -> String {
+        let name_order = if FAMILY_NAME_FIRST.contains(&self.language.as_str()) {
+            "family name first"
+        } else {
+            "given name first"
+        };
+        format!(
+            "Answer in {}. Write dates like {} and people's names {name_order}.",
+            self.language_name(),
+            self.format_date(2025, 1, 31),
+        )
+    }
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self::new("en", None)
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.region {
+            Some(region) => write!(f, "{}-{region}", self.language),
+            None => write!(f, "{}", self.language),
+        }
+    }
+}
+
+/// Guess the language `text` is written in by counting frequent words. Returns `None` when the
+/// text is too short or too ambiguous to tell.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut scores: Vec<(&str, usize)> = STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| {
+            let hits = words
+                .iter()
+                .filter(|word| stopwords.contains(&word.as_str()))
+                .count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|&(_, hits)| std::cmp::Reverse(hits));
+    match scores.as_slice() {
+        // A couple of hits could be names or loan words, and a tie tells nothing
+        [(language, best), (_, second), ..] if *best >= 3 && best > second => Some(language),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locale_formats_and_detection() {
+        let us = Locale::parse("en-US");
+        assert_eq!(us.to_string(), "en-US");
+        assert_eq!(us.format_date(2025, 3, 7), "03/07/2025");
+        assert_eq!(Locale::parse("ro_RO").format_date(2025, 3, 7), "07.03.2025");
+        assert_eq!(
+            Locale::parse("hu").format_name("Ferenc", "Puskás"),
+            "Puskás Ferenc"
+        );
+        assert!(
+            Locale::parse("fr")
+                .instruction()
+                .starts_with("Answer in French.")
+        );
+
+        assert_eq!(
+            detect_language(
+                "Just wanted to confirm that the meeting is at 10 and the reports are ready for you."
+            ),
+            Some("en")
+        );
+        assert_eq!(
+            detect_language(
+                "Votre paiement est disponible, veuillez vous connecter avec le lien pour le recevoir."
+            ),
+            Some("fr")
+        );
+        assert_eq!(detect_language("OK"), None);
+    }
+}
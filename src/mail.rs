@@ -0,0 +1,304 @@
+//! Sources of the emails read by the `read_emails` tools. Without a [`Mailbox`] in the
+//! [`Datastore`], the tools read the demo `INBOX`. With one, they read the latest emails of a real
+//! mailbox, through any [`EmailProvider`] such as an IMAP server or the Gmail API.
+//!
+//! Live emails are trusted when the receiving server verified a DKIM signature of theirs for the
+//! domain of their sender, that domain is one of the trusted domains and they hide no text from
+//! their reader. The `From:` header alone is written by the sender, so it proves nothing. Emails
+//! can be read by their sender and receivers. Labels range over the address universe of the mailbox, which has to be the one the
+//! other tools label their results over, such that the labels can be joined. Addresses outside of
+//! the universe are left out of the readers, which only makes the labels more restrictive.
+//!
+//! [`Datastore`]: crate::Datastore
+mod gmail;
+#[cfg(feature = "imap")]
+mod imap;
+
+pub use gmail::GmailProvider;
+#[cfg(feature = "imap")]
+pub use imap::ImapProvider;
+
+use crate::{
+    Integrity, ProductLattice,
+    ifc::{InverseLattice, LatticeError, PowersetLattice},
+    tools::{
+        Email, EmailAddressUniverse, EmailLabel, INBOX, MetaValue, ReadEmailsArgs,
+        ReadEmailsResults, ReadEmailsResultsLabeled, label_labeled_email_list,
+    },
+};
+use futures::future::BoxFuture;
+use std::{collections::HashSet, fmt, io, sync::Arc};
+
+// Number of latest emails looked through by filtered reads
+const SEARCH_WINDOW: usize = 100;
+
+#[derive(Debug)]
+pub enum MailError {
+    IoError(io::Error),
+    HttpError(reqwest::Error),
+    // The mail server refused a command, with its response
+    Refused(String),
+    // The response of the mail server could not be made sense of
+    Malformed(String),
+    LatticeError(LatticeError),
+}
+
+impl From<io::Error> for MailError {
+    fn from(err: io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<reqwest::Error> for MailError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::HttpError(err)
+    }
+}
+
+impl From<LatticeError> for MailError {
+    fn from(err: LatticeError) -> Self {
+        Self::LatticeError(err)
+    }
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "the mail server could not be reached ({err})"),
+            Self::HttpError(err) => write!(f, "the mail service could not be reached ({err})"),
+            Self::Refused(response) => write!(f, "the mail server refused ({response})"),
+            Self::Malformed(reason) => write!(f, "the mail server answered oddly ({reason})"),
+            Self::LatticeError(err) => write!(f, "the emails could not be labeled ({err:?})"),
+        }
+    }
+}
+
+/// Source of live emails
+pub trait EmailProvider: Send + Sync {
+    /// Fetch the latest `count` emails of the mailbox, oldest first
+    fn fetch(&self, count: usize) -> BoxFuture<'_, Result<Vec<Email>, MailError>>;
+}
+
+/// Mailbox the `read_emails` tools read from, along with how its emails are labeled
+#[derive(Clone)]
+pub struct Mailbox {
+    provider: Arc<dyn EmailProvider>,
+    // Domains whose senders are trusted, such as the one of the organisation
+    trusted_domains: Vec<String>,
+    // Addresses the labels of the emails range over
+    universe: HashSet<String>,
+}
+
+impl fmt::Debug for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mailbox")
+            .field("trusted_domains", &self.trusted_domains)
+            .field("universe", &self.universe)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mailbox {
+    /// Mailbox reading from the `provider`, trusting no domain and labeling over the addresses of
+    /// the demo `INBOX` until told otherwise
+    pub fn new<P: EmailProvider + 'static>(provider: P) -> Self {
+        Self {
+            provider: Arc::new(provider),
+            trusted_domains: vec![],
+            universe: EmailAddressUniverse::new(&INBOX).into_inner(),
+        }
+    }
+
+    /// Trust the senders authenticated for one of the `domains`
+    pub fn with_trusted_domains<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        domains: I,
+    ) -> Self {
+        self.trusted_domains = domains
+            .into_iter()
+            .map(|domain| domain.into().to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Label the emails over the addresses of the `universe`
+    pub fn with_universe(mut self, universe: HashSet<String>) -> Self {
+        self.universe = universe;
+        self
+    }
+
+    pub async fn fetch(&self, count: usize) -> Result<Vec<Email>, MailError> {
+        self.provider.fetch(count).await
+    }
+
+    /// Label the `email` by the domain its sender was authenticated for, the text it hides and its
+    /// readers
+    pub fn label(&self, email: Email) -> Result<MetaValue<Email, EmailLabel>, LatticeError> {
+        let domain = email
+            .sender()
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_lowercase());
+        // The signature has to be for the domain the email claims to come from
+        let authenticated = domain.filter(|domain| email.authenticated_domain() == Some(domain));
+        let trusted = authenticated.is_some_and(|domain| self.trusted_domains.contains(&domain));
+        let integrity = if trusted && !email.parsed_body().is_suspicious() {
+            Integrity::trusted()
+        } else {
+            Integrity::untrusted()
+        };
+        let readers = email
+            .readers()
+            .filter(|reader| self.universe.contains(*reader))
+            .map(str::to_string)
+            .collect();
+        let readers = PowersetLattice::new(readers, self.universe.clone())?;
+        Ok(MetaValue::new(
+            email,
+            ProductLattice::new(integrity, InverseLattice::new(readers)),
+        ))
+    }
+
+    // Latest emails of the mailbox asked for by the `args`. Filtered reads look through the
+    // `SEARCH_WINDOW` latest emails for the ones asked for, keeping the latest of them.
+    async fn fetch_matching(&self, args: &ReadEmailsArgs) -> Result<Vec<Email>, MailError> {
+        if !args.is_filtered() {
+            return self.fetch(args.count()).await;
+        }
+        let mut emails: Vec<_> = self
+            .fetch(args.count().max(SEARCH_WINDOW))
+            .await?
+            .into_iter()
+            .filter(|email| args.matches(email))
+            .collect();
+        let skip = emails.len().saturating_sub(args.count());
+        Ok(emails.split_off(skip))
+    }
+
+    /// Read the emails asked for by the `args`
+    pub async fn read(&self, args: ReadEmailsArgs) -> Result<ReadEmailsResults, MailError> {
+        Ok(ReadEmailsResults::new(self.fetch_matching(&args).await?))
+    }
+
+    /// Read the emails asked for by the `args`, labeling each of them and the list as a whole
+    pub async fn read_labeled(
+        &self,
+        args: ReadEmailsArgs,
+    ) -> Result<ReadEmailsResultsLabeled, MailError> {
+        let emails = self
+            .fetch_matching(&args)
+            .await?
+            .into_iter()
+            .map(|email| self.label(email))
+            .collect::<Result<Vec<_>, _>>()?;
+        // An empty list is readable by anyone, over the universe of the mailbox
+        let emails = match emails.is_empty() {
+            true => MetaValue::new(
+                emails,
+                ProductLattice::new(
+                    Integrity::trusted(),
+                    InverseLattice::new(PowersetLattice::top(self.universe.clone())),
+                ),
+            ),
+            false => label_labeled_email_list(emails)?,
+        };
+        Ok(ReadEmailsResultsLabeled::new(emails)?)
+    }
+}
+
+/// Email out of a `raw` RFC 822 message, as parsed by [`Email::parse`]
+pub fn parse_message(raw: &str) -> Result<Email, MailError> {
+    Email::parse(raw).map_err(|err| MailError::Malformed(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, Call, Datastore, Function, MetaFunction};
+    use serde_json::{Value, json};
+
+    // Provider handing out the same emails every time
+    struct Fixed(Vec<Email>);
+
+    impl EmailProvider for Fixed {
+        fn fetch(&self, count: usize) -> BoxFuture<'_, Result<Vec<Email>, MailError>> {
+            let skip = self.0.len().saturating_sub(count);
+            Box::pin(async move { Ok(self.0[skip..].to_vec()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn live_emails_are_read_and_labeled() {
+        let phishing = parse_message(
+            "From: \"Payroll\" <payroll@magnet.com.evil.biz>\r\n\
+            To: Bob <bob.sheffield@magnet.com>, eve@evil.com\r\n\
+            Subject: Your salary\r\n\
+            \r\n\
+            Confirm your bank details.",
+        )
+        .unwrap();
+        assert_eq!(phishing.sender(), "payroll@magnet.com.evil.biz");
+        assert_eq!(
+            phishing.receivers().collect::<Vec<_>>(),
+            vec!["bob.sheffield@magnet.com", "eve@evil.com"]
+        );
+        assert_eq!(phishing.body(), "Confirm your bank details.");
+        // The sender claims the trusted domain, which it was not authenticated for
+        let spoofed = parse_message(
+            "Authentication-Results: mx.magnet.com; dkim=fail header.d=magnet.com\r\n\
+            From: Alice <alice.hudson@magnet.com>\r\n\
+            To: bob.sheffield@magnet.com\r\n\
+            \r\n\
+            Send me the payroll.",
+        )
+        .unwrap();
+        let signed = parse_message(
+            "Authentication-Results: mx.magnet.com; spf=pass; dkim=pass header.d=magnet.com\r\n\
+            Authentication-Results: forged; dkim=pass header.d=evil.biz\r\n\
+            From: Alice <alice.hudson@magnet.com>\r\n\
+            To: bob.sheffield@magnet.com\r\n\
+            \r\n\
+            See you at 10.",
+        )
+        .unwrap();
+        assert_eq!(signed.authenticated_domain(), Some("magnet.com"));
+        let mailbox = Mailbox::new(Fixed(vec![INBOX[0].clone(), phishing]))
+            .with_trusted_domains(["magnet.com"]);
+        let mut datastore = Datastore::default().with_mailbox(mailbox);
+
+        let (result, label) = MetaFunction::new("read_emails_labeled".to_string())
+            .call(
+                Args::new(json!({ "count": "1" }).to_string()),
+                &mut datastore,
+            )
+            .await
+            .unwrap();
+        let emails: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(emails[0]["subject"], "Your salary");
+        assert_eq!(label.lattice1(), &Integrity::untrusted());
+        // Only Bob can read the email, as the other addresses are unknown
+        assert_eq!(
+            label.lattice2().inner().subset(),
+            &HashSet::from(["bob.sheffield@magnet.com".to_string()])
+        );
+
+        let result = Function::new("read_emails".to_string())
+            .call(Args::new(json!({ "count": 5 }).to_string()), &mut datastore)
+            .await
+            .unwrap();
+        let emails: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(emails["emails"].as_array().unwrap().len(), 2);
+        assert_eq!(emails["emails"][0]["sender"], "alice.hudson@magnet.com");
+        let mailbox = datastore.mailbox().unwrap();
+        let label = |email: &Email| {
+            mailbox
+                .label(email.clone())
+                .unwrap()
+                .label()
+                .lattice1()
+                .clone()
+        };
+        assert_eq!(label(&signed), Integrity::trusted());
+        assert_eq!(label(&spoofed), Integrity::untrusted());
+        assert_eq!(label(&INBOX[0]), Integrity::untrusted());
+    }
+}
@@ -0,0 +1,181 @@
+//! Emails read through the Gmail API, with an OAuth access token allowed to read the mailbox.
+use super::{EmailProvider, MailError, parse_message};
+use crate::{secrets::Secret, tools::Email};
+use base64::{
+    Engine,
+    alphabet::URL_SAFE,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use futures::future::BoxFuture;
+use serde_json::Value;
+
+// Gmail encodes raw messages in URL safe base64, with or without padding
+const RAW: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Provider reading the inbox of the user the access token was issued for
+#[derive(Debug, Clone)]
+pub struct GmailProvider {
+    client: reqwest::Client,
+    token: Secret,
+    // Address of the API, which only changes to point at a fake of it
+    endpoint: String,
+}
+
+impl GmailProvider {
+    pub fn new(token: Secret) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            token,
+            endpoint: "https://gmail.googleapis.com".to_string(),
+        }
+    }
+
+    /// Talk to the API at `endpoint` rather than to Google
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    async fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Value, MailError> {
+        Ok(self
+            .client
+            .get(format!("{}/gmail/v1/users/me/{path}", self.endpoint))
+            .query(query)
+            .bearer_auth(self.token.expose())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn latest(&self, count: usize) -> Result<Vec<Email>, MailError> {
+        let count = count.to_string();
+        let listed = self
+            .get(
+                "messages",
+                &[("maxResults", count.as_str()), ("labelIds", "INBOX")],
+            )
+            .await?;
+        // Inboxes without any message have no list at all
+        let ids: Vec<_> = match listed.get("messages").and_then(Value::as_array) {
+            Some(messages) => messages
+                .iter()
+                .map(|message| message["id"].as_str().map(str::to_string))
+                .collect::<Option<_>>()
+                .ok_or(MailError::Malformed("a message has no id".to_string()))?,
+            None => vec![],
+        };
+        let mut emails = Vec::with_capacity(ids.len());
+        // The newest messages are listed first
+        for id in ids.iter().rev() {
+            let message = self
+                .get(&format!("messages/{id}"), &[("format", "raw")])
+                .await?;
+            let raw = message["raw"]
+                .as_str()
+                .and_then(|raw| RAW.decode(raw).ok())
+                .ok_or(MailError::Malformed(format!(
+                    "message {id} has no raw content"
+                )))?;
+            let unread = message["labelIds"]
+                .as_array()
+                .is_some_and(|labels| labels.iter().any(|label| label == "UNREAD"));
+            emails.push(parse_message(&String::from_utf8_lossy(&raw))?.with_unread(unread));
+        }
+        Ok(emails)
+    }
+}
+
+impl EmailProvider for GmailProvider {
+    fn fetch(&self, count: usize) -> BoxFuture<'_, Result<Vec<Email>, MailError>> {
+        Box::pin(self.latest(count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Gmail API answering each request with the next of the `responses`, handing back the request
+    // lines
+    fn gmail(responses: Vec<Value>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    let mut request = String::new();
+                    reader.read_line(&mut request).unwrap();
+                    let mut authorization = String::new();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line.to_lowercase().starts_with("authorization:") {
+                            authorization = line.trim().to_string();
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let response = response.to_string();
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                        content-length: {}\r\nconnection: close\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .unwrap();
+                    format!("{} {authorization}", request.trim())
+                })
+                .collect()
+        });
+        (endpoint, handle)
+    }
+
+    #[tokio::test]
+    async fn gmail_messages_are_fetched_raw() {
+        let raw = |from: &str, subject: &str| {
+            RAW.encode(format!(
+                "From: {from}\r\nTo: bob.sheffield@magnet.com\r\nSubject: {subject}\r\n\
+                MIME-Version: 1.0\r\nContent-Type: text/html\r\n\r\n<p>{subject}</p>"
+            ))
+        };
+        let (endpoint, requests) = gmail(vec![
+            json!({ "messages": [{ "id": "m2" }, { "id": "m1" }] }),
+            json!({ "id": "m1", "raw": raw("alice.hudson@magnet.com", "Older") }),
+            json!({
+                "id": "m2",
+                "raw": raw("Charlie <charlie.hamadou@magnet.com>", "Newer"),
+                "labelIds": ["INBOX", "UNREAD"],
+            }),
+        ]);
+        let provider =
+            GmailProvider::new(Secret::new("token".to_string())).with_endpoint(&endpoint);
+        let emails = provider.fetch(2).await.unwrap();
+        assert_eq!(emails[0].subject(), "Older");
+        assert_eq!(emails[1].sender(), "charlie.hamadou@magnet.com");
+        assert_eq!(emails[1].parsed_body().text(), "Newer");
+        assert!(!emails[0].is_unread() && emails[1].is_unread());
+
+        let requests = requests.join().unwrap();
+        assert_eq!(
+            requests[0],
+            "GET /gmail/v1/users/me/messages?maxResults=2&labelIds=INBOX HTTP/1.1 \
+            authorization: Bearer token"
+        );
+        assert!(requests[1].starts_with("GET /gmail/v1/users/me/messages/m1?format=raw"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Emails read from an IMAP server over TLS, logging in with a username and password, such as an
+//! app password.
+//!
+//! Only the few commands needed to read the latest messages are spoken: `LOGIN`, `EXAMINE`, which
+//! opens the mailbox read-only, `FETCH` and `LOGOUT`. Messages are fetched with `BODY.PEEK[]`, such
+//! that reading them does not mark them as seen, along with their `FLAGS`, which tell whether they
+//! were seen already.
+use super::{EmailProvider, MailError, parse_message};
+use crate::{secrets::Secret, tools::Email};
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{ClientConfig, RootCertStore, crypto::ring, pki_types::ServerName},
+};
+
+/// Provider reading a mailbox of an IMAP server
+#[derive(Debug, Clone)]
+pub struct ImapProvider {
+    host: String,
+    port: u16,
+    username: String,
+    password: Secret,
+    mailbox: String,
+}
+
+impl ImapProvider {
+    /// Read the `INBOX` of `username` on the server at `host`, on the IMAPS port
+    pub fn new(host: &str, username: &str, password: Secret) -> Self {
+        Self {
+            host: host.to_string(),
+            port: 993,
+            username: username.to_string(),
+            password,
+            mailbox: "INBOX".to_string(),
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_mailbox(mut self, mailbox: &str) -> Self {
+        self.mailbox = mailbox.to_string();
+        self
+    }
+
+    // Connection to the server, trusting the certificates trusted by the system
+    async fn connect(&self) -> Result<impl AsyncRead + AsyncWrite + Unpin, MailError> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs().certs {
+            // Certificates rustls cannot parse are only a problem if the server uses them
+            let _ = roots.add(cert);
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| MailError::Malformed(err.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(self.host.clone())
+            .map_err(|err| MailError::Malformed(err.to_string()))?;
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        Ok(TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await?)
+    }
+
+    async fn latest(&self, count: usize) -> Result<Vec<Email>, MailError> {
+        let stream = self.connect().await?;
+        Session::new(stream)
+            .read_latest(&self.username, &self.password, &self.mailbox, count)
+            .await
+    }
+}
+
+impl EmailProvider for ImapProvider {
+    fn fetch(&self, count: usize) -> BoxFuture<'_, Result<Vec<Email>, MailError>> {
+        Box::pin(self.latest(count))
+    }
+}
+
+// Literal sent by the server, along with the rest of the line it was sent in
+struct Literal {
+    content: String,
+    line: String,
+}
+
+// Response to a command, made of its untagged lines, with their literals inlined, and the literals
+// themselves
+struct Response {
+    lines: Vec<String>,
+    literals: Vec<Literal>,
+}
+
+// Conversation with an IMAP server
+struct Session<S> {
+    stream: BufReader<S>,
+    // Number of the last command sent, which tags it
+    tag: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            tag: 0,
+        }
+    }
+
+    async fn read_latest(
+        &mut self,
+        username: &str,
+        password: &Secret,
+        mailbox: &str,
+        count: usize,
+    ) -> Result<Vec<Email>, MailError> {
+        let greeting = self.line().await?;
+        if !greeting.starts_with("* OK") && !greeting.starts_with("* PREAUTH") {
+            return Err(MailError::Refused(greeting));
+        }
+        self.command(&format!(
+            "LOGIN {} {}",
+            quoted(username),
+            quoted(password.expose())
+        ))
+        .await?;
+        let examined = self
+            .command(&format!("EXAMINE {}", quoted(mailbox)))
+            .await?;
+        let exists = examined
+            .lines
+            .iter()
+            .find_map(|line| {
+                line.strip_prefix("* ")?
+                    .strip_suffix(" EXISTS")?
+                    .parse()
+                    .ok()
+            })
+            .ok_or(MailError::Malformed(format!(
+                "{mailbox} has no message count"
+            )))?;
+        let emails = match (exists, count) {
+            (0, _) | (_, 0) => vec![],
+            (exists, count) => {
+                let first = exists - count.min(exists) + 1;
+                self.command(&format!("FETCH {first}:{exists} (FLAGS BODY.PEEK[])"))
+                    .await?
+                    .literals
+                    .iter()
+                    .map(|literal| {
+                        let seen = flags(&literal.line).any(|flag| flag == "\\Seen");
+                        Ok(parse_message(&literal.content)?.with_unread(!seen))
+                    })
+                    .collect::<Result<_, MailError>>()?
+            }
+        };
+        self.command("LOGOUT").await?;
+        Ok(emails)
+    }
+
+    // Send the `command` and wait for it to be done
+    async fn command(&mut self, command: &str) -> Result<Response, MailError> {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+        self.stream
+            .get_mut()
+            .write_all(format!("{tag} {command}\r\n").as_bytes())
+            .await?;
+        self.stream.get_mut().flush().await?;
+        let mut response = Response {
+            lines: vec![],
+            literals: vec![],
+        };
+        loop {
+            let mut line = self.line().await?;
+            // Literals are announced at the end of a line with their size, and the line goes on
+            // after them
+            while let Some(size) = literal_size(&line) {
+                let mut literal = vec![0; size];
+                self.stream.read_exact(&mut literal).await?;
+                let literal = String::from_utf8_lossy(&literal).into_owned();
+                let rest = self.line().await?;
+                response.literals.push(Literal {
+                    content: literal.clone(),
+                    line: format!("{line}{rest}"),
+                });
+                line.push_str(&literal);
+                line.push_str(&rest);
+            }
+            match line.strip_prefix(&format!("{tag} ")) {
+                Some(status) if status.starts_with("OK") => return Ok(response),
+                Some(_) => return Err(MailError::Refused(line)),
+                None => response.lines.push(line),
+            }
+        }
+    }
+
+    // Next line sent by the server, without its line ending
+    async fn line(&mut self) -> Result<String, MailError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(MailError::Malformed(
+                "the server closed the connection".to_string(),
+            ));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+// Size of the literal announced at the end of the `line`, such as `{342}`
+fn literal_size(line: &str) -> Option<usize> {
+    line.strip_suffix('}')?.rsplit_once('{')?.1.parse().ok()
+}
+
+// Flags listed in the `FLAGS` item of a `line`, such as `\Seen`
+fn flags(line: &str) -> impl Iterator<Item = &str> {
+    line.split_once("FLAGS (")
+        .and_then(|(_, flags)| flags.split_once(')'))
+        .map_or("", |(flags, _)| flags)
+        .split_whitespace()
+}
+
+// The `value` as an IMAP quoted string
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn imap_messages_are_fetched_read_only() {
+        let (client, server) = duplex(64 * 1024);
+        let message = "From: alice.hudson@magnet.com\r\nTo: bob.sheffield@magnet.com\r\n\
+            Subject: Re: Meeting\r\n\r\nSee you at 10.\r\n";
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut commands = vec![];
+            server.get_mut().write_all(b"* OK ready\r\n").await.unwrap();
+            let answers = [
+                "a1 OK logged in\r\n".to_string(),
+                "* 3 EXISTS\r\n* OK [READ-ONLY]\r\na2 OK [READ-ONLY] EXAMINE done\r\n".to_string(),
+                format!(
+                    "* 3 FETCH (FLAGS (\\Seen \\Answered) BODY[] {{{}}}\r\n{message})\r\n\
+                    a3 OK FETCH done\r\n",
+                    message.len()
+                ),
+                "* BYE\r\na4 OK LOGOUT done\r\n".to_string(),
+            ];
+            for answer in answers {
+                let mut command = String::new();
+                server.read_line(&mut command).await.unwrap();
+                commands.push(command.trim().to_string());
+                server.get_mut().write_all(answer.as_bytes()).await.unwrap();
+            }
+            commands
+        });
+
+        let emails = Session::new(client)
+            .read_latest("bob", &Secret::new("p\"ss".to_string()), "INBOX", 1)
+            .await
+            .unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].subject(), "Re: Meeting");
+        assert_eq!(emails[0].body(), "See you at 10.\r\n");
+        assert!(!emails[0].is_unread());
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "a1 LOGIN \"bob\" \"p\\\"ss\"",
+                "a2 EXAMINE \"INBOX\"",
+                "a3 FETCH 3:3 (FLAGS BODY.PEEK[])",
+                "a4 LOGOUT",
+            ]
+        );
+    }
+}
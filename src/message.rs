@@ -1,5 +1,14 @@
 use crate::{Args, Function, Label};
-use async_openai::types::ChatCompletionResponseMessage;
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartImage,
+        ChatCompletionRequestMessageContentPartText, ChatCompletionRequestToolMessageArgs,
+        ChatCompletionRequestUserMessageArgs, ChatCompletionRequestUserMessageContentPart,
+        ChatCompletionResponseMessage, ImageUrlArgs, Role,
+    },
+};
+use serde::{Deserialize, Serialize};
 
 // A message passed as information in the planner
 #[derive(Clone)]
@@ -15,10 +24,139 @@ pub enum _Message1 {
 }
 
 // A message passed as information in the planner
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Message {
     Chat(ChatCompletionResponseMessage),
     ToolResult(String, String),
+    // Request of the user, along with the images it is about
+    UserImages(String, Vec<Image>),
+    // Image resulting from the tool call with the given id, such as a screenshot of an email
+    ToolImage(Image, String),
+}
+
+/// Image shown to the model, either hosted at a URL or embedded as base64 data
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Image {
+    Url(String),
+    Base64 { media_type: String, data: String },
+}
+
+impl Image {
+    /// Image embedded as `data` encoded in base64, of the given `media_type` such as `image/png`
+    pub fn base64(media_type: &str, data: &str) -> Self {
+        Self::Base64 {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        }
+    }
+
+    /// The image at `url`, where data URLs such as `data:image/png;base64,...` embed the image
+    pub fn from_url(url: &str) -> Self {
+        url.strip_prefix("data:")
+            .and_then(|url| url.split_once(";base64,"))
+            .map(|(media_type, data)| Self::base64(media_type, data))
+            .unwrap_or_else(|| Self::Url(url.to_string()))
+    }
+
+    /// URL the model reads the image from, embedding the data of base64 images
+    pub fn url(&self) -> String {
+        match self {
+            Self::Url(url) => url.clone(),
+            Self::Base64 { media_type, data } => format!("data:{media_type};base64,{data}"),
+        }
+    }
+
+    /// The user message showing the image to the model, with some `text` before it
+    pub fn user_message(
+        text: &str,
+        images: &[Image],
+    ) -> Result<ChatCompletionRequestMessage, OpenAIError> {
+        let mut parts: Vec<ChatCompletionRequestUserMessageContentPart> = vec![];
+        if !text.is_empty() {
+            parts.push(ChatCompletionRequestMessageContentPartText::from(text).into());
+        }
+        for image in images {
+            let image_url = ImageUrlArgs::default().url(image.url()).build()?;
+            parts.push(ChatCompletionRequestMessageContentPartImage { image_url }.into());
+        }
+        Ok(ChatCompletionRequestUserMessageArgs::default()
+            .content(parts)
+            .build()?
+            .into())
+    }
+
+    /// The messages reporting the image the tool call `id` resulted in. Tool messages only carry
+    /// text, so the image follows the result of the call in a user message.
+    pub fn tool_messages(
+        &self,
+        id: &str,
+    ) -> Result<[ChatCompletionRequestMessage; 2], OpenAIError> {
+        let result = ChatCompletionRequestToolMessageArgs::default()
+            .content(TOOL_IMAGE_RESULT)
+            .tool_call_id(id)
+            .build()?
+            .into();
+        Ok([
+            result,
+            Self::user_message(TOOL_IMAGE_CAPTION, std::slice::from_ref(self))?,
+        ])
+    }
+}
+
+/// The messages reporting the `content` the tool call `id` resulted in, which show the image
+/// instead if the content is an image data URL, such as one read from a variable
+pub fn result_messages(
+    content: String,
+    id: String,
+) -> Result<Vec<ChatCompletionRequestMessage>, OpenAIError> {
+    match Message::tool_result(content, id) {
+        Message::ToolImage(image, id) => Ok(image.tool_messages(&id)?.to_vec()),
+        Message::ToolResult(content, id) => Ok(vec![
+            ChatCompletionRequestToolMessageArgs::default()
+                .content(content)
+                .tool_call_id(id)
+                .build()?
+                .into(),
+        ]),
+        _ => unreachable!("Tool results are either text or an image"),
+    }
+}
+
+// Result of a tool call which resulted in an image, which follows it
+pub(crate) const TOOL_IMAGE_RESULT: &str = "The result is the image below.";
+// Text introducing the image a tool call resulted in
+const TOOL_IMAGE_CAPTION: &str = "Image resulting from the latest tool call:";
+
+impl Message {
+    /// Request of the user, which planners answer by querying the model
+    #[allow(deprecated)]
+    pub fn user(content: &str) -> Self {
+        Self::Chat(ChatCompletionResponseMessage {
+            content: Some(content.to_string()),
+            refusal: None,
+            tool_calls: None,
+            role: Role::User,
+            function_call: None,
+            audio: None,
+        })
+    }
+
+    /// Whether the message is a request of the user, with or without images
+    pub fn is_user_request(&self) -> bool {
+        match self {
+            Self::Chat(message) => message.role == Role::User,
+            Self::UserImages(..) => true,
+            Self::ToolResult(..) | Self::ToolImage(..) => false,
+        }
+    }
+
+    /// Result of the tool call `id`, which is an image if the `content` is an image data URL
+    pub fn tool_result(content: String, id: String) -> Self {
+        match content.starts_with("data:image/") && content.contains(";base64,") {
+            true => Self::ToolImage(Image::from_url(&content), id),
+            false => Self::ToolResult(content, id),
+        }
+    }
 }
 
 #[derive(Clone)]
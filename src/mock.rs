@@ -0,0 +1,172 @@
+//! A scripted stand-in for the model, replaying a fixed transcript of assistant messages such that
+//! planners and planning loops can be exercised offline and deterministically.
+use async_openai::{
+    error::{ApiError, OpenAIError},
+    types::{
+        ChatChoice, ChatCompletionMessageToolCall, ChatCompletionRequestMessage,
+        ChatCompletionResponseMessage, ChatCompletionTool, ChatCompletionToolType,
+        CreateChatCompletionResponse, FinishReason, FunctionCall, Role,
+    },
+};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicUsize, Ordering},
+};
+
+/// Model which answers each chat request with the next message of its `transcript`
+#[derive(Debug)]
+pub struct MockLlm {
+    // The assistant messages returned, in order, for each chat request
+    transcript: Arc<Vec<ChatCompletionResponseMessage>>,
+    // Index of the next message to be returned from the transcript
+    cursor: AtomicUsize,
+    // Conversations the mock was queried with, in order
+    requests: Mutex<Vec<Vec<ChatCompletionRequestMessage>>>,
+    // Requests still to be turned down as rate limited before answering
+    rate_limits: AtomicUsize,
+}
+
+impl MockLlm {
+    pub fn new(transcript: Vec<ChatCompletionResponseMessage>) -> Self {
+        Self {
+            transcript: Arc::new(transcript),
+            cursor: AtomicUsize::new(0),
+            requests: Mutex::new(vec![]),
+            rate_limits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create a new mock replaying the same transcript from the start
+    pub fn replay(&self) -> Self {
+        Self {
+            transcript: self.transcript.clone(),
+            cursor: AtomicUsize::new(0),
+            requests: Mutex::new(vec![]),
+            rate_limits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create a new mock replaying the same transcript from the start, where the tool calls are
+    /// renamed with `rename`.
+    pub fn replay_renamed<F: Fn(&str) -> String>(&self, rename: F) -> Self {
+        let transcript = self
+            .transcript
+            .iter()
+            .cloned()
+            .map(|mut message| {
+                for tool_call in message.tool_calls.iter_mut().flatten() {
+                    tool_call.function.name = rename(&tool_call.function.name);
+                }
+                message
+            })
+            .collect();
+        Self::new(transcript)
+    }
+
+    /// Turn down the first `count` requests as rate limited, without moving on in the transcript
+    pub fn with_rate_limits(self, count: usize) -> Self {
+        self.rate_limits.store(count, Ordering::Relaxed);
+        self
+    }
+
+    pub fn transcript(&self) -> &[ChatCompletionResponseMessage] {
+        &self.transcript
+    }
+
+    /// Returns the conversations the mock was queried with so far
+    pub fn requests(&self) -> Vec<Vec<ChatCompletionRequestMessage>> {
+        self.requests.lock().expect("MockLlm lock poisoned").clone()
+    }
+
+    /// Answer the chat request with the next message in the transcript. The available `tools`
+    /// are ignored, as the transcript already decides which tools get called.
+    pub fn chat(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        _tools: Vec<ChatCompletionTool>,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        self.requests
+            .lock()
+            .expect("MockLlm lock poisoned")
+            .push(messages);
+        if self
+            .rate_limits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+        {
+            return Err(OpenAIError::ApiError(ApiError {
+                message: "Rate limit reached for requests".to_string(),
+                r#type: Some("requests".to_string()),
+                param: None,
+                code: Some("rate_limit_exceeded".to_string()),
+            }));
+        }
+        let index = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let message = self.transcript.get(index).cloned().ok_or_else(|| {
+            OpenAIError::InvalidArgument(format!(
+                "MockLlm transcript exhausted after {} messages",
+                self.transcript.len()
+            ))
+        })?;
+        let finish_reason = if message.tool_calls.is_some() {
+            FinishReason::ToolCalls
+        } else {
+            FinishReason::Stop
+        };
+
+        Ok(CreateChatCompletionResponse {
+            id: format!("mock-{index}"),
+            choices: vec![ChatChoice {
+                index: 0,
+                message,
+                finish_reason: Some(finish_reason),
+                logprobs: None,
+            }],
+            created: 0,
+            model: "mock".to_string(),
+            service_tier: None,
+            system_fingerprint: None,
+            object: "chat.completion".to_string(),
+            usage: None,
+        })
+    }
+
+    /// Build an assistant message with text `content`
+    #[allow(deprecated)]
+    pub fn assistant_text(content: &str) -> ChatCompletionResponseMessage {
+        ChatCompletionResponseMessage {
+            content: Some(content.to_string()),
+            refusal: None,
+            tool_calls: None,
+            role: Role::Assistant,
+            function_call: None,
+            audio: None,
+        }
+    }
+
+    /// Build an assistant message calling the tool `name` with the JSON `arguments`
+    #[allow(deprecated)]
+    pub fn assistant_tool_call(
+        id: &str,
+        name: &str,
+        arguments: serde_json::Value,
+    ) -> ChatCompletionResponseMessage {
+        ChatCompletionResponseMessage {
+            content: None,
+            refusal: None,
+            tool_calls: Some(vec![ChatCompletionMessageToolCall {
+                id: id.to_string(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: name.to_string(),
+                    arguments: arguments.to_string(),
+                },
+            }]),
+            role: Role::Assistant,
+            function_call: None,
+            audio: None,
+        }
+    }
+}
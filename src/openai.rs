@@ -1,16 +1,81 @@
+use crate::{
+    anthropic::AnthropicClient,
+    mock::MockLlm,
+    response_cache::{ResponseCache, cache_key},
+    retry::RetryPolicy,
+};
 use async_openai::{
     Client,
     config::OpenAIConfig,
     error::OpenAIError,
     types::{
-        ChatCompletionRequestMessage, ChatCompletionTool, CreateChatCompletionRequestArgs,
-        CreateChatCompletionResponse, CreateCompletionRequestArgs, CreateCompletionResponse,
-        Prompt,
+        ChatChoice, ChatCompletionMessageToolCall, ChatCompletionRequestFunctionMessageArgs,
+        ChatCompletionRequestMessage, ChatCompletionRequestToolMessageContent,
+        ChatCompletionRequestToolMessageContentPart, ChatCompletionResponseMessage,
+        ChatCompletionStreamOptions, ChatCompletionTool, ChatCompletionToolType, CompletionUsage,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse, CreateCompletionRequestArgs, CreateCompletionResponse,
+        FinishReason, FunctionCall, Prompt, ResponseFormat, Role,
     },
 };
+use futures::StreamExt;
+use serde_json::{Value, json};
+use std::{collections::HashMap, path::Path};
+
+// Endpoint of the OpenAI API, used unless configured otherwise
+const OPENAI_API_BASE: &str = "https://api.openai.com/v1";
+// Model answering the chat requests sent to the OpenAI API
+const OPENAI_CHAT_MODEL: &str = "gpt-4o";
+// Tokens the model may answer the chat requests sent to the OpenAI API with
+const OPENAI_MAX_COMPLETION_TOKENS: u32 = 500;
+
+// The functions API is deprecated in favour of tools, but it is exactly what the legacy mode needs
+#[allow(deprecated)]
+use async_openai::types::ChatCompletionFunctions;
+
+/// The API used to advertise tools to the model and to receive the model's tool calls.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ToolCallingMode {
+    /// The `tools` / `tool_calls` API
+    #[default]
+    Tools,
+    /// The deprecated `functions` / `function_call` API, which is the only one some proxies and
+    /// older gateways understand.
+    LegacyFunctions,
+}
+
+/// The service answering the requests of an [`LlmClient`]
+enum Backend {
+    OpenAI(Client<OpenAIConfig>),
+    Anthropic(AnthropicClient),
+    Mock(MockLlm),
+}
+
+/// Why an [`LlmClient`] could not be configured
+#[derive(Debug)]
+pub enum ConfigError {
+    // The API key was not found, along with where it was looked for
+    MissingKey(String),
+    IoError(std::io::Error),
+    // The configuration is malformed
+    Invalid(String),
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
 
 pub struct LlmClient {
-    client: Client<OpenAIConfig>,
+    backend: Backend,
+    mode: ToolCallingMode,
+    // Failed chat requests are sent again under this policy, if any
+    retry: Option<RetryPolicy>,
+    // Completions are replayed from this cache, if any, instead of being requested again
+    cache: Option<ResponseCache>,
+    // Format the model is asked to answer in, if any other than free text
+    response_format: Option<ResponseFormat>,
 }
 
 impl LlmClient {
@@ -21,7 +86,49 @@ impl LlmClient {
             .with_org_id("buciumede");
 
         let client = Client::with_config(config);
-        Self { client }
+        Self {
+            backend: Backend::OpenAI(client),
+            mode: ToolCallingMode::default(),
+            retry: None,
+            cache: None,
+            response_format: None,
+        }
+    }
+
+    /// Create a client answered by the scripted `mock` model instead of a real service
+    pub fn mock(mock: MockLlm) -> Self {
+        Self {
+            backend: Backend::Mock(mock),
+            mode: ToolCallingMode::default(),
+            retry: None,
+            cache: None,
+            response_format: None,
+        }
+    }
+
+    /// Create a client answered by Anthropic's `model`. Tool calling always uses tool use blocks,
+    /// regardless of the tool calling mode.
+    pub fn anthropic(api_key: &str, model: &str) -> Self {
+        Self::from_anthropic(AnthropicClient::new(api_key, model))
+    }
+
+    /// Create a client answered by the configured Anthropic `client`
+    pub fn from_anthropic(client: AnthropicClient) -> Self {
+        Self {
+            backend: Backend::Anthropic(client),
+            mode: ToolCallingMode::default(),
+            retry: None,
+            cache: None,
+            response_format: None,
+        }
+    }
+
+    /// Returns the mock model answering this client, if any
+    pub fn as_mock(&self) -> Option<&MockLlm> {
+        match &self.backend {
+            Backend::Mock(mock) => Some(mock),
+            Backend::OpenAI(_) | Backend::Anthropic(_) => None,
+        }
     }
 
     pub fn local_llama31() -> Self {
@@ -30,10 +137,175 @@ impl LlmClient {
         Self::new(api_key, api_base)
     }
 
+    /// Create a client of the OpenAI API configured from the environment, see [`from_env`].
+    ///
+    /// # Panics
+    ///
+    /// If `OPENAI_API_KEY` is not set.
+    ///
+    /// [`from_env`]: Self::from_env
     pub fn openai() -> Self {
-        let api_key = env!("OPENAI_API_KEY");
-        let api_base = "https://api.openai.com/v1";
-        Self::new(api_key, api_base)
+        Self::from_env().expect("OPENAI_API_KEY is not set")
+    }
+
+    /// Create a client of the OpenAI API with the key in the `OPENAI_API_KEY` environment
+    /// variable. Another endpoint can be given in `OPENAI_API_BASE`.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let api_key = std::env::var("OPENAI_API_KEY")
+            .map_err(|_| ConfigError::MissingKey("OPENAI_API_KEY".to_string()))?;
+        let api_base =
+            std::env::var("OPENAI_API_BASE").unwrap_or_else(|_| OPENAI_API_BASE.to_string());
+        Ok(Self::new(&api_key, &api_base))
+    }
+
+    /// Create a client from the JSON config file at `path`, see [`from_config`].
+    ///
+    /// [`from_config`]: Self::from_config
+    pub fn from_config_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let config = std::fs::read_to_string(path)?;
+        let config = serde_json::from_str(&config)
+            .map_err(|err| ConfigError::Invalid(format!("Config is not valid JSON: {err}")))?;
+        Self::from_config(&config)
+    }
+
+    /// Create a client from a JSON `config` such as
+    /// `{ "backend": "anthropic", "api_key_file": "/run/secrets/anthropic", "model": "claude-sonnet-4-5" }`.
+    /// The `backend` is either `openai`, the default, or `anthropic`, which needs a `model`. The key
+    /// is given either as `api_key` or in the file at `api_key_file`, and `api_base` optionally
+    /// points the client to another endpoint.
+    pub fn from_config(config: &Value) -> Result<Self, ConfigError> {
+        let field = |name: &str| config[name].as_str();
+        let api_key = match (field("api_key"), field("api_key_file")) {
+            (Some(api_key), _) => api_key.to_string(),
+            // Files usually end with a new line which is not part of the key
+            (None, Some(path)) => std::fs::read_to_string(path)?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            (None, None) => {
+                return Err(ConfigError::MissingKey(
+                    "`api_key` or `api_key_file` in the config".to_string(),
+                ));
+            }
+        };
+        match field("backend").unwrap_or("openai") {
+            "openai" => Ok(Self::new(
+                &api_key,
+                field("api_base").unwrap_or(OPENAI_API_BASE),
+            )),
+            "anthropic" => {
+                let model = field("model").ok_or_else(|| {
+                    ConfigError::Invalid("The anthropic backend needs a `model`".to_string())
+                })?;
+                let mut client = AnthropicClient::new(&api_key, model);
+                if let Some(api_base) = field("api_base") {
+                    client = client.with_api_base(api_base);
+                }
+                Ok(Self::from_anthropic(client))
+            }
+            backend => Err(ConfigError::Invalid(format!("Unknown backend `{backend}`"))),
+        }
+    }
+
+    /// Use the given tool calling `mode` when talking to the model. The planners keep working with
+    /// `tools` and `tool_calls` regardless, as the client converts to and from the legacy format.
+    pub fn with_tool_calling_mode(mut self, mode: ToolCallingMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn tool_calling_mode(&self) -> ToolCallingMode {
+        self.mode
+    }
+
+    /// Retry the chat requests failing with transient errors, such as rate limits, under the given
+    /// `policy`. Without a policy the errors are returned right away.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    /// Answer the chat requests from the `cache` when it already holds a completion for the same
+    /// messages, tools and options, and cache the completions of all the other requests
+    pub fn with_response_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn response_cache(&self) -> Option<&ResponseCache> {
+        self.cache.as_ref()
+    }
+
+    /// Ask the model to answer in the given `format`, such as the JSON schema of an
+    /// [`OutputSchema`]. Only the OpenAI backend enforces it, the other backends answer as usual.
+    ///
+    /// [`OutputSchema`]: crate::schema::OutputSchema
+    pub fn with_response_format(mut self, format: ResponseFormat) -> Self {
+        self.response_format = Some(format);
+        self
+    }
+
+    /// Ask the model to answer in the given `format` from now on, or in free text if `None`
+    pub fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.response_format = format;
+    }
+
+    pub fn response_format(&self) -> Option<&ResponseFormat> {
+        self.response_format.as_ref()
+    }
+
+    /// Most tokens the model may answer a request with. Scripted answers of mock models are not
+    /// capped.
+    pub fn max_completion_tokens(&self) -> u32 {
+        match &self.backend {
+            Backend::OpenAI(_) => OPENAI_MAX_COMPLETION_TOKENS,
+            Backend::Anthropic(client) => client.max_tokens(),
+            Backend::Mock(_) => 0,
+        }
+    }
+
+    // Options of the client which change the completions of the model, keying cached completions
+    fn cache_options(&self) -> Value {
+        let (backend, model) = match &self.backend {
+            Backend::OpenAI(_) => ("openai", OPENAI_CHAT_MODEL),
+            Backend::Anthropic(client) => ("anthropic", client.model()),
+            Backend::Mock(_) => ("mock", ""),
+        };
+        let mut options = json!({
+            "backend": backend,
+            "model": model,
+            "max_tokens": self.max_completion_tokens(),
+            "mode": format!("{:?}", self.mode),
+        });
+        // Left out of free text requests, such that their keys stay the same
+        if let Some(format) = &self.response_format {
+            options["response_format"] = json!(format);
+        }
+        options
+    }
+
+    // Look up the completion of the `messages` with the `tools` in the cache, returning its key
+    // such that the completion can be cached once requested
+    fn cached(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: &[ChatCompletionTool],
+    ) -> Option<(Value, Option<CreateChatCompletionResponse>)> {
+        let cache = self.cache.as_ref()?;
+        let key = cache_key(messages, tools, self.cache_options());
+        let response = cache.get(&key);
+        Some((key, response))
+    }
+
+    // Cache the `response` under the `key`, if the client has a cache
+    fn cache(&self, key: Option<Value>, response: &CreateChatCompletionResponse) {
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            // A completion which could not be written is simply requested again next time
+            let _ = cache.insert(key, response.clone());
+        }
     }
 
     pub async fn completion<V: Into<Prompt>>(
@@ -48,7 +320,12 @@ impl LlmClient {
             .max_tokens(100_u32)
             .build()?;
 
-        let response = self.client.completions().create(request).await?;
+        let Backend::OpenAI(client) = &self.backend else {
+            return Err(OpenAIError::InvalidArgument(
+                "Completions are only supported by the OpenAI backend".to_string(),
+            ));
+        };
+        let response = client.completions().create(request).await?;
         Ok(response)
     }
 
@@ -60,19 +337,352 @@ impl LlmClient {
         messages: M,
         tools: T,
     ) -> Result<CreateChatCompletionResponse, OpenAIError> {
-        let model = "gpt-4o";
-        // Create a `CreateCompletionRequest`
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(model)
-            .messages(messages)
-            .tools(tools)
-            .parallel_tool_calls(false)
-            .max_completion_tokens(500_u32)
-            .build()?;
+        let (messages, tools) = (messages.into(), tools.into());
+        let key = match self.cached(&messages, &tools) {
+            Some((_, Some(response))) => return Ok(response),
+            Some((key, None)) => Some(key),
+            None => None,
+        };
+        let mut attempt = 1;
+        loop {
+            let result = self.chat_once(messages.clone(), tools.clone()).await;
+            match (&result, &self.retry) {
+                (Err(err), Some(retry)) if retry.should_retry(attempt, err) => {
+                    retry.backoff(attempt).await
+                }
+                (Ok(response), _) => {
+                    self.cache(key, response);
+                    return result;
+                }
+                _ => return result,
+            }
+            attempt += 1;
+        }
+    }
+
+    // Send a single chat request, without retries
+    async fn chat_once(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        tools: Vec<ChatCompletionTool>,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let client = match &self.backend {
+            Backend::OpenAI(client) => client,
+            Backend::Anthropic(client) => return client.chat(messages, tools).await,
+            Backend::Mock(mock) => return mock.chat(messages, tools),
+        };
+        let request = self.chat_request(messages, tools)?;
+
+        let mut response = client.chat().create(request).await?;
+        if self.mode == ToolCallingMode::LegacyFunctions {
+            from_legacy_response(&mut response);
+        }
+        Ok(response)
+    }
+
+    /// Like [`chat`], but the content of the answer is passed to `on_delta` piece by piece as the
+    /// model writes it. The whole response is returned once the model is done. Backends which
+    /// cannot stream pass the content in a single piece.
+    ///
+    /// [`chat`]: Self::chat
+    pub async fn chat_stream<
+        M: Into<Vec<ChatCompletionRequestMessage>>,
+        T: Into<Vec<ChatCompletionTool>>,
+    >(
+        &self,
+        messages: M,
+        tools: T,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let (messages, tools) = (messages.into(), tools.into());
+        // Cached completions are passed to `on_delta` in a single piece
+        let key = match self.cached(&messages, &tools) {
+            Some((_, Some(response))) => {
+                if let Some(content) = &response.choices[0].message.content {
+                    on_delta(content);
+                }
+                return Ok(response);
+            }
+            Some((key, None)) => Some(key),
+            None => None,
+        };
+        let mut attempt = 1;
+        loop {
+            let mut streamed = false;
+            let result = self
+                .chat_stream_once(messages.clone(), tools.clone(), &mut |delta: &str| {
+                    streamed = true;
+                    on_delta(delta)
+                })
+                .await;
+            match (&result, &self.retry) {
+                // Content already passed to `on_delta` cannot be taken back, so only the requests
+                // failing before anything was streamed are retried
+                (Err(err), Some(retry)) if !streamed && retry.should_retry(attempt, err) => {
+                    retry.backoff(attempt).await
+                }
+                (Ok(response), _) => {
+                    self.cache(key, response);
+                    return result;
+                }
+                _ => return result,
+            }
+            attempt += 1;
+        }
+    }
 
-        let response = self.client.chat().create(request).await?;
+    // Send a single streamed chat request, without retries
+    async fn chat_stream_once(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        tools: Vec<ChatCompletionTool>,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let client = match &self.backend {
+            Backend::OpenAI(client) => client,
+            Backend::Anthropic(_) | Backend::Mock(_) => {
+                let response = self.chat_once(messages, tools).await?;
+                if let Some(content) = &response.choices[0].message.content {
+                    on_delta(content);
+                }
+                return Ok(response);
+            }
+        };
+        let mut request = self.chat_request(messages, tools)?;
+        request.stream = Some(true);
+        request.stream_options = Some(ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+
+        let mut stream = client.chat().create_stream(request).await?;
+        let mut response = StreamedResponse::default();
+        while let Some(chunk) = stream.next().await {
+            response.add(chunk?, on_delta);
+        }
+        let mut response = response.finish();
+        if self.mode == ToolCallingMode::LegacyFunctions {
+            from_legacy_response(&mut response);
+        }
         Ok(response)
     }
+
+    // Build the request for the OpenAI backend, in the tool calling mode of the client
+    fn chat_request<
+        M: Into<Vec<ChatCompletionRequestMessage>>,
+        T: Into<Vec<ChatCompletionTool>>,
+    >(
+        &self,
+        messages: M,
+        tools: T,
+    ) -> Result<CreateChatCompletionRequest, OpenAIError> {
+        // Create a `CreateCompletionRequest`
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request
+            .model(OPENAI_CHAT_MODEL)
+            .max_completion_tokens(OPENAI_MAX_COMPLETION_TOKENS);
+        if let Some(format) = &self.response_format {
+            request.response_format(format.clone());
+        }
+        match self.mode {
+            ToolCallingMode::Tools => {
+                request
+                    .messages(messages)
+                    .tools(tools)
+                    .parallel_tool_calls(false);
+            }
+            ToolCallingMode::LegacyFunctions => {
+                // Older gateways know nothing about tools, so both the tool schemas and the tool
+                // messages already in the conversation have to be converted to functions.
+                request
+                    .messages(legacy_messages(messages.into())?)
+                    .functions(legacy_functions(&tools.into()));
+            }
+        }
+        request.build()
+    }
+}
+
+/// Response assembled from the chunks streamed by the model
+#[derive(Debug, Default)]
+pub struct StreamedResponse {
+    id: String,
+    model: String,
+    created: u32,
+    content: String,
+    tool_calls: Vec<ChatCompletionMessageToolCall>,
+    // Legacy function call, which is streamed in pieces just like the tool calls
+    function_call: Option<FunctionCall>,
+    finish_reason: Option<FinishReason>,
+    usage: Option<CompletionUsage>,
+}
+
+impl StreamedResponse {
+    /// Add the `chunk` streamed by the model, passing its content to `on_delta`. Only the first
+    /// choice is kept, as the planners never ask for more.
+    #[allow(deprecated)]
+    pub fn add(
+        &mut self,
+        chunk: CreateChatCompletionStreamResponse,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) {
+        self.id = chunk.id;
+        self.model = chunk.model;
+        self.created = chunk.created;
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage;
+        }
+        let Some(choice) = chunk.choices.into_iter().find(|choice| choice.index == 0) else {
+            return;
+        };
+        if choice.finish_reason.is_some() {
+            self.finish_reason = choice.finish_reason;
+        }
+        let delta = choice.delta;
+        if let Some(content) = delta.content.filter(|content| !content.is_empty()) {
+            on_delta(&content);
+            self.content.push_str(&content);
+        }
+        // Tool calls start with their id and name, followed by pieces of their arguments
+        for chunk in delta.tool_calls.into_iter().flatten() {
+            let index = chunk.index as usize;
+            if self.tool_calls.len() <= index {
+                self.tool_calls
+                    .resize_with(index + 1, || ChatCompletionMessageToolCall {
+                        id: String::new(),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name: String::new(),
+                            arguments: String::new(),
+                        },
+                    });
+            }
+            let tool_call = &mut self.tool_calls[index];
+            if let Some(id) = chunk.id {
+                tool_call.id = id;
+            }
+            if let Some(function) = chunk.function {
+                tool_call.function.name += &function.name.unwrap_or_default();
+                tool_call.function.arguments += &function.arguments.unwrap_or_default();
+            }
+        }
+        if let Some(function) = delta.function_call {
+            let function_call = self.function_call.get_or_insert(FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            });
+            function_call.name += &function.name.unwrap_or_default();
+            function_call.arguments += &function.arguments.unwrap_or_default();
+        }
+    }
+
+    /// The whole response, as if it was not streamed
+    #[allow(deprecated)]
+    pub fn finish(self) -> CreateChatCompletionResponse {
+        CreateChatCompletionResponse {
+            id: self.id,
+            choices: vec![ChatChoice {
+                index: 0,
+                message: ChatCompletionResponseMessage {
+                    content: (!self.content.is_empty()).then_some(self.content),
+                    refusal: None,
+                    tool_calls: (!self.tool_calls.is_empty()).then_some(self.tool_calls),
+                    role: Role::Assistant,
+                    function_call: self.function_call,
+                    audio: None,
+                },
+                finish_reason: self.finish_reason,
+                logprobs: None,
+            }],
+            created: self.created,
+            model: self.model,
+            service_tier: None,
+            system_fingerprint: None,
+            object: "chat.completion".to_string(),
+            usage: self.usage,
+        }
+    }
+}
+
+/// Convert the `tools` advertised by the planners into the legacy `functions` schema.
+#[allow(deprecated)]
+pub fn legacy_functions(tools: &[ChatCompletionTool]) -> Vec<ChatCompletionFunctions> {
+    tools
+        .iter()
+        .map(|tool| ChatCompletionFunctions {
+            name: tool.function.name.clone(),
+            description: tool.function.description.clone(),
+            // Legacy endpoints require a parameters object, even if the function takes no
+            // arguments.
+            parameters: tool
+                .function
+                .parameters
+                .clone()
+                .unwrap_or(json!({ "type": "object", "properties": {} })),
+        })
+        .collect()
+}
+
+/// Convert the tool calls and tool results in `messages` into legacy function calls and function
+/// results. Legacy function results are matched with their call by name, instead of by id.
+#[allow(deprecated)]
+pub fn legacy_messages(
+    messages: Vec<ChatCompletionRequestMessage>,
+) -> Result<Vec<ChatCompletionRequestMessage>, OpenAIError> {
+    // Names of the called functions, indexed by the id of the tool call
+    let mut names = HashMap::new();
+    messages
+        .into_iter()
+        .map(|message| match message {
+            ChatCompletionRequestMessage::Assistant(mut message) => {
+                if let Some(tool_calls) = message.tool_calls.take() {
+                    for tool_call in tool_calls.iter() {
+                        names.insert(tool_call.id.clone(), tool_call.function.name.clone());
+                    }
+                    // The legacy API supports a single function call per message
+                    message.function_call = tool_calls.into_iter().next().map(|t| t.function);
+                }
+                Ok(ChatCompletionRequestMessage::Assistant(message))
+            }
+            ChatCompletionRequestMessage::Tool(message) => {
+                let content = match message.content {
+                    ChatCompletionRequestToolMessageContent::Text(text) => text,
+                    ChatCompletionRequestToolMessageContent::Array(parts) => parts
+                        .into_iter()
+                        .map(|part| match part {
+                            ChatCompletionRequestToolMessageContentPart::Text(text) => text.text,
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                };
+                let name = names
+                    .get(&message.tool_call_id)
+                    .cloned()
+                    .unwrap_or_default();
+                Ok(ChatCompletionRequestFunctionMessageArgs::default()
+                    .content(content)
+                    .name(name)
+                    .build()?
+                    .into())
+            }
+            message => Ok(message),
+        })
+        .collect()
+}
+
+/// Convert legacy function calls in the `response` into tool calls, such that the planners can
+/// handle them like any other tool call. Legacy function calls have no id, so one is derived from
+/// the id of the response and the index of the choice.
+#[allow(deprecated)]
+pub fn from_legacy_response(response: &mut CreateChatCompletionResponse) {
+    for choice in response.choices.iter_mut() {
+        if let Some(function) = choice.message.function_call.take() {
+            choice.message.tool_calls = Some(vec![ChatCompletionMessageToolCall {
+                id: format!("{}_{}", response.id, choice.index),
+                r#type: ChatCompletionToolType::Function,
+                function,
+            }]);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -103,24 +713,13 @@ mod tests {
         use crate::{
             ConversationHistory, Function,
             plan::{BasicPlanner, PlanningLoop},
+            prompt::{PlannerPrompt, PromptTemplate},
         };
         use async_openai::types::{
-            ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
-            ChatCompletionToolArgs, ChatCompletionToolType, FunctionObject,
+            ChatCompletionRequestSystemMessageArgs, ChatCompletionToolArgs, ChatCompletionToolType,
+            FunctionObject,
         };
         use serde_json::json;
-        let system_message = "You are a helpful email assistant with the ability to summarize emails and to send Slack messages.
-            You have access to the following Rust tools:
-            1. `read_emails(count: usize) -> Vec<HashMap>`: Reads the top n emails from the user's mailbox.
-            2. `send_slack_message(channel: String, message: String, preview: bool) -> String`: Sends a message to a Slack channel.
-
-            All arguments to tools have an `anyOf` schema, with a `kind` tag indicating whether the value is a literal value (`value`) or a variable name (`variable_name`).
-            When choosing tool call arguments, make sure to use the `kind` tag to indicate whether the value is a literal value or a variable name.
-            - If `kind` == \"value\", the value MUST be passed in the `value` field.
-            - If `kind` == \"variable\", a variable name MUST be passed in the `variable` field instead.
-            Make absolutely sure to respect this convention. You MUST NOT pass a variable name in the `value` field or vice versa.
-
-            The user's Slack alias is: bob.sheffield@magnet.com";
         let tools =
             vec![
             ChatCompletionToolArgs::default()
@@ -177,6 +776,10 @@ mod tests {
                 .unwrap(),
         ];
 
+        let system_message = PlannerPrompt::new(PromptTemplate::Basic)
+            .with_tools(&tools)
+            .with_user("bob.sheffield@magnet.com")
+            .build();
         let basic_planner = BasicPlanner::new(tools.clone());
 
         let client = LlmClient::openai();
@@ -187,15 +790,7 @@ mod tests {
             .build()
             .unwrap()
             .into();
-        let user_message = ChatCompletionRequestUserMessageArgs::default()
-            .content("Write a summary of my 5 most recent emails and send it to me as private Slack message.")
-            .build()
-            .unwrap()
-            .into();
-
-        let state: crate::State = ConversationHistory(vec![system_request, user_message]);
-        let chat_request = client.chat(state.0.clone(), tools);
-        let current_message = chat_request.await.unwrap().choices[0].message.clone();
+        let state: crate::State = ConversationHistory::new(vec![system_request]);
 
         let mut planning_loop = PlanningLoop::new(
             basic_planner,
@@ -206,9 +801,9 @@ mod tests {
             ],
         );
 
-        let mut datastore = crate::Datastore;
+        let mut datastore = crate::Datastore::default();
         let response = planning_loop
-            .run(state, &mut datastore, crate::Message::Chat(current_message))
+            .run_from_user(state, &mut datastore, "Write a summary of my 5 most recent emails and send it to me as private Slack message.")
             .await
             .expect("Failed to run");
         println!("{response:#?}");
@@ -219,28 +814,13 @@ mod tests {
         use crate::{
             ConversationHistory, Function,
             plan::{PlanningLoop, VarPlanner},
+            prompt::{PlannerPrompt, PromptTemplate},
         };
         use async_openai::types::{
-            ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
-            ChatCompletionToolArgs, ChatCompletionToolType, FunctionObject,
+            ChatCompletionRequestSystemMessageArgs, ChatCompletionToolArgs, ChatCompletionToolType,
+            FunctionObject,
         };
         use serde_json::json;
-        let system_message = "You are a helpful email assistant with the ability to summarize emails and to send Slack messages.
-            You have access to the following Rust tools:
-            1. `read_emails(count: usize) -> Vec<HashMap>`: Reads the top n emails from the user's mailbox.
-            2. `send_slack_message(channel: String, message: String, preview: bool) -> String`: Sends a message to a Slack channel.
-            3. `read_variable(variable: String) -> String`: Reads the contents of a variable to obtain results of a tool call.
-
-            Whenever you call a tool, you will not receive the result directly. Rather, a variable standing in for the result will be appended to the conversation. You can use the `read_variable` tool to read the contents of a variable and obtain the results of the previous tool call if you MUST know it before the next tool call.
-
-            All arguments to tools have an `anyOf` schema, with a `kind` tag indicating whether the value is a literal value (`value`) or a variable name (`variable_name`).
-            When choosing tool call arguments, make sure to use the `kind` tag to indicate whether the value is a literal value or a variable name.
-            - If `kind` == \"value\", the value MUST be passed in the `value` field.
-            - If `kind` == \"variable\", a variable name MUST be passed in the `variable` field instead.
-            Make absolutely sure to respect this convention. You MUST NOT pass a variable name in the `value` field or vice versa.
-
-            If you are not sure about the contents of data pertaining to the user’s request, use `read_variable` or gather the relevant information from other tools: do NOT guess or make up an answer.
-            The user's Slack alias is: bob.sheffield@contoso.com";
         let tools =
             vec![
             ChatCompletionToolArgs::default()
@@ -309,8 +889,13 @@ mod tests {
                                 "type": "string",
                                 "description": "The variable to be read",
                             },
+                            "pointer": {
+                                "type": "string",
+                                "description": "JSON pointer to the only part of the variable \
+                                    to read, such as /emails/0, or an empty string to read all of it",
+                            },
                         },
-                        "required": ["variable"],
+                        "required": ["variable", "pointer"],
                         "additionalProperties": false,
                     }), vec![])),
                     strict: Some(true),
@@ -320,6 +905,10 @@ mod tests {
                 .unwrap(),
         ];
 
+        let system_message = PlannerPrompt::new(PromptTemplate::Variables)
+            .with_tools(&tools)
+            .with_user("bob.sheffield@contoso.com")
+            .build();
         let var_planner = VarPlanner::new(tools.clone());
 
         let client = LlmClient::openai();
@@ -331,15 +920,7 @@ mod tests {
             .build()
             .unwrap()
             .into();
-        let user_message = ChatCompletionRequestUserMessageArgs::default()
-            .content("Write a summary of my 5 most recent emails and send it to me as private Slack message.")
-            .build()
-            .unwrap()
-            .into();
-
-        let state: crate::State = ConversationHistory(vec![system_request, user_message]);
-        let chat_request = client.chat(state.0.clone(), tools);
-        let current_message = chat_request.await.unwrap().choices[0].message.clone();
+        let state: crate::State = ConversationHistory::new(vec![system_request]);
 
         let mut planning_loop = PlanningLoop::new(
             var_planner,
@@ -351,37 +932,209 @@ mod tests {
             ],
         );
 
-        let mut datastore = crate::Datastore;
+        let mut datastore = crate::Datastore::default();
         let response = planning_loop
-            .run(state, &mut datastore, crate::Message::Chat(current_message))
+            .run_from_user(state, &mut datastore, "Write a summary of my 5 most recent emails and send it to me as private Slack message.")
             .await
             .expect("Failed to run");
         println!("{response:#?}");
     }
 
+    #[test]
+    #[allow(deprecated)]
+    fn legacy_function_messages() {
+        use async_openai::types::{
+            ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestToolMessageArgs,
+            FunctionCall,
+        };
+
+        let tool_call = ChatCompletionMessageToolCall {
+            id: "call_0".to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: "read_emails".to_string(),
+                arguments: "{\"count\":\"5\"}".to_string(),
+            },
+        };
+        let messages = vec![
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .tool_calls(vec![tool_call.clone()])
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestToolMessageArgs::default()
+                .content("[]")
+                .tool_call_id("call_0")
+                .build()
+                .unwrap()
+                .into(),
+        ];
+
+        let messages = legacy_messages(messages).expect("Failed to convert messages");
+        let ChatCompletionRequestMessage::Assistant(assistant) = &messages[0] else {
+            panic!("Expected an assistant message, got {:?}", messages[0]);
+        };
+        assert!(assistant.tool_calls.is_none());
+        assert_eq!(assistant.function_call, Some(tool_call.function));
+        let ChatCompletionRequestMessage::Function(function) = &messages[1] else {
+            panic!("Expected a function message, got {:?}", messages[1]);
+        };
+        assert_eq!(function.name, "read_emails");
+        assert_eq!(function.content.as_deref(), Some("[]"));
+    }
+
+    #[test]
+    fn clients_are_configured_at_runtime() {
+        let path = std::env::temp_dir().join(format!("gentlemen-api-key-{}", std::process::id()));
+        std::fs::write(&path, "sk-ant-test\n").unwrap();
+        let client = LlmClient::from_config(&json!({
+            "backend": "anthropic",
+            "api_key_file": path,
+            "model": "claude-sonnet-4-5",
+        }));
+        std::fs::remove_file(&path).unwrap();
+        let client = client.expect("Failed to configure the client");
+        let Backend::Anthropic(anthropic) = &client.backend else {
+            panic!("Expected the Anthropic backend");
+        };
+        assert_eq!(anthropic.model(), "claude-sonnet-4-5");
+
+        assert!(matches!(
+            LlmClient::from_config(&json!({ "api_base": "http://localhost:11434/v1" })),
+            Err(ConfigError::MissingKey(_))
+        ));
+        assert!(matches!(
+            LlmClient::from_config(&json!({ "backend": "gemini", "api_key": "key" })),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried() {
+        let policy = RetryPolicy::default()
+            .with_max_attempts(3)
+            .with_base_delay(std::time::Duration::from_millis(1));
+        let transcript = vec![MockLlm::assistant_text("You have no new emails.")];
+
+        let client = LlmClient::mock(MockLlm::new(transcript.clone()).with_rate_limits(2))
+            .with_retry_policy(policy.clone());
+        let response = client.chat(vec![], vec![]).await.expect("Failed to retry");
+        assert_eq!(
+            response.choices[0].message.content.as_deref(),
+            Some("You have no new emails.")
+        );
+        assert_eq!(client.as_mock().unwrap().requests().len(), 3);
+
+        // Giving up once the attempts run out
+        let client = LlmClient::mock(MockLlm::new(transcript.clone()).with_rate_limits(3))
+            .with_retry_policy(policy);
+        assert!(client.chat(vec![], vec![]).await.is_err());
+        let client = LlmClient::mock(MockLlm::new(transcript).with_rate_limits(1));
+        assert!(client.chat(vec![], vec![]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn completions_are_replayed_from_the_cache() {
+        let dir = std::env::temp_dir().join(format!("gentlemen-responses-{}", std::process::id()));
+        let cache = ResponseCache::on_disk(&dir).unwrap();
+        let client = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+            "You have no new emails.",
+        )]))
+        .with_response_cache(cache.clone());
+        let request = || {
+            vec![
+                async_openai::types::ChatCompletionRequestUserMessageArgs::default()
+                    .content("Any new emails?")
+                    .build()
+                    .unwrap()
+                    .into(),
+            ]
+        };
+        let first = client.chat(request(), vec![]).await.unwrap();
+        assert_eq!(client.chat(request(), vec![]).await.unwrap(), first);
+        assert_eq!(client.as_mock().unwrap().requests().len(), 1);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // A later run finds the completion on disk, while other requests still reach the model
+        let client = LlmClient::mock(MockLlm::new(vec![]))
+            .with_response_cache(ResponseCache::on_disk(&dir).unwrap());
+        let mut deltas = vec![];
+        let replayed = client
+            .chat_stream(request(), vec![], &mut |delta| {
+                deltas.push(delta.to_string())
+            })
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replayed, first);
+        assert_eq!(deltas, ["You have no new emails."]);
+        assert!(client.chat(vec![], vec![]).await.is_err());
+    }
+
+    #[test]
+    fn streamed_chunks_make_up_the_response() {
+        let chunk = |delta: serde_json::Value, finish_reason: serde_json::Value| {
+            serde_json::from_value::<CreateChatCompletionStreamResponse>(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            }))
+            .unwrap()
+        };
+        let mut deltas = vec![];
+        let mut on_delta = |delta: &str| deltas.push(delta.to_string());
+        let mut response = StreamedResponse::default();
+        for chunk in [
+            chunk(
+                json!({ "role": "assistant", "content": "Reading " }),
+                json!(null),
+            ),
+            chunk(json!({ "content": "your emails." }), json!(null)),
+            chunk(
+                json!({ "tool_calls": [{
+                    "index": 0,
+                    "id": "call_0",
+                    "type": "function",
+                    "function": { "name": "read_emails", "arguments": "{\"cou" },
+                }] }),
+                json!(null),
+            ),
+            chunk(
+                json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "nt\":5}" } }] }),
+                json!("tool_calls"),
+            ),
+        ] {
+            response.add(chunk, &mut on_delta);
+        }
+
+        let response = response.finish();
+        assert_eq!(deltas, ["Reading ", "your emails."]);
+        let message = &response.choices[0].message;
+        assert_eq!(message.content.as_deref(), Some("Reading your emails."));
+        let tool_calls = message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].id, "call_0");
+        assert_eq!(tool_calls[0].function.name, "read_emails");
+        assert_eq!(tool_calls[0].function.arguments, "{\"count\":5}");
+        assert_eq!(
+            response.choices[0].finish_reason,
+            Some(FinishReason::ToolCalls)
+        );
+    }
+
     #[tokio::test]
     async fn taint_tracking_planner() {
         use crate::{
-            Integrity, Message, MetaFunction, Policy,
+            Integrity, MetaFunction, Policy,
             plan::{PlanningLoop, TaintTrackingPlanner},
+            prompt::{PlannerPrompt, PromptTemplate},
         };
         use async_openai::types::{
-            ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
-            ChatCompletionToolArgs, ChatCompletionToolType, FunctionObject,
+            ChatCompletionRequestSystemMessageArgs, ChatCompletionToolArgs, ChatCompletionToolType,
+            FunctionObject,
         };
         use serde_json::json;
-        let system_message = "You are a helpful email assistant with the ability to summarize emails and to send Slack messages.
-            You have access to the following Rust tools:
-            1. `read_emails_labeled(count: usize) -> Vec<HashMap>`: Reads the top n emails from the user's mailbox.
-            2. `send_slack_message_labeled(channel: String, message: String, preview: bool) -> String`: Sends a message to a Slack channel.
-
-            All arguments to tools have an `anyOf` schema, with a `kind` tag indicating whether the value is a literal value (`value`) or a variable name (`variable_name`).
-            When choosing tool call arguments, make sure to use the `kind` tag to indicate whether the value is a literal value or a variable name.
-            - If `kind` == \"value\", the value MUST be passed in the `value` field.
-            - If `kind` == \"variable\", a variable name MUST be passed in the `variable` field instead.
-            Make absolutely sure to respect this convention. You MUST NOT pass a variable name in the `value` field or vice versa.
-
-            The user's Slack alias is: bob.sheffield@magnet.com";
         let tools =
             vec![
             ChatCompletionToolArgs::default()
@@ -450,8 +1203,13 @@ mod tests {
                                 "type": "string",
                                 "description": "The variable to be read",
                             },
+                            "pointer": {
+                                "type": "string",
+                                "description": "JSON pointer to the only part of the variable \
+                                    to read, such as /emails/0, or an empty string to read all of it",
+                            },
                         },
-                        "required": ["variable"],
+                        "required": ["variable", "pointer"],
                         "additionalProperties": false,
                     }), vec![])),
                     strict: Some(true),
@@ -461,6 +1219,10 @@ mod tests {
                 .unwrap(),
         ];
 
+        let system_message = PlannerPrompt::new(PromptTemplate::TaintTracking { variables: false })
+            .with_tools(&tools)
+            .with_user("bob.sheffield@magnet.com")
+            .build();
         let tt_planner = TaintTrackingPlanner::new(tools.clone());
 
         let client = LlmClient::openai();
@@ -472,15 +1234,7 @@ mod tests {
             .build()
             .unwrap()
             .into();
-        let user_message = ChatCompletionRequestUserMessageArgs::default()
-            .content("Write a summary of my 5 most recent emails and send it to me as private Slack message.")
-            .build()
-            .unwrap()
-            .into();
-
-        let state: crate::State = crate::ConversationHistory(vec![system_request, user_message]);
-        let chat_request = client.chat(state.0.clone(), tools);
-        let current_message = chat_request.await.unwrap().choices[0].message.clone();
+        let state: crate::State = crate::ConversationHistory::new(vec![system_request]);
 
         let mut planning_loop = PlanningLoop::new(
             tt_planner,
@@ -491,8 +1245,7 @@ mod tests {
             ],
         );
 
-        let email_universe: Vec<crate::tools::Email> =
-            crate::tools::INBOX.iter().cloned().collect();
+        let email_universe: Vec<crate::tools::Email> = crate::tools::INBOX.to_vec();
         // Create the address universe of all the possible addresses in the email list above
         let address_universe =
             crate::tools::EmailAddressUniverse::new(&email_universe).into_inner();
@@ -502,15 +1255,13 @@ mod tests {
             crate::tools::readers_label(address_universe.clone(), address_universe)
                 .expect("Failed to build confidentiality label for test");
 
-        let mut datastore = crate::Datastore;
+        let mut datastore = crate::Datastore::default();
         let response = planning_loop
-            .run_with_policy(
+            .run_from_user_with_policy(
                 state,
                 &mut datastore,
-                crate::tools::MetaValue::new(
-                    Message::Chat(current_message),
-                    crate::ProductLattice::new(Integrity::trusted(), least_confidentiality),
-                ),
+                "Write a summary of my 5 most recent emails and send it to me as private Slack message.",
+                crate::ProductLattice::new(Integrity::trusted(), least_confidentiality),
                 Policy::new(crate::plan::policy::policy_no_untrusted_url),
             )
             .await
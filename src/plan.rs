@@ -1,31 +1,73 @@
+pub mod approval;
+pub mod audit;
 mod basic;
+pub mod checkpoint;
+pub mod combinators;
+pub mod dag;
+pub mod differential;
+pub mod few_shot;
+pub mod finish;
+pub mod honeypot;
+#[cfg(feature = "telemetry")]
+pub mod jobs;
 mod labeled;
+pub mod middleware;
+pub mod observer;
+#[cfg(feature = "opa")]
+pub mod opa;
+#[cfg(feature = "telemetry")]
+pub mod orchestrator;
 mod plan_loop;
 pub mod policy;
+pub mod quarantine;
+pub mod recovery;
+pub mod repair;
+pub mod replay;
+pub mod rules;
+pub mod sanitize;
+pub mod session;
+#[cfg(feature = "telemetry")]
+pub mod sink;
+pub mod upfront;
 mod var;
 
+pub use approval::ApprovalGate;
+pub use audit::AuditLog;
 pub use basic::BasicPlanner;
-pub use labeled::{TaintTrackingPlanner, Trace};
-pub use plan_loop::PlanningLoop;
-pub use policy::Policy;
+pub use checkpoint::LoopCheckpoint;
+pub use combinators::{Fallback, Sequenced, WithRetries};
+pub use few_shot::FewShotPlanner;
+pub use finish::{FinishCriteria, FinishingPlanner};
+pub use honeypot::Honeypot;
+#[cfg(feature = "telemetry")]
+pub use jobs::JobQueue;
+pub use labeled::{InternedFunction, LabeledTool, TaintLabel, TaintTrackingPlanner, Trace};
+pub use middleware::{Middleware, MiddlewarePlanner};
+#[cfg(feature = "telemetry")]
+pub use orchestrator::Orchestrator;
+pub use plan_loop::{LoopMessage, PlanningLoop, RunStep, RunTrace};
+pub use policy::{Policy, PolicyCheck, PolicySet, Shadowed};
+pub use quarantine::QuarantinePlanner;
+pub use recovery::ViolationHandler;
+pub use session::Session;
+pub use upfront::UpfrontPlanner;
 pub use var::VarPlanner;
 
-use crate::ifc::LatticeError;
+use crate::{
+    Action, Plan,
+    ifc::LatticeError,
+    quota::{QuotaError, QuotaExceeded},
+    schema::SchemaError,
+    tools::{EmailLabel, ReferenceError},
+};
 use async_openai::error::OpenAIError;
+use honeypot::Compromise;
 use serde_json::Value;
-
-/// Enables a state passing planner which is plugged into the `PlanningLoop`
-pub trait Plan<S, M> {
-    /// The type of action returned by one call of the `plan` function
-    type Action;
-    type Error: std::fmt::Debug;
-    /// Take and process a previous known `state` and the current `message` and returns a new state
-    /// which contains the previous message and an action to be taken by the caller.
-    fn plan(&mut self, state: S, message: M) -> Result<(S, Self::Action), Self::Error>;
-}
+use std::time::Duration;
 
 /// Error issued by either one of the planners which implement [`Plan`] or the [`PlanningLoop`]
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum PlanError {
     NoUserContent,
     NoToolContent,
@@ -43,6 +85,64 @@ pub enum PlanError {
     MissingVariable(String),
     LatticeError(LatticeError),
     FunctionNotFound(String),
+    // The run would spend more tokens than its budget allows
+    BudgetExceeded {
+        budget: u32,
+        needed: u32,
+    },
+    // The model called a decoy tool of the honeypot
+    Compromised(Box<Compromise>),
+    // A variable could not be resolved, or was used above its clearance
+    ReferenceError(ReferenceError),
+    // The run would go over the quota of a user or session
+    QuotaExceeded(QuotaExceeded),
+    // The quota counters could not be persisted
+    QuotaStoreError(std::io::Error),
+    // The run took as many actions as it was allowed to without finishing, which were these
+    IterationLimit {
+        limit: usize,
+        trace: Vec<Action>,
+    },
+    // The run did not finish in time, after taking the actions of the `trace`
+    Timeout {
+        limit: Duration,
+        trace: Vec<Action>,
+    },
+    // The checkpoint could not be written, or cannot be resumed from
+    CheckpointError(std::io::Error),
+    // The answer carries a label which does not flow to the clearance of whoever reads it
+    ClearanceViolation(Box<ClearanceViolation>),
+    // No agent of the orchestrator goes by this name
+    UnknownAgent(String),
+    // The output schema does not match the type its answers are parsed into
+    InvalidOutputSchema(SchemaError),
+    // The final answer does not follow the output schema it was asked for
+    InvalidOutput {
+        answer: String,
+        error: serde_json::Error,
+    },
+}
+
+/// Answer withheld because its label does not flow to the clearance of whoever reads it
+#[derive(Debug)]
+pub struct ClearanceViolation {
+    // Missing if the labels cannot be told as `EmailLabel`s
+    label: Option<EmailLabel>,
+    clearance: Option<EmailLabel>,
+}
+
+impl ClearanceViolation {
+    pub fn new(label: Option<EmailLabel>, clearance: Option<EmailLabel>) -> Self {
+        Self { label, clearance }
+    }
+
+    pub fn label(&self) -> Option<&EmailLabel> {
+        self.label.as_ref()
+    }
+
+    pub fn clearance(&self) -> Option<&EmailLabel> {
+        self.clearance.as_ref()
+    }
 }
 
 impl From<OpenAIError> for PlanError {
@@ -57,6 +157,30 @@ impl From<serde_json::Error> for PlanError {
     }
 }
 
+impl From<ReferenceError> for PlanError {
+    fn from(err: ReferenceError) -> Self {
+        match err {
+            ReferenceError::Missing(variable) => Self::MissingVariable(variable),
+            err => Self::ReferenceError(err),
+        }
+    }
+}
+
+impl From<QuotaExceeded> for PlanError {
+    fn from(err: QuotaExceeded) -> Self {
+        Self::QuotaExceeded(err)
+    }
+}
+
+impl From<QuotaError> for PlanError {
+    fn from(err: QuotaError) -> Self {
+        match err {
+            QuotaError::Exceeded(err) => Self::QuotaExceeded(err),
+            QuotaError::StoreError(err) => Self::QuotaStoreError(err),
+        }
+    }
+}
+
 impl From<LatticeError> for PlanError {
     fn from(err: LatticeError) -> Self {
         Self::LatticeError(err)
@@ -0,0 +1,213 @@
+//! Human-in-the-loop approval of tool calls. Some tools, such as the ones sending data outside of
+//! the organization, should not be called on the model's word alone, however the plan got there.
+//! An [`ApprovalGate`] designates those tools, and the [`PlanningLoop`] waits on it before making
+//! any call to them, such that a UI or CLI can ask the user to approve, deny or modify the call.
+//!
+//! [`PlanningLoop`]: super::PlanningLoop
+use crate::{
+    Args,
+    registry::{Capability, ToolRegistry},
+};
+use futures::future::BoxFuture;
+use std::{future::Future, sync::Arc};
+
+/// Tool call waiting for approval
+#[derive(Debug, Clone)]
+pub struct ApprovalRequest {
+    // Index in the trace of the call
+    pub step: usize,
+    // Name of the tool called
+    pub function: String,
+    pub args: Args,
+    // Id of the tool call
+    pub tool_call_id: String,
+    // Why the policy denied the call, when the gate is asked whether to make it anyway
+    pub denied_because: Option<String>,
+}
+
+/// Decision taken on a tool call waiting for approval
+#[derive(Debug, Clone)]
+pub enum Decision {
+    /// Make the call as it is
+    Approve,
+    /// Do not make the call, and tell the model why
+    Deny(String),
+    /// Make the call with these arguments instead
+    Modify(Args),
+}
+
+/// Reviews the calls to designated tools before they are made
+pub trait ApprovalGate: Send + Sync {
+    /// Whether calls to the tool called `name` have to be approved before being made
+    fn gates(&self, name: &str) -> bool;
+
+    /// Decide on the call of the `request`, such as by asking the user
+    fn review(&self, request: ApprovalRequest) -> BoxFuture<'_, Decision>;
+}
+
+type Review = dyn Fn(ApprovalRequest) -> BoxFuture<'static, Decision> + Send + Sync;
+
+/// Gate passing the calls to a set of tools to an async callback
+#[derive(Clone)]
+pub struct ToolGate {
+    tools: Vec<String>,
+    // Whether every tool sending data, which by convention is named `send_*`, is gated as well
+    sends: bool,
+    review: Arc<Review>,
+}
+
+impl ToolGate {
+    /// Gate the calls to the `tools` with `review`
+    pub fn new<R, Fut>(tools: &[&str], review: R) -> Self
+    where
+        R: Fn(ApprovalRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Decision> + Send + 'static,
+    {
+        Self {
+            tools: tools.iter().map(|tool| tool.to_string()).collect(),
+            sends: false,
+            review: Arc::new(move |request| Box::pin(review(request))),
+        }
+    }
+
+    /// Gate the calls to every tool sending data with `review`
+    pub fn sends<R, Fut>(review: R) -> Self
+    where
+        R: Fn(ApprovalRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Decision> + Send + 'static,
+    {
+        Self {
+            sends: true,
+            ..Self::new(&[], review)
+        }
+    }
+
+    /// Gate the calls to every tool of the `registry` with any of the `capabilities`, such as
+    /// every tool sending data externally whatever its name. Tools registered afterwards are not
+    /// gated.
+    pub fn capable<R, Fut>(registry: &ToolRegistry, capabilities: &[Capability], review: R) -> Self
+    where
+        R: Fn(ApprovalRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Decision> + Send + 'static,
+    {
+        Self::new(&registry.capable(capabilities), review)
+    }
+}
+
+impl ApprovalGate for ToolGate {
+    fn gates(&self, name: &str) -> bool {
+        (self.sends && name.starts_with("send_")) || self.tools.iter().any(|tool| tool == name)
+    }
+
+    fn review(&self, request: ApprovalRequest) -> BoxFuture<'_, Decision> {
+        (self.review)(request)
+    }
+}
+
+/// Message telling the model that the call to the tool called `name` was denied for `reason`
+pub fn denied_message(name: &str, reason: &str) -> String {
+    format!("The call to `{name}` was not made, as the user denied it: {reason}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BasicPlanner, Datastore, Function, PlanningLoop, mock::MockLlm, registry::ToolEntry,
+        schema::ToolDefinition, simulation::SimulatedEnvironment,
+    };
+    use serde_json::json;
+
+    async fn send_with(gate: ToolGate) -> (SimulatedEnvironment, String) {
+        let environment = SimulatedEnvironment::new(
+            "Tell Alice the meeting moved to 3pm.",
+            vec![
+                MockLlm::assistant_tool_call(
+                    "call_0",
+                    "send_slack_message",
+                    json!({
+                        "channel": { "kind": "value", "value": "alice@fides.github.io" },
+                        "message": { "kind": "value", "value": "The meeting moved to 3pm." },
+                        "preview": { "kind": "value", "value": "false" },
+                    }),
+                ),
+                MockLlm::assistant_text("Done."),
+            ],
+        );
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            environment.model(),
+            vec![Function::new("send_slack_message".to_string())],
+        )
+        .with_approval_gate(gate);
+        planning_loop
+            .run(
+                environment.state(),
+                &mut Datastore::default(),
+                environment.request(),
+            )
+            .await
+            .expect("Failed to run");
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let last_message = serde_json::to_string(requests[1].last().unwrap()).unwrap();
+        (environment, last_message)
+    }
+
+    #[tokio::test]
+    async fn gated_calls_wait_on_their_review() {
+        // The user sends the message to the internal address of Alice instead
+        let (environment, _) = send_with(ToolGate::sends(|request| async move {
+            assert_eq!(request.function, "send_slack_message");
+            let mut args: serde_json::Value = serde_json::from_str(request.args.value()).unwrap();
+            args["channel"] = json!("alice.hudson@magnet.com");
+            Decision::Modify(Args::new(args.to_string()))
+        }))
+        .await;
+        // Other tests share the outbox
+        let sent: Vec<_> = environment
+            .sent()
+            .into_iter()
+            .filter(|sent| sent.message() == "The meeting moved to 3pm.")
+            .collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].channel(), "alice.hudson@magnet.com");
+
+        let (environment, last_message) =
+            send_with(ToolGate::new(&["send_slack_message"], |_| async {
+                Decision::Deny("Alice is not at fides.github.io".to_string())
+            }))
+            .await;
+        assert!(
+            environment
+                .sent()
+                .iter()
+                .all(|sent| sent.channel() != "alice@fides.github.io")
+        );
+        assert!(last_message.contains("the user denied it: Alice is not at fides.github.io"));
+    }
+
+    #[test]
+    fn tools_are_gated_by_their_capabilities() {
+        let schema = |name: &str| {
+            ToolDefinition::<serde_json::Value>::new(name, "")
+                .build()
+                .unwrap()
+        };
+        let registry = ToolRegistry::new()
+            .with_tool(
+                ToolEntry::new(schema("post_tweet")).with_capability(Capability::SendsExternally),
+            )
+            .unwrap()
+            .with_tool(
+                ToolEntry::new(schema("read_emails")).with_capability(Capability::ReadsUserData),
+            )
+            .unwrap();
+        let gate = ToolGate::capable(&registry, &[Capability::SendsExternally], |_| async {
+            Decision::Approve
+        });
+        assert!(gate.gates("post_tweet"));
+        assert!(!gate.gates("read_emails"));
+        // Unregistered tools are not gated by their name alone
+        assert!(!gate.gates("send_slack_message"));
+    }
+}
@@ -0,0 +1,195 @@
+//! Audit log of the policy decisions taken during a run. Every time an action, or a variant of a
+//! denied call, is checked against the policy, the trace records which policy decided, on which
+//! action and label, and why it allowed or blocked it. The log is written out as JSON lines, such
+//! that compliance teams can review the decisions with their usual tools. Logs outlive the runs
+//! they record and are read by people other than the readers of the data, so the arguments and
+//! answers of actions which not everyone may read are redacted.
+use super::policy::{PolicyViolation, Severity};
+use crate::{
+    Action,
+    ifc::{Integrity, InverseLattice, LabeledValue, PowersetLattice, ProductLattice},
+    redact::redact_for_model,
+    registry::label_manifest,
+    tools::EmailLabel,
+};
+use serde_json::{Value, json};
+use std::io::{self, Write};
+
+/// What the policy decided about an action
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Allowed,
+    Denied {
+        explanation: String,
+        severity: Severity,
+        // Name of the policy of a set which was violated, such as a rule loaded from JSON
+        rule: Option<String>,
+    },
+    // The shadow check would have denied the action, which was not blocked for it
+    Shadowed {
+        explanation: String,
+        severity: Severity,
+        rule: Option<String>,
+    },
+}
+
+impl Outcome {
+    /// Outcome of a shadow check which would have denied the action with `violation`
+    pub fn shadowed(violation: &PolicyViolation) -> Self {
+        let (explanation, severity, rule) = details(violation);
+        Self::Shadowed {
+            explanation,
+            severity,
+            rule,
+        }
+    }
+}
+
+impl From<Option<&PolicyViolation>> for Outcome {
+    fn from(violation: Option<&PolicyViolation>) -> Self {
+        let Some(violation) = violation else {
+            return Self::Allowed;
+        };
+        let (explanation, severity, rule) = details(violation);
+        Self::Denied {
+            explanation,
+            severity,
+            rule,
+        }
+    }
+}
+
+// Explanation, severity and violated rule of the `violation`
+fn details(violation: &PolicyViolation) -> (String, Severity, Option<String>) {
+    let rule = match violation {
+        PolicyViolation::Named(named) => Some(named.name().to_string()),
+        _ => None,
+    };
+    (
+        violation.explanation().to_string(),
+        violation.severity(),
+        rule,
+    )
+}
+
+/// One check of an action against the policy
+#[derive(Debug, Clone)]
+pub struct PolicyDecision {
+    // Index in the trace of the action checked
+    pub step: usize,
+    // Name of the policy, if it was given one
+    pub policy: Option<String>,
+    pub action: Action,
+    // Label of the action, for runs whose labels can be told as `EmailLabel`s
+    pub label: Option<EmailLabel>,
+    pub outcome: Outcome,
+}
+
+impl PolicyDecision {
+    /// Describe the decision as JSON
+    pub fn to_json(&self) -> Value {
+        let outcome = match &self.outcome {
+            Outcome::Allowed => json!({ "decision": "allowed" }),
+            Outcome::Denied {
+                explanation,
+                severity,
+                rule,
+            } => json!({
+                "decision": "denied",
+                "explanation": explanation,
+                "severity": format!("{severity:?}").to_lowercase(),
+                "rule": rule,
+            }),
+            Outcome::Shadowed {
+                explanation,
+                severity,
+                rule,
+            } => json!({
+                "decision": "shadowed",
+                "explanation": explanation,
+                "severity": format!("{severity:?}").to_lowercase(),
+                "rule": rule,
+            }),
+        };
+        json!({
+            "step": self.step,
+            "policy": self.policy,
+            "action": redacted_action_json(&self.action, self.label.as_ref()),
+            "label": self.label.as_ref().map(label_manifest),
+            "outcome": outcome,
+        })
+    }
+}
+
+/// Every policy decision taken during a run, in order
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    decisions: Vec<PolicyDecision>,
+}
+
+impl AuditLog {
+    pub fn decisions(&self) -> &[PolicyDecision] {
+        &self.decisions
+    }
+
+    pub fn record(&mut self, decision: PolicyDecision) {
+        self.decisions.push(decision);
+    }
+
+    /// Write each decision as a line of JSON to the `writer`
+    pub fn write_json_lines<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for decision in &self.decisions {
+            writeln!(writer, "{}", decision.to_json())?;
+        }
+        writer.flush()
+    }
+}
+
+/// Describe the `action` as JSON. Queries only report the size of the conversation, as the whole
+/// conversation would drown out everything else. Nothing is redacted, which is what policy engines
+/// deciding on the action need, while logs use [`redacted_action_json`].
+pub fn action_json(action: &Action) -> Value {
+    match action {
+        Action::Query(conv_history, tools) => json!({
+            "kind": "query",
+            "messages": conv_history.messages().len(),
+            "tools": tools.len(),
+        }),
+        Action::MakeCall(function, args, id) => json!({
+            "kind": "call",
+            "function": function.name(),
+            "args": args.value(),
+            "tool_call_id": id,
+        }),
+        Action::Finish(answer) => json!({
+            "kind": "finish",
+            "answer": answer,
+        }),
+        Action::Custom(custom) => json!({
+            "kind": "custom",
+            "name": custom.name(),
+        }),
+    }
+}
+
+/// Describe the `action` as JSON like [`action_json`], with the arguments and answer replaced by
+/// placeholders unless everyone may read the `label`, keeping the names of the arguments. Actions
+/// of loops which do not track labels carry no `label` and are not redacted.
+pub fn redacted_action_json(action: &Action, label: Option<&EmailLabel>) -> Value {
+    let mut json = action_json(action);
+    let Some(label) = label else {
+        return json;
+    };
+    let universe = label.lattice2().inner().universe().clone();
+    let public = ProductLattice::new(
+        Integrity::untrusted(),
+        InverseLattice::new(PowersetLattice::top(universe)),
+    );
+    for field in ["args", "answer"] {
+        if let Some(value) = json.get_mut(field) {
+            let labeled = LabeledValue::new(value.take(), Some(label.clone()));
+            *value = redact_for_model(&labeled, &public);
+        }
+    }
+    json
+}
@@ -1,20 +1,23 @@
 use super::{Plan, PlanError};
-use crate::{Action, Args, Function, Message, State};
+use crate::{Action, Args, Function, Image, Message, State};
 use async_openai::types::{
     ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestToolMessageArgs,
     ChatCompletionRequestUserMessageArgs, ChatCompletionTool, FunctionCall, Role,
 };
 use serde_json::{Map, Value};
+use std::sync::Arc;
 
 /// A planner that takes a set of actions given an array of tools
 pub struct BasicPlanner {
-    tools: Vec<ChatCompletionTool>,
+    tools: Arc<Vec<ChatCompletionTool>>,
 }
 
 impl BasicPlanner {
     /// Create a new [`BasicPlanner`] given an array of `tools`
     pub fn new(tools: Vec<ChatCompletionTool>) -> Self {
-        Self { tools }
+        Self {
+            tools: Arc::new(tools),
+        }
     }
 
     /// Normalize the arguments passed by the LLM.
@@ -100,7 +103,7 @@ impl Plan<State, Message> for BasicPlanner {
                             .build()?
                             .into();
                         // Update the state with the new message
-                        new_state.0.push(conv_message);
+                        new_state.push(conv_message);
                         // In this case, the action to take is to query the LLM with the updated
                         // state and the set of available tools
                         let action = Action::Query(new_state.clone(), self.tools.clone());
@@ -120,7 +123,7 @@ impl Plan<State, Message> for BasicPlanner {
                             .build()?
                             .into();
                         // Update the state with the new message
-                        new_state.0.push(conv_message);
+                        new_state.push(conv_message);
 
                         // In this case, the action to take is to query the LLM with the updated
                         // state and the set of available tools
@@ -149,7 +152,7 @@ impl Plan<State, Message> for BasicPlanner {
                                 .build()?
                                 .into();
                             // Update the state with the new message
-                            new_state.0.push(conv_message);
+                            new_state.push(conv_message);
 
                             // In this case, the action to take is to call the specified tool with
                             // the specified arguments, keeping the id of the tool call such that
@@ -157,7 +160,7 @@ impl Plan<State, Message> for BasicPlanner {
                             // the tool result.
                             let action = Action::MakeCall(
                                 Function::new(name),
-                                Args(arguments?),
+                                Args::new(arguments?),
                                 tool_calls[0].clone().id,
                             );
                             (new_state, action)
@@ -170,7 +173,7 @@ impl Plan<State, Message> for BasicPlanner {
                                 .build()?
                                 .into();
                             // Update the state with the new message
-                            new_state.0.push(conv_message);
+                            new_state.push(conv_message);
                             // In this case, the assistant gave the "final" answer as we want to
                             // take a finishing action and return the result to the caller.
                             let action = Action::Finish(content);
@@ -193,13 +196,27 @@ impl Plan<State, Message> for BasicPlanner {
                     .build()?
                     .into();
                 // Update the state with the new message
-                new_state.0.push(conv_message);
+                new_state.push(conv_message);
 
                 // In this case, the action to take is to query the LLM with the updated
                 // state and the set of available tools
                 let action = Action::Query(new_state.clone(), self.tools.clone());
                 (new_state, action)
             }
+            // Images are shown to the model in user messages, following the result of the call
+            // for those of tools
+            Message::UserImages(content, images) => {
+                new_state.push(Image::user_message(&content, &images)?);
+                let action = Action::Query(new_state.clone(), self.tools.clone());
+                (new_state, action)
+            }
+            Message::ToolImage(image, id) => {
+                for conv_message in image.tool_messages(&id)? {
+                    new_state.push(conv_message);
+                }
+                let action = Action::Query(new_state.clone(), self.tools.clone());
+                (new_state, action)
+            }
         };
         Ok((new_state, action))
     }
@@ -0,0 +1,481 @@
+//! Checkpoints of planning loops, such that a run interrupted by a crash or a restart can resume
+//! where it left off instead of starting over. A [`LoopCheckpoint`] holds everything the loop
+//! carries from one step to the next: the conversation, the message the next step plans from
+//! along with its label, the memory of the planner and the trace of the actions taken so far.
+//!
+//! A loop built [`with_checkpoint_file`] writes its checkpoint before planning every step, and
+//! [`resume`] or [`resume_with_policy`] carry on from it on a fresh loop. The step is planned again
+//! on resuming, so a tool call which was being made when the run stopped is made again.
+//!
+//! The file is a log of JSON lines. The first checkpoint of a run replaces the file, and every
+//! later one only appends what changed since, such that a step costs what it added rather than the
+//! whole run. Loading replays the log, leaving out a last line cut short by a crash.
+//!
+//! [`with_checkpoint_file`]: super::PlanningLoop::with_checkpoint_file
+//! [`resume`]: super::PlanningLoop::resume
+//! [`resume_with_policy`]: super::PlanningLoop::resume_with_policy
+use super::{RunStep, RunTrace, Trace};
+use crate::{
+    Action, Args, CustomAction, CustomOutcome, Datastore, Function, Message, State, ifc::Lattice,
+    tools::MetaValue,
+};
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionTool};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::io::AsyncWriteExt;
+
+/// Action of a checkpointed trace. Custom actions are kept by name, as the application defines
+/// what they do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ActionSnapshot {
+    Query {
+        messages: Vec<ChatCompletionRequestMessage>,
+        tools: Vec<ChatCompletionTool>,
+    },
+    MakeCall {
+        function: String,
+        args: String,
+        id: String,
+    },
+    Finish {
+        answer: String,
+    },
+    Custom {
+        name: String,
+    },
+}
+
+impl From<&Action> for ActionSnapshot {
+    fn from(action: &Action) -> Self {
+        match action {
+            Action::Query(conv_history, tools) => Self::Query {
+                messages: conv_history.messages().to_vec(),
+                tools: tools.to_vec(),
+            },
+            Action::MakeCall(function, args, id) => Self::MakeCall {
+                function: function.name().to_string(),
+                args: args.value().to_string(),
+                id: id.clone(),
+            },
+            Action::Finish(answer) => Self::Finish {
+                answer: answer.clone(),
+            },
+            Action::Custom(custom) => Self::Custom {
+                name: custom.name().to_string(),
+            },
+        }
+    }
+}
+
+impl From<ActionSnapshot> for Action {
+    fn from(snapshot: ActionSnapshot) -> Self {
+        match snapshot {
+            ActionSnapshot::Query { messages, tools } => {
+                Action::Query(State::new(messages), Arc::new(tools))
+            }
+            ActionSnapshot::MakeCall { function, args, id } => {
+                Action::MakeCall(Function::new(function), Args::new(args), id)
+            }
+            ActionSnapshot::Finish { answer } => Action::Finish(answer),
+            ActionSnapshot::Custom { name } => Action::Custom(Box::new(RestoredAction { name })),
+        }
+    }
+}
+
+// Custom action restored from a checkpoint, which stands in the trace for the action taken before
+// the run was interrupted. Actions of the trace are never taken again.
+#[derive(Debug, Clone)]
+struct RestoredAction {
+    name: String,
+}
+
+impl CustomAction for RestoredAction {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn execute(&self, _datastore: &mut Datastore) -> CustomOutcome {
+        CustomOutcome::Finish(format!(
+            "The action `{}` was restored from a checkpoint and cannot be taken again",
+            self.name
+        ))
+    }
+
+    fn clone_box(&self) -> Box<dyn CustomAction> {
+        Box::new(self.clone())
+    }
+}
+
+/// Step of a checkpointed trace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepSnapshot {
+    pub action: ActionSnapshot,
+    // Label of the action as JSON, for the runs checked against a policy
+    pub label: Option<Value>,
+    // When the action was taken, for the unlabeled runs which keep track of it
+    pub taken_at: Option<SystemTime>,
+    pub outcome: Option<Message>,
+}
+
+// What a run resumes from: the conversation, the message to plan from, the memory of the planner
+// and the trace of the actions taken so far
+type Resumed<M, T> = (State, M, Option<Value>, T);
+
+/// State of a planning loop between two steps of a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoopCheckpoint {
+    // Conversation history the next step is planned from
+    state: Vec<ChatCompletionRequestMessage>,
+    // Message the next step is planned from, with its label as JSON for the runs checked against a
+    // policy
+    message: Message,
+    label: Option<Value>,
+    // Memory of the planner, if it keeps any
+    planner: Option<Value>,
+    trace: Vec<StepSnapshot>,
+}
+
+impl LoopCheckpoint {
+    /// Checkpoint of an unlabeled run about to plan from `state` and `message`
+    pub(super) fn new(
+        state: &State,
+        message: &Message,
+        planner: Option<Value>,
+        trace: &RunTrace,
+    ) -> Self {
+        Self {
+            state: state.messages().to_vec(),
+            message: message.clone(),
+            label: None,
+            planner,
+            trace: trace
+                .steps()
+                .iter()
+                .map(|step| StepSnapshot {
+                    action: ActionSnapshot::from(&step.action),
+                    label: None,
+                    taken_at: Some(step.taken_at),
+                    outcome: step.outcome.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Checkpoint of a run checked against a policy, about to plan from `state` and `message`
+    pub(super) fn labeled<L: Lattice + Serialize>(
+        state: &State,
+        message: &MetaValue<Message, L>,
+        planner: Option<Value>,
+        trace: &Trace<L>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            state: state.messages().to_vec(),
+            message: message.value().clone(),
+            label: Some(serde_json::to_value(message.label())?),
+            planner,
+            trace: trace
+                .value()
+                .iter()
+                .map(|action| {
+                    Ok(StepSnapshot {
+                        action: ActionSnapshot::from(action.value()),
+                        label: Some(serde_json::to_value(action.label())?),
+                        taken_at: None,
+                        outcome: None,
+                    })
+                })
+                .collect::<serde_json::Result<_>>()?,
+        })
+    }
+
+    /// Read the checkpoint from the log at `path`, replaying its entries
+    pub async fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let log = tokio::fs::read_to_string(path).await?;
+        let mut lines = log.lines().peekable();
+        let mut checkpoint: Option<Self> = None;
+        while let Some(line) = lines.next() {
+            let entry: CheckpointEntry = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                // The last entry may have been cut short by a crash while it was appended
+                Err(_) if lines.peek().is_none() && checkpoint.is_some() => break,
+                Err(err) => return Err(err.into()),
+            };
+            checkpoint = Some(entry.apply(checkpoint));
+        }
+        checkpoint.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty checkpoint"))
+    }
+
+    /// Write the checkpoint to the log at `path`, replacing what it held
+    pub async fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        replace(path.as_ref(), &CheckpointEntry::changes(None, self)).await
+    }
+
+    /// Number of actions the run took before the checkpoint
+    pub fn step(&self) -> usize {
+        self.trace.len()
+    }
+
+    pub fn trace(&self) -> &[StepSnapshot] {
+        &self.trace
+    }
+
+    pub fn planner_memory(&self) -> Option<&Value> {
+        self.planner.as_ref()
+    }
+
+    /// Split the checkpoint into what an unlabeled run resumes from
+    pub(super) fn into_run(self) -> Resumed<Message, RunTrace> {
+        let steps = self
+            .trace
+            .into_iter()
+            .map(|step| RunStep {
+                action: step.action.into(),
+                taken_at: step.taken_at.unwrap_or_else(SystemTime::now),
+                outcome: step.outcome,
+            })
+            .collect();
+        (
+            State::new(self.state),
+            self.message,
+            self.planner,
+            RunTrace::from_steps(steps),
+        )
+    }
+
+    /// Split the checkpoint into what a run checked against a policy resumes from. Fails if the
+    /// checkpoint was taken by an unlabeled run, as its labels are lost, or by a run with labels
+    /// of another type.
+    pub(super) fn into_labeled_run<L: Lattice + DeserializeOwned>(
+        self,
+    ) -> Option<Resumed<MetaValue<Message, L>, Trace<L>>> {
+        let label = serde_json::from_value(self.label?).ok()?;
+        let mut trace = Trace::default();
+        for step in self.trace {
+            let action: Action = step.action.into();
+            let label = serde_json::from_value(step.label?).ok()?;
+            trace.value_mut().push(MetaValue::new(action, label));
+        }
+        Some((
+            State::new(self.state),
+            MetaValue::new(self.message, label),
+            self.planner,
+            trace,
+        ))
+    }
+}
+
+// Entry of a checkpoint log, holding what changed since the entries before it
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointEntry {
+    // Messages of the conversation from `state_from` on, replacing those logged before
+    state_from: usize,
+    state: Vec<ChatCompletionRequestMessage>,
+    message: Message,
+    label: Option<Value>,
+    // Memory of the planner, left out when it did not change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    planner: Option<Value>,
+    // Steps of the trace from `trace_from` on, replacing those logged before
+    trace_from: usize,
+    trace: Vec<StepSnapshot>,
+}
+
+impl CheckpointEntry {
+    // What changed from the `logged` checkpoint to the `checkpoint`, or all of it if nothing was
+    // logged yet
+    fn changes(logged: Option<&LoopCheckpoint>, checkpoint: &LoopCheckpoint) -> Self {
+        let (state_from, trace_from, planner) = match logged {
+            Some(logged) => (
+                // Compacting the conversation rewrites it, so only its common start is kept
+                logged
+                    .state
+                    .iter()
+                    .zip(&checkpoint.state)
+                    .take_while(|(logged, message)| logged == message)
+                    .count(),
+                // A run only settles or replaces its latest step, so the steps before the last
+                // one logged stay as they are
+                logged
+                    .trace
+                    .len()
+                    .saturating_sub(1)
+                    .min(checkpoint.trace.len()),
+                (logged.planner != checkpoint.planner)
+                    .then(|| checkpoint.planner.clone())
+                    .flatten(),
+            ),
+            None => (0, 0, checkpoint.planner.clone()),
+        };
+        Self {
+            state_from,
+            state: checkpoint.state[state_from..].to_vec(),
+            message: checkpoint.message.clone(),
+            label: checkpoint.label.clone(),
+            planner,
+            trace_from,
+            trace: checkpoint.trace[trace_from..].to_vec(),
+        }
+    }
+
+    // The `checkpoint` replayed so far, if any, with the changes of the entry
+    fn apply(self, checkpoint: Option<LoopCheckpoint>) -> LoopCheckpoint {
+        let mut checkpoint = checkpoint.unwrap_or_else(|| LoopCheckpoint {
+            state: vec![],
+            message: self.message.clone(),
+            label: None,
+            planner: None,
+            trace: vec![],
+        });
+        checkpoint.state.truncate(self.state_from);
+        checkpoint.state.extend(self.state);
+        checkpoint.message = self.message;
+        checkpoint.label = self.label;
+        if self.planner.is_some() {
+            checkpoint.planner = self.planner;
+        }
+        checkpoint.trace.truncate(self.trace_from);
+        checkpoint.trace.extend(self.trace);
+        checkpoint
+    }
+}
+
+// Replace the log at `path` with the single `entry`, written to the side first such that a crash
+// does not leave a truncated log
+async fn replace(path: &Path, entry: &CheckpointEntry) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, serde_json::to_string(entry)? + "\n").await?;
+    tokio::fs::rename(temporary, path).await
+}
+
+/// Log the checkpoints of a loop are appended to, remembering what it holds so far
+#[derive(Debug)]
+pub(super) struct CheckpointLog {
+    path: PathBuf,
+    // Checkpoint the log replays to, unless the run starts over
+    logged: Mutex<Option<LoopCheckpoint>>,
+}
+
+impl CheckpointLog {
+    pub(super) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            logged: Mutex::new(None),
+        }
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Start the log over with the next checkpoint, for a new run
+    pub(super) fn restart(&self) {
+        *self.logged.lock().expect("Checkpoint lock poisoned") = None;
+    }
+
+    // Append what changed since the last checkpoint logged, or replace the log with the
+    // `checkpoint` if the run just started
+    pub(super) async fn append(&self, checkpoint: &LoopCheckpoint) -> io::Result<()> {
+        let (entry, restarted) = {
+            let logged = self.logged.lock().expect("Checkpoint lock poisoned");
+            (
+                CheckpointEntry::changes(logged.as_ref(), checkpoint),
+                logged.is_none(),
+            )
+        };
+        if restarted {
+            replace(&self.path, &entry).await?;
+        } else {
+            let mut file = tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all((serde_json::to_string(&entry)? + "\n").as_bytes())
+                .await?;
+            file.flush().await?;
+        }
+        // Only what made it to the log is diffed against next time
+        *self.logged.lock().expect("Checkpoint lock poisoned") = Some(checkpoint.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicPlanner, PlanningLoop, mock::MockLlm, plan::PlanError};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn interrupted_runs_resume_from_their_checkpoint() {
+        let path =
+            std::env::temp_dir().join(format!("gentlemen-checkpoint-{}.json", std::process::id()));
+        let mut request = MockLlm::assistant_text("Read my latest email.");
+        request.role = async_openai::types::Role::User;
+        // The run stops once the emails are read, before the model is asked for its answer
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            crate::openai::LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+                "call_0",
+                "read_emails",
+                json!({ "count": { "kind": "value", "value": "1" } }),
+            )])),
+            vec![Function::new("read_emails".to_string())],
+        )
+        .with_checkpoint_file(&path)
+        .with_max_iterations(2);
+        let interrupted = planning_loop
+            .run(
+                State::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request),
+            )
+            .await;
+        assert!(matches!(
+            interrupted,
+            Err(PlanError::IterationLimit { limit: 2, .. })
+        ));
+
+        // Every step appended what it changed, and a step cut short by a crash is left out
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 3);
+        let last: Value = serde_json::from_str(log.lines().last().unwrap()).unwrap();
+        assert_eq!(last["state_from"], 1);
+        std::fs::write(&path, log + r#"{"state_from":0,"sta"#).unwrap();
+        let checkpoint = LoopCheckpoint::load(&path)
+            .await
+            .expect("Failed to load the checkpoint");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.step(), 2);
+        assert!(matches!(
+            checkpoint.trace()[1].outcome,
+            Some(Message::ToolResult(_, ref id)) if id == "call_0"
+        ));
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            crate::openai::LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+                "Alice confirmed the meeting.",
+            )])),
+            vec![Function::new("read_emails".to_string())],
+        );
+        let answer = planning_loop
+            .resume(checkpoint, &mut Datastore::default())
+            .await
+            .expect("Failed to resume");
+        assert_eq!(answer, "Alice confirmed the meeting.");
+        // The model picks up the conversation with the emails read before the interruption
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        assert_eq!(requests.len(), 1);
+        assert!(
+            serde_json::to_string(&requests[0])
+                .unwrap()
+                .contains("call_0")
+        );
+    }
+}
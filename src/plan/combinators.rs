@@ -0,0 +1,296 @@
+//! Combinators building planners out of other planners. [`Fallback`] hands a run over to a second
+//! planner once the first one fails, [`WithRetries`] plans a step again when it failed and
+//! [`Sequenced`] runs planners one after the other, each one picking up the request where the one
+//! before finished. Planners of different types are combined by boxing them, such as
+//! `Box<dyn Plan<State, Message, Action = Action, Error = PlanError> + Send>`.
+use super::{Plan, few_shot::AsAction};
+use crate::Action;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+
+/// Planner handing the run over to the `fallback` planner once the `primary` planner fails to plan
+/// a step. The fallback planner plans the failed step again and every step after it, until it
+/// finishes the run, such that a single planner sees each run through.
+pub struct Fallback<A, B> {
+    primary: A,
+    fallback: B,
+    // Whether the fallback planner took over the current run
+    falling_back: bool,
+}
+
+impl<A, B> Fallback<A, B> {
+    pub fn new(primary: A, fallback: B) -> Self {
+        Self {
+            primary,
+            fallback,
+            falling_back: false,
+        }
+    }
+
+    /// Whether the fallback planner took over the current run
+    pub fn is_falling_back(&self) -> bool {
+        self.falling_back
+    }
+}
+
+impl<S, M, A, B> Plan<S, M> for Fallback<A, B>
+where
+    S: Clone,
+    M: Clone,
+    A: Plan<S, M, Action: AsAction>,
+    B: Plan<S, M, Action = A::Action, Error = A::Error>,
+{
+    type Action = A::Action;
+    type Error = A::Error;
+
+    fn plan(&mut self, state: S, message: M) -> Result<(S, Self::Action), Self::Error> {
+        if !self.falling_back {
+            match self.primary.plan(state.clone(), message.clone()) {
+                Ok(planned) => return Ok(planned),
+                Err(_) => self.falling_back = true,
+            }
+        }
+        let (state, mut action) = self.fallback.plan(state, message)?;
+        // The next run starts with the primary planner again
+        if matches!(action.action_mut(), Action::Finish(_)) {
+            self.falling_back = false;
+        }
+        Ok((state, action))
+    }
+
+    fn memory(&self) -> Option<Value> {
+        Some(json!({
+            "falling_back": self.falling_back,
+            "primary": self.primary.memory(),
+            "fallback": self.fallback.memory(),
+        }))
+    }
+
+    fn restore_memory(&mut self, memory: Value) -> Result<(), serde_json::Error> {
+        let (falling_back, primary, fallback): (bool, Option<Value>, Option<Value>) =
+            serde_json::from_value(json!([
+                memory["falling_back"],
+                memory["primary"],
+                memory["fallback"]
+            ]))?;
+        self.falling_back = falling_back;
+        if let Some(primary) = primary {
+            self.primary.restore_memory(primary)?;
+        }
+        if let Some(fallback) = fallback {
+            self.fallback.restore_memory(fallback)?;
+        }
+        Ok(())
+    }
+}
+
+/// Planner planning every step with the `inner` planner again when it fails, up to `retries` more
+/// times, and failing with the last error after that. Only helps planners whose failures do not
+/// come from their input alone, such as planners asking a model or a service.
+pub struct WithRetries<P> {
+    inner: P,
+    retries: usize,
+}
+
+impl<P> WithRetries<P> {
+    pub fn new(inner: P, retries: usize) -> Self {
+        Self { inner, retries }
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<S: Clone, M: Clone, P: Plan<S, M>> Plan<S, M> for WithRetries<P> {
+    type Action = P::Action;
+    type Error = P::Error;
+
+    fn plan(&mut self, state: S, message: M) -> Result<(S, Self::Action), Self::Error> {
+        for _ in 0..self.retries {
+            if let Ok(planned) = self.inner.plan(state.clone(), message.clone()) {
+                return Ok(planned);
+            }
+        }
+        self.inner.plan(state, message)
+    }
+
+    fn memory(&self) -> Option<Value> {
+        self.inner.memory()
+    }
+
+    fn restore_memory(&mut self, memory: Value) -> Result<(), serde_json::Error> {
+        self.inner.restore_memory(memory)
+    }
+}
+
+/// Planner running the `planners` one after the other on every request. Once a planner finishes,
+/// the next one is given the request again along with the conversation so far, which holds the
+/// answer of the planner before it, and the last planner gives the final answer.
+pub struct Sequenced<P, M> {
+    planners: Vec<P>,
+    // Planner running the current stage of the run
+    stage: usize,
+    // Request of the current run, handed to every stage
+    request: Option<M>,
+}
+
+impl<P, M> Sequenced<P, M> {
+    /// Run the `planners` in order. Panics if there are none.
+    pub fn new(planners: Vec<P>) -> Self {
+        assert!(!planners.is_empty(), "Nothing to sequence");
+        Self {
+            planners,
+            stage: 0,
+            request: None,
+        }
+    }
+
+    /// Index of the planner running the current stage of the run
+    pub fn stage(&self) -> usize {
+        self.stage
+    }
+}
+
+impl<S, M, P> Plan<S, M> for Sequenced<P, M>
+where
+    M: Clone + Serialize + DeserializeOwned,
+    P: Plan<S, M, Action: AsAction>,
+{
+    type Action = P::Action;
+    type Error = P::Error;
+
+    fn plan(&mut self, state: S, message: M) -> Result<(S, Self::Action), Self::Error> {
+        let request = self.request.get_or_insert_with(|| message.clone()).clone();
+        let (mut state, mut action) = self.planners[self.stage].plan(state, message)?;
+        // Every stage but the last one hands the request over instead of finishing
+        while matches!(action.action_mut(), Action::Finish(_)) {
+            if self.stage + 1 >= self.planners.len() {
+                self.stage = 0;
+                self.request = None;
+                break;
+            }
+            self.stage += 1;
+            (state, action) = self.planners[self.stage].plan(state, request.clone())?;
+        }
+        Ok((state, action))
+    }
+
+    fn memory(&self) -> Option<Value> {
+        Some(json!({
+            "stage": self.stage,
+            "request": serde_json::to_value(&self.request).ok()?,
+            "planners": self.planners.iter().map(Plan::memory).collect::<Vec<_>>(),
+        }))
+    }
+
+    fn restore_memory(&mut self, memory: Value) -> Result<(), serde_json::Error> {
+        let (stage, request, planners): (usize, Option<M>, Vec<Option<Value>>) =
+            serde_json::from_value(json!([
+                memory["stage"],
+                memory["request"],
+                memory["planners"]
+            ]))?;
+        self.stage = stage;
+        self.request = request;
+        for (planner, memory) in self.planners.iter_mut().zip(planners) {
+            if let Some(memory) = memory {
+                planner.restore_memory(memory)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BasicPlanner, Datastore, Message, PlanningLoop, State, mock::MockLlm, plan::PlanError,
+        simulation::SimulatedEnvironment,
+    };
+
+    type BoxedPlanner = Box<dyn Plan<State, Message, Action = Action, Error = PlanError> + Send>;
+
+    // Planner failing the given number of times before planning like a basic planner
+    struct Flaky {
+        failures: usize,
+        attempts: usize,
+    }
+
+    impl Plan<State, Message> for Flaky {
+        type Action = Action;
+        type Error = PlanError;
+
+        fn plan(&mut self, state: State, message: Message) -> Result<(State, Action), PlanError> {
+            self.attempts += 1;
+            if self.attempts <= self.failures {
+                return Err(PlanError::CannotPlan("Flaky".to_string()));
+            }
+            BasicPlanner::new(vec![]).plan(state, message)
+        }
+    }
+
+    async fn run<P>(planner: P, responses: &[&str]) -> (Result<String, PlanError>, P)
+    where
+        P: Plan<State, Message, Action = Action>,
+    {
+        let environment = SimulatedEnvironment::new(
+            "Summarize my latest email.",
+            responses
+                .iter()
+                .map(|&text| MockLlm::assistant_text(text))
+                .collect(),
+        );
+        let mut planning_loop = PlanningLoop::new(planner, environment.model(), vec![]);
+        let answer = planning_loop
+            .run(
+                environment.state(),
+                &mut Datastore::default(),
+                environment.request(),
+            )
+            .await;
+        (answer, planning_loop.planner)
+    }
+
+    #[tokio::test]
+    async fn failing_planners_are_retried_or_replaced() {
+        let flaky = Flaky {
+            failures: 2,
+            attempts: 0,
+        };
+        let (answer, planner) = run(WithRetries::new(flaky, 2), &["Alice is coming."]).await;
+        assert_eq!(answer.unwrap(), "Alice is coming.");
+        assert_eq!(planner.into_inner().attempts, 4);
+
+        let flaky = Flaky {
+            failures: 1,
+            attempts: 0,
+        };
+        let fallback = Fallback::new(flaky, BasicPlanner::new(vec![]));
+        let (answer, planner) = run(fallback, &["Alice is coming."]).await;
+        assert_eq!(answer.unwrap(), "Alice is coming.");
+        // The run is over, so the next one starts with the primary planner
+        assert!(!planner.is_falling_back());
+        assert_eq!(planner.primary.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn sequenced_planners_pick_up_where_the_last_finished() {
+        let planners: Vec<BoxedPlanner> = vec![
+            Box::new(BasicPlanner::new(vec![])),
+            Box::new(BasicPlanner::new(vec![])),
+        ];
+        let (answer, planner) = run(
+            Sequenced::new(planners),
+            &["Alice is coming, I think.", "Alice confirmed the meeting."],
+        )
+        .await;
+        assert_eq!(answer.unwrap(), "Alice confirmed the meeting.");
+        assert_eq!(planner.stage(), 0);
+    }
+}
@@ -0,0 +1,514 @@
+//! Plans as dependency graphs of tool calls. The [`PlanningLoop`] takes one action at a time, such
+//! that reading three inboxes takes three round trips even though none of the reads needs the
+//! others. A planner can instead emit a [`PlanDag`], where every call names the calls it depends
+//! on, and have [`execute`] run all the calls whose dependencies are done concurrently.
+//!
+//! The arguments of a call can refer to the result of a call it depends on with
+//! `{"kind": "output", "node": <id>}`, next to the literal values the model passes as they are.
+//!
+//! [`execute_labeled`] labels every call with the results it depends on and checks it against a
+//! policy before making it.
+//!
+//! [`PlanningLoop`]: super::PlanningLoop
+use super::{
+    labeled::Trace,
+    policy::{Policy, PolicyViolation},
+};
+use crate::{
+    Action, Args, Call, Datastore, Function, MetaFunction,
+    ifc::{Lattice, LatticeError},
+    tools::{EmailLabel, MetaValue, service_authority},
+    validate::ValidationError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use tokio::task::JoinSet;
+
+/// Index of a call in its graph
+pub type NodeId = usize;
+
+/// Tool call of a graph
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DagNode {
+    function: String,
+    // Arguments of the call, where each argument may refer to the result of an earlier call
+    args: Map<String, Value>,
+    // Calls which have to be done before this one, on top of the ones referred to by the arguments
+    #[serde(default)]
+    after: Vec<NodeId>,
+}
+
+impl DagNode {
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    pub fn args(&self) -> &Map<String, Value> {
+        &self.args
+    }
+
+    /// Calls whose results the arguments take, in the order of the arguments
+    pub fn references(&self) -> Vec<NodeId> {
+        self.args.values().filter_map(output_reference).collect()
+    }
+
+    /// Calls which have to be done before this one, in increasing order
+    pub fn dependencies(&self) -> Vec<NodeId> {
+        let mut dependencies = self.after.clone();
+        dependencies.extend(self.references());
+        dependencies.sort_unstable();
+        dependencies.dedup();
+        dependencies
+    }
+}
+
+// The node the `value` of an argument refers to, if it refers to the result of a call
+fn output_reference(value: &Value) -> Option<NodeId> {
+    let reference = value.as_object()?;
+    if reference.get("kind")?.as_str()? != "output" {
+        return None;
+    }
+    usize::try_from(reference.get("node")?.as_u64()?).ok()
+}
+
+/// Error issued while building or executing a graph
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DagError {
+    // The node depends on a node which does not come before it, which could form a cycle
+    InvalidDependency { node: NodeId, dependency: NodeId },
+    FunctionNotFound(String),
+    // The arguments of the node were rejected by the validators of its tool
+    ValidationError(NodeId, ValidationError),
+    // The call of the node failed, panicked or was cancelled
+    CallFailed(NodeId, String),
+    // The policy denied the call of the node, which was not made
+    PolicyViolation(NodeId, PolicyViolation),
+    LatticeError(LatticeError),
+    SerdeJsonError(serde_json::Error),
+}
+
+impl From<LatticeError> for DagError {
+    fn from(err: LatticeError) -> Self {
+        Self::LatticeError(err)
+    }
+}
+
+impl From<serde_json::Error> for DagError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::SerdeJsonError(err)
+    }
+}
+
+/// Dependency graph of tool calls. Calls only depend on calls added before them, such that the
+/// graph never has a cycle.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlanDag {
+    nodes: Vec<DagNode>,
+}
+
+impl PlanDag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the graph the model answered with, such as
+    /// `{"nodes": [{"function": "read_emails", "args": {"count": 5}}]}`
+    pub fn from_json(json: &str) -> Result<Self, DagError> {
+        let dag: Self = serde_json::from_str(json)?;
+        for (node, call) in dag.nodes.iter().enumerate() {
+            dag.check_dependencies(node, call)?;
+        }
+        Ok(dag)
+    }
+
+    /// Add a call to `function` with `args`, which is made once the calls `after` and the calls
+    /// the arguments refer to are done
+    pub fn add(
+        &mut self,
+        function: &str,
+        args: Map<String, Value>,
+        after: Vec<NodeId>,
+    ) -> Result<NodeId, DagError> {
+        let call = DagNode {
+            function: function.to_string(),
+            args,
+            after,
+        };
+        let node = self.nodes.len();
+        self.check_dependencies(node, &call)?;
+        self.nodes.push(call);
+        Ok(node)
+    }
+
+    fn check_dependencies(&self, node: NodeId, call: &DagNode) -> Result<(), DagError> {
+        match call
+            .dependencies()
+            .into_iter()
+            .find(|&dependency| dependency >= node)
+        {
+            Some(dependency) => Err(DagError::InvalidDependency { node, dependency }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn nodes(&self) -> &[DagNode] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Arguments of the call `node`, where the references to the calls it depends on are replaced
+    /// with their `results`
+    pub fn resolve(&self, node: NodeId, results: &[Option<String>]) -> Args {
+        let args: Map<String, Value> = self.nodes[node]
+            .args
+            .iter()
+            .map(|(name, value)| {
+                let value = match output_reference(value) {
+                    Some(dependency) => Value::String(
+                        results[dependency]
+                            .clone()
+                            .expect("Dependencies are done before their dependents"),
+                    ),
+                    None => value.clone(),
+                };
+                (name.clone(), value)
+            })
+            .collect();
+        Args::new(Value::Object(args).to_string())
+    }
+}
+
+/// Tool which can be called from a graph
+pub trait DagTool: Call<Args = Args, Output: DagOutput> + Clone + Send + Sync + 'static {
+    fn name(&self) -> &str;
+}
+
+impl DagTool for Function {
+    fn name(&self) -> &str {
+        self.name()
+    }
+}
+
+impl DagTool for MetaFunction {
+    fn name(&self) -> &str {
+        self.name()
+    }
+}
+
+/// Result of a tool called from a graph, which the calls depending on it can refer to
+pub trait DagOutput: Send + 'static {
+    fn text(&self) -> &str;
+}
+
+impl DagOutput for String {
+    fn text(&self) -> &str {
+        self
+    }
+}
+
+impl DagOutput for (String, EmailLabel) {
+    fn text(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Execute the calls of the `dag` with the `tools`, returning their results in the order of the
+/// graph. Every call is made as soon as the calls it depends on are done, in a task of its own,
+/// such that calls which do not depend on each other run concurrently. Each call is passed a clone
+/// of the `datastore`, whose writes are merged back into the `datastore` once the call is done.
+pub async fn execute<T: DagTool>(
+    dag: &PlanDag,
+    tools: &[T],
+    datastore: &mut Datastore,
+) -> Result<Vec<T::Output>, DagError> {
+    run(dag, tools, datastore, |_, _, _| Ok(())).await
+}
+
+// Execute the calls of the `dag` like `execute`, passing each of them to `before_call` with its
+// resolved arguments and the results done so far before making it. The graph stops at the first
+// call `before_call` fails.
+async fn run<T: DagTool>(
+    dag: &PlanDag,
+    tools: &[T],
+    datastore: &mut Datastore,
+    mut before_call: impl FnMut(NodeId, &Args, &[Option<T::Output>]) -> Result<(), DagError>,
+) -> Result<Vec<T::Output>, DagError> {
+    let calls = dag
+        .nodes
+        .iter()
+        .map(|call| {
+            tools
+                .iter()
+                .find(|tool| tool.name() == call.function)
+                .cloned()
+                .ok_or_else(|| DagError::FunctionNotFound(call.function.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let dependencies: Vec<_> = dag.nodes.iter().map(DagNode::dependencies).collect();
+    let mut results: Vec<Option<T::Output>> = (0..dag.len()).map(|_| None).collect();
+    let mut texts: Vec<Option<String>> = vec![None; dag.len()];
+    let mut started = vec![false; dag.len()];
+    let mut running = JoinSet::new();
+    // The node each running task makes the call of, along with the datastore it was cloned from
+    let mut nodes = HashMap::new();
+    loop {
+        // Start every call whose dependencies are all done
+        for node in 0..dag.len() {
+            let ready = dependencies[node]
+                .iter()
+                .all(|&dependency| texts[dependency].is_some());
+            if started[node] || !ready {
+                continue;
+            }
+            started[node] = true;
+            let args = dag.resolve(node, &texts);
+            let tool = calls[node].clone();
+            tool.validate(&args)
+                .map_err(|err| DagError::ValidationError(node, err))?;
+            before_call(node, &args, &results)?;
+            let base = datastore.snapshot();
+            let mut branch = datastore.clone();
+            let task = running.spawn(async move {
+                let output = tool.call(args, &mut branch).await;
+                (output, branch)
+            });
+            nodes.insert(task.id(), (node, base));
+        }
+        let Some(done) = running.join_next_with_id().await else {
+            break;
+        };
+        let (node, output) = match done {
+            Ok((task, (Ok(output), branch))) => {
+                let (node, base) = &nodes[&task];
+                datastore.merge(branch, base);
+                (*node, output)
+            }
+            Ok((task, (Err(err), _))) => {
+                return Err(DagError::CallFailed(nodes[&task].0, err.to_string()));
+            }
+            Err(err) => return Err(DagError::CallFailed(nodes[&err.id()].0, err.to_string())),
+        };
+        texts[node] = Some(output.text().to_string());
+        results[node] = Some(output);
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| result.expect("Every call of the graph is made"))
+        .collect())
+}
+
+/// Execute the calls of the `dag` like [`execute`], labeling every call with the `label` of the
+/// context the graph was planned in joined with the labels of the results it depends on, such
+/// that a call depending on an untrusted result is itself untrusted. Each call is checked against
+/// the `policy` with its label and resolved arguments before it is made, and the graph stops at
+/// the first call denied. The result of a call carries its label joined with the one of the tool
+/// result.
+pub async fn execute_labeled(
+    dag: &PlanDag,
+    tools: &[MetaFunction],
+    datastore: &mut Datastore,
+    label: EmailLabel,
+    policy: &Policy,
+) -> Result<Vec<MetaValue<String, EmailLabel>>, DagError> {
+    let mut trace = Trace::default();
+    let mut labels: Vec<Option<EmailLabel>> = vec![None; dag.len()];
+    let outputs = run(dag, tools, datastore, |node, args, results| {
+        let mut call_label = Some(label.clone());
+        for dependency in dag.nodes[node].dependencies() {
+            let (_, output_label) = results[dependency]
+                .as_ref()
+                .expect("Dependencies are done before their dependents");
+            let dependency_label = labels[dependency]
+                .clone()
+                .expect("Dependencies are labeled before they are made");
+            let output_label = propagate(
+                tools,
+                dag.nodes[dependency].function(),
+                &dependency_label,
+                output_label.clone(),
+            )?;
+            call_label = call_label
+                .and_then(|call_label| call_label.join(dependency_label))
+                .and_then(|call_label| call_label.join(output_label.clone()));
+        }
+        let call_label = call_label.ok_or(LatticeError::LabelJoinFailed)?;
+        let action = Action::MakeCall(
+            Function::new(dag.nodes[node].function.clone()),
+            args.clone(),
+            format!("dag_{node}"),
+        );
+        trace
+            .value_mut()
+            .push(MetaValue::new(action, call_label.clone()));
+        if let Some(violation) = policy.check(&trace) {
+            return Err(DagError::PolicyViolation(node, violation));
+        }
+        labels[node] = Some(call_label);
+        Ok(())
+    })
+    .await?;
+    outputs
+        .into_iter()
+        .zip(labels)
+        .enumerate()
+        .map(|(node, ((output, output_label), call_label))| {
+            let call_label = call_label.expect("Every call of the graph is labeled");
+            let output_label =
+                propagate(tools, dag.nodes[node].function(), &call_label, output_label)?;
+            let label = call_label
+                .join(output_label)
+                .ok_or(LatticeError::LabelJoinFailed)?;
+            Ok(MetaValue::new(output, label))
+        })
+        .collect()
+}
+
+// Label of the result the tool called `function` labeled `output`, derived like the planning loop
+// does with the label of its call and the authority the tool runs with
+fn propagate(
+    tools: &[MetaFunction],
+    function: &str,
+    call_label: &EmailLabel,
+    output: EmailLabel,
+) -> Result<EmailLabel, DagError> {
+    let tool = tools
+        .iter()
+        .find(|tool| tool.name() == function)
+        .ok_or_else(|| DagError::FunctionNotFound(function.to_string()))?;
+    let authority = tool.authority().cloned().unwrap_or_else(service_authority);
+    let label = tool
+        .label_propagation()
+        .propagate(call_label, output, &authority)
+        .ok_or(LatticeError::LabelJoinFailed)?;
+    Ok(label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Integrity, ProductLattice,
+        plan::policy::policy_no_untrusted_url,
+        tools::{EmailAddressUniverse, INBOX, readers_label, trusted_service_authority},
+    };
+    use serde_json::json;
+
+    fn args(value: Value) -> Map<String, Value> {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[tokio::test]
+    async fn labels_are_joined_where_calls_merge() {
+        let mut dag = PlanDag::new();
+        let read = dag
+            .add("read_emails_labeled", args(json!({ "count": "5" })), vec![])
+            .unwrap();
+        let status = dag
+            .add(
+                "get_message_status_labeled",
+                args(json!({ "send_id": "send-dag" })),
+                vec![],
+            )
+            .unwrap();
+        let send = dag
+            .add(
+                "send_slack_message_labeled",
+                args(json!({
+                    "channel": "bob.sheffield@magnet.com",
+                    "message": { "kind": "output", "node": read },
+                    "preview": "false",
+                })),
+                vec![status],
+            )
+            .unwrap();
+        assert_eq!(dag.nodes()[send].dependencies(), [read, status]);
+
+        let tools = [
+            MetaFunction::new("read_emails_labeled".to_string()),
+            MetaFunction::new("get_message_status_labeled".to_string())
+                .with_authority(trusted_service_authority()),
+            MetaFunction::new("send_slack_message_labeled".to_string()),
+        ];
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            Integrity::trusted(),
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let mut datastore = Datastore::default();
+        let results = execute_labeled(&dag, &tools, &mut datastore, label, &Policy::new(|_| None))
+            .await
+            .expect("Failed to execute");
+        assert_eq!(results.len(), 3);
+        assert!(results[send].value().contains("Message sent!"));
+        // The send carries the untrusted emails it was given, though the send itself is trusted
+        assert_eq!(results[read].label().lattice1(), &Integrity::untrusted());
+        assert_eq!(results[status].label().lattice1(), &Integrity::trusted());
+        assert_eq!(results[send].label().lattice1(), &Integrity::untrusted());
+        // The effect of the send made on a clone of the datastore is kept
+        assert!(datastore.store().contains("send_slack_message_labeled/0"));
+    }
+
+    #[tokio::test]
+    async fn calls_are_checked_with_the_labels_of_their_dependencies_before_being_made() {
+        let mut dag = PlanDag::new();
+        let read = dag
+            .add("read_emails_labeled", args(json!({ "count": "5" })), vec![])
+            .unwrap();
+        dag.add(
+            "send_slack_message_labeled",
+            args(json!({
+                "channel": "bob.sheffield@magnet.com",
+                "message": { "kind": "output", "node": read },
+                "preview": "false",
+            })),
+            vec![],
+        )
+        .unwrap();
+        let tools = [
+            MetaFunction::new("read_emails_labeled".to_string()),
+            MetaFunction::new("send_slack_message_labeled".to_string()),
+        ];
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            Integrity::trusted(),
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let mut datastore = Datastore::default();
+        let result = execute_labeled(
+            &dag,
+            &tools,
+            &mut datastore,
+            label,
+            &Policy::new(policy_no_untrusted_url),
+        )
+        .await;
+        assert!(matches!(result, Err(DagError::PolicyViolation(1, _))));
+        assert!(datastore.store().is_empty());
+    }
+
+    #[test]
+    fn calls_only_depend_on_earlier_calls() {
+        let dag = PlanDag::from_json(
+            r#"{"nodes": [
+                {"function": "read_emails", "args": {"count": {"kind": "output", "node": 1}}},
+                {"function": "read_emails", "args": {"count": 5}}
+            ]}"#,
+        );
+        assert!(matches!(
+            dag,
+            Err(DagError::InvalidDependency {
+                node: 0,
+                dependency: 1
+            })
+        ));
+    }
+}
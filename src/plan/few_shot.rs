@@ -0,0 +1,411 @@
+//! Warm-starting planners with prior successful runs of similar tasks. The [`FewShotPlanner`]
+//! looks up the runs whose query is the most similar to the user's, and shows them to the model as
+//! examples in the system prompt, which helps the model pick the right tools for recurring tasks.
+//! Prior runs may have read data the current reader is not cleared for, so examples keep the label
+//! of their run and the conversation they are shown in is tainted with it.
+use super::{Plan, TaintLabel};
+use crate::{Action, State, ifc::Lattice, sealed::Sealed, tools::EmailLabel};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessageContent,
+};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+// Start of the system message holding the examples, also used to recognize it
+const FEW_SHOT_HEADER: &str = "Here are examples of how similar requests were solved before:";
+
+/// Turns text into vectors whose cosine similarity reflects how similar the texts are
+pub trait Embedder {
+    fn embed(&self, text: &str) -> Vec<f32>;
+}
+
+/// Embeds text as hashed word counts. Cheap and good enough to tell apart recurring tasks, which
+/// tend to reuse the same words.
+#[derive(Debug, Clone)]
+pub struct BagOfWords {
+    dimensions: usize,
+}
+
+impl BagOfWords {
+    pub fn new(dimensions: usize) -> Self {
+        Self { dimensions }
+    }
+}
+
+impl Default for BagOfWords {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+impl Embedder for BagOfWords {
+    fn embed(&self, text: &str) -> Vec<f32> {
+        let mut embedding = vec![0.0; self.dimensions];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.len() > 2)
+        {
+            let mut hasher = DefaultHasher::new();
+            word.to_lowercase().hash(&mut hasher);
+            embedding[hasher.finish() as usize % self.dimensions] += 1.0;
+        }
+        embedding
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+/// A successful run, reduced to what the model needs to learn from it
+#[derive(Debug, Clone)]
+pub struct Example {
+    query: String,
+    embedding: Vec<f32>,
+    // Tool calls made during the run, rendered as text
+    steps: Vec<String>,
+    answer: String,
+    // Label of everything the run read, for runs which tracked labels
+    label: Option<EmailLabel>,
+}
+
+impl Example {
+    fn render(&self) -> String {
+        let steps = self
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(idx, step)| format!("{}. {step}", idx + 1))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("Request: {}\n{steps}\nAnswer: {}", self.query, self.answer)
+    }
+}
+
+/// Prior successful runs, searchable by the similarity of their queries
+#[derive(Debug, Clone)]
+pub struct TraceLibrary<E> {
+    embedder: E,
+    examples: Vec<Example>,
+}
+
+impl<E: Embedder> TraceLibrary<E> {
+    pub fn new(embedder: E) -> Self {
+        Self {
+            embedder,
+            examples: vec![],
+        }
+    }
+
+    /// Remember the run which answered `query` with `answer` by taking `actions`
+    pub fn record(&mut self, query: &str, actions: &[Action], answer: &str) {
+        self.record_example(query, actions, answer, None);
+    }
+
+    /// Like [`record`], for a run whose answer carries the `label`, which taints the conversations
+    /// the run is shown in as an example
+    ///
+    /// [`record`]: Self::record
+    pub fn record_labeled(
+        &mut self,
+        query: &str,
+        actions: &[Action],
+        answer: &str,
+        label: EmailLabel,
+    ) {
+        self.record_example(query, actions, answer, Some(label));
+    }
+
+    fn record_example(
+        &mut self,
+        query: &str,
+        actions: &[Action],
+        answer: &str,
+        label: Option<EmailLabel>,
+    ) {
+        let steps = actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::MakeCall(function, args, _) => {
+                    Some(format!("Call `{}` with {}", function.name(), args.value()))
+                }
+                _ => None,
+            })
+            .collect();
+        self.examples.push(Example {
+            query: query.to_string(),
+            embedding: self.embedder.embed(query),
+            steps,
+            answer: answer.to_string(),
+            label,
+        });
+    }
+
+    pub fn examples(&self) -> &[Example] {
+        &self.examples
+    }
+
+    /// Returns the `k` examples whose query is the most similar to `query`
+    pub fn most_similar(&self, query: &str, k: usize) -> Vec<&Example> {
+        let embedding = self.embedder.embed(query);
+        let mut scored: Vec<(f32, &Example)> = self
+            .examples
+            .iter()
+            .map(|example| (cosine(&embedding, &example.embedding), example))
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(k)
+            .map(|(_, example)| example)
+            .collect()
+    }
+}
+
+/// Gives access to the [`Action`] inside the actions of the planners, labeled or not
+pub trait AsAction: Sealed {
+    fn action_mut(&mut self) -> &mut Action;
+
+    /// Join the email `label` into the label of the action, if it is labeled. Returns false if
+    /// the label of the action cannot carry it.
+    fn taint(&mut self, label: &EmailLabel) -> bool;
+}
+
+impl Sealed for Action {}
+impl<L> Sealed for (Action, L) {}
+
+impl AsAction for Action {
+    fn action_mut(&mut self) -> &mut Action {
+        self
+    }
+
+    // Plain actions carry no label, as do the conversations they belong to
+    fn taint(&mut self, _label: &EmailLabel) -> bool {
+        true
+    }
+}
+
+impl<L: TaintLabel> AsAction for (Action, L) {
+    fn action_mut(&mut self) -> &mut Action {
+        &mut self.0
+    }
+
+    fn taint(&mut self, label: &EmailLabel) -> bool {
+        let Some(joined) =
+            L::from_email_label(label.clone()).and_then(|label| self.1.clone().join(label))
+        else {
+            return false;
+        };
+        self.1 = joined;
+        true
+    }
+}
+
+/// Planner showing the model the `k` prior runs most similar to the user's request, and otherwise
+/// delegating to the `inner` planner
+pub struct FewShotPlanner<P, E> {
+    inner: P,
+    library: TraceLibrary<E>,
+    k: usize,
+}
+
+impl<P, E: Embedder> FewShotPlanner<P, E> {
+    pub fn new(inner: P, library: TraceLibrary<E>, k: usize) -> Self {
+        Self { inner, library, k }
+    }
+
+    pub fn library_mut(&mut self) -> &mut TraceLibrary<E> {
+        &mut self.library
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    // Build the system message holding the examples for `query`, if there are any, along with the
+    // join of the labels of the examples. Returns `None` if the labels cannot be joined.
+    fn examples_message(
+        &self,
+        query: &str,
+    ) -> Option<(ChatCompletionRequestMessage, Option<EmailLabel>)> {
+        let examples = self.library.most_similar(query, self.k);
+        if examples.is_empty() {
+            return None;
+        }
+        let label = examples
+            .iter()
+            .filter_map(|example| example.label.clone())
+            .try_fold(None, |joined: Option<EmailLabel>, label| match joined {
+                Some(joined) => joined.join(label).map(Some),
+                None => Some(Some(label)),
+            })?;
+        let examples = examples
+            .iter()
+            .map(|example| example.render())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let message = ChatCompletionRequestSystemMessageArgs::default()
+            .content(format!("{FEW_SHOT_HEADER}\n\n{examples}"))
+            .build()
+            .ok()?;
+        Some((message.into(), label))
+    }
+}
+
+// Returns the text of the first user message of the conversation
+fn user_query(state: &State) -> Option<&str> {
+    state.messages().iter().find_map(|message| match message {
+        ChatCompletionRequestMessage::User(message) => match &message.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => Some(text.as_str()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+// Returns true if the examples were already added to the conversation
+fn has_examples(state: &State) -> bool {
+    state.messages().iter().any(|message| {
+        matches!(
+            message,
+            ChatCompletionRequestMessage::System(message)
+                if matches!(
+                    &message.content,
+                    ChatCompletionRequestSystemMessageContent::Text(text)
+                        if text.starts_with(FEW_SHOT_HEADER)
+                )
+        )
+    })
+}
+
+impl<M, P, E> Plan<State, M> for FewShotPlanner<P, E>
+where
+    P: Plan<State, M>,
+    P::Action: AsAction,
+    E: Embedder,
+{
+    type Action = P::Action;
+    type Error = P::Error;
+
+    fn plan(&mut self, state: State, message: M) -> Result<(State, Self::Action), Self::Error> {
+        let (mut state, mut action) = self.inner.plan(state, message)?;
+        // The user's request only shows up in the state once the inner planner took it in, so
+        // the examples are added right after, once per conversation
+        // Examples whose label the action cannot carry are not shown
+        if !has_examples(&state)
+            && let Some((examples, label)) =
+                user_query(&state).and_then(|query| self.examples_message(query))
+            && label.is_none_or(|label| action.taint(&label))
+        {
+            state.messages_mut().insert(0, examples.clone());
+            if let Action::Query(history, _) = action.action_mut() {
+                history.messages_mut().insert(0, examples);
+            }
+        }
+        Ok((state, action))
+    }
+
+    fn memory(&self) -> Option<serde_json::Value> {
+        self.inner.memory()
+    }
+
+    fn restore_memory(&mut self, memory: serde_json::Value) -> Result<(), serde_json::Error> {
+        self.inner.restore_memory(memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Args, BasicPlanner, ConversationHistory, Function, Integrity, Message,
+        TaintTrackingPlanner,
+        mock::MockLlm,
+        tools::{MetaValue, readers_label},
+    };
+    use async_openai::types::Role;
+    use std::collections::HashSet;
+
+    #[test]
+    fn similar_runs_are_shown_as_examples() {
+        let mut library = TraceLibrary::new(BagOfWords::default());
+        library.record(
+            "Summarize my recent emails and send the summary on Slack",
+            &[Action::MakeCall(
+                Function::new("read_emails".to_string()),
+                Args::new("{\"count\":5}".to_string()),
+                "call_0".to_string(),
+            )],
+            "Sent the summary of your emails.",
+        );
+        library.record(
+            "Book a meeting room for tomorrow morning",
+            &[],
+            "Booked room 3 for 10 AM.",
+        );
+        let mut planner = FewShotPlanner::new(BasicPlanner::new(vec![]), library, 1);
+
+        let mut request = MockLlm::assistant_text("Please summarize my emails");
+        request.role = Role::User;
+        let (state, action) = planner
+            .plan(ConversationHistory::new(vec![]), Message::Chat(request))
+            .expect("Failed to plan");
+        let Action::Query(history, _) = action else {
+            panic!("Expected a query, got {action:?}");
+        };
+        assert_eq!(history.messages().len(), 2);
+        let ChatCompletionRequestMessage::System(examples) = &history.messages()[0] else {
+            panic!("Expected the examples first");
+        };
+        let ChatCompletionRequestSystemMessageContent::Text(examples) = &examples.content else {
+            panic!("Expected text examples");
+        };
+        assert!(examples.contains("Call `read_emails` with {\"count\":5}"));
+        assert!(!examples.contains("meeting room"));
+        assert!(has_examples(&state));
+    }
+
+    #[test]
+    fn examples_taint_the_conversation_with_their_label() {
+        let universe: HashSet<String> = ["alice@magnet.com", "bob@magnet.com"]
+            .map(String::from)
+            .into();
+        let readable_by = |readers: &[&str]| {
+            EmailLabel::new(
+                Integrity::trusted(),
+                readers_label(
+                    readers.iter().map(|reader| reader.to_string()).collect(),
+                    universe.clone(),
+                )
+                .unwrap(),
+            )
+        };
+        let alice = readable_by(&["alice@magnet.com"]);
+        let mut library = TraceLibrary::new(BagOfWords::default());
+        library.record_labeled(
+            "Summarize my recent emails",
+            &[],
+            "Alice's raise was approved.",
+            alice.clone(),
+        );
+        let mut planner = FewShotPlanner::new(TaintTrackingPlanner::new(vec![]), library, 1);
+
+        let mut request = MockLlm::assistant_text("Please summarize my emails");
+        request.role = Role::User;
+        let (state, (_, label)) = planner
+            .plan(
+                ConversationHistory::new(vec![]),
+                MetaValue::new(
+                    Message::Chat(request),
+                    readable_by(&["alice@magnet.com", "bob@magnet.com"]),
+                ),
+            )
+            .expect("Failed to plan");
+        assert!(has_examples(&state));
+        assert_eq!(label, alice);
+    }
+}
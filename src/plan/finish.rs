@@ -0,0 +1,291 @@
+//! Criteria deciding when the model is done with a task. By default, any assistant message without
+//! tool calls is taken as the final answer, which makes chatty models end their runs on interim
+//! commentary. The [`FinishingPlanner`] only lets the run finish once its [`FinishCriteria`] is
+//! met, and otherwise tells the model what is missing and queries it again.
+use super::{Plan, few_shot::AsAction};
+use crate::{Action, State, tools::variable_schema_gen};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestUserMessageArgs, ChatCompletionTool,
+    ChatCompletionToolArgs, ChatCompletionToolType, FunctionObject,
+};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+/// When the final answer of the model is accepted
+#[derive(Debug, Clone, Default)]
+pub enum FinishCriteria {
+    // Any assistant message without tool calls is the final answer
+    #[default]
+    AnyText,
+    // The model has to call the tool with this name, passing the final answer as `answer`
+    FinishTool(String),
+    // The final answer has to be a JSON object matching this schema
+    Schema(Value),
+    // The model has to complete at least this many tool calls before answering
+    SubGoals(usize),
+}
+
+impl FinishCriteria {
+    /// Check whether the `answer` given at the end of the conversation in `state` meets the
+    /// criteria, returning what is missing otherwise
+    pub fn check(&self, state: &State, answer: &str) -> Result<(), String> {
+        match self {
+            Self::AnyText => Ok(()),
+            Self::FinishTool(name) => Err(format!(
+                "Call the `{name}` tool with your final answer once you are done."
+            )),
+            Self::Schema(schema) => match serde_json::from_str(answer) {
+                Ok(answer) => matches_schema(&answer, schema).map_err(|reason| {
+                    format!("Your final answer does not match the expected schema: {reason}.")
+                }),
+                Err(_) => Err(format!(
+                    "Give your final answer as a JSON object matching this schema: {schema}"
+                )),
+            },
+            Self::SubGoals(count) => {
+                let completed = state
+                    .messages()
+                    .iter()
+                    .filter(|message| matches!(message, ChatCompletionRequestMessage::Tool(_)))
+                    .count();
+                if completed >= *count {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "You completed {completed} of at least {count} steps. Carry on with the \
+                        task before giving your final answer."
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Schema of the tool the model calls to finish under [`FinishCriteria::FinishTool`]
+pub fn finish_tool(name: &str) -> ChatCompletionTool {
+    ChatCompletionToolArgs::default()
+        .function(FunctionObject {
+            name: name.to_string(),
+            description: Some("Give the final {answer} to the user once the task is done".into()),
+            parameters: Some(variable_schema_gen(
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "answer": {
+                            "type": "string",
+                            "description": "The final answer to the user",
+                        },
+                    },
+                    "required": ["answer"],
+                    "additionalProperties": false,
+                }),
+                vec![],
+            )),
+            strict: Some(true),
+        })
+        .r#type(ChatCompletionToolType::Function)
+        .build()
+        .expect("Invalid finish tool")
+}
+
+// Check the `required` properties and the `type` of the properties of `value` against `schema`.
+// Nested schemas are not checked, which is as far as final answers usually go.
+fn matches_schema(value: &Value, schema: &Value) -> Result<(), String> {
+    let Value::Object(object) = value else {
+        return Err("the answer is not a JSON object".to_string());
+    };
+    for field in schema["required"].as_array().into_iter().flatten() {
+        let field = field.as_str().unwrap_or_default();
+        if !object.contains_key(field) {
+            return Err(format!("`{field}` is missing"));
+        }
+    }
+    for (field, property) in schema["properties"].as_object().into_iter().flatten() {
+        let (Some(value), Some(kind)) = (object.get(field), property["type"].as_str()) else {
+            continue;
+        };
+        let matches = match kind {
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!("`{field}` must be of type {kind}"));
+        }
+    }
+    Ok(())
+}
+
+/// Planner which only lets the `inner` planner finish once the final answer meets the `criteria`
+pub struct FinishingPlanner<P> {
+    inner: P,
+    criteria: FinishCriteria,
+    // Tools of the latest query, offered again when the model is asked to carry on
+    tools: Arc<Vec<ChatCompletionTool>>,
+}
+
+impl<P> FinishingPlanner<P> {
+    pub fn new(inner: P, criteria: FinishCriteria) -> Self {
+        Self {
+            inner,
+            criteria,
+            tools: Arc::default(),
+        }
+    }
+
+    pub fn criteria(&self) -> &FinishCriteria {
+        &self.criteria
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<M, P> Plan<State, M> for FinishingPlanner<P>
+where
+    P: Plan<State, M>,
+    P::Action: AsAction,
+{
+    type Action = P::Action;
+    type Error = P::Error;
+
+    fn plan(&mut self, state: State, message: M) -> Result<(State, Self::Action), Self::Error> {
+        let (mut state, mut action) = self.inner.plan(state, message)?;
+        let finish_tool_name = match &self.criteria {
+            FinishCriteria::FinishTool(name) => Some(name.as_str()),
+            _ => None,
+        };
+        let replacement = match action.action_mut() {
+            Action::Query(_, tools) => {
+                if let Some(name) = finish_tool_name
+                    && !tools.iter().any(|tool| tool.function.name == name)
+                {
+                    Arc::make_mut(tools).push(finish_tool(name));
+                }
+                self.tools = tools.clone();
+                None
+            }
+            // Calling the finish tool is the only way to finish, with the answer it was given
+            Action::MakeCall(function, args, _) if Some(function.name()) == finish_tool_name => {
+                let answer = serde_json::from_str::<Value>(args.value())
+                    .ok()
+                    .and_then(|args| args["answer"].as_str().map(str::to_string))
+                    .unwrap_or_else(|| args.value().to_string());
+                Some(Action::Finish(answer))
+            }
+            // An answer which does not meet the criteria is interim commentary, so the model is
+            // told what is missing and queried again
+            Action::Finish(answer) => match self.criteria.check(&state, answer) {
+                Ok(()) => None,
+                Err(missing) => {
+                    if let Ok(message) = ChatCompletionRequestUserMessageArgs::default()
+                        .content(missing)
+                        .build()
+                    {
+                        state.push(message.into());
+                    }
+                    Some(Action::Query(state.clone(), self.tools.clone()))
+                }
+            },
+            _ => None,
+        };
+        if let Some(replacement) = replacement {
+            *action.action_mut() = replacement;
+        }
+        Ok((state, action))
+    }
+
+    fn memory(&self) -> Option<serde_json::Value> {
+        self.inner.memory()
+    }
+
+    fn restore_memory(&mut self, memory: serde_json::Value) -> Result<(), serde_json::Error> {
+        self.inner.restore_memory(memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BasicPlanner, ConversationHistory, Datastore, Message, PlanningLoop, mock::MockLlm,
+        openai::LlmClient,
+    };
+    use async_openai::types::{ChatCompletionRequestUserMessageContent, Role};
+
+    #[tokio::test]
+    async fn interim_commentary_does_not_finish_the_run() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_text("Let me think about it first."),
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "finish",
+                json!({ "answer": { "kind": "value", "value": "All done." } }),
+            ),
+        ]));
+        let planner = FinishingPlanner::new(
+            BasicPlanner::new(vec![]),
+            FinishCriteria::FinishTool("finish".to_string()),
+        );
+        let mut planning_loop = PlanningLoop::new(planner, model, vec![]);
+
+        let mut request = MockLlm::assistant_text("Tidy up my inbox");
+        request.role = Role::User;
+        let answer = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "All done.");
+
+        // The model was told to call the finish tool after its commentary
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        assert_eq!(requests.len(), 2);
+        let ChatCompletionRequestMessage::User(nudge) = requests[1].last().unwrap() else {
+            panic!("Expected the model to be told what is missing");
+        };
+        let ChatCompletionRequestUserMessageContent::Text(nudge) = &nudge.content else {
+            panic!("Expected a text nudge");
+        };
+        assert!(nudge.contains("`finish`"));
+    }
+
+    #[test]
+    fn structured_answers_are_checked_against_the_schema() {
+        let criteria = FinishCriteria::Schema(json!({
+            "type": "object",
+            "properties": {
+                "summary": { "type": "string" },
+                "emails": { "type": "integer" },
+            },
+            "required": ["summary", "emails"],
+        }));
+        let state = ConversationHistory::new(vec![]);
+        assert!(criteria.check(&state, "Here is your summary").is_err());
+        assert!(
+            criteria
+                .check(&state, r#"{"summary": "Quiet day"}"#)
+                .is_err()
+        );
+        assert!(
+            criteria
+                .check(&state, r#"{"summary": "Quiet day", "emails": "two"}"#)
+                .is_err()
+        );
+        assert!(
+            criteria
+                .check(&state, r#"{"summary": "Quiet day", "emails": 2}"#)
+                .is_ok()
+        );
+        assert!(FinishCriteria::SubGoals(1).check(&state, "Done").is_err());
+    }
+}
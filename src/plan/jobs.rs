@@ -0,0 +1,554 @@
+//! Running planning loops as jobs, for services which take requests faster than a single loop can
+//! answer them. The [`JobQueue`] hands each submitted task to the next idle loop of its pool and
+//! keeps everything a service needs to report on it: the status of the job, the steps taken so
+//! far and, once done, its [`RunResult`].
+//!
+//! Jobs can be cancelled one by one. Shutting the queue down cancels all of them and returns a
+//! [`Checkpoint`] for each job which did not get to finish, such that it can be resumed later from
+//! where its loop stopped. Finished jobs are kept for their results to be fetched, up to a
+//! [retention] after which the oldest are forgotten.
+//!
+//! [retention]: JobQueue::with_retention
+use super::{
+    Plan,
+    checkpoint::LoopCheckpoint,
+    plan_loop::PlanningLoop,
+    sink::{Overflow, SinkError, TraceEntry, TraceSink, TraceStream},
+};
+use crate::{Action, Datastore, Function, Message, State};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+use tokio::{
+    sync::{Mutex as AsyncMutex, broadcast, mpsc, oneshot, watch},
+    task::{self, JoinHandle},
+};
+
+// Steps of a job which are buffered for subscribers lagging behind
+const EVENT_CAPACITY: usize = 64;
+// Finished jobs kept by default, before the oldest are forgotten
+const RETENTION: usize = 1024;
+
+/// Identifier of a job submitted to a [`JobQueue`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobId(usize);
+
+/// Where a job is in its life
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job is over, one way or another
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, Self::Queued | Self::Running)
+    }
+}
+
+/// Outcome of a job which ran to completion
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    pub answer: String,
+    // Every action taken by the loop during the run
+    pub steps: Vec<TraceEntry>,
+}
+
+/// Job which did not get to finish before the queue was shut down, along with the steps taken so
+/// far. Jobs which got to run are resumed from where their loop stopped, the others from their
+/// task.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub id: JobId,
+    pub state: State,
+    pub message: Message,
+    pub steps: Vec<TraceEntry>,
+    // State of the loop before the step it was cancelled in, if the job got to run
+    pub checkpoint: Option<LoopCheckpoint>,
+}
+
+// Everything known about one job
+struct JobRecord {
+    state: State,
+    message: Message,
+    // Where the loop running the job resumes from, if it ran before
+    checkpoint: Option<LoopCheckpoint>,
+    status: watch::Sender<JobStatus>,
+    steps: Vec<TraceEntry>,
+    events: broadcast::Sender<TraceEntry>,
+    result: Option<RunResult>,
+    // Stops the job while it is running
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+type Jobs = Arc<Mutex<HashMap<JobId, JobRecord>>>;
+
+fn lock(jobs: &Jobs) -> std::sync::MutexGuard<'_, HashMap<JobId, JobRecord>> {
+    jobs.lock().expect("JobQueue lock poisoned")
+}
+
+// Sink recording the steps of one job and passing them on to its subscribers
+struct JobSink {
+    id: JobId,
+    jobs: Jobs,
+}
+
+impl TraceSink for JobSink {
+    async fn write(&mut self, entry: &TraceEntry) -> Result<(), SinkError> {
+        if let Some(record) = lock(&self.jobs).get_mut(&self.id) {
+            record.steps.push(entry.clone());
+            // Nobody listening is not an error
+            let _ = record.events.send(entry.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Queue of planning jobs run by a pool of planning loops
+pub struct JobQueue {
+    jobs: Jobs,
+    sender: mpsc::UnboundedSender<JobId>,
+    next_id: AtomicUsize,
+    shutting_down: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+    // Finished jobs kept before the oldest are forgotten
+    retention: usize,
+}
+
+impl JobQueue {
+    /// Start running the jobs submitted to the queue on the given `loops`, one job per loop at a
+    /// time. The loops are not `Send`, so this must be called from within a [`LocalSet`].
+    ///
+    /// [`LocalSet`]: tokio::task::LocalSet
+    pub fn new<P>(loops: Vec<PlanningLoop<State, Message, Function, P>>) -> Self
+    where
+        P: Plan<State, Message, Action = Action> + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let receiver = Arc::new(AsyncMutex::new(receiver));
+        let jobs = Jobs::default();
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let workers = loops
+            .into_iter()
+            .map(|planning_loop| {
+                task::spawn_local(work(
+                    planning_loop,
+                    receiver.clone(),
+                    jobs.clone(),
+                    shutting_down.clone(),
+                ))
+            })
+            .collect();
+        Self {
+            jobs,
+            sender,
+            next_id: AtomicUsize::new(0),
+            shutting_down,
+            workers,
+            retention: RETENTION,
+        }
+    }
+
+    /// Keep the outcome of at most `retention` finished jobs, forgetting the oldest ones as new
+    /// jobs are submitted. Defaults to 1024.
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Queue the task of answering `message` in the conversation `state`
+    pub fn submit(&self, state: State, message: Message) -> JobId {
+        self.enqueue(state, message, vec![], None)
+    }
+
+    // Queue a job carrying on from the `steps` taken so far and the `checkpoint` of its loop
+    fn enqueue(
+        &self,
+        state: State,
+        message: Message,
+        steps: Vec<TraceEntry>,
+        checkpoint: Option<LoopCheckpoint>,
+    ) -> JobId {
+        let id = JobId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut jobs = lock(&self.jobs);
+        evict(&mut jobs, self.retention);
+        jobs.insert(
+            id,
+            JobRecord {
+                state,
+                message,
+                checkpoint,
+                status: watch::Sender::new(JobStatus::Queued),
+                steps,
+                events: broadcast::Sender::new(EVENT_CAPACITY),
+                result: None,
+                cancel: None,
+            },
+        );
+        drop(jobs);
+        // The workers only stop once the queue is dropped, so this cannot fail
+        let _ = self.sender.send(id);
+        id
+    }
+
+    /// Resubmit the jobs of the `checkpoints`, returning their new ids in order. Jobs carry on
+    /// from where their loop stopped, with the steps they took before.
+    pub fn resume(&self, checkpoints: Vec<Checkpoint>) -> Vec<JobId> {
+        checkpoints
+            .into_iter()
+            .map(|checkpoint| {
+                let mut steps = checkpoint.steps;
+                // The step the job was cancelled in is taken again
+                if let Some(checkpoint) = &checkpoint.checkpoint {
+                    steps.truncate(checkpoint.step());
+                }
+                self.enqueue(
+                    checkpoint.state,
+                    checkpoint.message,
+                    steps,
+                    checkpoint.checkpoint,
+                )
+            })
+            .collect()
+    }
+
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        lock(&self.jobs)
+            .get(&id)
+            .map(|record| record.status.borrow().clone())
+    }
+
+    /// Wait for the job to be over, returning how it ended
+    pub async fn wait(&self, id: JobId) -> Option<JobStatus> {
+        let mut status = lock(&self.jobs).get(&id)?.status.subscribe();
+        status
+            .wait_for(JobStatus::is_terminal)
+            .await
+            .ok()
+            .map(|status| status.clone())
+    }
+
+    /// Steps taken by the job so far
+    pub fn steps(&self, id: JobId) -> Option<Vec<TraceEntry>> {
+        lock(&self.jobs).get(&id).map(|record| record.steps.clone())
+    }
+
+    /// Receive the steps of the job as they are taken from now on
+    pub fn subscribe(&self, id: JobId) -> Option<broadcast::Receiver<TraceEntry>> {
+        lock(&self.jobs)
+            .get(&id)
+            .map(|record| record.events.subscribe())
+    }
+
+    /// Outcome of the job, once it ran to completion
+    pub fn result(&self, id: JobId) -> Option<RunResult> {
+        lock(&self.jobs)
+            .get(&id)
+            .and_then(|record| record.result.clone())
+    }
+
+    /// Cancel the job, whether it is queued or running. Returns false if the job is unknown or
+    /// already over.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let mut jobs = lock(&self.jobs);
+        let Some(record) = jobs.get_mut(&id) else {
+            return false;
+        };
+        if record.status.borrow().is_terminal() {
+            return false;
+        }
+        match record.cancel.take() {
+            // The worker running the job marks it as cancelled once it stopped
+            Some(cancel) => {
+                let _ = cancel.send(());
+            }
+            None => {
+                record.status.send_replace(JobStatus::Cancelled);
+            }
+        }
+        true
+    }
+
+    /// Stop running jobs, cancelling the ones in progress, and return a checkpoint for each job
+    /// which did not get to finish
+    pub async fn shutdown(self) -> Vec<Checkpoint> {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        let mut unfinished: Vec<_> = lock(&self.jobs)
+            .iter()
+            .filter(|(_, record)| !record.status.borrow().is_terminal())
+            .map(|(id, _)| *id)
+            .collect();
+        unfinished.sort_by_key(|id| id.0);
+        for id in unfinished.iter() {
+            self.cancel(*id);
+        }
+        drop(self.sender);
+        for worker in self.workers {
+            let _ = worker.await;
+        }
+
+        let jobs = lock(&self.jobs);
+        unfinished
+            .into_iter()
+            .filter_map(|id| {
+                let record = jobs.get(&id)?;
+                Some(Checkpoint {
+                    id,
+                    state: record.state.clone(),
+                    message: record.message.clone(),
+                    steps: record.steps.clone(),
+                    checkpoint: record.checkpoint.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Forget the oldest finished jobs of `jobs` until at most `retention` of them are left
+fn evict(jobs: &mut HashMap<JobId, JobRecord>, retention: usize) {
+    let mut finished: Vec<_> = jobs
+        .iter()
+        .filter(|(_, record)| record.status.borrow().is_terminal())
+        .map(|(id, _)| *id)
+        .collect();
+    if finished.len() <= retention {
+        return;
+    }
+    finished.sort_by_key(|id| id.0);
+    for id in &finished[..finished.len() - retention] {
+        jobs.remove(id);
+    }
+}
+
+// Run the jobs taken from `receiver` on `planning_loop`, one at a time, until the queue shuts down
+async fn work<P>(
+    mut planning_loop: PlanningLoop<State, Message, Function, P>,
+    receiver: Arc<AsyncMutex<mpsc::UnboundedReceiver<JobId>>>,
+    jobs: Jobs,
+    shutting_down: Arc<AtomicBool>,
+) where
+    P: Plan<State, Message, Action = Action> + 'static,
+{
+    loop {
+        let Some(id) = receiver.lock().await.recv().await else {
+            return;
+        };
+        if shutting_down.load(Ordering::Relaxed) {
+            return;
+        }
+        let (cancel, cancelled) = oneshot::channel();
+        let (state, message, checkpoint) = {
+            let mut jobs = lock(&jobs);
+            let Some(record) = jobs.get_mut(&id) else {
+                continue;
+            };
+            // Jobs cancelled while queued are skipped
+            if *record.status.borrow() != JobStatus::Queued {
+                continue;
+            }
+            record.cancel = Some(cancel);
+            record.status.send_replace(JobStatus::Running);
+            (
+                record.state.clone(),
+                record.message.clone(),
+                record.checkpoint.take(),
+            )
+        };
+
+        planning_loop.trace_stream = Some(TraceStream::new(
+            JobSink {
+                id,
+                jobs: jobs.clone(),
+            },
+            EVENT_CAPACITY,
+            Overflow::Block,
+        ));
+        // The loop keeps its state before every step, for the job to resume from if cancelled
+        planning_loop.kept_checkpoint = Some(Default::default());
+        let mut datastore = Datastore::default();
+        let run = async {
+            match checkpoint {
+                Some(checkpoint) => planning_loop.resume(checkpoint, &mut datastore).await,
+                None => planning_loop.run(state, &mut datastore, message).await,
+            }
+        };
+        let outcome = tokio::select! {
+            answer = run => Some(answer),
+            _ = cancelled => None,
+        };
+        let kept = planning_loop
+            .kept_checkpoint
+            .take()
+            .and_then(|kept| kept.into_inner().ok().flatten());
+        // Wait for the steps to be recorded before reporting the job as over
+        if let Some(stream) = planning_loop.take_trace_stream() {
+            stream.close().await;
+        }
+
+        let mut jobs = lock(&jobs);
+        let Some(record) = jobs.get_mut(&id) else {
+            continue;
+        };
+        record.cancel = None;
+        let status = match outcome {
+            Some(Ok(answer)) => {
+                record.result = Some(RunResult {
+                    answer,
+                    steps: record.steps.clone(),
+                });
+                JobStatus::Done
+            }
+            Some(Err(err)) => JobStatus::Failed(format!("{err:?}")),
+            None => {
+                record.checkpoint = kept;
+                JobStatus::Cancelled
+            }
+        };
+        record.status.send_replace(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicPlanner, ConversationHistory, mock::MockLlm, openai::LlmClient};
+    use async_openai::types::Role;
+    use tokio::task::LocalSet;
+
+    fn planning_loop(answers: &[&str]) -> PlanningLoop<State, Message, Function, BasicPlanner> {
+        let transcript = answers
+            .iter()
+            .map(|answer| MockLlm::assistant_text(answer))
+            .collect();
+        PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            LlmClient::mock(MockLlm::new(transcript)),
+            vec![],
+        )
+    }
+
+    fn request(text: &str) -> Message {
+        let mut request = MockLlm::assistant_text(text);
+        request.role = Role::User;
+        Message::Chat(request)
+    }
+
+    #[tokio::test]
+    async fn jobs_run_to_completion() {
+        LocalSet::new()
+            .run_until(async {
+                let queue =
+                    JobQueue::new(vec![planning_loop(&["You have 2 new emails.", "Sent."])]);
+                let first =
+                    queue.submit(ConversationHistory::default(), request("Any new emails?"));
+                let second =
+                    queue.submit(ConversationHistory::default(), request("Send the summary."));
+                // Cancelled before the only loop of the pool gets to it
+                let third = queue.submit(ConversationHistory::default(), request("Archive them."));
+                assert!(queue.cancel(third));
+                assert_eq!(queue.status(first), Some(JobStatus::Queued));
+
+                assert_eq!(queue.wait(first).await, Some(JobStatus::Done));
+                assert_eq!(queue.wait(second).await, Some(JobStatus::Done));
+                assert_eq!(queue.status(third), Some(JobStatus::Cancelled));
+                assert!(!queue.cancel(first));
+
+                let result = queue.result(first).expect("Missing result");
+                assert_eq!(result.answer, "You have 2 new emails.");
+                // The user's request is answered by querying the model, which gives the final answer
+                assert_eq!(result.steps.len(), 2);
+                assert_eq!(queue.result(second).unwrap().answer, "Sent.");
+                assert!(queue.result(third).is_none());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn unfinished_jobs_are_checkpointed_on_shutdown() {
+        LocalSet::new()
+            .run_until(async {
+                let queue = JobQueue::new(vec![planning_loop(&["Done."])]);
+                let id = queue.submit(ConversationHistory::default(), request("Tidy up my inbox."));
+                let checkpoints = queue.shutdown().await;
+                assert_eq!(checkpoints.len(), 1);
+                assert_eq!(checkpoints[0].id, id);
+
+                let queue = JobQueue::new(vec![planning_loop(&["Done."])]);
+                let ids = queue.resume(checkpoints);
+                assert_eq!(queue.wait(ids[0]).await, Some(JobStatus::Done));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn cancelled_jobs_resume_where_their_loop_stopped() {
+        LocalSet::new()
+            .run_until(async {
+                // The model keeps reading emails, such that the job is still running on shutdown
+                let transcript = (0..20)
+                    .map(|idx| {
+                        MockLlm::assistant_tool_call(
+                            &format!("call_{idx}"),
+                            "read_emails",
+                            serde_json::json!({ "count": { "kind": "value", "value": "1" } }),
+                        )
+                    })
+                    .collect();
+                let reading = |transcript| {
+                    PlanningLoop::new(
+                        BasicPlanner::new(vec![]),
+                        LlmClient::mock(MockLlm::new(transcript)),
+                        vec![Function::new("read_emails".to_string())],
+                    )
+                };
+                let queue = JobQueue::new(vec![reading(transcript)]);
+                let id = queue.submit(ConversationHistory::default(), request("Read my emails."));
+                let mut events = queue.subscribe(id).unwrap();
+                while events.recv().await.unwrap().step() < 2 {}
+                let checkpoints = queue.shutdown().await;
+                let checkpoint = checkpoints[0]
+                    .checkpoint
+                    .clone()
+                    .expect("Missing checkpoint");
+                assert!(checkpoint.step() >= 2);
+
+                let queue = JobQueue::new(vec![reading(vec![MockLlm::assistant_text(
+                    "You have 1 new email.",
+                )])]);
+                let ids = queue.resume(checkpoints);
+                assert_eq!(queue.wait(ids[0]).await, Some(JobStatus::Done));
+                let result = queue.result(ids[0]).unwrap();
+                assert_eq!(result.answer, "You have 1 new email.");
+                // The steps taken before the shutdown are not taken again
+                let steps: Vec<_> = result.steps.iter().map(TraceEntry::step).collect();
+                assert_eq!(steps, (0..steps.len()).collect::<Vec<_>>());
+                assert!(steps.len() > checkpoint.step());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn oldest_finished_jobs_are_forgotten() {
+        LocalSet::new()
+            .run_until(async {
+                let queue = JobQueue::new(vec![planning_loop(&["One.", "Two."])]).with_retention(1);
+                let first = queue.submit(ConversationHistory::default(), request("First?"));
+                queue.wait(first).await;
+                let second = queue.submit(ConversationHistory::default(), request("Second?"));
+                queue.wait(second).await;
+                // Submitting evicts the first job, while the second stays within the retention
+                queue.submit(ConversationHistory::default(), request("Third?"));
+                assert_eq!(queue.status(first), None);
+                assert_eq!(queue.result(second).unwrap().answer, "Two.");
+            })
+            .await;
+    }
+}
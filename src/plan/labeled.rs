@@ -1,16 +1,43 @@
+#[cfg(feature = "telemetry")]
+use crate::plan::sink::TraceEntry;
 use crate::{
-    Action, Args, Call, Datastore, Function, Integrity, Message, Plan, PlanningLoop,
-    ProductLattice, State,
-    function::MetaFunction,
-    ifc::{InverseLattice, Lattice, LatticeError, PowersetLattice},
-    plan::{PlanError, Policy},
-    tools::{EmailLabel, MetaValue},
+    Action, Args, Call, CustomOutcome, Datastore, Function, Image, Integrity, Label, Message, Plan,
+    PlanningLoop, ProductLattice, State, ToolError,
+    authority::Authority,
+    function::{LabelPropagation, MetaFunction},
+    ifc::{
+        DcLabel, InternedEmailLabel, InternedPowerset, InverseLattice, Lattice, LatticeError,
+        PowersetLattice, Universe,
+    },
+    injection::InjectionFlag,
+    message::result_messages,
+    plan::{
+        ClearanceViolation, PlanError, Policy,
+        approval::{ApprovalRequest, Decision, denied_message},
+        audit::{AuditLog, Outcome, PolicyDecision},
+        checkpoint::LoopCheckpoint,
+        observer::{Event, LabelCreep},
+        plan_loop::{check_budget, notify, refund, reserve, side_effect},
+        policy::{PolicyCheck, PolicyViolation, refusal_message},
+        recovery::{Recovery, skipped_message},
+        repair::{hidden_args, repair_request, repaired_call, rewrite_tool_call},
+        sanitize::sanitize,
+    },
+    quota::Quotas,
+    tokens::{TokenBudget, spent_tokens},
+    tools::{
+        EmailLabel, GetMessageStatusArgs, MetaValue, Variable, VariableMemory,
+        get_message_status_labeled,
+    },
+    validate::ValidationError,
 };
 use async_openai::types::{
     ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestToolMessageArgs,
     ChatCompletionRequestUserMessageArgs, ChatCompletionTool, FunctionCall, Role,
 };
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::{Map, Value};
+use std::sync::Arc;
 
 // Planners get instrumented with dynamic information-flow control via taint-tracking. For this,
 // labels are attached to messages, actions, tool arguments and results, and vairables in the
@@ -21,85 +48,693 @@ use serde_json::{Map, Value};
 // the planner propagates from messages to actions
 // and the planning loop propagates throughout its execution.
 //
-// We add a metadata field to label each node in the syntax tree of tools results.
-// When non-empty, such a label applied to all fields of that node and below.
+// We add a metadata field to label each node in the syntax tree of tools results, which is what
+// `LabeledValue` holds. When non-empty, such a label applied to all fields of that node and below.
 //
 // Also attach metadata field to label individual messages in the conversation history.
 // The initial system and user messages are typically considered trusted and public and by default.
 
 // A trace is a sequence of actions that the model takes starting from a user's Message::Query
 // and ending with an `Action::Finish`.
-pub struct Trace<L: Lattice>(Vec<MetaValue<Action, L>>);
+// The trace also keeps the audit log of the policy decisions taken on its actions.
+pub struct Trace<L: Lattice> {
+    actions: Vec<MetaValue<Action, L>>,
+    audit: AuditLog,
+    // Whether the run ended refusing an action the policy denied
+    refused: bool,
+}
 
 impl<L: Lattice> Trace<L> {
     pub fn into_inner(self) -> Vec<MetaValue<Action, L>> {
-        self.0
+        self.actions
     }
 
     pub fn value(&self) -> &[MetaValue<Action, L>] {
-        &self.0
+        &self.actions
     }
 
     pub fn value_mut(&mut self) -> &mut Vec<MetaValue<Action, L>> {
-        &mut self.0
+        &mut self.actions
+    }
+
+    /// The actions of the trace, without their labels
+    pub fn into_actions(self) -> Vec<Action> {
+        self.actions
+            .into_iter()
+            .map(|action| action.into_raw_parts().0)
+            .collect()
+    }
+
+    /// Policy decisions taken on the actions of the trace
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
     }
 }
 
 impl<L: Lattice> Default for Trace<L> {
     fn default() -> Self {
-        Self(vec![])
+        Self {
+            actions: vec![],
+            audit: AuditLog::default(),
+            refused: false,
+        }
     }
 }
 
 pub type ActionLabel = ProductLattice<Integrity, InverseLattice<PowersetLattice<String>>>;
 
-impl<P: Plan<State, MetaValue<Message, EmailLabel>, Action = (Action, ActionLabel)>>
-    PlanningLoop<State, MetaValue<Message, EmailLabel>, MetaFunction, P>
+/// Labels the taint-tracking loop and planner can run with. Besides joining labels, the loop only
+/// looks into them for the features built around the labels of the email tools, which are label
+/// creep warnings, the tool cache, integrity quorums and verified sends. These features are
+/// skipped for labels which have no [`EmailLabel`] counterpart.
+pub trait TaintLabel: Lattice + Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// The counterpart of the label among email labels, if any
+    fn to_email_label(&self) -> Option<EmailLabel> {
+        None
+    }
+
+    /// The counterpart of the email `label`, if any
+    fn from_email_label(_label: EmailLabel) -> Option<Self> {
+        None
+    }
+
+    /// Integrity and number of readers of the counterpart of the label among email labels, if
+    /// any, which labels can tell without being converted
+    fn integrity_and_readers(&self) -> Option<(Integrity, usize)> {
+        let label = self.to_email_label()?;
+        Some((
+            label.lattice1().clone(),
+            label.lattice2().inner().subset().len(),
+        ))
+    }
+}
+
+impl TaintLabel for EmailLabel {
+    fn to_email_label(&self) -> Option<EmailLabel> {
+        Some(self.clone())
+    }
+
+    fn from_email_label(label: EmailLabel) -> Option<Self> {
+        Some(label)
+    }
+
+    fn integrity_and_readers(&self) -> Option<(Integrity, usize)> {
+        Some((
+            self.lattice1().clone(),
+            self.lattice2().inner().subset().len(),
+        ))
+    }
+}
+
+impl TaintLabel for Integrity {}
+
+impl TaintLabel for Label {}
+
+impl TaintLabel for DcLabel {}
+
+// The readers are interned into the universe shared by the labels over the same addresses
+impl TaintLabel for InternedEmailLabel {
+    fn to_email_label(&self) -> Option<EmailLabel> {
+        Some(EmailLabel::new(
+            self.lattice1().clone(),
+            InverseLattice::new(self.lattice2().inner().to_powerset()),
+        ))
+    }
+
+    fn from_email_label(label: EmailLabel) -> Option<Self> {
+        let readers = label.lattice2().inner();
+        let universe = Universe::shared(readers.universe());
+        Some(Self::new(
+            label.lattice1().clone(),
+            InverseLattice::new(InternedPowerset::intern(readers, &universe).ok()?),
+        ))
+    }
+
+    fn integrity_and_readers(&self) -> Option<(Integrity, usize)> {
+        Some((self.lattice1().clone(), self.lattice2().inner().len()))
+    }
+}
+
+/// Tool the taint-tracking loop can call, which returns its result along with the label `L` of the
+/// result
+pub trait LabeledTool<L>: Call<Args = Args, Output = (String, L)> {
+    fn name(&self) -> &str;
+
+    /// Call the tool with the `authority` of the loop. Tools labeling their results regardless of
+    /// any authority just call themselves.
+    fn call_with_authority(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        _authority: &Authority,
+    ) -> impl Future<Output = Result<(String, L), ToolError>> + Send {
+        self.call(args, datastore)
+    }
+
+    /// Call the tool with the `authority` of the loop, given the label of the `inputs` of the call.
+    /// Tools which do not pass the label of their inputs along just call themselves with the
+    /// authority.
+    fn call_with_inputs(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        authority: &Authority,
+        _inputs: &L,
+    ) -> impl Future<Output = Result<(String, L), ToolError>> + Send {
+        self.call_with_authority(args, datastore, authority)
+    }
+
+    /// Label of a result the tool labeled `output`, given the label of the `inputs` of the call and
+    /// the `authority` of the loop. Tools keep the labels they give their results unless they say otherwise.
+    fn propagate(&self, _inputs: &L, output: L, _authority: &Authority) -> Option<L> {
+        Some(output)
+    }
+}
+
+impl LabeledTool<EmailLabel> for MetaFunction {
+    fn name(&self) -> &str {
+        self.name()
+    }
+
+    async fn call_with_authority(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        authority: &Authority,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        MetaFunction::call_with_authority(self, args, datastore, authority).await
+    }
+
+    async fn call_with_inputs(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        authority: &Authority,
+        inputs: &EmailLabel,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        MetaFunction::call_with_inputs(self, args, datastore, authority, inputs).await
+    }
+
+    fn propagate(
+        &self,
+        inputs: &EmailLabel,
+        output: EmailLabel,
+        authority: &Authority,
+    ) -> Option<EmailLabel> {
+        let authority = self.authority().unwrap_or(authority);
+        self.label_propagation()
+            .propagate(inputs, output, authority)
+    }
+}
+
+/// Function labeling its results with [`InternedEmailLabel`]s, such that the email tools can be
+/// called by loops running with interned labels. The label of each result is interned once, into
+/// the universe shared by the labels over the same addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InternedFunction(MetaFunction);
+
+impl InternedFunction {
+    pub fn new(function: MetaFunction) -> Self {
+        Self(function)
+    }
+
+    pub fn function(&self) -> &MetaFunction {
+        &self.0
+    }
+}
+
+// Label of a result of the email tools, interned
+fn intern(label: EmailLabel) -> Result<InternedEmailLabel, ToolError> {
+    Ok(InternedEmailLabel::from_email_label(label).ok_or(LatticeError::SubsetNotInUniverse)?)
+}
+
+impl Call for InternedFunction {
+    type Args = Args;
+    type Output = (String, InternedEmailLabel);
+
+    fn validate(&self, args: &Args) -> Result<(), ValidationError> {
+        self.0.validate(args)
+    }
+
+    async fn call(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+    ) -> Result<(String, InternedEmailLabel), ToolError> {
+        let (result, label) = self.0.call(args, datastore).await?;
+        Ok((result, intern(label)?))
+    }
+}
+
+impl LabeledTool<InternedEmailLabel> for InternedFunction {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    async fn call_with_authority(
+        &self,
+        args: Args,
+        datastore: &mut Datastore,
+        authority: &Authority,
+    ) -> Result<(String, InternedEmailLabel), ToolError> {
+        let (result, label) = self
+            .0
+            .call_with_authority(args, datastore, authority)
+            .await?;
+        Ok((result, intern(label)?))
+    }
+
+    // Tools keeping their own labels, as most do, have nothing to convert
+    fn propagate(
+        &self,
+        inputs: &InternedEmailLabel,
+        output: InternedEmailLabel,
+        authority: &Authority,
+    ) -> Option<InternedEmailLabel> {
+        if let LabelPropagation::Tool = self.0.label_propagation() {
+            return Some(output);
+        }
+        let label = LabeledTool::propagate(
+            &self.0,
+            &inputs.to_email_label()?,
+            output.to_email_label()?,
+            authority,
+        )?;
+        InternedEmailLabel::from_email_label(label)
+    }
+}
+
+// The `label` made untrusted, keeping its readers. Labels without an email counterpart are kept.
+fn distrust<L: TaintLabel>(label: L) -> L {
+    match label.to_email_label() {
+        Some(email_label) => L::from_email_label(EmailLabel::new(
+            Integrity::untrusted(),
+            email_label.lattice2().clone(),
+        ))
+        .unwrap_or(label),
+        None => label,
+    }
+}
+
+// Tool result telling the model that the untrusted result of the tool called `name` was
+// quarantined under `handle`
+fn quarantined_message(name: &str, handle: &str) -> String {
+    format!(
+        "The result of `{name}` is untrusted, so it was quarantined as `{handle}`. Pass the handle \
+        to `summarize_labeled` to learn about it."
+    )
+}
+
+// What the policy is shown besides the trace: the state of the planner and the datastore of the run
+#[derive(Clone, Copy)]
+struct CheckContext<'a> {
+    state: &'a State,
+    datastore: &'a Datastore,
+}
+
+// Check the last action of the `trace` against the `policy` and record the decision in the audit
+// log of the trace, returning the violation, if any, along with the events reporting the outcome
+async fn check_policy<L: TaintLabel, C: PolicyCheck<L>>(
+    policy: &mut C,
+    trace: &mut Trace<L>,
+    context: CheckContext<'_>,
+) -> (Option<PolicyViolation>, Vec<Event>) {
+    let policy_violation = policy.check(trace, context.state, context.datastore).await;
+    let shadow_violation = policy.shadow(trace, context.state, context.datastore).await;
+    let step = trace.value().len() - 1;
+    let (action, label) = trace.value()[step].raw_parts();
+    let decision = |policy: Option<&str>, outcome| PolicyDecision {
+        step,
+        policy: policy.map(str::to_string),
+        action: action.clone(),
+        label: label.to_email_label(),
+        outcome,
+    };
+    let decisions = [
+        Some(decision(policy.name(), policy_violation.as_ref().into())),
+        shadow_violation
+            .as_ref()
+            .map(|violation| decision(policy.shadow_name(), Outcome::shadowed(violation))),
+    ];
+    for decision in decisions.into_iter().flatten() {
+        trace.audit.record(decision);
+    }
+    // Would-be violations are only reported, and never block the action
+    let shadowed = shadow_violation
+        .map(|violation| Event::ShadowViolation(step, violation.explanation().to_string()));
+    let checked = Event::PolicyChecked(
+        step,
+        policy_violation
+            .as_ref()
+            .map(|violation| violation.explanation().to_string()),
+    );
+    (
+        policy_violation,
+        shadowed.into_iter().chain([checked]).collect(),
+    )
+}
+
+// Returns true if the `label` is untrusted and can be read by at most `readers` readers, which is
+// as restrictive as labels get in practice.
+fn is_crept((integrity, count): (Integrity, usize), readers: usize) -> bool {
+    integrity == Integrity::Untrusted && count <= readers
+}
+
+impl<L: TaintLabel, F: LabeledTool<L>>
+    PlanningLoop<State, MetaValue<Message, L>, F, TaintTrackingPlanner<L>>
+{
+    /// Run the loop like [`run_with_policy`], with the policy the planner checks its actions
+    /// against, such that the loop checks them a second time. Fails with `PlanError::CannotPlan`
+    /// if the planner has no policy.
+    ///
+    /// [`run_with_policy`]: PlanningLoop::run_with_policy
+    pub async fn run_with_planner_policy(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: MetaValue<Message, L>,
+    ) -> Result<String, PlanError> {
+        let policy = self
+            .planner
+            .policy()
+            .cloned()
+            .ok_or_else(|| PlanError::CannotPlan("The planner has no policy".to_string()))?;
+        self.run_with_policy(state, datastore, message, policy)
+            .await
+    }
+}
+
+impl<L, F, P> PlanningLoop<State, MetaValue<Message, L>, F, P>
+where
+    L: TaintLabel,
+    F: LabeledTool<L>,
+    P: Plan<State, MetaValue<Message, L>, Action = (Action, L)>,
 {
     // At each iteration of the loop, the current `state`, the latest `message` of the conversation
-    // and the `datastore` are passed.
-    pub async fn run_with_policy(
+    // and the `datastore` are passed. The `policy` is either a plain `Policy` or any other
+    // `PolicyCheck`, which may wait on other services and keep state along the run.
+    pub async fn run_with_policy<C: PolicyCheck<L>>(
         &mut self,
         state: State,
         datastore: &mut Datastore,
-        message: MetaValue<Message, EmailLabel>,
-        policy: Policy,
+        message: MetaValue<Message, L>,
+        policy: C,
+    ) -> Result<String, PlanError> {
+        self.run_labeled_with_policy(state, datastore, message, policy)
+            .await
+            .map(|answer| answer.into_raw_parts().0)
+    }
+
+    /// Run the loop like [`run_with_policy`], returning the answer along with its label, such
+    /// that it can be handed over to whoever is allowed to read it
+    ///
+    /// [`run_with_policy`]: PlanningLoop::run_with_policy
+    pub async fn run_labeled_with_policy<C: PolicyCheck<L>>(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: MetaValue<Message, L>,
+        policy: C,
+    ) -> Result<MetaValue<String, L>, PlanError> {
+        // Create a new trace of actions, handed back to the caller if the run is cut short
+        self.run_with_policy_from(state, datastore, message, policy, Trace::default())
+            .await
+    }
+
+    /// Run the loop like [`run_with_policy`] on the `query` of the user, labeled with `label`,
+    /// which the planner starts by asking the model
+    ///
+    /// [`run_with_policy`]: PlanningLoop::run_with_policy
+    pub async fn run_from_user_with_policy<C: PolicyCheck<L>>(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        query: &str,
+        label: L,
+        policy: C,
+    ) -> Result<String, PlanError> {
+        let message = MetaValue::new(Message::user(query), label);
+        self.run_with_policy(state, datastore, message, policy)
+            .await
+    }
+
+    /// Resume the run saved in the `checkpoint` where it left off, like [`resume`], checking it
+    /// against the `policy` with the labels it had. Fails with `PlanError::CheckpointError` if
+    /// the checkpoint was taken by a run which was not checked against a policy.
+    ///
+    /// [`resume`]: PlanningLoop::resume
+    pub async fn resume_with_policy<C: PolicyCheck<L>>(
+        &mut self,
+        checkpoint: LoopCheckpoint,
+        datastore: &mut Datastore,
+        policy: C,
+    ) -> Result<String, PlanError> {
+        let (state, message, memory, trace) = checkpoint.into_labeled_run().ok_or_else(|| {
+            PlanError::CheckpointError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The checkpoint has no labels to check the policy with",
+            ))
+        })?;
+        if let Some(memory) = memory {
+            self.planner.restore_memory(memory)?;
+        }
+        self.run_with_policy_from(state, datastore, message, policy, trace)
+            .await
+            .map(|answer| answer.into_raw_parts().0)
+    }
+
+    // Run the loop on top of the actions of the `trace` taken so far
+    async fn run_with_policy_from<C: PolicyCheck<L>>(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: MetaValue<Message, L>,
+        policy: C,
+        mut trace: Trace<L>,
+    ) -> Result<MetaValue<String, L>, PlanError> {
+        let timeout = self.timeout;
+        let request_label = message.label().clone();
+        let snapshot = self.atomic.then(|| datastore.snapshot());
+        let steps = self.run_steps_with_policy(state, datastore, message, policy, &mut trace);
+        let answer = match timeout {
+            None => steps.await,
+            Some(limit) => match tokio::time::timeout(limit, steps).await {
+                Ok(answer) => answer,
+                Err(_) => Err(PlanError::Timeout {
+                    limit,
+                    trace: trace
+                        .value()
+                        .iter()
+                        .map(|action| action.value().clone())
+                        .collect(),
+                }),
+            },
+        };
+        // A refused run did not do what it was asked to either
+        if let Some(snapshot) = snapshot
+            && (answer.is_err() || trace.refused)
+        {
+            datastore.rollback(snapshot);
+        }
+        self.audit = std::mem::take(&mut trace.audit);
+        // The answer is labeled like the action which finished the run
+        let label = trace.value().last().map(|action| action.label().clone());
+        answer.map(|answer| MetaValue::new(answer, label.unwrap_or(request_label)))
+    }
+
+    async fn run_steps_with_policy<C: PolicyCheck<L>>(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: MetaValue<Message, L>,
+        mut policy: C,
+        trace: &mut Trace<L>,
     ) -> Result<String, PlanError> {
-        // Create a new trace of actions
-        let mut trace: Trace<ActionLabel> = Trace::default();
         let mut current_message = message;
         let mut current_state = state;
+        let mut budget = self.token_budget.map(TokenBudget::new);
+        let quotas = datastore.quotas().cloned();
+        // A resumed run carries on from the actions it took before
+        let start = trace.value().len();
         loop {
-            let action;
+            // Written before the limit is checked, such that a run cut short can be resumed
+            self.checkpoint(trace.value().len() == start, || {
+                LoopCheckpoint::labeled(
+                    &current_state,
+                    &current_message,
+                    self.planner.memory(),
+                    trace,
+                )
+            })
+            .await?;
+            self.check_iterations(trace.value().len(), || std::mem::take(trace).into_actions())
+                .await?;
+            let mut action;
             let action_label;
             (current_state, (action, action_label)) = self
                 .planner_mut()
                 .plan(current_state, current_message.clone())
                 .map_err(|e| PlanError::CannotPlan(format!("{:?}", e)))?;
+            // Decoys are called no matter what the policy says, as the call alone gives the
+            // attack away
+            let checked =
+                self.check_honeypot(trace.value().len(), &mut action, current_state.messages());
+            #[cfg(feature = "telemetry")]
+            if self.trace_stream.is_some() {
+                self.stream(TraceEntry::new(
+                    trace.value().len(),
+                    action.clone(),
+                    action_label.to_email_label(),
+                ))
+                .await;
+            }
+            checked?;
+            // Calls are sanitized before the policy looks at them, such that it checks the call
+            // which is made
+            if !self.sanitizers.is_empty()
+                && let Some(sanitized) = sanitize(
+                    &self.sanitizers,
+                    &action,
+                    action_label.to_email_label().as_ref(),
+                )
+            {
+                self.notify(Event::Sanitized(
+                    trace.value().len(),
+                    Box::new(sanitized.clone()),
+                ));
+                action = sanitized;
+            }
             trace
                 .value_mut()
                 .push(MetaValue::new(action.clone(), action_label));
 
-            if let Some(policy_violation) = policy.check(&trace) {
-                panic!("Policy Violation {:#?}", policy_violation);
+            // The policy rules on every action before it is taken, queries included, such that
+            // nothing reaches the model which the policy would not let out
+            let context = CheckContext {
+                state: &current_state,
+                datastore,
+            };
+            let (policy_violation, checked) = check_policy(&mut policy, trace, context).await;
+            // Allowed queries are reported while the model works on them, everything else before
+            // it is taken
+            let mut ruling = vec![];
+            match (&policy_violation, &action) {
+                (None, Action::Query(..)) => ruling = checked,
+                _ => checked.into_iter().for_each(|event| self.notify(event)),
+            }
+            // Whether the approval gate already reviewed the action while recovering from its denial
+            let mut reviewed = false;
+            // If the action violates the policy and cannot be repaired, we do not take it and
+            // instead finish the run with an answer explaining to the user why their request
+            // could not be completed.
+            if let Some(policy_violation) = policy_violation {
+                let context = CheckContext {
+                    state: &current_state,
+                    datastore,
+                };
+                let repaired = self
+                    .repair(
+                        (&mut policy, context),
+                        trace,
+                        &policy_violation,
+                        &mut budget,
+                        quotas.as_ref(),
+                    )
+                    .await?;
+                let recovered = match repaired {
+                    Some(repaired) => Recovered::Take(repaired),
+                    None => {
+                        self.recover((&mut policy, context), trace, &policy_violation)
+                            .await
+                    }
+                };
+                match recovered {
+                    // The conversation goes on with the call which is made
+                    Recovered::Take(recovered) => {
+                        rewrite_tool_call(current_state.messages_mut(), &action, &recovered);
+                        action = recovered;
+                    }
+                    Recovered::Reviewed(recovered) => {
+                        rewrite_tool_call(current_state.messages_mut(), &action, &recovered);
+                        action = recovered;
+                        reviewed = true;
+                    }
+                    // The denial only tells the model about its own call, so the label of the
+                    // conversation stays the same. The call was not made, so it is left out of
+                    // the trace.
+                    Recovered::Skip(message) => {
+                        trace.value_mut().pop();
+                        current_message = MetaValue::new(message, current_message.label().clone());
+                        continue;
+                    }
+                    Recovered::Refuse => {
+                        trace.refused = true;
+                        return Ok(refusal_message(&action, &policy_violation));
+                    }
+                }
             }
             match action {
-                Action::Query(conv_history, tools) => {
+                Action::Query(mut conv_history, tools) => {
+                    let prepared: Result<_, PlanError> = async {
+                        // Only the conversation the policy ruled on is compacted, summaries
+                        // included. Queries carry the state of the planner, which goes on with the
+                        // compacted conversation, labeled like the query as it joins the labels of
+                        // the turns it replaces.
+                        if let Some(compacted) = self
+                            .compact_context(trace.value().len() - 1, &conv_history, &tools)
+                            .await?
+                        {
+                            current_state = compacted.clone();
+                            conv_history = compacted;
+                        }
+                        let estimate = check_budget(
+                            budget.as_ref(),
+                            quotas.as_ref(),
+                            &self.model,
+                            conv_history.messages(),
+                            &tools,
+                        )?;
+                        self.reserve_query(quotas.as_ref(), estimate).await?;
+                        Ok(estimate)
+                    }
+                    .await;
+                    // The ruling is reported even if the query is not sent after all
+                    let estimate = match prepared {
+                        Ok(estimate) => estimate,
+                        Err(err) => {
+                            ruling.into_iter().for_each(|event| self.notify(event));
+                            return Err(err);
+                        }
+                    };
                     // When querying the model, this planning loop is responsible to propages the
                     // labels from the action to the model's response, signifying the inability to
                     // precisely propagate labels through LLMs.
-
-                    // Build a chat request with all the previous conversation history and the
-                    // available tools
-                    let chat_request = self.model().chat(conv_history.0, tools);
-                    // Send the request and save the first response choice as the new message,
-                    // while also maintaining the label associated with the current loop.
+                    // The query is sent first, such that the observers hear about the ruling on it
+                    // while it is in flight
+                    let observers = &mut self.observers;
+                    let (response, ()) = tokio::join!(
+                        self.model
+                            .chat(conv_history.into_inner(), Arc::unwrap_or_clone(tools)),
+                        async move {
+                            for event in ruling {
+                                notify(observers, event);
+                            }
+                        },
+                    );
+                    let response = self
+                        .charge_query(quotas.as_ref(), response, estimate)
+                        .await?;
+                    self.usage.record(response.usage.as_ref());
+                    if let Some(budget) = &mut budget {
+                        budget.spend(spent_tokens(&response, estimate));
+                    }
+                    // Save the first response choice as the new message, labeled like the query,
+                    // which carries the label of everything the model was shown.
                     // Note: The response from the LLM should also be checked for PII and policies
                     // associated with it.
-                    current_message = MetaValue::new(
-                        Message::Chat(chat_request.await?.choices[0].message.clone()),
-                        current_message.label().clone(),
-                    );
+                    let label = trace.value()[trace.value().len() - 1].label().clone();
+                    current_message =
+                        MetaValue::new(Message::Chat(response.choices[0].message.clone()), label);
                 }
                 Action::MakeCall(ref function, ref args, id) => {
                     // Before making the actual call, we check that the call satisfies the security
@@ -109,37 +744,617 @@ impl<P: Plan<State, MetaValue<Message, EmailLabel>, Action = (Action, ActionLabe
                         // Do not perform the action
                         continue;
                     }*/
-                    let (tool_result, label) = self
-                        .tools()
+                    // Calls to gated tools wait on their review, after the policy allowed them.
+                    // Modified calls are checked against the policy again, as the policy cannot
+                    // tell the user's changes apart from the model's.
+                    let step = trace.value().len() - 1;
+                    let decision = match reviewed {
+                        true => Decision::Approve,
+                        false => self.review(step, function.name(), args, &id).await,
+                    };
+                    let args = match decision {
+                        Decision::Approve => args.clone(),
+                        Decision::Modify(modified) => {
+                            let candidate =
+                                Action::MakeCall(function.clone(), modified.clone(), id.clone());
+                            let context = CheckContext {
+                                state: &current_state,
+                                datastore,
+                            };
+                            if let Some(violation) = self
+                                .check_variant((&mut policy, context), trace, candidate.clone())
+                                .await
+                            {
+                                trace.refused = true;
+                                return Ok(refusal_message(&candidate, &violation));
+                            }
+                            modified
+                        }
+                        // Denied calls are not made, so they are left out of the trace
+                        Decision::Deny(reason) => {
+                            trace.value_mut().pop();
+                            current_message = MetaValue::new(
+                                Message::ToolResult(denied_message(function.name(), &reason), id),
+                                current_message.label().clone(),
+                            );
+                            continue;
+                        }
+                    };
+                    let args = &args;
+                    let tool = self
+                        .tools
                         .iter()
                         .find(|&f| f.name() == function.name())
-                        .ok_or(PlanError::FunctionNotFound(function.name().to_string()))?
-                        .call(args.clone(), datastore);
+                        .ok_or(PlanError::FunctionNotFound(function.name().to_string()))?;
+                    // Arguments which do not make sense are sent back to the model to be fixed,
+                    // leaving the call, which is not made, out of the trace. The correction only
+                    // carries what the model already knew, so the label of the conversation stays
+                    // the same.
+                    if let Err(err) = tool.validate(args) {
+                        trace.value_mut().pop();
+                        current_message = MetaValue::new(
+                            Message::ToolResult(err.corrective_message(function.name()), id),
+                            current_message.label().clone(),
+                        );
+                        continue;
+                    }
+                    // Serve the result from the cache when the context is cleared to see it,
+                    // otherwise call the tool and cache its result
+                    let cached = self.tool_cache.as_ref().and_then(|(cache, clearance)| {
+                        cache.called(function.name());
+                        let (result, label) =
+                            cache.get(function.name(), args.value(), clearance)?;
+                        Some((result, L::from_email_label(label)?))
+                    });
+                    let mut sent = None;
+                    let (tool_result, label) = match cached {
+                        Some(cached) => cached,
+                        None => {
+                            let side_effect = side_effect(quotas.as_ref(), function.name());
+                            if let Some(spent) = &side_effect {
+                                reserve(quotas.as_ref(), spent, &mut self.observers).await?;
+                            }
+                            // The label of the call carries those of its inputs
+                            let inputs = trace.value()[trace.value().len() - 1].label();
+                            let called = tool
+                                .call_with_inputs(args.clone(), datastore, &self.authority, inputs)
+                                .await;
+                            // Sends are only verified when the tool reports one itself, rather
+                            // than by looking for an id in a result anybody may have written
+                            sent = datastore.take_send();
+                            // Like corrections, failures only tell the model about its own call
+                            let (tool_result, label) = match called {
+                                Ok(called) => called,
+                                Err(err) => {
+                                    if let Some(spent) = &side_effect {
+                                        refund(quotas.as_ref(), spent).await?;
+                                    }
+                                    self.notify(Event::ToolFailed(step, err.to_string()));
+                                    // The failure may carry what the service the tool reaches
+                                    // answered, which nobody vouches for
+                                    let label = distrust(inputs.clone())
+                                        .join(current_message.label().clone())
+                                        .ok_or(LatticeError::LabelJoinFailed)?;
+                                    current_message = MetaValue::new(
+                                        Message::ToolResult(
+                                            err.failure_message(function.name()),
+                                            id,
+                                        ),
+                                        label,
+                                    );
+                                    continue;
+                                }
+                            };
+                            let label = tool
+                                .propagate(inputs, label, &self.authority)
+                                .ok_or(LatticeError::LabelJoinFailed)?;
+                            if let Some((cache, _)) = &self.tool_cache
+                                && let Some(label) = label.to_email_label()
+                            {
+                                cache.insert(
+                                    function.name(),
+                                    args.value(),
+                                    tool_result.clone(),
+                                    label,
+                                );
+                            }
+                            (tool_result, label)
+                        }
+                    };
+                    // Let the quorum decide whether the result can be trusted, keeping its
+                    // verdicts for audits
+                    let label = if let Some(quorum) = &self.integrity_quorum
+                        && let Some(email_label) = label.to_email_label()
+                    {
+                        let (endorsed, endorsement) =
+                            quorum.endorse(email_label, &tool_result, &self.authority);
+                        notify(
+                            &mut self.observers,
+                            Event::Endorsed(trace.value().len() - 1, endorsement),
+                        );
+                        L::from_email_label(endorsed).unwrap_or(label)
+                    } else {
+                        label
+                    };
+                    // Defuse injections before the model gets to read the result
+                    let (tool_result, label) = match &self.injection_detector {
+                        Some(detector) => {
+                            let scan = detector.scan(&tool_result);
+                            let step = trace.value().len() - 1;
+                            let label = if scan.is_injection() && detector.distrusts() {
+                                distrust(label)
+                            } else {
+                                label
+                            };
+                            if scan.is_injection() {
+                                if detector.flags() {
+                                    datastore.flag_injection(InjectionFlag {
+                                        step,
+                                        function: function.name().to_string(),
+                                        findings: scan.findings.clone(),
+                                    });
+                                }
+                                self.notify(Event::InjectionDetected(step, scan.findings));
+                            }
+                            (scan.content, label)
+                        }
+                        None => (tool_result, label),
+                    };
+                    // Verify the send reported by the tool, if any. The status is only trusted as
+                    // far as the authority of the loop goes.
+                    let (tool_result, label) = match sent {
+                        Some(send_id) if self.verify_sends => {
+                            let (status, status_label) =
+                                get_message_status_labeled(GetMessageStatusArgs::new(send_id))
+                                    .into_raw_parts();
+                            let status_label = LabelPropagation::Endorsed
+                                .propagate(&status_label, status_label.clone(), &self.authority)
+                                .ok_or(LatticeError::LabelJoinFailed)?;
+                            let label = match L::from_email_label(status_label) {
+                                Some(status_label) => label
+                                    .join(status_label)
+                                    .ok_or(LatticeError::LabelJoinFailed)?,
+                                None => label,
+                            };
+                            (format!("{tool_result}\nDelivery status: {status}"), label)
+                        }
+                        _ => (tool_result, label),
+                    };
+                    // Hold untrusted results out of the conversation, which only sees their handle
+                    // and so keeps the label of the call
+                    let untrusted = label
+                        .integrity_and_readers()
+                        .is_some_and(|(integrity, _)| integrity == Integrity::Untrusted);
+                    let held = (self.quarantine && untrusted)
+                        .then(|| label.to_email_label())
+                        .flatten();
+                    let (tool_result, label) = match held {
+                        Some(email_label) => {
+                            let handle = datastore
+                                .quarantine_mut()
+                                .hold(MetaValue::new(tool_result, email_label));
+                            let inputs = trace.value()[trace.value().len() - 1].label().clone();
+                            (quarantined_message(function.name(), &handle), inputs)
+                        }
+                        _ => (tool_result, label),
+                    };
                     // The tool call above also issues a result and a label, which we need to
                     // convert here into a Message and a `Label`
                     let current_label = label
                         .join(current_message.label().clone())
                         .ok_or(LatticeError::LabelJoinFailed)?;
+                    // Warn the user if this result is the one that pushed the label of the
+                    // conversation all the way to its most restrictive value
+                    let readers = self.label_creep_readers();
+                    if let (Some(before), Some(after)) = (
+                        current_message.label().integrity_and_readers(),
+                        current_label.integrity_and_readers(),
+                    ) && !is_crept(before, readers)
+                        && is_crept(after, readers)
+                        && let (Some(before), Some(after)) = (
+                            current_message.label().to_email_label(),
+                            current_label.to_email_label(),
+                        )
+                    {
+                        self.notify(Event::LabelCreep(Box::new(LabelCreep {
+                            step: trace.value().len() - 1,
+                            function: function.name().to_string(),
+                            tool_call_id: id.clone(),
+                            before,
+                            after,
+                        })));
+                    }
                     current_message =
-                        MetaValue::new(Message::ToolResult(tool_result, id), current_label);
+                        MetaValue::new(Message::tool_result(tool_result, id), current_label);
                 }
-                Action::Finish(result) => return Ok(result),
+                // The answer carries the label of everything it was made of, which has to flow
+                // to the clearance of whoever reads it
+                Action::Finish(result) => {
+                    self.check_clearance(trace.value()[trace.value().len() - 1].label())?;
+                    return Ok(result);
+                }
+                // Custom actions produce no new data from the model's perspective, so their
+                // messages carry the label of the conversation, as do the answers they finish with
+                Action::Custom(custom) => match custom.execute(datastore) {
+                    CustomOutcome::Continue(message) => {
+                        current_message = MetaValue::new(message, current_message.label().clone());
+                    }
+                    CustomOutcome::Finish(result) => {
+                        self.check_clearance(trace.value()[trace.value().len() - 1].label())?;
+                        return Ok(result);
+                    }
+                },
             }
         }
     }
 }
 
-pub struct TaintTrackingPlanner {
-    tools: Vec<ChatCompletionTool>,
+impl<L, F, P> PlanningLoop<State, MetaValue<Message, L>, F, P>
+where
+    L: TaintLabel,
+    F: LabeledTool<L>,
+    P: Plan<State, MetaValue<Message, L>, Action = (Action, L)>,
+{
+    // Fail the run unless an answer carrying the `label` flows to the clearance of whoever reads
+    // it
+    fn check_clearance(&self, label: &L) -> Result<(), PlanError> {
+        match &self.clearance {
+            Some(clearance) if label <= clearance => Ok(()),
+            Some(clearance) => Err(PlanError::ClearanceViolation(Box::new(
+                ClearanceViolation::new(label.to_email_label(), clearance.to_email_label()),
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Look for a variant of the tool call last in the `trace`, denied with `violation`, which
+    // complies with the `policy`. Each variant takes the place of the denied call in the trace
+    // while it is checked, keeping its label, and the denied call is put back if none complies.
+    async fn repair<C: PolicyCheck<L>>(
+        &mut self,
+        policy: (&mut C, CheckContext<'_>),
+        trace: &mut Trace<L>,
+        violation: &PolicyViolation,
+        budget: &mut Option<TokenBudget>,
+        quotas: Option<&Quotas>,
+    ) -> Result<Option<Action>, PlanError> {
+        let Some(repair) = self.repair.clone() else {
+            return Ok(None);
+        };
+        let step = trace.value().len() - 1;
+        let original = trace.value()[step].value().clone();
+        if !matches!(original, Action::MakeCall(..)) {
+            return Ok(None);
+        }
+        let mut rewrites = repair.rewrites(&original, violation).into_iter();
+        let mut model_attempts = repair.model_attempts();
+        // The model is only shown the arguments it wrote itself
+        let hidden = hidden_args(&original, policy.1.state.messages());
+        // The model is asked to fix the last variant denied, knowing why it was denied
+        let mut denied = (original.clone(), violation.clone());
+        loop {
+            let candidate = match rewrites.next() {
+                Some(candidate) => candidate,
+                None if model_attempts > 0 => {
+                    model_attempts -= 1;
+                    let Some(messages) = repair_request(&denied.0, &denied.1, &hidden) else {
+                        break;
+                    };
+                    let estimate =
+                        check_budget(budget.as_ref(), quotas, &self.model, &messages, &[])?;
+                    self.reserve_query(quotas, estimate).await?;
+                    let response = self.model.chat(messages, vec![]).await;
+                    let response = self.charge_query(quotas, response, estimate).await?;
+                    self.usage.record(response.usage.as_ref());
+                    if let Some(budget) = budget {
+                        budget.spend(spent_tokens(&response, estimate));
+                    }
+                    let answer = response.choices[0].message.content.as_deref();
+                    match answer.and_then(|answer| repaired_call(&denied.0, answer, &hidden)) {
+                        Some(candidate) => candidate,
+                        None => continue,
+                    }
+                }
+                None => break,
+            };
+            match self
+                .check_variant((&mut *policy.0, policy.1), trace, candidate.clone())
+                .await
+            {
+                None => {
+                    self.notify(Event::Repaired(step, Box::new(candidate.clone())));
+                    return Ok(Some(candidate));
+                }
+                Some(violation) => denied = (candidate, violation),
+            }
+        }
+        Ok(None)
+    }
+
+    // Check the `candidate` against the `policy` in place of the action last in the `trace`,
+    // keeping its label. The action is put back if the candidate is denied as well.
+    async fn check_variant<C: PolicyCheck<L>>(
+        &mut self,
+        (policy, context): (&mut C, CheckContext<'_>),
+        trace: &mut Trace<L>,
+        candidate: Action,
+    ) -> Option<PolicyViolation> {
+        let step = trace.value().len() - 1;
+        let label = trace.value()[step].label().clone();
+        let original = std::mem::replace(
+            &mut trace.value_mut()[step],
+            MetaValue::new(candidate, label),
+        );
+        let (violation, checked) = check_policy(policy, trace, context).await;
+        checked.into_iter().for_each(|event| self.notify(event));
+        if violation.is_some() {
+            trace.value_mut()[step] = original;
+        }
+        violation
+    }
+
+    // Ask the violation handler, if any, how to recover from the action last in the `trace` being
+    // denied with `violation`
+    async fn recover<C: PolicyCheck<L>>(
+        &mut self,
+        policy: (&mut C, CheckContext<'_>),
+        trace: &mut Trace<L>,
+        violation: &PolicyViolation,
+    ) -> Recovered {
+        // The handler is taken out of the loop while deciding, such that the loop can notify the
+        // observers and check the policy in the meantime
+        let Some(mut handler) = self.violation_handler.take() else {
+            return Recovered::Refuse;
+        };
+        let step = trace.value().len() - 1;
+        let action = trace.value()[step].value().clone();
+        let recovery = handler.recover(&action, violation);
+        self.notify(Event::Recovered(step, recovery.clone()));
+        let recovered = match (recovery, action) {
+            (Recovery::SkipAction, Action::MakeCall(function, _, id)) => Recovered::Skip(
+                Message::ToolResult(skipped_message(function.name(), violation), id),
+            ),
+            (Recovery::RequireHumanApproval, Action::MakeCall(function, args, id)) => {
+                let request = ApprovalRequest {
+                    step,
+                    function: function.name().to_string(),
+                    args: args.clone(),
+                    tool_call_id: id.clone(),
+                    denied_because: Some(violation.explanation().to_string()),
+                };
+                match self.ask_gate(request).await {
+                    Some(Decision::Approve) => {
+                        Recovered::Reviewed(Action::MakeCall(function, args, id))
+                    }
+                    Some(Decision::Modify(args)) => {
+                        let candidate = Action::MakeCall(function, args, id);
+                        match self.check_variant(policy, trace, candidate.clone()).await {
+                            None => Recovered::Reviewed(candidate),
+                            Some(_) => Recovered::Refuse,
+                        }
+                    }
+                    Some(Decision::Deny(reason)) => Recovered::Skip(Message::ToolResult(
+                        denied_message(function.name(), &reason),
+                        id,
+                    )),
+                    None => Recovered::Refuse,
+                }
+            }
+            (Recovery::RewriteArgs(args), Action::MakeCall(function, _, id)) => {
+                let candidate = Action::MakeCall(function, args, id);
+                match self.check_variant(policy, trace, candidate.clone()).await {
+                    None => Recovered::Take(candidate),
+                    Some(_) => Recovered::Refuse,
+                }
+            }
+            _ => Recovered::Refuse,
+        };
+        self.violation_handler = Some(handler);
+        recovered
+    }
+}
+
+// How the loop goes on after an action was denied by the policy
+enum Recovered {
+    // Take this action instead
+    Take(Action),
+    // Take this action, which the approval gate already reviewed
+    Reviewed(Action),
+    // Skip the action, continuing with this message
+    Skip(Message),
+    // Finish the run with a refusal
+    Refuse,
 }
 
-impl TaintTrackingPlanner {
+// Arguments of the `read_variable` tool, which reads all of the result mapped to `variable` unless
+// it is given the JSON `pointer` to the only part of the result to read
+#[derive(Deserialize)]
+struct ReadVariable {
+    #[serde(flatten)]
+    variable: Variable,
+    #[serde(default)]
+    pointer: Option<String>,
+}
+
+/// Planner propagating the labels of the messages it plans from to its actions, labeled with `L`
+pub struct TaintTrackingPlanner<L: Lattice = ActionLabel> {
+    tools: Arc<Vec<ChatCompletionTool>>,
+    // Policy the actions are checked against before being emitted, if any
+    policy: Option<Policy<L>>,
+    // Actions emitted since the latest request of the user, which the policy is checked on
+    trace: Trace<L>,
+    // Tool results the model is shown variables for instead, if it is
+    variables: Option<VariableMemory<L>>,
+    // Label of everything the model read since the latest request of the user, which is the label
+    // of its actions when it is shown variables
+    context: Option<L>,
+}
+
+impl<L: Lattice> TaintTrackingPlanner<L> {
     pub fn new(tools: Vec<ChatCompletionTool>) -> Self {
-        Self { tools }
+        Self {
+            tools: Arc::new(tools),
+            policy: None,
+            trace: Trace::default(),
+            variables: None,
+            context: None,
+        }
+    }
+
+    /// Show the model a variable standing for every tool result instead of the result, which it
+    /// reads with the `read_variable` tool or passes to other tools as a `variable` argument. The
+    /// results keep their label in the `memory`, such that only reading a result taints the
+    /// conversation with it, and only the calls it is passed to. Given a JSON `pointer`, the model
+    /// only reads that part of the result, which only taints the conversation with its labels.
+    pub fn with_variables(mut self, memory: VariableMemory<L>) -> Self {
+        self.variables = Some(memory);
+        self
+    }
+
+    pub fn variables(&self) -> Option<&VariableMemory<L>> {
+        self.variables.as_ref()
+    }
+
+    /// Check every action against the `policy` before emitting it, and emit a refusal instead of
+    /// the actions it denies. The loop still checks the actions against its own policy, which
+    /// makes for a second layer of defense, however it never gets to repair or recover from the
+    /// actions the planner refused.
+    pub fn with_policy(mut self, policy: Policy<L>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub fn policy(&self) -> Option<&Policy<L>> {
+        self.policy.as_ref()
+    }
+
+    // The result mapped to `variable` with the variables it references resolved, along with the
+    // join of their labels
+    fn read(&self, variable: &Variable) -> Result<(Value, L), PlanError> {
+        let memory = self
+            .variables
+            .as_ref()
+            .ok_or_else(|| PlanError::InvalidArgumentKind("variable".to_string()))?;
+        Ok(memory.resolve(variable)?.into_raw_parts())
+    }
+
+    // The part of the result mapped to the variable that `read` points to, along with the join of
+    // the labels of that part and of those above it
+    fn read_pointer(&self, read: &ReadVariable) -> Result<(Value, L), PlanError> {
+        let pointer = read.pointer.as_deref().unwrap_or_default();
+        let memory = self
+            .variables
+            .as_ref()
+            .ok_or_else(|| PlanError::InvalidArgumentKind("variable".to_string()))?;
+        let resolved = memory
+            .resolve_labeled(&read.variable)?
+            .pointer(pointer)?
+            .ok_or_else(|| PlanError::InvalidObjectKey(pointer.to_string()))?;
+        let label = resolved
+            .joined_label()?
+            .ok_or(LatticeError::LabelJoinFailed)?;
+        Ok((resolved.to_value(), label))
+    }
+
+    // Plan the steps which only involve variables, which are storing a tool result behind a fresh
+    // variable and reading a variable for the model, along with the label of the conversation
+    // after them. Returns `None` for any other `message`, or if the model is not shown variables.
+    fn plan_variables(
+        &mut self,
+        state: &mut State,
+        message: &Message,
+        label: &L,
+    ) -> Result<Option<(Action, L)>, PlanError> {
+        if self.variables.is_none() {
+            return Ok(None);
+        }
+        let context = self.context.get_or_insert_with(|| label.clone()).clone();
+        let (content, id, context) = match message {
+            // The model only sees the variable, so the conversation keeps its label
+            Message::ToolResult(content, id) => {
+                let variable = Variable::fresh();
+                if let Some(memory) = &mut self.variables {
+                    memory.insert(variable.clone(), content.clone(), label.clone());
+                }
+                (variable.value, id.clone(), context)
+            }
+            // Images are stored as their URL, and shown as images once read
+            Message::ToolImage(image, id) => {
+                let variable = Variable::fresh();
+                if let Some(memory) = &mut self.variables {
+                    memory.insert(variable.clone(), image.url(), label.clone());
+                }
+                (variable.value, id.clone(), context)
+            }
+            Message::Chat(message) if message.role == Role::Assistant => {
+                let Some(tool_call) = message
+                    .tool_calls
+                    .iter()
+                    .flatten()
+                    .next()
+                    .filter(|tool_call| tool_call.function.name == "read_variable")
+                else {
+                    return Ok(None);
+                };
+                let read: ReadVariable = serde_json::from_str(
+                    &self.normalize_args(tool_call.function.arguments.clone())?,
+                )?;
+                let (result, result_label) = self.read_pointer(&read)?;
+                let result = match result {
+                    Value::String(result) => result,
+                    result => result.to_string(),
+                };
+                state.push(
+                    ChatCompletionRequestAssistantMessageArgs::default()
+                        .tool_calls(vec![tool_call.clone()])
+                        .build()?
+                        .into(),
+                );
+                // Reading the result is what taints the conversation with its label
+                let context = context
+                    .join(result_label)
+                    .ok_or(LatticeError::LabelJoinFailed)?;
+                self.context = Some(context.clone());
+                (result, tool_call.id.clone(), context)
+            }
+            _ => return Ok(None),
+        };
+        for message in result_messages(content, id)? {
+            state.push(message);
+        }
+        Ok(Some((
+            Action::Query(state.clone(), self.tools.clone()),
+            context,
+        )))
+    }
+
+    // The `action` with its `label` if the policy allows it, and otherwise a refusal explaining
+    // why it was denied
+    fn check(&mut self, action: Action, label: &L) -> Action {
+        let Some(policy) = &self.policy else {
+            return action;
+        };
+        self.trace
+            .value_mut()
+            .push(MetaValue::new(action.clone(), label.clone()));
+        let Some(violation) = policy.check(&self.trace) else {
+            return action;
+        };
+        let refusal = Action::Finish(refusal_message(&action, &violation));
+        let trace = self.trace.value_mut();
+        trace.pop();
+        trace.push(MetaValue::new(refusal.clone(), label.clone()));
+        refusal
     }
 
     /// Normalize the arguments passed by the LLM.
     pub fn normalize_args(&self, args: String) -> Result<String, PlanError> {
+        Ok(self.normalize_labeled_args(args)?.0)
+    }
+
+    // Normalize the arguments passed by the LLM, along with the join of the labels of the
+    // variables passed, if any
+    fn normalize_labeled_args(&self, args: String) -> Result<(String, Option<L>), PlanError> {
         // Convert the arguments to a [`serder_json::Value`]
         let args = serde_json::from_str(&args)?;
 
@@ -151,6 +1366,8 @@ impl TaintTrackingPlanner {
 
         // Create a new [`Map`] that will hold the arguments in their normalized form
         let mut new_args = Map::new();
+        // Join of the labels of the variables passed
+        let mut used = None;
 
         // For each argument
         for (arg_name, value) in map.into_iter() {
@@ -171,10 +1388,22 @@ impl TaintTrackingPlanner {
                                 .ok_or(PlanError::InvalidObjectKey("value".to_string()))?
                                 .clone(),
                         ),
-                        // If it is a variable, we need to query it in the internal [`Memory`].
-                        // However this is an interesting case as currently the LLM does not listen
-                        // to our instructions and never returns a `kind: variable` value.
-                        Some("variable") => todo!(),
+                        // If it is a variable, the argument is the result it maps to in memory,
+                        // which taints the call with its label
+                        Some("variable") => {
+                            let name = kind_map
+                                .get("value")
+                                .and_then(Value::as_str)
+                                .ok_or(PlanError::InvalidObjectKey("value".to_string()))?;
+                            let (value, label) = self.read(&Variable::new(name.into()))?;
+                            used = match used {
+                                Some(used) => {
+                                    Some(label.join(used).ok_or(LatticeError::LabelJoinFailed)?)
+                                }
+                                None => Some(label),
+                            };
+                            new_args.insert(arg_name, value)
+                        }
                         // Any other kind value is an error
                         Some(kind) => return Err(PlanError::InvalidArgumentKind(kind.to_string())),
                         // If the kind field is missing, we return an error
@@ -187,20 +1416,20 @@ impl TaintTrackingPlanner {
         }
 
         // Convert the new map into a string and return it
-        Ok(serde_json::to_string(&Value::Object(new_args))?)
+        Ok((serde_json::to_string(&Value::Object(new_args))?, used))
     }
 }
 
 // Taint-tracking planner which is plugged into the `PlanningLoop`
-impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
-    type Action = (Action, ActionLabel);
+impl<L: TaintLabel> Plan<State, MetaValue<Message, L>> for TaintTrackingPlanner<L> {
+    type Action = (Action, L);
     type Error = PlanError;
     // Given a [`LabeledMessage`], a security policy and a [`LabeledState`], return an action with
     // individually labeled components.
     fn plan(
         &mut self,
         state: State,
-        message: MetaValue<Message, ActionLabel>,
+        message: MetaValue<Message, L>,
     ) -> Result<(State, Self::Action), Self::Error> {
         // Bind the state to a mutable state such that we can update it.
         let mut new_state = state;
@@ -208,6 +1437,16 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
         // Deconstruct the `MetaValue` such that we get individual access to the message and the
         // label passed
         let (message, label) = message.into_raw_parts();
+        // A new request starts a new trace, and the model has only read the request so far
+        if message.is_user_request() {
+            self.trace = Trace::default();
+            self.context = Some(label.clone());
+        }
+        if let Some((action, label)) = self.plan_variables(&mut new_state, &message, &label)? {
+            let action = self.check(action, &label);
+            return Ok((new_state, (action, label)));
+        }
+        let mut label = label;
 
         // Create a new state, action and action label based on the message that we get. This match
         // also converts the message from a completion response type message to a completion
@@ -226,7 +1465,7 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
                             .build()?
                             .into();
                         // Update the state with the new message
-                        new_state.0.push(conv_message);
+                        new_state.push(conv_message);
                         // In this case, the action to take is to query the LLM with the updated
                         // state and the set of available tools
                         let action = Action::Query(new_state.clone(), self.tools.clone());
@@ -246,7 +1485,7 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
                             .build()?
                             .into();
                         // Update the state with the new message
-                        new_state.0.push(conv_message);
+                        new_state.push(conv_message);
 
                         // In this case, the action to take is to query the LLM with the updated
                         // state and the set of available tools
@@ -266,8 +1505,11 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
                             let FunctionCall { name, arguments } = tool_calls[0].clone().function;
 
                             // Normalize arguments such that we could parse them in their correct
-                            // function input
-                            let arguments = self.normalize_args(arguments);
+                            // function input. Results passed by variable taint the call.
+                            let (arguments, used) = self.normalize_labeled_args(arguments)?;
+                            if let Some(used) = used {
+                                label = label.join(used).ok_or(LatticeError::LabelJoinFailed)?;
+                            }
 
                             // Convert the message to a request to update the state
                             let conv_message = ChatCompletionRequestAssistantMessageArgs::default()
@@ -275,7 +1517,7 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
                                 .build()?
                                 .into();
                             // Update the state with the new message
-                            new_state.0.push(conv_message);
+                            new_state.push(conv_message);
 
                             // In this case, the action to take is to call the specified tool with
                             // the specified arguments, keeping the id of the tool call such that
@@ -283,7 +1525,7 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
                             // the tool result.
                             let action = Action::MakeCall(
                                 Function::new(name),
-                                Args(arguments?),
+                                Args::new(arguments),
                                 tool_calls[0].clone().id,
                             );
                             (new_state, action)
@@ -296,7 +1538,7 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
                                 .build()?
                                 .into();
                             // Update the state with the new message
-                            new_state.0.push(conv_message);
+                            new_state.push(conv_message);
                             // In this case, the assistant gave the "final" answer as we want to
                             // take a finishing action and return the result to the caller.
                             let action = Action::Finish(content);
@@ -319,14 +1561,1689 @@ impl Plan<State, MetaValue<Message, ActionLabel>> for TaintTrackingPlanner {
                     .build()?
                     .into();
                 // Update the state with the new message
-                new_state.0.push(conv_message);
+                new_state.push(conv_message);
 
                 // In this case, the action to take is to query the LLM with the updated
                 // state and the set of available tools
                 let action = Action::Query(new_state.clone(), self.tools.clone());
                 (new_state, action)
             }
+            // Images are shown to the model in user messages, following the result of the call
+            // for those of tools
+            Message::UserImages(content, images) => {
+                new_state.push(Image::user_message(&content, &images)?);
+                let action = Action::Query(new_state.clone(), self.tools.clone());
+                (new_state, action)
+            }
+            Message::ToolImage(image, id) => {
+                for conv_message in image.tool_messages(&id)? {
+                    new_state.push(conv_message);
+                }
+                let action = Action::Query(new_state.clone(), self.tools.clone());
+                (new_state, action)
+            }
         };
+        let action = self.check(action, &label);
         Ok((new_state, (action, label)))
     }
+
+    fn memory(&self) -> Option<Value> {
+        let variables = self.variables.as_ref()?;
+        serde_json::to_value((variables, &self.context)).ok()
+    }
+
+    fn restore_memory(&mut self, memory: Value) -> Result<(), serde_json::Error> {
+        let (variables, context) = serde_json::from_value(memory)?;
+        self.variables = Some(variables);
+        self.context = context;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Confidentiality, ConversationHistory, LabelPropagation,
+        authority::Principal,
+        fixtures::Fixture,
+        injection::{InjectionDetector, mentions_tools},
+        mock::MockLlm,
+        openai::LlmClient,
+        plan::{
+            PolicySet, Shadowed,
+            approval::ToolGate,
+            observer::Observer,
+            policy::{
+                Composition, EGRESS, Severity, policy_no_exfiltration, policy_no_untrusted_url,
+            },
+            recovery::ViolationHandler,
+            repair::{PlanRepair, internal_recipient},
+            sanitize::strip_untrusted_urls,
+        },
+        tools::{
+            EmailAddressUniverse, INBOX, LabeledMemory, OUTBOX, WEB, label_email, readers_label,
+            trusted_service_authority,
+        },
+        validate::Validator,
+    };
+    use serde_json::json;
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+
+    // Label of what everyone in the inbox can read, with the given `integrity`
+    fn inbox_label(integrity: Integrity) -> EmailLabel {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        ProductLattice::new(
+            integrity,
+            readers_label(universe.clone(), universe).unwrap(),
+        )
+    }
+
+    // Trusted request of the user, calling `function` with `args`
+    fn trusted_request(function: &str, args: Value) -> MetaValue<Message, EmailLabel> {
+        let request = MockLlm::assistant_tool_call("call_0", function, args);
+        MetaValue::new(Message::Chat(request), inbox_label(Integrity::trusted()))
+    }
+
+    // Observer collecting the events it is notified about
+    struct Collect(Arc<Mutex<Vec<Event>>>);
+
+    impl Observer for Collect {
+        fn notify(&mut self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn label_creep_is_reported() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "read_emails_labeled",
+                json!({ "count": { "kind": "value", "value": "5" } }),
+            ),
+            MockLlm::assistant_text("You have 5 new emails."),
+        ]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![MetaFunction::new("read_emails_labeled".to_string())],
+        )
+        .with_observer(Collect(events.clone()));
+
+        // Reading a trusted email addressed to a couple of readers does not creep, while reading
+        // the untrusted ones, which only the user can read, does.
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "1" } }),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "You have 5 new emails.");
+
+        let events = events.lock().unwrap();
+        let creeps: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::LabelCreep(creep) => Some(creep),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(creeps.len(), 1);
+        let creep = creeps[0];
+        assert_eq!(creep.step, 2);
+        assert_eq!(creep.function, "read_emails_labeled");
+        assert_eq!(creep.tool_call_id, "call_1");
+        assert_eq!(creep.after.lattice1(), &Integrity::Untrusted);
+    }
+
+    #[tokio::test]
+    async fn email_tools_run_with_interned_labels() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "read_emails_labeled",
+                json!({ "count": { "kind": "value", "value": "5" } }),
+            ),
+            MockLlm::assistant_text("You have 5 new emails."),
+        ]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let read = InternedFunction::new(MetaFunction::new("read_emails_labeled".to_string()));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::<InternedEmailLabel>::new(vec![]),
+            model,
+            vec![read.clone()],
+        )
+        .with_observer(Collect(events.clone()));
+
+        let label =
+            InternedEmailLabel::from_email_label(inbox_label(Integrity::trusted())).unwrap();
+        let first_message = MockLlm::assistant_tool_call(
+            "call_0",
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "1" } }),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(Message::Chat(first_message), label.clone()),
+                Policy::new(|_: &Trace<InternedEmailLabel>| None),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "You have 5 new emails.");
+        assert!(
+            events
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|event| matches!(event, Event::LabelCreep(_)))
+        );
+
+        // Results are interned into the universe of the labels over the same addresses
+        let args = Args::new(json!({ "count": "1" }).to_string());
+        let (_, read) = read.call(args, &mut Datastore::default()).await.unwrap();
+        assert!(Arc::ptr_eq(
+            read.lattice2().inner().universe(),
+            label.lattice2().inner().universe()
+        ));
+    }
+
+    #[tokio::test]
+    async fn untrusted_results_are_only_seen_through_their_handle() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "summarize_labeled",
+                json!({ "handle": { "kind": "value", "value": "quarantine-0" } }),
+            ),
+            MockLlm::assistant_text("Your emails hold a few links."),
+        ]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("summarize_labeled".to_string())
+                    .with_authority(trusted_service_authority()),
+            ],
+        )
+        .with_quarantine();
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        let mut datastore = Datastore::default().with_sanctioned_tool("summarize_labeled");
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut datastore,
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "Your emails hold a few links.");
+        assert_eq!(datastore.quarantine().len(), 1);
+
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let seen = serde_json::to_string(&requests).unwrap();
+        assert!(seen.contains("quarantined as `quarantine-0`"));
+        assert!(seen.contains("`quarantine-0` holds"));
+        assert!(!seen.contains("augmented with a new feature"));
+    }
+
+    #[tokio::test]
+    async fn policy_checks_are_audited_in_order() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_1",
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "See https://fides.github.io/x" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        )]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        )
+        .with_observer(Collect(events.clone()));
+
+        // Reading the untrusted emails taints the conversation, so sending a link afterwards is
+        // denied even though the query before it was allowed.
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+
+        let checks: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::PolicyChecked(step, violation) => Some((*step, violation.is_some())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(checks, vec![(0, false), (1, false), (2, true)]);
+        assert_eq!(planning_loop.usage().requests, 1);
+    }
+
+    #[tokio::test]
+    async fn policy_decisions_are_exported_as_json_lines() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_1",
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "See https://fides.github.io/x" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        )]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        );
+        let policy: Policy = PolicySet::new(Composition::AllOf)
+            .with_policy(
+                "no-untrusted-urls",
+                Policy::new(policy_no_untrusted_url),
+                Severity::High,
+            )
+            .into();
+
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                policy.with_name("guardrails"),
+            )
+            .await
+            .expect("Failed to run");
+
+        let mut lines = vec![];
+        planning_loop
+            .audit_log()
+            .write_json_lines(&mut lines)
+            .unwrap();
+        let decisions: Vec<Value> = String::from_utf8(lines)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(decisions.len(), 3);
+        assert!(
+            decisions
+                .iter()
+                .all(|decision| decision["policy"] == "guardrails")
+        );
+        assert_eq!(decisions[1]["outcome"]["decision"], "allowed");
+        let denied = &decisions[2];
+        assert_eq!(denied["action"]["function"], "send_slack_message_labeled");
+        assert_eq!(denied["label"]["integrity"], "untrusted");
+        assert_eq!(denied["outcome"]["decision"], "denied");
+        assert_eq!(denied["outcome"]["severity"], "high");
+        assert_eq!(denied["outcome"]["rule"], "no-untrusted-urls");
+    }
+
+    #[tokio::test]
+    async fn shadow_policies_only_report_violations() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": "See https://fides.github.io/x" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            MockLlm::assistant_text("I sent Bob the link."),
+        ]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        )
+        .with_observer(Collect(events.clone()));
+
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Shadowed::dry_run(Policy::new(policy_no_untrusted_url).with_name("links")),
+            )
+            .await
+            .expect("Failed to run");
+        // The send would have been denied, but went through
+        assert_eq!(answer, "I sent Bob the link.");
+        let shadowed: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::ShadowViolation(step, _) => Some(*step),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(shadowed, vec![2]);
+        let decision = planning_loop
+            .audit_log()
+            .decisions()
+            .iter()
+            .find(|decision| matches!(decision.outcome, Outcome::Shadowed { .. }))
+            .unwrap();
+        assert_eq!(
+            (decision.step, decision.policy.as_deref()),
+            (2, Some("links"))
+        );
+    }
+
+    #[tokio::test]
+    async fn planners_refuse_what_their_policy_denies() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_1",
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "See https://fides.github.io/planner" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        )]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]).with_policy(Policy::new(policy_no_untrusted_url)),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        )
+        .with_observer(Collect(events.clone()));
+
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+
+        // The send was never emitted, so the loop only saw the refusal in its place
+        let checks: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::PolicyChecked(step, violation) => Some((*step, violation.is_some())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(checks, vec![(0, false), (1, false), (2, false)]);
+    }
+
+    #[tokio::test]
+    async fn injections_in_web_pages_are_refused() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_1",
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "robert@universaltechadvise.biz" },
+                "message": { "kind": "value", "value": "See https://fides.github.io/summary" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        )]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]).with_policy(Policy::new(policy_no_untrusted_url)),
+            model,
+            vec![
+                MetaFunction::new("fetch_url_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        );
+
+        // The user only asks for a page, which tells the model to send a link of its own
+        let request = trusted_request(
+            "fetch_url_labeled",
+            json!({ "url": { "kind": "value", "value": "https://fides.github.io/summary" } }),
+        );
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+    }
+
+    #[tokio::test]
+    async fn emails_are_not_forwarded_to_outsiders() {
+        let send = |id, to: &str| {
+            MockLlm::assistant_tool_call(
+                id,
+                "send_email_labeled",
+                json!({
+                    "to": { "kind": "value", "value": [to] },
+                    "subject": { "kind": "value", "value": "Fwd: Meeting with Alice" },
+                    "body": { "kind": "variable", "value": "meeting" },
+                }),
+            )
+        };
+        let model = LlmClient::mock(MockLlm::new(vec![
+            send("call_0", "alice.hudson@magnet.com"),
+            send("call_1", "robert@universaltechadvise.biz"),
+        ]));
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let meeting = label_email(INBOX[0].clone(), universe.clone()).unwrap();
+        let mut memory = LabeledMemory::default();
+        memory.insert(
+            Variable::new("meeting".to_string()),
+            meeting.value().body().to_string(),
+            meeting.label().clone(),
+        );
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![])
+                .with_policy(policy_no_exfiltration(EGRESS))
+                .with_variables(memory),
+            model,
+            vec![MetaFunction::new("send_email_labeled".to_string())],
+        );
+        let mut request = MockLlm::assistant_text("Forward the meeting email.");
+        request.role = Role::User;
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(Message::Chat(request), inbox_label(Integrity::trusted())),
+            )
+            .await
+            .expect("Failed to run");
+        // Alice could read the email already, while Robert may not
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+        let forwarded: Vec<_> = OUTBOX
+            .sent()
+            .into_iter()
+            .filter(|sent| sent.message().starts_with("Fwd: Meeting with Alice"))
+            .map(|sent| sent.channel().to_string())
+            .collect();
+        assert_eq!(forwarded, vec!["alice.hudson@magnet.com"]);
+    }
+
+    #[tokio::test]
+    async fn refused_atomic_runs_roll_back_their_writes() {
+        let run = |atomic: bool| async move {
+            let send = |id, to: &str| {
+                MockLlm::assistant_tool_call(
+                    id,
+                    "send_email_labeled",
+                    json!({
+                        "to": { "kind": "value", "value": [to] },
+                        "subject": { "kind": "value", "value": "Fwd: Meeting" },
+                        "body": { "kind": "variable", "value": "meeting" },
+                    }),
+                )
+            };
+            let model = LlmClient::mock(MockLlm::new(vec![
+                send("call_0", "alice.hudson@magnet.com"),
+                send("call_1", "robert@universaltechadvise.biz"),
+            ]));
+            let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+            let meeting = label_email(INBOX[0].clone(), universe.clone()).unwrap();
+            let mut memory = LabeledMemory::default();
+            memory.insert(
+                Variable::new("meeting".to_string()),
+                meeting.value().body().to_string(),
+                meeting.label().clone(),
+            );
+            let planning_loop = PlanningLoop::new(
+                TaintTrackingPlanner::new(vec![]).with_variables(memory),
+                model,
+                vec![MetaFunction::new("send_email_labeled".to_string())],
+            );
+            let mut planning_loop = match atomic {
+                true => planning_loop.with_atomic_runs(),
+                false => planning_loop,
+            };
+            let mut request = MockLlm::assistant_text("Forward the meeting email.");
+            request.role = Role::User;
+            let mut datastore = Datastore::default();
+            let answer = planning_loop
+                .run_with_policy(
+                    ConversationHistory::new(vec![]),
+                    &mut datastore,
+                    MetaValue::new(Message::Chat(request), inbox_label(Integrity::trusted())),
+                    policy_no_exfiltration(EGRESS),
+                )
+                .await
+                .expect("Failed to run");
+            assert!(answer.starts_with("I couldn't complete your request"));
+            datastore.store().len()
+        };
+
+        // The email to Alice went out before the one to Robert was refused
+        assert_eq!(run(false).await, 1);
+        assert_eq!(run(true).await, 0);
+    }
+
+    // Tools of a payroll service, whose salaries are confidential
+    struct Payroll(&'static str);
+
+    impl Call for Payroll {
+        type Args = Args;
+        type Output = (String, Label);
+
+        async fn call(
+            &self,
+            _args: Args,
+            _datastore: &mut Datastore,
+        ) -> Result<(String, Label), ToolError> {
+            Ok(match self.0 {
+                "raise_salary" => {
+                    return Err(ToolError::PermissionDenied(
+                        "only managers raise salaries".to_string(),
+                    ));
+                }
+                "read_salary" => (
+                    "Alice earns 100k.".to_string(),
+                    Label::new(Confidentiality::high(), Integrity::trusted()),
+                ),
+                _ => (
+                    "Posted.".to_string(),
+                    Label::new(Confidentiality::low(), Integrity::trusted()),
+                ),
+            })
+        }
+    }
+
+    impl LabeledTool<Label> for Payroll {
+        fn name(&self) -> &str {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_are_checked_with_labels_of_any_type() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call("call_0", "read_salary", json!({})),
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "post_message",
+                json!({ "message": { "kind": "value", "value": "Alice earns 100k." } }),
+            ),
+        ]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![Payroll("read_salary"), Payroll("post_message")],
+        );
+        // Nothing confidential is posted
+        let policy = Policy::new(|trace: &Trace<Label>| {
+            let last = trace.value().last()?;
+            match last.value() {
+                Action::MakeCall(function, _, _)
+                    if function.name() == "post_message"
+                        && last.label().lattice1() == &Confidentiality::high() =>
+                {
+                    Some(PolicyViolation::Standard(
+                        "the salary would be posted".to_string(),
+                    ))
+                }
+                _ => None,
+            }
+        });
+        let mut request = MockLlm::assistant_text("Post Alice's salary.");
+        request.role = Role::User;
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(
+                    Message::Chat(request),
+                    Label::new(Confidentiality::low(), Integrity::trusted()),
+                ),
+                policy,
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.contains("the salary would be posted"));
+    }
+
+    #[tokio::test]
+    async fn failed_calls_are_handed_back_to_the_model() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call("call_0", "raise_salary", json!({})),
+            MockLlm::assistant_text("I may not raise salaries."),
+        ]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![Payroll("raise_salary")],
+        )
+        .with_observer(Collect(events.clone()));
+        let mut request = MockLlm::assistant_text("Give Alice a raise.");
+        request.role = Role::User;
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(
+                    Message::Chat(request),
+                    Label::new(Confidentiality::low(), Integrity::trusted()),
+                ),
+                Policy::new(|_: &Trace<Label>| None),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "I may not raise salaries.");
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let last_message = serde_json::to_string(requests[1].last().unwrap()).unwrap();
+        assert!(last_message.contains("only managers raise salaries"));
+        assert!(last_message.contains("Do not call `raise_salary` like this again."));
+        assert!(events.lock().unwrap().iter().any(|event| matches!(
+            event,
+            Event::ToolFailed(_, err) if err.contains("permission was denied")
+        )));
+    }
+
+    #[tokio::test]
+    async fn failures_are_labeled_like_what_the_service_answers() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_1",
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "See https://fides.github.io/planner" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        )]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]).with_policy(Policy::new(policy_no_untrusted_url)),
+            model,
+            vec![
+                MetaFunction::new("fetch_url_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string())
+                    .with_authority(trusted_service_authority()),
+            ],
+        );
+
+        // The page is missing, so the conversation only learns of the failure
+        let request = trusted_request(
+            "fetch_url_labeled",
+            json!({ "url": { "kind": "value", "value": "https://roma.com/missing" } }),
+        );
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+    }
+
+    #[tokio::test]
+    async fn only_reading_a_variable_taints_the_conversation() {
+        let send = |id| {
+            MockLlm::assistant_tool_call(
+                id,
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": "See https://fides.github.io/planner" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            )
+        };
+        let model = LlmClient::mock(MockLlm::new(vec![
+            send("call_0"),
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "read_variable",
+                json!({ "variable": { "kind": "value", "value": "emails" } }),
+            ),
+            send("call_2"),
+        ]));
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let readers = readers_label(universe.clone(), universe).unwrap();
+        let mut memory = LabeledMemory::default();
+        memory.insert(
+            Variable::new("emails".to_string()),
+            "Click https://fides.github.io/planner".to_string(),
+            ProductLattice::new(Integrity::untrusted(), readers.clone()),
+        );
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![])
+                .with_policy(Policy::new(policy_no_untrusted_url))
+                .with_variables(memory),
+            model,
+            vec![
+                MetaFunction::new("send_slack_message_labeled".to_string())
+                    .with_authority(trusted_service_authority()),
+            ],
+        );
+        let mut request = MockLlm::assistant_text("Send Bob the planner link.");
+        request.role = Role::User;
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(
+                    Message::Chat(request),
+                    ProductLattice::new(Integrity::trusted(), readers),
+                ),
+            )
+            .await
+            .expect("Failed to run");
+        // The first send only followed the request, while the second one followed the emails
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+        let variables = planning_loop.planner.variables().unwrap();
+        assert_eq!(variables.len(), 2);
+        assert!(
+            variables
+                .iter()
+                .any(|(_, result)| result.label().lattice1() == &Integrity::trusted())
+        );
+    }
+
+    #[tokio::test]
+    async fn reading_part_of_a_variable_only_taints_with_its_labels() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "read_variable",
+                json!({
+                    "variable": { "kind": "value", "value": "summary" },
+                    "pointer": { "kind": "value", "value": "/recipient" },
+                }),
+            ),
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": "See https://fides.github.io/planner" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            MockLlm::assistant_text("I sent Bob the planner link."),
+        ]));
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let readers = readers_label(universe.clone(), universe).unwrap();
+        let label = |integrity| ProductLattice::new(integrity, readers.clone());
+        let mut memory = LabeledMemory::default();
+        memory.insert(
+            Variable::new("emails".to_string()),
+            "Click https://fides.github.io/planner".to_string(),
+            label(Integrity::untrusted()),
+        );
+        memory.insert(
+            Variable::new("recipient".to_string()),
+            "bob.sheffield@magnet.com".to_string(),
+            label(Integrity::trusted()),
+        );
+        // A summary pulling in the untrusted emails, next to the recipient
+        memory.insert(
+            Variable::new("summary".to_string()),
+            json!({
+                "recipient": { "kind": "variable", "value": "recipient" },
+                "body": { "kind": "variable", "value": "emails" },
+            })
+            .to_string(),
+            label(Integrity::trusted()),
+        );
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![])
+                .with_policy(Policy::new(policy_no_untrusted_url))
+                .with_variables(memory),
+            model,
+            vec![
+                MetaFunction::new("send_slack_message_labeled".to_string())
+                    .with_authority(trusted_service_authority()),
+            ],
+        );
+        let mut request = MockLlm::assistant_text("Send Bob the planner link.");
+        request.role = Role::User;
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(Message::Chat(request), label(Integrity::trusted())),
+            )
+            .await
+            .expect("Failed to run");
+        // Only the recipient was read, so the link still follows the request alone
+        assert_eq!(answer, "I sent Bob the planner link.");
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let read = serde_json::to_string(requests[1].last().unwrap()).unwrap();
+        assert!(read.contains("bob.sheffield@magnet.com"));
+        assert!(!read.contains("Click"));
+    }
+
+    #[tokio::test]
+    async fn denied_calls_are_repaired() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": "See https://fides.github.io/x" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            // The model's fix, once switching the recipient did not help
+            MockLlm::assistant_text(
+                r#"```json
+                {"channel": "bob.sheffield@magnet.com", "message": "See the summary", "preview": "false"}
+                ```"#,
+            ),
+            MockLlm::assistant_text("I sent Bob the summary."),
+        ]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        )
+        .with_observer(Collect(events.clone()))
+        .with_repair(
+            PlanRepair::new()
+                .with_rewrite(internal_recipient("alice.hudson@magnet.com"))
+                .with_model_attempts(1),
+        );
+
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "I sent Bob the summary.");
+
+        let events = events.lock().unwrap();
+        let checks: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::PolicyChecked(step, violation) => Some((*step, violation.is_some())),
+                _ => None,
+            })
+            .collect();
+        // The send, the rewritten send and the model's fix were all checked at the same step,
+        // followed by the query with the result of the send and the final answer
+        assert_eq!(
+            checks,
+            vec![
+                (0, false),
+                (1, false),
+                (2, true),
+                (2, true),
+                (2, false),
+                (3, false),
+                (4, false)
+            ]
+        );
+        let repaired = events.iter().find_map(|event| match event {
+            Event::Repaired(2, action) => Some(action),
+            _ => None,
+        });
+        let Some(Action::MakeCall(_, args, _)) = repaired.map(|action| action.as_ref()) else {
+            panic!("Expected the send to be repaired");
+        };
+        assert!(args.value().contains("See the summary"));
+        // The model goes on knowing which call was made
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let conversation = serde_json::to_string(&requests[2]).unwrap();
+        assert!(conversation.contains("See the summary"));
+        assert!(!conversation.contains("fides.github.io/x"));
+    }
+
+    #[tokio::test]
+    async fn repairs_only_show_the_model_what_it_wrote() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "variable", "value": "emails" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            // The fix cannot change what the model referred to by variable
+            MockLlm::assistant_text(
+                r#"{"channel": "bob.sheffield@magnet.com", "message": "Hi", "preview": "false"}"#,
+            ),
+        ]));
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let readers = readers_label(universe.clone(), universe).unwrap();
+        let mut memory = LabeledMemory::default();
+        memory.insert(
+            Variable::new("emails".to_string()),
+            "Click https://fides.github.io/planner".to_string(),
+            ProductLattice::new(Integrity::untrusted(), readers.clone()),
+        );
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]).with_variables(memory),
+            model,
+            vec![MetaFunction::new("send_slack_message_labeled".to_string())],
+        )
+        .with_repair(PlanRepair::new().with_model_attempts(1));
+        let mut request = MockLlm::assistant_text("Forward Bob my emails.");
+        request.role = Role::User;
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(
+                    Message::Chat(request),
+                    ProductLattice::new(Integrity::trusted(), readers),
+                ),
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let repair = serde_json::to_string(&requests[1]).unwrap();
+        assert!(repair.contains("bob.sheffield@magnet.com"));
+        assert!(!repair.contains("Click"));
+    }
+
+    #[tokio::test]
+    async fn calls_are_sanitized_before_being_checked() {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": "See https://fides.github.io/x" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            MockLlm::assistant_text("I sent Bob the summary."),
+        ]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        )
+        .with_observer(Collect(events.clone()))
+        .with_sanitizer(strip_untrusted_urls());
+
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+        // The link was taken out of the untrusted send, which the policy then let through
+        assert_eq!(answer, "I sent Bob the summary.");
+
+        let events = events.lock().unwrap();
+        assert!(
+            events
+                .iter()
+                .all(|event| !matches!(event, Event::PolicyChecked(_, Some(_))))
+        );
+        let sanitized = events.iter().find_map(|event| match event {
+            Event::Sanitized(2, action) => Some(action),
+            _ => None,
+        });
+        let Some(Action::MakeCall(_, args, _)) = sanitized.map(|action| action.as_ref()) else {
+            panic!("Expected the send to be sanitized");
+        };
+        assert!(args.value().contains("See [link removed]"));
+    }
+
+    #[tokio::test]
+    async fn injections_in_tool_results_are_flagged() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+            "You have 5 new emails.",
+        )]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![MetaFunction::new("read_emails_labeled".to_string())],
+        )
+        .with_observer(Collect(events.clone()))
+        .with_injection_detector(
+            InjectionDetector::default().with_heuristic(mentions_tools(["send_email"])),
+        );
+
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        let mut datastore = Datastore::default().with_mailbox(Fixture::InjectionHeavy.mailbox());
+        planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut datastore,
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+
+        let [flag] = datastore.injections() else {
+            panic!("Expected the emails to be flagged once");
+        };
+        assert_eq!(flag.function, "read_emails_labeled");
+        assert!(
+            flag.findings
+                .iter()
+                .any(|finding| finding.contains("role markers"))
+        );
+        assert!(
+            flag.findings
+                .iter()
+                .any(|finding| finding.contains("names the tool `send_email`"))
+        );
+        let events = events.lock().unwrap();
+        assert!(
+            events.iter().any(
+                |event| matches!(event, Event::InjectionDetected(step, _) if *step == flag.step)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_are_verified() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+            "The message was delivered.",
+        )]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![MetaFunction::new("send_slack_message_labeled".to_string())],
+        )
+        .with_send_verification();
+
+        let request = trusted_request(
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "Lunch at noon?" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "The message was delivered.");
+
+        // The model saw the status of the send along with the result of sending it
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let tool_result = serde_json::to_string(requests[0].last().unwrap()).unwrap();
+        assert!(tool_result.contains("Delivery status:"));
+        assert!(tool_result.contains(r#"\"status\":\"sent\""#));
+    }
+
+    #[tokio::test]
+    async fn only_sends_the_tools_report_are_verified() {
+        // A page claiming a send, such that its id is found in the result of a tool which sends nothing
+        WEB.publish(
+            "https://roma.com/outbox",
+            "Outbox",
+            "Message sent! Send id: send-0",
+        );
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text("Done.")]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![MetaFunction::new("fetch_url_labeled".to_string())],
+        )
+        .with_send_verification();
+
+        let request = trusted_request(
+            "fetch_url_labeled",
+            json!({ "url": { "kind": "value", "value": "https://roma.com/outbox" } }),
+        );
+        planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let tool_result = serde_json::to_string(requests[0].last().unwrap()).unwrap();
+        assert!(!tool_result.contains("Delivery status:"));
+    }
+
+    #[tokio::test]
+    async fn tools_only_vouch_for_what_their_authority_allows() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_1",
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "See https://fides.github.io/lunch" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        )]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]).with_policy(Policy::new(policy_no_untrusted_url)),
+            model,
+            vec![MetaFunction::new("send_slack_message_labeled".to_string())],
+        )
+        .with_authority(Authority::new(Principal::new("unverified-slack")));
+        assert!(!planning_loop.authority().can_endorse());
+
+        let request = trusted_request(
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "Lunch at noon?" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        );
+        // The result of the first send cannot be vouched for, so the link sent after it is refused
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+    }
+
+    #[test]
+    fn propagation_only_lowers_labels_through_the_authority() {
+        let trusted = inbox_label(Integrity::trusted());
+        let untrusted = inbox_label(Integrity::untrusted());
+        let tool = MetaFunction::new("send_slack_message_labeled".to_string())
+            .with_label_propagation(LabelPropagation::Constant(trusted.clone()));
+        // A constant label cannot vouch for the result on its own
+        let authority = Authority::new(Principal::new("unverified-slack"));
+        let label = LabeledTool::propagate(&tool, &trusted, untrusted.clone(), &authority);
+        assert_eq!(label, Some(untrusted.clone()));
+        // The authority granted to the tool can
+        let tool = tool.with_authority(trusted_service_authority());
+        let label = LabeledTool::propagate(&tool, &trusted, untrusted, &authority);
+        assert_eq!(label, Some(trusted));
+    }
+
+    #[tokio::test]
+    async fn results_are_labeled_by_their_propagation() {
+        let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_tool_call(
+            "call_1",
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "See https://fides.github.io/lunch" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        )]));
+        let untrusted = inbox_label(Integrity::untrusted());
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]).with_policy(Policy::new(policy_no_untrusted_url)),
+            model,
+            vec![
+                MetaFunction::new("send_slack_message_labeled".to_string())
+                    .with_label_propagation(LabelPropagation::Constant(untrusted)),
+            ],
+        );
+
+        let request = trusted_request(
+            "send_slack_message_labeled",
+            json!({
+                "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                "message": { "kind": "value", "value": "Lunch at noon?" },
+                "preview": { "kind": "value", "value": "false" },
+            }),
+        );
+        // The service vouches for the send, but the propagation of the tool overrides it
+        let answer = planning_loop
+            .run_with_planner_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.starts_with("I couldn't complete your request. I was about to call"));
+    }
+
+    #[tokio::test]
+    async fn denied_queries_never_reach_the_model() {
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+                "Payroll is due.",
+            )])),
+            Vec::<MetaFunction>::new(),
+        );
+        let no_queries = Policy::new(|trace: &Trace<EmailLabel>| {
+            let Action::Query(..) = trace.value().last()?.value() else {
+                return None;
+            };
+            Some(PolicyViolation::Standard(
+                "nothing may reach the model".to_string(),
+            ))
+        });
+        let label = inbox_label(Integrity::trusted());
+        let answer = planning_loop
+            .run_from_user_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                "When is payroll due?",
+                label,
+                no_queries,
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.starts_with("I couldn't complete your request"));
+        assert!(
+            planning_loop
+                .model()
+                .as_mock()
+                .unwrap()
+                .requests()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_are_only_handed_over_within_clearance() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let readable_by = |reader: &str| {
+            ProductLattice::new(
+                Integrity::trusted(),
+                readers_label(HashSet::from([reader.to_string()]), universe.clone()).unwrap(),
+            )
+        };
+        let answer = |clearance: EmailLabel| {
+            let mut planning_loop = PlanningLoop::new(
+                TaintTrackingPlanner::new(vec![]),
+                LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(
+                    "Payroll is due.",
+                )])),
+                Vec::<MetaFunction>::new(),
+            )
+            .with_clearance(clearance);
+            let mut request = MockLlm::assistant_text("When is payroll due?");
+            request.role = Role::User;
+            // Only Alice may read what the answer is made of
+            let message = MetaValue::new(
+                Message::Chat(request),
+                readable_by("alice.hudson@magnet.com"),
+            );
+            async move {
+                planning_loop
+                    .run_with_policy(
+                        ConversationHistory::new(vec![]),
+                        &mut Datastore::default(),
+                        message,
+                        Policy::new(policy_no_untrusted_url),
+                    )
+                    .await
+            }
+        };
+
+        assert_eq!(
+            answer(readable_by("alice.hudson@magnet.com"))
+                .await
+                .unwrap(),
+            "Payroll is due."
+        );
+        assert!(matches!(
+            answer(readable_by("bob.sheffield@magnet.com")).await,
+            Err(PlanError::ClearanceViolation(violation)) if violation.label().is_some()
+        ));
+    }
+
+    // Hands every conversation over to a human, with the label of the conversation
+    #[derive(Debug, Clone)]
+    struct Escalate;
+
+    impl crate::CustomAction for Escalate {
+        fn name(&self) -> &str {
+            "escalate"
+        }
+
+        fn execute(&self, _datastore: &mut Datastore) -> CustomOutcome {
+            CustomOutcome::Finish("Payroll is due, a human will confirm.".to_string())
+        }
+
+        fn clone_box(&self) -> Box<dyn crate::CustomAction> {
+            Box::new(self.clone())
+        }
+    }
+
+    struct EscalatingPlanner;
+
+    impl Plan<State, MetaValue<Message, EmailLabel>> for EscalatingPlanner {
+        type Action = (Action, EmailLabel);
+        type Error = ();
+
+        fn plan(
+            &mut self,
+            state: State,
+            message: MetaValue<Message, EmailLabel>,
+        ) -> Result<(State, Self::Action), ()> {
+            let label = message.label().clone();
+            Ok((state, (Action::Custom(Box::new(Escalate)), label)))
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_answers_are_only_handed_over_within_clearance() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let readable_by = |reader: &str| {
+            ProductLattice::new(
+                Integrity::trusted(),
+                readers_label(HashSet::from([reader.to_string()]), universe.clone()).unwrap(),
+            )
+        };
+        let mut planning_loop = PlanningLoop::new(
+            EscalatingPlanner,
+            LlmClient::mock(MockLlm::new(vec![])),
+            Vec::<MetaFunction>::new(),
+        )
+        .with_clearance(readable_by("bob.sheffield@magnet.com"));
+        let message = MetaValue::new(
+            Message::Chat(MockLlm::assistant_text("When is payroll due?")),
+            readable_by("alice.hudson@magnet.com"),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                message,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await;
+        assert!(matches!(answer, Err(PlanError::ClearanceViolation(_))));
+    }
+
+    // Lets the run send a single message, counting the sends it is asked about
+    struct SingleSend {
+        sends: usize,
+    }
+
+    impl PolicyCheck<ActionLabel> for SingleSend {
+        async fn check(
+            &mut self,
+            trace: &Trace<ActionLabel>,
+            _state: &State,
+            _datastore: &Datastore,
+        ) -> Option<PolicyViolation> {
+            let Action::MakeCall(function, _, _) = trace.value().last()?.value() else {
+                return None;
+            };
+            // Stands in for asking a service keeping count across agents
+            tokio::task::yield_now().await;
+            self.sends += usize::from(function.name().starts_with("send_"));
+            (self.sends > 1)
+                .then(|| PolicyViolation::Standard("only one message may be sent".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn policies_keep_state_across_checks() {
+        let send = |id: &str, message: &str| {
+            MockLlm::assistant_tool_call(
+                id,
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": message },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            )
+        };
+        let model = LlmClient::mock(MockLlm::new(vec![send("call_1", "And dinner?")]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![MetaFunction::new("send_slack_message_labeled".to_string())],
+        );
+        let label = inbox_label(Integrity::trusted());
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                MetaValue::new(Message::Chat(send("call_0", "Lunch?")), label),
+                SingleSend { sends: 0 },
+            )
+            .await
+            .expect("Failed to run");
+        assert!(answer.contains("only one message may be sent"));
+    }
+
+    // Run a send denied by the policy with the `handler` and the approval `gate`, if any, returning
+    // the last message the model was queried with and the events of the run
+    async fn recover_with<H: ViolationHandler + 'static>(
+        handler: H,
+        gate: Option<ToolGate>,
+    ) -> (String, Vec<Event>) {
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_1",
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": "See https://fides.github.io/x" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            ),
+            MockLlm::assistant_text("Done."),
+        ]));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            model,
+            vec![
+                MetaFunction::new("read_emails_labeled".to_string()),
+                MetaFunction::new("send_slack_message_labeled".to_string()),
+            ],
+        )
+        .with_observer(Collect(events.clone()))
+        .with_violation_handler(handler);
+        if let Some(gate) = gate {
+            planning_loop = planning_loop.with_approval_gate(gate);
+        }
+        let request = trusted_request(
+            "read_emails_labeled",
+            json!({ "count": { "kind": "value", "value": "5" } }),
+        );
+        let answer = planning_loop
+            .run_with_policy(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                request,
+                Policy::new(policy_no_untrusted_url),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "Done.");
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let last_message = serde_json::to_string(requests[1].last().unwrap()).unwrap();
+        let events = events.lock().unwrap().clone();
+        (last_message, events)
+    }
+
+    #[tokio::test]
+    async fn denied_calls_are_recovered_from() {
+        // The model is told about the denial, and finishes on its own
+        let (last_message, events) =
+            recover_with(|_: &Action, _: &PolicyViolation| Recovery::SkipAction, None).await;
+        assert!(last_message.contains("was not made"));
+        assert!(last_message.contains("untrusted link"));
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, Event::Recovered(2, Recovery::SkipAction)))
+        );
+        // The skipped send left no trace, so the answer took its place
+        let checked_at_send = events
+            .iter()
+            .filter(|event| matches!(event, Event::PolicyChecked(2, _)))
+            .count();
+        assert_eq!(checked_at_send, 2);
+
+        // The gate approves the send, which is made despite the policy, though it does not gate
+        // sends otherwise
+        let approving = ToolGate::new(&[], |_| async { Decision::Approve });
+        let (last_message, events) = recover_with(
+            |_: &Action, _: &PolicyViolation| Recovery::RequireHumanApproval,
+            Some(approving),
+        )
+        .await;
+        assert!(last_message.contains("Message sent!"));
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, Event::Reviewed(2, Decision::Approve)))
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_which_are_not_made_leave_no_trace() {
+        // Steps the policy ruled on in a run whose request is a send, which the `gate`, if any,
+        // reviews and the `validator` checks
+        async fn checked_steps(gate: Option<ToolGate>, validator: Validator) -> Vec<usize> {
+            let model = LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text("Done.")]));
+            let events = Arc::new(Mutex::new(vec![]));
+            let mut planning_loop = PlanningLoop::new(
+                TaintTrackingPlanner::new(vec![]),
+                model,
+                vec![
+                    MetaFunction::new("send_slack_message_labeled".to_string())
+                        .with_validator(validator),
+                ],
+            )
+            .with_observer(Collect(events.clone()));
+            if let Some(gate) = gate {
+                planning_loop = planning_loop.with_approval_gate(gate);
+            }
+            let request = trusted_request(
+                "send_slack_message_labeled",
+                json!({
+                    "channel": { "kind": "value", "value": "bob.sheffield@magnet.com" },
+                    "message": { "kind": "value", "value": "Lunch?" },
+                    "preview": { "kind": "value", "value": "false" },
+                }),
+            );
+            let answer = planning_loop
+                .run_with_policy(
+                    ConversationHistory::new(vec![]),
+                    &mut Datastore::default(),
+                    request,
+                    Policy::new(policy_no_untrusted_url),
+                )
+                .await
+                .expect("Failed to run");
+            assert_eq!(answer, "Done.");
+            let events = events.lock().unwrap();
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    Event::PolicyChecked(step, _) => Some(*step),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        let denying = ToolGate::new(&["send_slack_message_labeled"], |_| async {
+            Decision::Deny("Not now".to_string())
+        });
+        // The query after the denied send takes its step, as the trace has the same length as
+        // before the send
+        let steps = checked_steps(Some(denying), Validator::one_of("preview", &["false"])).await;
+        assert_eq!(steps, vec![0, 0, 1]);
+        // Same for a send with arguments which do not make sense
+        let steps = checked_steps(None, Validator::one_of("preview", &["true"])).await;
+        assert_eq!(steps, vec![0, 0, 1]);
+    }
 }
@@ -0,0 +1,130 @@
+//! Observers are notified about noteworthy events happening while a [`PlanningLoop`] runs, such
+//! that users can monitor and tune their agents without the loop failing on them.
+//!
+//! [`PlanningLoop`]: super::PlanningLoop
+use super::{approval::Decision, honeypot::Compromise, recovery::Recovery};
+use crate::{Action, quorum::Endorsement, quota::QuotaWarning, tools::EmailLabel};
+
+/// Noteworthy event happening during a run of the planning loop
+#[derive(Debug, Clone)]
+pub enum Event {
+    LabelCreep(Box<LabelCreep>),
+    // The action at the given step of the trace was checked against the policy, with the
+    // explanation of the violation if it was not allowed.
+    PolicyChecked(usize, Option<String>),
+    // The action at the given step of the trace would have been denied by the shadow check, with
+    // the given explanation, but was not blocked for it
+    ShadowViolation(usize, String),
+    // The integrity of the result of the action at the given step of the trace was decided by the
+    // integrity quorum
+    Endorsed(usize, Endorsement),
+    // The model called a decoy tool, and the run was aborted
+    Compromised(Box<Compromise>),
+    // The spending of a user or session got near the limit of its quota
+    QuotaNearing(QuotaWarning),
+    // The action at the given step of the trace was denied by the policy, and replaced with the
+    // given variant of it which complies
+    Repaired(usize, Box<Action>),
+    // The result of the tool call at the given step of the trace looked like a prompt injection,
+    // for the given reasons
+    InjectionDetected(usize, Vec<String>),
+    // The tool call at the given step of the trace was rewritten by the sanitizers into the given
+    // action before being checked against the policy
+    Sanitized(usize, Box<Action>),
+    // The action at the given step of the trace was denied by the policy, and the violation
+    // handler decided to recover from it as given
+    Recovered(usize, Recovery),
+    // The tool call at the given step of the trace was reviewed by the approval gate before being
+    // made, with the given decision
+    Reviewed(usize, Decision),
+    // The tool call at the given step of the trace failed with the given error, which was handed
+    // back to the model
+    ToolFailed(usize, String),
+    // The conversation of the query at the given step of the trace did not fit in the context
+    // window, and was compacted from the first to the second estimate of its prompt tokens
+    ContextCompacted(usize, u32, u32),
+}
+
+/// Warning issued when a tool result drove the label of the conversation to its most restrictive
+/// value. From there on, every action carries that label, which usually ends in policy denials
+/// later in the run.
+#[derive(Debug, Clone)]
+pub struct LabelCreep {
+    // Index in the trace of the action whose result caused the creep
+    pub step: usize,
+    // Name of the function whose result caused the creep
+    pub function: String,
+    // Id of the tool call whose result caused the creep
+    pub tool_call_id: String,
+    // Label of the conversation before and after joining the label of the tool result
+    pub before: EmailLabel,
+    pub after: EmailLabel,
+}
+
+/// Receives the events of a planning loop
+pub trait Observer: Send {
+    fn notify(&mut self, event: &Event);
+}
+
+/// Observer printing every event to stdout
+#[cfg(feature = "telemetry")]
+pub struct LogObserver;
+
+#[cfg(feature = "telemetry")]
+impl Observer for LogObserver {
+    fn notify(&mut self, event: &Event) {
+        match event {
+            Event::LabelCreep(creep) => println!(
+                "Warning: the result of `{}` (tool call {:?}, step {}) made the conversation \
+                label as restrictive as it gets; every following action will carry it. \
+                Consider using fewer or more specific tool calls.\n{:#?}",
+                creep.function, creep.tool_call_id, creep.step, creep.after
+            ),
+            Event::PolicyChecked(step, Some(violation)) => {
+                println!("Policy denied the action at step {step}: {violation}")
+            }
+            Event::PolicyChecked(step, None) => {
+                println!("Policy allowed the action at step {step}")
+            }
+            Event::ShadowViolation(step, violation) => {
+                println!("Shadow policy would have denied the action at step {step}: {violation}")
+            }
+            Event::Endorsed(step, endorsement) => println!(
+                "The result of the action at step {step} is {:?}: {:?}",
+                endorsement.integrity, endorsement.verdicts
+            ),
+            Event::Compromised(compromise) => println!(
+                "Alert: the model called the decoy tool `{}` (tool call {:?}, step {}) with {}; \
+                the conversation is compromised and the run was aborted.",
+                compromise.function, compromise.tool_call_id, compromise.step, compromise.arguments
+            ),
+            Event::QuotaNearing(warning) => println!(
+                "Warning: `{}` used {} of its {:?} quota of {}",
+                warning.scope, warning.used, warning.kind, warning.limit
+            ),
+            Event::Repaired(step, action) => {
+                println!("Repaired the action at step {step}, denied by the policy: {action:?}")
+            }
+            Event::InjectionDetected(step, findings) => println!(
+                "Warning: the result of the tool call at step {step} looks like a prompt \
+                injection: {findings:?}"
+            ),
+            Event::Sanitized(step, action) => {
+                println!("Sanitized the tool call at step {step} into: {action:?}")
+            }
+            Event::Recovered(step, recovery) => {
+                println!("Recovered from the denial of the action at step {step}: {recovery:?}")
+            }
+            Event::Reviewed(step, decision) => {
+                println!("The tool call at step {step} was reviewed: {decision:?}")
+            }
+            Event::ToolFailed(step, err) => {
+                println!("The tool call at step {step} failed: {err}")
+            }
+            Event::ContextCompacted(step, before, after) => println!(
+                "The conversation of the query at step {step} was compacted from about {before} \
+                to {after} tokens"
+            ),
+        }
+    }
+}
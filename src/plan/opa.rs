@@ -0,0 +1,356 @@
+//! Policy checks delegated to an Open Policy Agent sidecar, such that the agent is governed by the
+//! same policy-as-code as the rest of the organisation.
+//!
+//! Each check posts an input document to the OPA data API, holding the action about to be taken
+//! with its label and arguments, along with a summary of the actions taken before it: how many
+//! there were, the functions they called and the join of their labels. The check keeps the summary
+//! up to date as the run goes on, such that neither the input nor the work of building it grows
+//! with the run. The Rego rule queried decides in any of the usual shapes:
+//!
+//! - a boolean, allowing the action when an `allow` rule is `true`, or when a `deny` rule is
+//!   `false`
+//! - a set of messages, such as `deny contains msg if { ... }`, allowing it when empty
+//! - an object with an `allow` boolean and an optional `reason`
+//!
+//! What a boolean or a set means is told by the last segment of the path of the rule, or by
+//! [`OpaPolicy::with_kind`]. Anything else, including ambiguous results, the sidecar being
+//! unreachable or the rule being undefined, denies the action, as a guardrail which fails open is
+//! no guardrail.
+use super::{
+    audit::action_json,
+    labeled::{TaintLabel, Trace},
+    policy::{PolicyCheck, PolicyViolation},
+};
+use crate::{Action, Datastore, State, ifc::Lattice, registry::label_manifest, tools::EmailLabel};
+use serde_json::{Value, json};
+use std::{collections::BTreeSet, time::Duration};
+
+// Time the sidecar is given to decide by default
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the booleans and sets decided by a rule mean
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleKind {
+    // `true` allows the action
+    Allow,
+    // `true`, or any message, denies the action
+    Deny,
+}
+
+impl RuleKind {
+    // Kind of the rule at `path`, told by its last segment, if any
+    fn of(path: &str) -> Option<Self> {
+        match path.rsplit('/').next()? {
+            "allow" => Some(Self::Allow),
+            "deny" => Some(Self::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// Check querying the rule at `path` of the policies loaded into an OPA sidecar
+#[derive(Debug, Clone)]
+pub struct OpaPolicy {
+    client: reqwest::Client,
+    // Address of the data API of the rule, such as `http://localhost:8181/v1/data/agent/deny`
+    url: String,
+    path: String,
+    // Missing if the results of the rule can only be told apart by their shape
+    kind: Option<RuleKind>,
+    // Summary of the actions of the run checked so far
+    history: History,
+}
+
+impl OpaPolicy {
+    /// Query the rule at `path`, such as `agent/deny`, of the OPA sidecar listening at `endpoint`
+    pub fn sidecar(endpoint: &str, path: &str) -> Self {
+        let path = path.trim_matches('/').to_string();
+        Self {
+            client: client(TIMEOUT),
+            url: format!("{}/v1/data/{path}", endpoint.trim_end_matches('/')),
+            kind: RuleKind::of(&path),
+            path,
+            history: History::default(),
+        }
+    }
+
+    /// Take the booleans and sets decided by the rule as a rule of `kind`, whatever it is called
+    pub fn with_kind(mut self, kind: RuleKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Deny the actions the sidecar takes longer than `timeout` to decide on
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = client(timeout);
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn query(&self, input: Value) -> Result<Value, reqwest::Error> {
+        self.client
+            .post(&self.url)
+            .json(&json!({ "input": input }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+// Client of the sidecar, failing the requests left unanswered for longer than `timeout`
+fn client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("Failed to build the HTTP client")
+}
+
+/// Summary of the actions taken before the one checked
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    steps: usize,
+    functions: BTreeSet<String>,
+    // Join of the labels of the actions, missing until one can be told as an `EmailLabel`
+    label: Option<EmailLabel>,
+}
+
+impl History {
+    /// Bring the summary up to date with the actions of the `trace` before its last one. Actions
+    /// summarized already are skipped, unless the trace is one of a new run.
+    pub fn update<L: TaintLabel>(&mut self, trace: &Trace<L>) {
+        let before = trace.value().len().saturating_sub(1);
+        if before < self.steps {
+            *self = Self::default();
+        }
+        for step in &trace.value()[self.steps..before] {
+            let (action, label) = step.raw_parts();
+            if let Action::MakeCall(function, _, _) = action {
+                self.functions.insert(function.name().to_string());
+            }
+            self.label = match (self.label.take(), label.to_email_label()) {
+                (Some(joined), Some(label)) => joined.join(label),
+                (joined, label) => joined.or(label),
+            };
+        }
+        self.steps = before;
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "steps": self.steps,
+            "functions": self.functions,
+            "label": self.label.as_ref().map(label_manifest),
+        })
+    }
+}
+
+/// Input document OPA evaluates the last action of the `trace` against, given the `history` of
+/// the actions before it. Labels which cannot be told as `EmailLabel`s are left out.
+pub fn opa_input<L: TaintLabel>(trace: &Trace<L>, history: &History) -> Value {
+    let Some(last) = trace.value().last() else {
+        return json!({ "action": null, "history": history.to_json() });
+    };
+    let (action, label) = last.raw_parts();
+    let mut input = json!({
+        "step": trace.value().len() - 1,
+        "action": action_json(action),
+        "label": label.to_email_label().as_ref().map(label_manifest),
+        "history": history.to_json(),
+    });
+    // Rules look at arguments by name rather than parsing them out of the call themselves
+    if let Action::MakeCall(_, args, _) = action {
+        input["args"] = serde_json::from_str(args.value()).unwrap_or(Value::Null);
+    }
+    input
+}
+
+// Violation, if any, of the `result` of a query for the rule at `path` of `kind`
+fn decide(path: &str, kind: Option<RuleKind>, result: Option<&Value>) -> Option<PolicyViolation> {
+    let violation = |reason: String| Some(PolicyViolation::Standard(reason));
+    let ambiguous = |result: &Value| {
+        violation(format!(
+            "`{path}` decided {result}, which is ambiguous for a rule which is neither an \
+            `allow` nor a `deny` rule"
+        ))
+    };
+    match result {
+        Some(Value::Bool(decision)) => match (kind, decision) {
+            (Some(RuleKind::Allow), true) | (Some(RuleKind::Deny), false) => None,
+            (Some(_), _) => violation(format!("`{path}` does not allow the action")),
+            (None, _) => ambiguous(&Value::Bool(*decision)),
+        },
+        Some(result @ Value::Array(_)) if kind != Some(RuleKind::Deny) => ambiguous(result),
+        Some(Value::Array(reasons)) if reasons.is_empty() => None,
+        Some(Value::Array(reasons)) => violation(
+            reasons
+                .iter()
+                .map(|reason| match reason {
+                    Value::String(reason) => reason.clone(),
+                    reason => reason.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+        ),
+        Some(Value::Object(decision)) => match decision.get("allow") {
+            Some(Value::Bool(true)) => None,
+            Some(Value::Bool(false)) => violation(
+                decision
+                    .get("reason")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("`{path}` does not allow the action")),
+            ),
+            _ => violation(format!("`{path}` decided without an `allow` field")),
+        },
+        Some(result) => violation(format!("`{path}` decided {result}, which is no decision")),
+        None => violation(format!("`{path}` is not defined")),
+    }
+}
+
+impl<L: TaintLabel> PolicyCheck<L> for OpaPolicy {
+    async fn check(
+        &mut self,
+        trace: &Trace<L>,
+        _state: &State,
+        _datastore: &Datastore,
+    ) -> Option<PolicyViolation> {
+        self.history.update(trace);
+        match self.query(opa_input(trace, &self.history)).await {
+            Ok(response) => decide(&self.path, self.kind, response.get("result")),
+            Err(err) => Some(PolicyViolation::Standard(format!(
+                "the policy agent could not be reached ({err})"
+            ))),
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Args, Function, Integrity, ProductLattice,
+        plan::labeled::ActionLabel,
+        tools::{EmailAddressUniverse, INBOX, MetaValue, readers_label},
+    };
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Sidecar answering each request with the next of the `results`, handing back the inputs
+    fn sidecar(results: Vec<Value>) -> (String, thread::JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            results
+                .into_iter()
+                .map(|result| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    let response = json!({ "result": result }).to_string();
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                        content-length: {}\r\nconnection: close\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .unwrap();
+                    let request: Value = serde_json::from_slice(&body).unwrap();
+                    request["input"].clone()
+                })
+                .collect()
+        });
+        (endpoint, handle)
+    }
+
+    #[tokio::test]
+    async fn opa_decides_on_the_actions() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            Integrity::untrusted(),
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let mut trace: Trace<ActionLabel> = Trace::default();
+        let read = Action::MakeCall(
+            Function::new("read_emails".to_string()),
+            Args::new(json!({ "count": 5 }).to_string()),
+            "call_0".to_string(),
+        );
+        trace.value_mut().push(MetaValue::new(read, label.clone()));
+        trace.value_mut().push(MetaValue::new(
+            Action::MakeCall(
+                Function::new("send_slack_message".to_string()),
+                Args::new(json!({ "channel": "eve@evil.com", "message": "Hi" }).to_string()),
+                "call_1".to_string(),
+            ),
+            label,
+        ));
+        let (endpoint, inputs) = sidecar(vec![
+            json!([]),
+            json!(["eve@evil.com is not a colleague"]),
+            json!({ "allow": false, "reason": "no sends after reading emails" }),
+        ]);
+        let mut policy = OpaPolicy::sidecar(&endpoint, "/agent/deny");
+        assert_eq!(policy.url(), format!("{endpoint}/v1/data/agent/deny"));
+
+        let mut check = async || {
+            PolicyCheck::check(
+                &mut policy,
+                &trace,
+                &State::new(vec![]),
+                &Datastore::default(),
+            )
+            .await
+            .map(|violation| violation.explanation().to_string())
+        };
+        assert_eq!(check().await, None);
+        assert_eq!(
+            check().await.as_deref(),
+            Some("eve@evil.com is not a colleague")
+        );
+        assert_eq!(
+            check().await.as_deref(),
+            Some("no sends after reading emails")
+        );
+
+        let input = &inputs.join().unwrap()[0];
+        assert_eq!(input["action"]["function"], "send_slack_message");
+        assert_eq!(input["args"]["channel"], "eve@evil.com");
+        assert_eq!(input["label"]["integrity"], "untrusted");
+        assert_eq!(input["history"]["steps"], 1);
+        assert_eq!(input["history"]["functions"], json!(["read_emails"]));
+        assert_eq!(input["history"]["label"]["integrity"], "untrusted");
+        // Rules which are not defined deny every action
+        let deny = Some(RuleKind::Deny);
+        assert!(decide("agent/deny", deny, None).is_some());
+        // A `true` deny rule denies, and booleans of rules of no known kind are ambiguous
+        assert!(decide("agent/deny", deny, Some(&json!(true))).is_some());
+        assert!(decide("agent/deny", deny, Some(&json!(false))).is_none());
+        assert!(decide("agent/allow", Some(RuleKind::Allow), Some(&json!(true))).is_none());
+        assert!(decide("agent/check", None, Some(&json!(true))).is_some());
+        assert!(decide("agent/check", None, Some(&json!([]))).is_some());
+    }
+}
@@ -0,0 +1,480 @@
+//! Several agents working at once, each on its own task. The [`Orchestrator`] runs the planning
+//! loops of its agents concurrently, each with its own conversation, its own namespace in its
+//! datastore and its own clearance.
+//!
+//! Agents pass each other [`AgentMessage`]s, which carry the label of everything they were made
+//! of. A message is only delivered to an agent whose clearance it flows to, and the run answering
+//! it starts with its label, such that the receiving loop checks everything it does with the
+//! message against its own policy. The answer of an agent can be forwarded to another agent in
+//! the same way, with the label the loop gave it.
+//!
+//! Each agent is bound to its own [`AgentChannel`] when it is registered, such that its messages
+//! cannot be sent under the name of another agent. The application sends on a channel of its own.
+use super::{ClearanceViolation, LabeledTool, Plan, PlanError, Policy, TaintLabel};
+use crate::{
+    Action, Datastore, Message, MetaFunction, PlanningLoop, State,
+    tools::{EmailLabel, MetaValue},
+};
+use futures::future::join_all;
+use std::collections::VecDeque;
+use tokio::sync::mpsc;
+
+// Rounds a run of the orchestrator takes by default before handing the pending messages back
+const MAX_ROUNDS: usize = 16;
+
+/// Message from one agent to another, labeled with everything it was made of
+#[derive(Debug, Clone)]
+pub struct AgentMessage<L: TaintLabel = EmailLabel> {
+    // Agent which sent the message, or none if the application did
+    from: Option<String>,
+    to: String,
+    content: MetaValue<String, L>,
+}
+
+impl<L: TaintLabel> AgentMessage<L> {
+    fn new(from: Option<&str>, to: &str, content: MetaValue<String, L>) -> Self {
+        Self {
+            from: from.map(str::to_string),
+            to: to.to_string(),
+            content,
+        }
+    }
+
+    /// Agent which sent the message, or `None` if the application did
+    pub fn from(&self) -> Option<&str> {
+        self.from.as_deref()
+    }
+
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+
+    pub fn content(&self) -> &MetaValue<String, L> {
+        &self.content
+    }
+}
+
+/// Sending end of the channel between the agents of an [`Orchestrator`], sending on behalf of the
+/// agent it was bound to, or of the application
+#[derive(Clone)]
+pub struct AgentChannel<L: TaintLabel = EmailLabel> {
+    from: Option<String>,
+    sender: mpsc::UnboundedSender<AgentMessage<L>>,
+}
+
+impl<L: TaintLabel> AgentChannel<L> {
+    /// Send the `content` to the agent called `to`, which gets it on the next round
+    pub fn send(&self, to: &str, content: MetaValue<String, L>) {
+        // The orchestrator holds the receiving end for as long as channels can be made
+        let _ = self
+            .sender
+            .send(AgentMessage::new(self.from.as_deref(), to, content));
+    }
+}
+
+/// Agent run by an [`Orchestrator`], answering the messages it gets with its own planning loop
+pub struct Agent<P, L = EmailLabel, F = MetaFunction>
+where
+    L: TaintLabel,
+    F: LabeledTool<L>,
+    P: Plan<State, MetaValue<Message, L>>,
+{
+    name: String,
+    planning_loop: PlanningLoop<State, MetaValue<Message, L>, F, P>,
+    policy: Policy<L>,
+    clearance: L,
+    // Conversation every message is answered from, such as one holding the system prompt
+    state: State,
+    datastore: Datastore,
+    // Agent the answers are sent to, if any
+    forward: Option<String>,
+    // Channel sending on behalf of the agent, bound when the agent is registered
+    channel: Option<AgentChannel<L>>,
+    // Messages waiting to be answered, in the order they were delivered
+    inbox: VecDeque<AgentMessage<L>>,
+}
+
+impl<P, L, F> Agent<P, L, F>
+where
+    L: TaintLabel,
+    F: LabeledTool<L>,
+    P: Plan<State, MetaValue<Message, L>, Action = (Action, L)>,
+{
+    /// Agent called `name`, checking its runs against the `policy` and only reading messages, or
+    /// handing over answers, whose label flows to its `clearance`
+    pub fn new(
+        name: &str,
+        planning_loop: PlanningLoop<State, MetaValue<Message, L>, F, P>,
+        policy: Policy<L>,
+        clearance: L,
+    ) -> Self {
+        let mut datastore = Datastore::default();
+        datastore.enter(name);
+        Self {
+            name: name.to_string(),
+            planning_loop: planning_loop.with_clearance(clearance.clone()),
+            policy,
+            clearance,
+            state: State::default(),
+            datastore,
+            forward: None,
+            channel: None,
+            inbox: VecDeque::new(),
+        }
+    }
+
+    /// Answer every message from the conversation `state`
+    pub fn with_state(mut self, state: State) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Run with the `datastore`, in the namespace of the agent
+    pub fn with_datastore(mut self, mut datastore: Datastore) -> Self {
+        datastore.enter(&self.name);
+        self.datastore = datastore;
+        self
+    }
+
+    /// Send every answer to the agent called `to`, labeled like the answer
+    pub fn forward_to(mut self, to: &str) -> Self {
+        self.forward = Some(to.to_string());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn clearance(&self) -> &L {
+        &self.clearance
+    }
+
+    pub fn datastore(&self) -> &Datastore {
+        &self.datastore
+    }
+
+    /// Channel sending messages on behalf of the agent, once it is registered with an
+    /// [`Orchestrator`]
+    pub fn channel(&self) -> Option<&AgentChannel<L>> {
+        self.channel.as_ref()
+    }
+
+    pub fn planning_loop_mut(&mut self) -> &mut PlanningLoop<State, MetaValue<Message, L>, F, P> {
+        &mut self.planning_loop
+    }
+
+    // Answer the `message`, starting the run with its label
+    async fn answer(
+        &mut self,
+        message: &AgentMessage<L>,
+    ) -> Result<MetaValue<String, L>, PlanError> {
+        let (content, label) = message.content.raw_parts();
+        self.planning_loop
+            .run_labeled_with_policy(
+                self.state.clone(),
+                &mut self.datastore,
+                MetaValue::new(Message::user(content), label.clone()),
+                self.policy.clone(),
+            )
+            .await
+    }
+}
+
+/// What became of a message sent to an agent
+#[derive(Debug)]
+pub struct AgentOutcome<L: TaintLabel = EmailLabel> {
+    pub message: AgentMessage<L>,
+    // The labeled answer of the agent, or why the message was not answered
+    pub answer: Result<MetaValue<String, L>, PlanError>,
+}
+
+/// Agents answering their messages concurrently and passing messages to each other
+pub struct Orchestrator<P, L = EmailLabel, F = MetaFunction>
+where
+    L: TaintLabel,
+    F: LabeledTool<L>,
+    P: Plan<State, MetaValue<Message, L>>,
+{
+    agents: Vec<Agent<P, L, F>>,
+    sender: mpsc::UnboundedSender<AgentMessage<L>>,
+    receiver: mpsc::UnboundedReceiver<AgentMessage<L>>,
+    max_rounds: usize,
+}
+
+impl<P, L, F> Default for Orchestrator<P, L, F>
+where
+    L: TaintLabel,
+    F: LabeledTool<L>,
+    P: Plan<State, MetaValue<Message, L>, Action = (Action, L)>,
+{
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            agents: vec![],
+            sender,
+            receiver,
+            max_rounds: MAX_ROUNDS,
+        }
+    }
+}
+
+impl<P, L, F> Orchestrator<P, L, F>
+where
+    L: TaintLabel,
+    F: LabeledTool<L>,
+    P: Plan<State, MetaValue<Message, L>, Action = (Action, L)>,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the `agent`, binding it to a channel sending on its behalf
+    pub fn with_agent(mut self, mut agent: Agent<P, L, F>) -> Self {
+        agent.channel = Some(AgentChannel {
+            from: Some(agent.name.clone()),
+            sender: self.sender.clone(),
+        });
+        self.agents.push(agent);
+        self
+    }
+
+    /// Stop a run after `rounds` rounds, leaving the messages still pending for the next run, such
+    /// that agents forwarding answers to each other cannot keep it going forever
+    pub fn with_max_rounds(mut self, rounds: usize) -> Self {
+        self.max_rounds = rounds;
+        self
+    }
+
+    /// Channel sending messages on behalf of the application. Agents send on the channel they
+    /// were bound to when registered.
+    pub fn channel(&self) -> AgentChannel<L> {
+        AgentChannel {
+            from: None,
+            sender: self.sender.clone(),
+        }
+    }
+
+    pub fn agent(&self, name: &str) -> Option<&Agent<P, L, F>> {
+        self.agents.iter().find(|agent| agent.name == name)
+    }
+
+    pub fn agent_mut(&mut self, name: &str) -> Option<&mut Agent<P, L, F>> {
+        self.agents.iter_mut().find(|agent| agent.name == name)
+    }
+
+    /// Deliver the messages sent so far and let the agents answer them, each agent answering one
+    /// message per round while all of them run concurrently. Rounds go on until no message is
+    /// left, or until the maximum number of rounds is reached. Returns what became of every
+    /// message, in the order they were handled.
+    pub async fn run(&mut self) -> Vec<AgentOutcome<L>> {
+        let mut outcomes = vec![];
+        for _ in 0..self.max_rounds {
+            outcomes.extend(self.deliver());
+            let answering = self
+                .agents
+                .iter_mut()
+                .filter_map(|agent| {
+                    let message = agent.inbox.pop_front()?;
+                    Some(async move {
+                        let answer = agent.answer(&message).await;
+                        // Answers are forwarded with their label, and checked on delivery
+                        if let (Ok(answer), Some(to), Some(channel)) =
+                            (&answer, &agent.forward, &agent.channel)
+                        {
+                            channel.send(to, answer.clone());
+                        }
+                        AgentOutcome { message, answer }
+                    })
+                })
+                .collect::<Vec<_>>();
+            if answering.is_empty() {
+                break;
+            }
+            outcomes.extend(join_all(answering).await);
+        }
+        outcomes
+    }
+
+    // Move the messages sent so far to the inboxes of the agents they are allowed to reach,
+    // returning the outcome of those which are not
+    fn deliver(&mut self) -> Vec<AgentOutcome<L>> {
+        let mut refused = vec![];
+        while let Ok(message) = self.receiver.try_recv() {
+            let Some(agent) = self
+                .agents
+                .iter_mut()
+                .find(|agent| agent.name == message.to)
+            else {
+                let answer = Err(PlanError::UnknownAgent(message.to.clone()));
+                refused.push(AgentOutcome { message, answer });
+                continue;
+            };
+            // Agents never read what they are not cleared for
+            let label = message.content.label();
+            let cleared = label <= &agent.clearance;
+            if !cleared {
+                let violation = ClearanceViolation::new(
+                    label.to_email_label(),
+                    agent.clearance.to_email_label(),
+                );
+                let answer = Err(PlanError::ClearanceViolation(Box::new(violation)));
+                refused.push(AgentOutcome { message, answer });
+                continue;
+            }
+            agent.inbox.push_back(message);
+        }
+        refused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Integrity, ProductLattice, TaintTrackingPlanner,
+        ifc::InternedEmailLabel,
+        mock::MockLlm,
+        openai::LlmClient,
+        plan::{InternedFunction, Trace},
+        policy::policy_no_untrusted_url,
+        tools::{EmailAddressUniverse, INBOX, readers_label},
+    };
+    use std::collections::HashSet;
+
+    fn readable_by(reader: &str) -> EmailLabel {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        ProductLattice::new(
+            Integrity::trusted(),
+            readers_label(HashSet::from([reader.to_string()]), universe).unwrap(),
+        )
+    }
+
+    fn agent(name: &str, answers: &[&str], reader: &str) -> Agent<TaintTrackingPlanner> {
+        let planning_loop = PlanningLoop::new(
+            TaintTrackingPlanner::new(vec![]),
+            LlmClient::mock(MockLlm::new(
+                answers
+                    .iter()
+                    .map(|answer| MockLlm::assistant_text(answer))
+                    .collect(),
+            )),
+            vec![],
+        );
+        Agent::new(
+            name,
+            planning_loop,
+            Policy::new(policy_no_untrusted_url),
+            readable_by(reader),
+        )
+    }
+
+    #[tokio::test]
+    async fn agents_only_read_the_messages_they_are_cleared_for() {
+        let mut orchestrator = Orchestrator::new()
+            .with_agent(
+                agent("reader", &["Payroll is due."], "alice.hudson@magnet.com")
+                    .forward_to("writer"),
+            )
+            .with_agent(agent(
+                "writer",
+                &["Reminder drafted."],
+                "alice.hudson@magnet.com",
+            ))
+            .with_agent(agent("outsider", &[], "bob.sheffield@magnet.com"));
+        let user = orchestrator.channel();
+        let task = MetaValue::new(
+            "When is payroll due?".to_string(),
+            readable_by("alice.hudson@magnet.com"),
+        );
+        user.send("reader", task.clone());
+        user.send("outsider", task.clone());
+        user.send("nobody", task);
+
+        let outcomes = orchestrator.run().await;
+        let answers: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| (outcome.message.to(), outcome.answer.as_ref()))
+            .collect();
+        assert!(matches!(
+            &answers[..],
+            [
+                ("outsider", Err(PlanError::ClearanceViolation(_))),
+                ("nobody", Err(PlanError::UnknownAgent(_))),
+                ("reader", Ok(_)),
+                ("writer", Ok(answer)),
+            ] if answer.value() == "Reminder drafted."
+        ));
+        // The application sends under no agent's name
+        assert_eq!(outcomes[2].message.from(), None);
+        // The writer is handed the answer of the reader, with its label
+        let forwarded = &outcomes[3].message;
+        assert_eq!(forwarded.from(), Some("reader"));
+        assert_eq!(forwarded.content().value(), "Payroll is due.");
+        assert_eq!(
+            forwarded.content().label(),
+            &readable_by("alice.hudson@magnet.com")
+        );
+        assert_eq!(
+            orchestrator
+                .agent("writer")
+                .unwrap()
+                .datastore()
+                .namespace(),
+            Some("writer")
+        );
+    }
+
+    #[tokio::test]
+    async fn agents_run_with_interned_labels() {
+        let readable_by =
+            |reader| InternedEmailLabel::from_email_label(readable_by(reader)).unwrap();
+        let agent = |name, answer, reader| {
+            let planning_loop = PlanningLoop::new(
+                TaintTrackingPlanner::<InternedEmailLabel>::new(vec![]),
+                LlmClient::mock(MockLlm::new(vec![MockLlm::assistant_text(answer)])),
+                Vec::<InternedFunction>::new(),
+            );
+            Agent::new(
+                name,
+                planning_loop,
+                Policy::new(|_: &Trace<InternedEmailLabel>| None),
+                readable_by(reader),
+            )
+        };
+        let mut orchestrator = Orchestrator::new()
+            .with_agent(
+                agent("reader", "Payroll is due.", "alice.hudson@magnet.com").forward_to("writer"),
+            )
+            .with_agent(agent(
+                "writer",
+                "Reminder drafted.",
+                "alice.hudson@magnet.com",
+            ));
+        // Agents are bound to their channel when they are registered
+        let channel = orchestrator
+            .agent("writer")
+            .unwrap()
+            .channel()
+            .unwrap()
+            .clone();
+        channel.send(
+            "reader",
+            MetaValue::new(
+                "Any news?".to_string(),
+                readable_by("alice.hudson@magnet.com"),
+            ),
+        );
+
+        let outcomes = orchestrator.run().await;
+        let senders: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| (outcome.message.from(), outcome.message.to()))
+            .collect();
+        assert_eq!(
+            senders,
+            vec![(Some("writer"), "reader"), (Some("reader"), "writer")]
+        );
+        assert!(outcomes.iter().all(|outcome| outcome.answer.is_ok()));
+    }
+}
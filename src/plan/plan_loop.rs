@@ -1,22 +1,217 @@
-use super::{Plan, PlanError};
-use crate::{Action, Call, Datastore, Function, Message, State, openai::LlmClient};
-use std::marker::PhantomData;
+#[cfg(feature = "telemetry")]
+use super::sink::{TraceEntry, TraceStream};
+use super::{
+    Plan, PlanError,
+    approval::{ApprovalGate, ApprovalRequest, Decision, denied_message},
+    audit::AuditLog,
+    checkpoint::{CheckpointLog, LoopCheckpoint},
+    honeypot::{Compromise, Honeypot},
+    observer::{Event, Observer},
+    recovery::ViolationHandler,
+    repair::PlanRepair,
+    sanitize::Sanitizer,
+};
+use crate::{
+    Action, Args, Call, CustomOutcome, Datastore, Function, Message, State,
+    authority::Authority,
+    cache::ToolCache,
+    context::{
+        Compaction, ContextWindow, compactable, summary_message, summary_request,
+        truncate_tool_results,
+    },
+    ifc::Lattice,
+    injection::InjectionDetector,
+    openai::LlmClient,
+    quorum::IntegrityQuorum,
+    quota::{QuotaUsage, Quotas},
+    registry::ToolRegistry,
+    schema::OutputSchema,
+    tokens::{TokenBudget, estimate_prompt_tokens, spent_tokens},
+    tools::{EmailLabel, MetaValue, service_authority},
+};
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionResponseMessage, ChatCompletionTool,
+        CompletionUsage, CreateChatCompletionResponse,
+    },
+};
+use serde::de::DeserializeOwned;
+use std::{
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Model usage accumulated by a planning loop over all its runs
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Usage {
+    // Number of requests sent to the model
+    pub requests: u32,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl Usage {
+    /// Account for one request to the model which reported the given `usage`, if any
+    pub fn record(&mut self, usage: Option<&CompletionUsage>) {
+        self.requests += 1;
+        if let Some(usage) = usage {
+            self.prompt_tokens += usage.prompt_tokens;
+            self.completion_tokens += usage.completion_tokens;
+        }
+    }
+}
+
+/// Action taken by [`PlanningLoop::run`], along with when it was taken and what it led to
+#[derive(Debug, Clone)]
+pub struct RunStep {
+    pub action: Action,
+    pub taken_at: SystemTime,
+    // The model's response to a query, the result of a tool call or the message a custom action
+    // continued with. Actions finishing the run lead to none.
+    pub outcome: Option<Message>,
+}
+
+/// Record of everything a run of the unlabeled loop did, for audits and replays
+#[derive(Debug, Clone, Default)]
+pub struct RunTrace {
+    steps: Vec<RunStep>,
+}
+
+impl RunTrace {
+    pub fn steps(&self) -> &[RunStep] {
+        &self.steps
+    }
+
+    /// The actions taken, in order
+    pub fn actions(&self) -> Vec<Action> {
+        self.steps.iter().map(|step| step.action.clone()).collect()
+    }
+
+    /// The model's responses, in order, such that a [`MockLlm`] can replay the run
+    ///
+    /// [`MockLlm`]: crate::mock::MockLlm
+    pub fn transcript(&self) -> Vec<ChatCompletionResponseMessage> {
+        self.steps
+            .iter()
+            .filter(|step| matches!(step.action, Action::Query(..)))
+            .filter_map(|step| match &step.outcome {
+                Some(Message::Chat(response)) => Some(response.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(super) fn from_steps(steps: Vec<RunStep>) -> Self {
+        Self { steps }
+    }
+
+    fn push(&mut self, action: Action) {
+        self.steps.push(RunStep {
+            action,
+            taken_at: SystemTime::now(),
+            outcome: None,
+        });
+    }
+
+    // Record the `outcome` of the last action, if it has none yet
+    fn settle(&mut self, outcome: &Message) {
+        if let Some(step) = self.steps.last_mut().filter(|step| step.outcome.is_none()) {
+            step.outcome = Some(outcome.clone());
+        }
+    }
+}
+
+/// Callback receiving the content of the model's answers piece by piece
+pub type AnswerStream = Box<dyn FnMut(&str) + Send>;
+
+/// Message exchanged by a planning loop, telling the label the answers of the loop carry
+pub trait LoopMessage: Clone {
+    type Label;
+}
+
+// Plain messages carry no label, so their answers are never checked against a clearance
+impl LoopMessage for Message {
+    type Label = ();
+}
+
+impl<L: Lattice> LoopMessage for MetaValue<Message, L> {
+    type Label = L;
+}
 
 /// Planning loop orchestrates the communication with the model and handles the `Planner`'s
 /// required actions.
-pub struct PlanningLoop<S, M: Clone, F: Call, P: Plan<S, M>> {
+pub struct PlanningLoop<S, M: LoopMessage, F: Call, P: Plan<S, M>> {
     // The planner used to plan the next action in the loop
-    planner: P,
+    pub(super) planner: P,
     // The LLM model used to accomplish the task
-    model: LlmClient,
+    pub(super) model: LlmClient,
     // The tools the LLM model has access to
-    tools: Vec<F>,
+    pub(super) tools: Vec<F>,
+    // Tools defined outside of this crate, called when none of the `tools` has the requested name
+    pub(super) registry: Option<ToolRegistry>,
+    // Observers notified about the events happening in the loop
+    pub(super) observers: Vec<Box<dyn Observer>>,
+    // A label is considered to have crept to its most restrictive value once it is untrusted and
+    // readable by at most this many readers.
+    pub(super) label_creep_readers: usize,
+    // Usage of the model accumulated so far
+    pub(super) usage: Usage,
+    // Stream receiving the actions of the loop as they are taken
+    #[cfg(feature = "telemetry")]
+    pub(super) trace_stream: Option<TraceStream>,
+    // Cache of tool results, together with the clearance of the contexts served by this loop
+    pub(super) tool_cache: Option<(ToolCache, EmailLabel)>,
+    // Most restrictive label the answers of this loop may carry to reach the user
+    pub(super) clearance: Option<M::Label>,
+    // Decides the integrity of tool results, instead of trusting the labels of the tools
+    pub(super) integrity_quorum: Option<IntegrityQuorum>,
+    // Looks for prompt injections in tool results before they are appended to the conversation
+    pub(super) injection_detector: Option<InjectionDetector>,
+    // Whether the delivery status of every send is appended to the result of the sending tool
+    pub(super) verify_sends: bool,
+    // Whether untrusted tool results are quarantined in the datastore, out of the conversation
+    pub(super) quarantine: bool,
+    // Authority the tools run with, which bounds the labels they may give their results
+    pub(super) authority: Authority,
+    // Receives the content of the model's answers as it is written
+    pub(super) answer_stream: Option<AnswerStream>,
+    // Tokens each run is allowed to spend on requests to the model
+    pub(super) token_budget: Option<u32>,
+    // Decoy tools offered to the model, which abort the run when called
+    pub(super) honeypot: Option<Honeypot>,
+    // Calls to decoy tools seen so far
+    pub(super) compromises: Vec<Compromise>,
+    // Policy decisions of the last run checked against a policy
+    pub(super) audit: AuditLog,
+    // Rewrites the tool calls denied by the policy into compliant variants, if any
+    pub(super) repair: Option<PlanRepair>,
+    // Rewrite the tool calls before they are checked against the policy and made
+    pub(super) sanitizers: Vec<Sanitizer>,
+    // Decides how to recover from the actions denied by the policy, which abort the run otherwise
+    pub(super) violation_handler: Option<Box<dyn ViolationHandler>>,
+    // Reviews the calls to the tools it gates before they are made
+    pub(super) approval_gate: Option<Box<dyn ApprovalGate>>,
+    // Number of actions each run is allowed to take
+    pub(super) max_iterations: Option<usize>,
+    // Time each run is allowed to take
+    pub(super) timeout: Option<Duration>,
+    // Whether the writes of a failed run to the datastore are rolled back
+    pub(super) atomic: bool,
+    // Log the state of the loop is appended to before every step
+    pub(super) checkpoint_file: Option<CheckpointLog>,
+    // Latest state of the loop, kept in memory for the job queues to resume from
+    pub(super) kept_checkpoint: Option<Mutex<Option<LoopCheckpoint>>>,
+    // Context window the conversation of every query is compacted to fit in
+    pub(super) context_window: Option<ContextWindow>,
     // Phantom data such that we can bind the type of `Message` that the planner `P` uses
     phantom_message: PhantomData<M>,
     phantom_state: PhantomData<S>,
 }
 
-impl<S, M: Clone, F: Call, P: Plan<S, M>> PlanningLoop<S, M, F, P> {
+impl<S, M: LoopMessage, F: Call, P: Plan<S, M>> PlanningLoop<S, M, F, P> {
     pub fn planner_mut(&mut self) -> &mut P {
         &mut self.planner
     }
@@ -29,6 +224,497 @@ impl<S, M: Clone, F: Call, P: Plan<S, M>> PlanningLoop<S, M, F, P> {
         &self.model
     }
 
+    /// Call the tools of the `registry` which were registered with their implementation, such
+    /// that tools defined downstream can be called without being built into the loop. The tools
+    /// the loop was created with take precedence over those of the registry.
+    pub fn with_registry(mut self, registry: ToolRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn registry(&self) -> Option<&ToolRegistry> {
+        self.registry.as_ref()
+    }
+
+    /// Register an `observer` to be notified about the events happening in the loop
+    pub fn with_observer<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Warn about label creep once the label of the conversation is untrusted and readable by at
+    /// most `readers` readers. Defaults to 1, which is the user alone.
+    pub fn with_label_creep_readers(mut self, readers: usize) -> Self {
+        self.label_creep_readers = readers;
+        self
+    }
+
+    pub fn label_creep_readers(&self) -> usize {
+        self.label_creep_readers
+    }
+
+    /// Usage of the model accumulated by the loop so far
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// Stream every action taken by the loop to `stream`
+    #[cfg(feature = "telemetry")]
+    pub fn with_trace_stream(mut self, stream: TraceStream) -> Self {
+        self.trace_stream = Some(stream);
+        self
+    }
+
+    /// Serve tool results from `cache` whenever their label can flow to `clearance`, the most
+    /// restrictive label the runs of this loop are allowed to see
+    pub fn with_tool_cache(mut self, cache: ToolCache, clearance: EmailLabel) -> Self {
+        self.tool_cache = Some((cache, clearance));
+        self
+    }
+
+    /// Only hand over answers whose label flows to `clearance`, the most restrictive label the
+    /// user or the sink reading the answers is allowed to see. Any other answer fails the run.
+    pub fn with_clearance(mut self, clearance: M::Label) -> Self {
+        self.clearance = Some(clearance);
+        self
+    }
+
+    pub fn clearance(&self) -> Option<&M::Label> {
+        self.clearance.as_ref()
+    }
+
+    /// Have `quorum` decide the integrity of every tool result
+    pub fn with_integrity_quorum(mut self, quorum: IntegrityQuorum) -> Self {
+        self.integrity_quorum = Some(quorum);
+        self
+    }
+
+    /// Have the `detector` strip the role markers from every tool result and look for prompt
+    /// injections in it, before the result is appended to the conversation
+    pub fn with_injection_detector(mut self, detector: InjectionDetector) -> Self {
+        self.injection_detector = Some(detector);
+        self
+    }
+
+    /// Verify every send the tools make by appending the delivery status of the message to the
+    /// result of the sending tool, such that the model does not finish before the send went
+    /// through. Sends cannot be undone, so the planner should not have to remember to check them.
+    pub fn with_send_verification(mut self) -> Self {
+        self.verify_sends = true;
+        self
+    }
+
+    /// Quarantine every untrusted tool result in the datastore, such that the model only ever
+    /// sees its handle. Only the tools the datastore sanctions, such as `summarize_labeled`,
+    /// dereference the handle, so instructions hidden in the result never reach the model.
+    pub fn with_quarantine(mut self) -> Self {
+        self.quarantine = true;
+        self
+    }
+
+    /// Run the tools without an authority of their own with `authority` instead of that of the
+    /// demo services, which vouches for nothing. Results the tools produce themselves are only
+    /// trusted if the authority they run with may vouch for them, so endorsement is best granted
+    /// to the few tools which need it with [`MetaFunction::with_authority`].
+    ///
+    /// [`MetaFunction::with_authority`]: crate::MetaFunction::with_authority
+    pub fn with_authority(mut self, authority: Authority) -> Self {
+        self.authority = authority;
+        self
+    }
+
+    pub fn authority(&self) -> &Authority {
+        &self.authority
+    }
+
+    /// Pass the content of the model's answers to `on_delta` piece by piece, as the model writes
+    /// it, such that the final answer can be shown before the model is done with it. Tool calls
+    /// are not streamed, though the model may comment on them as it makes them.
+    pub fn with_answer_stream<D: FnMut(&str) + Send + 'static>(mut self, on_delta: D) -> Self {
+        self.answer_stream = Some(Box::new(on_delta));
+        self
+    }
+
+    /// Fail every run which would spend more than `tokens` on requests to the model with
+    /// `PlanError::BudgetExceeded`. Requests are priced before they are sent, along with the most
+    /// tokens the model may answer them with, so the run stops before going over the budget rather
+    /// than after.
+    pub fn with_token_budget(mut self, tokens: u32) -> Self {
+        self.token_budget = Some(tokens);
+        self
+    }
+
+    pub fn token_budget(&self) -> Option<u32> {
+        self.token_budget
+    }
+
+    /// Try to `repair` the tool calls denied by the policy before refusing the request. A repaired
+    /// call is only made once it complies with the policy, and the observers are notified of it.
+    pub fn with_repair(mut self, repair: PlanRepair) -> Self {
+        self.repair = Some(repair);
+        self
+    }
+
+    /// Rewrite every tool call with `sanitizer` before it is checked against the policy, after the
+    /// sanitizers added before it. The observers are notified of every call sanitized.
+    pub fn with_sanitizer(mut self, sanitizer: Sanitizer) -> Self {
+        self.sanitizers.push(sanitizer);
+        self
+    }
+
+    /// Have the `handler` decide how to recover from the actions denied by the policy, once they
+    /// cannot be repaired, instead of ending the run with a refusal. Every decision is reported to
+    /// the observers.
+    pub fn with_violation_handler<H: ViolationHandler + 'static>(mut self, handler: H) -> Self {
+        self.violation_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Wait on the `gate` before making any call to the tools it gates, which it may approve, deny
+    /// or modify. Denied calls are not made and the model is told why, while the arguments of
+    /// modified calls are validated, and checked against the policy if any, like the model's own.
+    pub fn with_approval_gate<G: ApprovalGate + 'static>(mut self, gate: G) -> Self {
+        self.approval_gate = Some(Box::new(gate));
+        self
+    }
+
+    /// Have the approval gate, if any, review the call at `step` to `function` with `args`,
+    /// notifying the observers of its decision. Calls to tools which are not gated are approved.
+    pub(super) async fn review(
+        &mut self,
+        step: usize,
+        function: &str,
+        args: &Args,
+        tool_call_id: &str,
+    ) -> Decision {
+        let gated = self
+            .approval_gate
+            .as_ref()
+            .is_some_and(|gate| gate.gates(function));
+        if !gated {
+            return Decision::Approve;
+        }
+        let request = ApprovalRequest {
+            step,
+            function: function.to_string(),
+            args: args.clone(),
+            tool_call_id: tool_call_id.to_string(),
+            denied_because: None,
+        };
+        self.ask_gate(request).await.unwrap_or(Decision::Approve)
+    }
+
+    /// Have the approval gate, if any, decide on the call of the `request` whether it gates the
+    /// tool or not, notifying the observers of its decision
+    pub(super) async fn ask_gate(&mut self, request: ApprovalRequest) -> Option<Decision> {
+        let gate = self.approval_gate.as_ref()?;
+        let step = request.step;
+        let decision = gate.review(request).await;
+        self.notify(Event::Reviewed(step, decision.clone()));
+        Some(decision)
+    }
+
+    /// Fail every run which takes more than `iterations` actions without finishing with
+    /// `PlanError::IterationLimit`, such that a model which keeps calling tools cannot keep the
+    /// loop going forever
+    pub fn with_max_iterations(mut self, iterations: usize) -> Self {
+        self.max_iterations = Some(iterations);
+        self
+    }
+
+    pub fn max_iterations(&self) -> Option<usize> {
+        self.max_iterations
+    }
+
+    /// Fail every run which takes longer than `timeout` with `PlanError::Timeout`. The deadline
+    /// is checked between actions and while waiting on the model, while tools run to completion.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Run every request as one transaction against the datastore: when a run fails, or ends
+    /// refusing a call the policy of the loop denied, the writes of its earlier calls to the store
+    /// are rolled back rather than left half applied.
+    pub fn with_atomic_runs(mut self) -> Self {
+        self.atomic = true;
+        self
+    }
+
+    /// Fail the run with `PlanError::IterationLimit` if it is about to take an action at `step`
+    /// while only allowed to take as many, handing it the actions of the `trace` taken so far.
+    /// Every step yields to the runtime first, such that the timeout of the run gets checked even
+    /// when none of the actions waits on anything.
+    pub(super) async fn check_iterations<T>(&self, step: usize, trace: T) -> Result<(), PlanError>
+    where
+        T: FnOnce() -> Vec<Action>,
+    {
+        tokio::task::yield_now().await;
+        match self.max_iterations {
+            Some(limit) if step >= limit => Err(PlanError::IterationLimit {
+                limit,
+                trace: trace(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write a [`LoopCheckpoint`] to the file at `path` before planning every step, such that a
+    /// run which was interrupted can be resumed from it. Failing to write it fails the run with
+    /// `PlanError::CheckpointError`.
+    pub fn with_checkpoint_file<T: AsRef<Path>>(mut self, path: T) -> Self {
+        self.checkpoint_file = Some(CheckpointLog::new(path.as_ref().to_path_buf()));
+        self
+    }
+
+    pub fn checkpoint_file(&self) -> Option<&Path> {
+        self.checkpoint_file.as_ref().map(CheckpointLog::path)
+    }
+
+    /// Append the `checkpoint` to the checkpoint file, if the loop has one, and keep it in memory
+    /// if the loop is asked to. The checkpoint is only built when it is written or kept. The first
+    /// checkpoint of a run, which is `starting`, replaces what the file held.
+    pub(super) async fn checkpoint<C>(&self, starting: bool, checkpoint: C) -> Result<(), PlanError>
+    where
+        C: FnOnce() -> std::io::Result<LoopCheckpoint>,
+    {
+        if self.checkpoint_file.is_none() && self.kept_checkpoint.is_none() {
+            return Ok(());
+        }
+        let checkpoint = checkpoint().map_err(PlanError::CheckpointError)?;
+        if let Some(log) = &self.checkpoint_file {
+            if starting {
+                log.restart();
+            }
+            log.append(&checkpoint)
+                .await
+                .map_err(PlanError::CheckpointError)?;
+        }
+        if let Some(kept) = &self.kept_checkpoint {
+            *kept.lock().expect("Checkpoint lock poisoned") = Some(checkpoint);
+        }
+        Ok(())
+    }
+
+    /// Compact the conversation of every query which does not fit in the `window` before sending
+    /// it, and carry on with the compacted conversation. Summaries are requested from the model of
+    /// the loop and count towards its usage.
+    pub fn with_context_window(mut self, window: ContextWindow) -> Self {
+        self.context_window = Some(window);
+        self
+    }
+
+    pub fn context_window(&self) -> Option<&ContextWindow> {
+        self.context_window.as_ref()
+    }
+
+    /// Compact the `conversation` the query at `step` sends with the `tools`, if it does not fit in
+    /// the context window, notifying the observers. Returns `None` if it was left as it is.
+    pub(super) async fn compact_context(
+        &mut self,
+        step: usize,
+        conversation: &State,
+        tools: &[ChatCompletionTool],
+    ) -> Result<Option<State>, PlanError> {
+        let Some(window) = self
+            .context_window
+            .clone()
+            .filter(|window| window.is_exceeded_by(conversation.messages(), tools))
+        else {
+            return Ok(None);
+        };
+        let mut messages = conversation.messages().to_vec();
+        match window.compaction() {
+            Compaction::TruncateToolResults { max_chars } => {
+                if !truncate_tool_results(&mut messages, *max_chars) {
+                    return Ok(None);
+                }
+            }
+            Compaction::Summarize { keep_recent } => {
+                let Some(turns) = compactable(&messages, *keep_recent) else {
+                    return Ok(None);
+                };
+                let response = self
+                    .model
+                    .chat(summary_request(&messages[turns.clone()])?, vec![])
+                    .await?;
+                self.usage.record(response.usage.as_ref());
+                let summary = response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.message.content)
+                    .ok_or_else(|| {
+                        PlanError::CannotPlan("The model did not summarize the turns".to_string())
+                    })?;
+                messages.splice(turns, [summary_message(&summary)?]);
+            }
+        }
+        self.notify(Event::ContextCompacted(
+            step,
+            estimate_prompt_tokens(conversation.messages(), tools),
+            estimate_prompt_tokens(&messages, tools),
+        ));
+        Ok(Some(State::new(messages)))
+    }
+
+    /// Offer the decoy tools of the `honeypot` to the model with every query. A call to any of them
+    /// aborts the run with `PlanError::Compromised`, notifies the observers and is kept in
+    /// [`compromises`].
+    ///
+    /// [`compromises`]: Self::compromises
+    pub fn with_honeypot(mut self, honeypot: Honeypot) -> Self {
+        self.honeypot = Some(honeypot);
+        self
+    }
+
+    /// Calls to decoy tools the loop caught so far, across all its runs
+    pub fn compromises(&self) -> &[Compromise] {
+        &self.compromises
+    }
+
+    /// Audit log of the policy decisions taken during the last run checked against a policy,
+    /// whether it finished or failed
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Whether the model ever called a decoy tool
+    pub fn is_compromised(&self) -> bool {
+        !self.compromises.is_empty()
+    }
+
+    /// Offer the decoys of the honeypot, if any, along with the tools of a query, and abort the run
+    /// if the `action` at `step` calls one of them. The `conversation` leading to the action is
+    /// kept for forensics.
+    pub(super) fn check_honeypot(
+        &mut self,
+        step: usize,
+        action: &mut Action,
+        conversation: &[ChatCompletionRequestMessage],
+    ) -> Result<(), PlanError> {
+        let Some(honeypot) = &self.honeypot else {
+            return Ok(());
+        };
+        match action {
+            Action::Query(_, tools) => honeypot.advertise(Arc::make_mut(tools)),
+            Action::MakeCall(function, args, id) if honeypot.is_decoy(function.name()) => {
+                let compromise = Compromise {
+                    step,
+                    function: function.name().to_string(),
+                    tool_call_id: id.clone(),
+                    arguments: args.value().to_string(),
+                    conversation: conversation.to_vec(),
+                };
+                self.compromises.push(compromise.clone());
+                self.notify(Event::Compromised(Box::new(compromise.clone())));
+                return Err(PlanError::Compromised(Box::new(compromise)));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Reserve the prompt of a request estimated at `prompt_estimate` tokens with the `quotas`, if
+    /// any, which fails once the request would go over them
+    pub(super) async fn reserve_query(
+        &mut self,
+        quotas: Option<&Quotas>,
+        prompt_estimate: u32,
+    ) -> Result<(), PlanError> {
+        let Some(quotas) = quotas else {
+            return Ok(());
+        };
+        let reserved = quotas.query_usage(prompt_estimate.into(), 0);
+        reserve(Some(quotas), &reserved, &mut self.observers).await
+    }
+
+    /// Settle the tokens reserved for a request of `prompt_estimate` tokens with the `quotas`, if
+    /// any, once the model gave its `response`: what the request spent beyond the reservation is
+    /// charged, and the rest refunded. Failed requests are refunded entirely. When the model does
+    /// not report its usage, the prompt is accounted for with the estimate.
+    pub(super) async fn charge_query(
+        &mut self,
+        quotas: Option<&Quotas>,
+        response: Result<CreateChatCompletionResponse, OpenAIError>,
+        prompt_estimate: u32,
+    ) -> Result<CreateChatCompletionResponse, PlanError> {
+        let Some(quotas) = quotas else {
+            return Ok(response?);
+        };
+        let reserved = quotas.query_usage(prompt_estimate.into(), 0);
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                refund(Some(quotas), &reserved).await?;
+                return Err(err.into());
+            }
+        };
+        let (prompt, completion) = match &response.usage {
+            Some(usage) => (usage.prompt_tokens, usage.completion_tokens),
+            None => (
+                prompt_estimate,
+                spent_tokens(&response, prompt_estimate) - prompt_estimate,
+            ),
+        };
+        let spent = quotas.query_usage(prompt.into(), completion.into());
+        self.charge(Some(quotas), spent.saturating_sub(&reserved))
+            .await?;
+        refund(Some(quotas), &reserved.saturating_sub(&spent)).await?;
+        Ok(response)
+    }
+
+    /// Charge what was `spent` to the `quotas`, if any, warning the observers about every quota
+    /// getting near its limit
+    pub(super) async fn charge(
+        &mut self,
+        quotas: Option<&Quotas>,
+        spent: QuotaUsage,
+    ) -> Result<(), PlanError> {
+        let Some(quotas) = quotas else {
+            return Ok(());
+        };
+        let warnings = quotas
+            .charge(&spent)
+            .await
+            .map_err(PlanError::QuotaStoreError)?;
+        for warning in warnings {
+            self.notify(Event::QuotaNearing(warning));
+        }
+        Ok(())
+    }
+
+    // Cache the `result` of calling `function` with `args` in the plain loop, labeled with the
+    // clearance of the loop
+    fn cache_result(&self, function: &str, args: &str, result: &str) {
+        if let Some((cache, clearance)) = &self.tool_cache {
+            cache.insert(function, args, result.to_string(), clearance.clone());
+        }
+    }
+
+    /// Detach the trace stream from the loop, such that it can be closed
+    #[cfg(feature = "telemetry")]
+    pub fn take_trace_stream(&mut self) -> Option<TraceStream> {
+        self.trace_stream.take()
+    }
+
+    /// Send the `entry` to the trace stream, if the loop has one
+    #[cfg(feature = "telemetry")]
+    pub async fn stream(&self, entry: TraceEntry) {
+        if let Some(stream) = &self.trace_stream {
+            stream.send(entry).await;
+        }
+    }
+
+    /// Notify all the observers about the `event`
+    pub fn notify(&mut self, event: Event) {
+        notify(&mut self.observers, event);
+    }
+
     /// Create a new `PlanninLoop` with an action `planner` a `model` to do the work and available
     /// `tools` that the model can call
     pub fn new(planner: P, model: LlmClient, tools: Vec<F>) -> Self {
@@ -36,6 +722,34 @@ impl<S, M: Clone, F: Call, P: Plan<S, M>> PlanningLoop<S, M, F, P> {
             planner,
             model,
             tools,
+            registry: None,
+            observers: vec![],
+            label_creep_readers: 1,
+            usage: Usage::default(),
+            #[cfg(feature = "telemetry")]
+            trace_stream: None,
+            tool_cache: None,
+            clearance: None,
+            integrity_quorum: None,
+            injection_detector: None,
+            verify_sends: false,
+            quarantine: false,
+            authority: service_authority(),
+            answer_stream: None,
+            token_budget: None,
+            honeypot: None,
+            compromises: vec![],
+            audit: AuditLog::default(),
+            repair: None,
+            sanitizers: vec![],
+            violation_handler: None,
+            approval_gate: None,
+            max_iterations: None,
+            timeout: None,
+            atomic: false,
+            checkpoint_file: None,
+            kept_checkpoint: None,
+            context_window: None,
             phantom_message: PhantomData,
             phantom_state: PhantomData,
         }
@@ -50,6 +764,106 @@ impl<P: Plan<State, Message, Action = Action>> PlanningLoop<State, Message, Func
         state: State,
         datastore: &mut Datastore,
         message: Message,
+    ) -> Result<String, PlanError> {
+        self.run_traced(state, datastore, message).await.0
+    }
+
+    /// Run the loop on the `query` of the user, which the planner starts by asking the model, such
+    /// that the `state` only holds what comes before the query, such as the system prompt
+    pub async fn run_from_user(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        query: &str,
+    ) -> Result<String, PlanError> {
+        self.run(state, datastore, Message::user(query)).await
+    }
+
+    /// Run the loop like [`run`], asking the model to answer following the `output` schema and
+    /// parsing its final answer into `T`
+    ///
+    /// [`run`]: Self::run
+    pub async fn run_structured<T: DeserializeOwned>(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: Message,
+        output: &OutputSchema<T>,
+    ) -> Result<T, PlanError> {
+        let format = output.build().map_err(PlanError::InvalidOutputSchema)?;
+        // The format is only asked for during this run
+        let previous = self.model.response_format().cloned();
+        self.model.set_response_format(Some(format));
+        let answer = self.run(state, datastore, message).await;
+        self.model.set_response_format(previous);
+        let answer = answer?;
+        output
+            .parse(&answer)
+            .map_err(|error| PlanError::InvalidOutput { answer, error })
+    }
+
+    /// Run the loop like [`run`], also returning the trace of everything the run did, whether it
+    /// finished or failed
+    ///
+    /// [`run`]: Self::run
+    pub async fn run_traced(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: Message,
+    ) -> (Result<String, PlanError>, RunTrace) {
+        self.run_from(state, datastore, message, RunTrace::default())
+            .await
+    }
+
+    /// Resume the run saved in the `checkpoint` where it left off, with the planner memory and the
+    /// trace it had. The actions of the trace are not taken again, while the step the run was
+    /// about to plan is.
+    pub async fn resume(
+        &mut self,
+        checkpoint: LoopCheckpoint,
+        datastore: &mut Datastore,
+    ) -> Result<String, PlanError> {
+        let (state, message, memory, trace) = checkpoint.into_run();
+        if let Some(memory) = memory {
+            self.planner.restore_memory(memory)?;
+        }
+        self.run_from(state, datastore, message, trace).await.0
+    }
+
+    // Run the loop on top of the actions of the `trace` taken so far
+    async fn run_from(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: Message,
+        mut trace: RunTrace,
+    ) -> (Result<String, PlanError>, RunTrace) {
+        let timeout = self.timeout;
+        let snapshot = self.atomic.then(|| datastore.snapshot());
+        let steps = self.run_steps(state, datastore, message, &mut trace);
+        let answer = match timeout {
+            None => steps.await,
+            Some(limit) => match tokio::time::timeout(limit, steps).await {
+                Ok(answer) => answer,
+                Err(_) => Err(PlanError::Timeout {
+                    limit,
+                    trace: trace.actions(),
+                }),
+            },
+        };
+        if let (Err(_), Some(snapshot)) = (&answer, snapshot) {
+            datastore.rollback(snapshot);
+        }
+        (answer, trace)
+    }
+
+    async fn run_steps(
+        &mut self,
+        state: State,
+        datastore: &mut Datastore,
+        message: Message,
+        trace: &mut RunTrace,
     ) -> Result<String, PlanError> {
         // Bind the given message to a mutable variable as it will be updated inside the following
         // loop based on what action the loop is taking.
@@ -57,8 +871,27 @@ impl<P: Plan<State, Message, Action = Action>> PlanningLoop<State, Message, Func
         // Bind the given state to a mutable variable as it will be updates insied the following
         // loop with a new message.
         let mut current_state = state;
-        loop {
-            let action;
+        let mut budget = self.token_budget.map(TokenBudget::new);
+        // Quotas are shared with the other loops of the same user, so they are checked against
+        // the store before every request and side effect
+        let quotas = datastore.quotas().cloned();
+        // A resumed run carries on from the actions it took before
+        let start = trace.steps().len();
+        for step in start.. {
+            // The message the loop goes on with is what the previous action led to
+            trace.settle(&current_message);
+            // Written before the limit is checked, such that a run cut short can be resumed
+            self.checkpoint(step == start, || {
+                Ok(LoopCheckpoint::new(
+                    &current_state,
+                    &current_message,
+                    self.planner.memory(),
+                    trace,
+                ))
+            })
+            .await?;
+            self.check_iterations(step, || trace.actions()).await?;
+            let mut action;
             // Plan the next action giving the current message and state. The new message is sent
             // separate from the state as it will be converted by the planner from a
             // `ChatCompletionRequest{Type}` message to a `ChatCompletionResponse{Type}` message.
@@ -66,33 +899,221 @@ impl<P: Plan<State, Message, Action = Action>> PlanningLoop<State, Message, Func
                 .planner
                 .plan(current_state, current_message)
                 .map_err(|e| PlanError::CannotPlan(format!("{:?}", e)))?;
+            let checked = self.check_honeypot(step, &mut action, current_state.messages());
+            #[cfg(feature = "telemetry")]
+            if self.trace_stream.is_some() {
+                self.stream(TraceEntry::new(step, action.clone(), None))
+                    .await;
+            }
+            // Calls to decoys are streamed before aborting, such that the trace shows them
+            checked?;
+            // Queries carry the state of the planner, which goes on with the compacted conversation
+            if let Action::Query(conv_history, tools) = &mut action
+                && let Some(compacted) = self.compact_context(step, conv_history, tools).await?
+            {
+                current_state = compacted.clone();
+                *conv_history = compacted;
+            }
+            trace.push(action.clone());
             match action {
                 // We have to query the model
                 Action::Query(conv_history, tools) => {
+                    let estimate = check_budget(
+                        budget.as_ref(),
+                        quotas.as_ref(),
+                        &self.model,
+                        conv_history.messages(),
+                        &tools,
+                    )?;
                     // Build a chat request with all the previous conversation history and the
-                    // available tools
-                    let chat_request = self.model.chat(conv_history.0, tools);
-                    // Send the request and save the first response choice as the new message
-                    current_message = Message::Chat(chat_request.await?.choices[0].message.clone());
+                    // available tools. Send the request and save the first response choice as the
+                    // new message, streaming its content if the caller asked for it.
+                    self.reserve_query(quotas.as_ref(), estimate).await?;
+                    let response = match &mut self.answer_stream {
+                        Some(on_delta) => {
+                            self.model
+                                .chat_stream(
+                                    conv_history.into_inner(),
+                                    Arc::unwrap_or_clone(tools),
+                                    on_delta.as_mut(),
+                                )
+                                .await
+                        }
+                        None => {
+                            self.model
+                                .chat(conv_history.into_inner(), Arc::unwrap_or_clone(tools))
+                                .await
+                        }
+                    };
+                    let response = self
+                        .charge_query(quotas.as_ref(), response, estimate)
+                        .await?;
+                    self.usage.record(response.usage.as_ref());
+                    if let Some(budget) = &mut budget {
+                        budget.spend(spent_tokens(&response, estimate));
+                    }
+                    current_message = Message::Chat(response.choices[0].message.clone());
                 }
                 // We have to call a tool requested by the model
                 Action::MakeCall(function, args, id) => {
                     // Find the requested `function` and call it with the given arguments and using
                     // the available datastore.
-                    let tool_result = self
-                        .tools
-                        .iter()
-                        .find(|&f| f == &function)
-                        .unwrap()
-                        .call(args, datastore);
+                    let args = match self.review(step, function.name(), &args, &id).await {
+                        Decision::Approve => args,
+                        Decision::Modify(args) => args,
+                        Decision::Deny(reason) => {
+                            current_message =
+                                Message::ToolResult(denied_message(function.name(), &reason), id);
+                            continue;
+                        }
+                    };
+                    // Results of the plain loop carry no label of their own, so they are cached
+                    // with the clearance of the loop
+                    let cached = self.tool_cache.as_ref().and_then(|(cache, clearance)| {
+                        cache.called(function.name());
+                        cache.get(function.name(), args.value(), clearance)
+                    });
+                    let raw_args = args.value().to_string();
+                    let tool_result = match (cached, self.tools.iter().find(|&f| f == &function)) {
+                        (Some((tool_result, _)), _) => tool_result,
+                        // Arguments which do not make sense are sent back to the model to be fixed
+                        (None, Some(tool)) => match tool.validate(&args) {
+                            Ok(()) => {
+                                let side_effect = side_effect(quotas.as_ref(), function.name());
+                                if let Some(spent) = &side_effect {
+                                    reserve(quotas.as_ref(), spent, &mut self.observers).await?;
+                                }
+                                match tool.call(args, datastore).await {
+                                    Ok(tool_result) => {
+                                        self.cache_result(function.name(), &raw_args, &tool_result);
+                                        tool_result
+                                    }
+                                    // Failed calls are reported to the model, which may retry them
+                                    Err(err) => {
+                                        if let Some(spent) = &side_effect {
+                                            refund(quotas.as_ref(), spent).await?;
+                                        }
+                                        self.notify(Event::ToolFailed(step, err.to_string()));
+                                        err.failure_message(function.name())
+                                    }
+                                }
+                            }
+                            Err(err) => err.corrective_message(function.name()),
+                        },
+                        (None, None) => {
+                            let tool = self
+                                .registry
+                                .as_ref()
+                                .and_then(|registry| registry.tool(function.name()))
+                                .ok_or(PlanError::FunctionNotFound(function.name().to_string()))?;
+                            let side_effect = side_effect(quotas.as_ref(), function.name());
+                            if let Some(spent) = &side_effect {
+                                reserve(quotas.as_ref(), spent, &mut self.observers).await?;
+                            }
+                            match tool.execute(args, datastore).await {
+                                Ok(tool_result) => {
+                                    self.cache_result(function.name(), &raw_args, &tool_result);
+                                    tool_result
+                                }
+                                Err(err) => {
+                                    if let Some(spent) = &side_effect {
+                                        refund(quotas.as_ref(), spent).await?;
+                                    }
+                                    self.notify(Event::ToolFailed(step, err.to_string()));
+                                    err.failure_message(function.name())
+                                }
+                            }
+                        }
+                    };
                     // New message represents the result we got from calling the above tool and we
                     // also keep the tool id such that the model can associate the tools request
                     // with the tool id.
-                    current_message = Message::ToolResult(tool_result, id);
+                    current_message = Message::tool_result(tool_result, id);
                 }
                 // We got the final model response and we return it back to the caller
                 Action::Finish(result) => return Ok(result),
+                // The application decides what its own actions do
+                Action::Custom(custom) => match custom.execute(datastore) {
+                    CustomOutcome::Continue(message) => current_message = message,
+                    CustomOutcome::Finish(result) => return Ok(result),
+                },
             }
         }
+        unreachable!("The planning loop only stops when finishing")
+    }
+}
+
+/// Check that sending the `messages` with the `tools` to the `model` fits in what is left of the
+/// `budget`, if any, counting the longest answer the model may give. Returns the estimated prompt
+/// tokens of the request, which are needed as well to reserve them with the `quotas`.
+pub(super) fn check_budget(
+    budget: Option<&TokenBudget>,
+    quotas: Option<&Quotas>,
+    model: &LlmClient,
+    messages: &[ChatCompletionRequestMessage],
+    tools: &[ChatCompletionTool],
+) -> Result<u32, PlanError> {
+    if budget.is_none() && quotas.is_none() {
+        return Ok(0);
+    }
+    let estimate = estimate_prompt_tokens(messages, tools);
+    let needed = estimate.saturating_add(model.max_completion_tokens());
+    if let Some(budget) = budget
+        && !budget.allows(needed)
+    {
+        return Err(PlanError::BudgetExceeded {
+            budget: budget.limit(),
+            needed: budget.spent().saturating_add(needed),
+        });
+    }
+    Ok(estimate)
+}
+
+/// Reserve what is about to be `spent` with the `quotas`, if any, warning the `observers` about
+/// every quota getting near its limit. Fails without reserving anything once the spending would go
+/// over the quotas.
+pub(super) async fn reserve(
+    quotas: Option<&Quotas>,
+    spent: &QuotaUsage,
+    observers: &mut [Box<dyn Observer>],
+) -> Result<(), PlanError> {
+    let Some(quotas) = quotas else {
+        return Ok(());
+    };
+    for warning in quotas.reserve(spent).await? {
+        notify(observers, Event::QuotaNearing(warning));
+    }
+    Ok(())
+}
+
+/// Give back to the `quotas`, if any, what was reserved and not spent
+pub(super) async fn refund(
+    quotas: Option<&Quotas>,
+    refunded: &QuotaUsage,
+) -> Result<(), PlanError> {
+    match quotas {
+        Some(quotas) => quotas
+            .refund(refunded)
+            .await
+            .map_err(PlanError::QuotaStoreError),
+        None => Ok(()),
+    }
+}
+
+/// What calling the tool called `name` spends of the `quotas`, if there are any and the tool has
+/// side effects
+pub(super) fn side_effect(quotas: Option<&Quotas>, name: &str) -> Option<QuotaUsage> {
+    quotas
+        .filter(|quotas| quotas.is_side_effect(name))
+        .map(|_| QuotaUsage {
+            side_effects: 1,
+            ..Default::default()
+        })
+}
+
+/// Notify all the `observers` about the `event`
+pub(super) fn notify(observers: &mut [Box<dyn Observer>], event: Event) {
+    for observer in observers.iter_mut() {
+        observer.notify(&event);
     }
 }
@@ -1,29 +1,91 @@
 use super::labeled::{ActionLabel, Trace};
-use crate::{Action, Integrity, tools::SendSlackMessageArgs};
+use crate::{
+    Action, Datastore, Integrity, State,
+    files::{Sandbox, WriteFileArgs},
+    ifc::Lattice,
+    locale::{Locale, detect_language},
+    registry::{Capability, ToolRegistry},
+    tools::{Reputation, URL_REPUTATION},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Arguments of the tools sending messages which carry what is sent, such as the subject and body
+/// of an email
+pub const MESSAGE_ARGS: &[&str] = &["message", "subject", "body"];
+
+// Text of the `args` of a call to a tool sending messages carrying what is sent
+fn message_texts(args: &serde_json::Value) -> impl Iterator<Item = &str> {
+    MESSAGE_ARGS
+        .iter()
+        .filter_map(|arg| args.get(*arg)?.as_str())
+}
 
 pub fn contains_url(text: &str) -> Result<bool, regex::Error> {
-    let pattern = r"http[s]?:\/\/
-        (?:[a-zA-Z]|[0-9]|[$-_@.&+.])+\.[a-zA-Z]{2,}";
+    Ok(find_url(text)?.is_some())
+}
+
+/// Returns the first URL found in `text`, if any.
+pub fn find_url(text: &str) -> Result<Option<&str>, regex::Error> {
+    Ok(find_urls(text)?.first().copied())
+}
+
+/// Returns all the URLs found in `text`, in order.
+pub fn find_urls(text: &str) -> Result<Vec<&str>, regex::Error> {
+    let pattern = r"http[s]?://(?:[a-zA-Z]|[0-9]|[$-_@.&+])+\.[a-zA-Z]{2,}[^\s]*";
 
     let re = regex::Regex::new(pattern)?;
-    Ok(re.is_match(text))
+    Ok(re.find_iter(text).map(|m| m.as_str()).collect())
+}
+
+/// Policy requiring every URL in an outgoing message to have been checked with the `check_url`
+/// tool and found benign. Links only reach someone else once the checker vouched for them.
+pub fn policy_checked_urls(trace: &Trace<ActionLabel>) -> Option<PolicyViolation> {
+    let Action::MakeCall(function, args, _) = trace.value().last()?.value() else {
+        return None;
+    };
+    if !function.name().starts_with("send_") {
+        return None;
+    }
+    let args: serde_json::Value = serde_json::from_str(args.value()).ok()?;
+    let urls = message_texts(&args)
+        .map(find_urls)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    urls.into_iter().flatten().find_map(|url| {
+        match URL_REPUTATION.verdict(url) {
+            None => Some(format!("the link {url} was not checked before sending it")),
+            Some(verdict) if verdict.reputation() != Reputation::Benign => Some(format!(
+                "the link {url} leads to {}, which the `{}` checker found {}",
+                verdict.expanded(),
+                verdict.checker(),
+                verdict.reputation()
+            )),
+            Some(_) => None,
+        }
+        .map(PolicyViolation::Standard)
+    })
 }
 
-/// Policy that stops sending untrusted Teams messages containing a URL.
+/// Policy that stops sending untrusted Slack messages or emails containing a URL.
 pub fn policy_no_untrusted_url(trace: &Trace<ActionLabel>) -> Option<PolicyViolation> {
     if let (Action::MakeCall(function, args, id), label) = trace.value().last()?.raw_parts() {
-        if function.name().starts_with("send_slack_message") {
+        if ["send_slack_message", "send_email"]
+            .iter()
+            .any(|tool| function.name().starts_with(tool))
+        {
             println!(
                 "Checking tool call {:?} -> {:#?}({:#?}) with label {:#?}\n",
                 id, function, args, label
             );
-            let args: SendSlackMessageArgs = serde_json::from_str(&args.0).ok()?;
+            let args: serde_json::Value = serde_json::from_str(args.value()).ok()?;
             // Check if the integrity label of the message is `untrusted` and if the message
             // contains an URL.
-            if label.lattice1() == &Integrity::Untrusted && contains_url(args.message()).ok()? {
-                Some(PolicyViolation::Standard(
-                    "Attempted to send a message with an untrusted URL".to_string(),
-                ))
+            if label.lattice1() == &Integrity::Untrusted {
+                let url = message_texts(&args).find_map(|text| find_url(text).ok()?)?;
+                Some(PolicyViolation::Standard(format!(
+                    "the message contained an untrusted link ({url})"
+                )))
             } else {
                 None
             }
@@ -35,21 +97,834 @@ pub fn policy_no_untrusted_url(trace: &Trace<ActionLabel>) -> Option<PolicyViola
     }
 }
 
-pub struct Policy {
-    inner: fn(&Trace<ActionLabel>) -> Option<PolicyViolation>,
+/// Policy stopping calls made with untrusted data to the tools of the `registry` with any of the
+/// `capabilities`, such as every tool sending data externally or deleting data, whatever they are
+/// called. Tools registered afterwards are not checked.
+pub fn policy_untrusted_capabilities(
+    registry: &ToolRegistry,
+    capabilities: &[Capability],
+) -> Policy {
+    let registry = registry.clone();
+    let capabilities = capabilities.to_vec();
+    Policy::new(move |trace: &Trace<ActionLabel>| {
+        let (Action::MakeCall(function, _, _), label) = trace.value().last()?.raw_parts() else {
+            return None;
+        };
+        if label.lattice1() != &Integrity::Untrusted {
+            return None;
+        }
+        let tool = registry.get(function.name())?;
+        let capability = capabilities
+            .iter()
+            .find(|capability| tool.has_capability(**capability))?;
+        Some(PolicyViolation::Standard(format!(
+            "`{}` was called with untrusted data, while it has the `{}` capability",
+            function.name(),
+            serde_json::to_value(capability).ok()?.as_str()?
+        )))
+    })
+}
+
+/// Tools of the crate sending data to someone, given by name prefix, along with the argument naming
+/// who they send to, as expected by [`policy_no_exfiltration`]
+pub const EGRESS: &[(&str, &str)] = &[("send_slack_message", "channel"), ("send_email", "to")];
+
+/// Policy keeping data from flowing to anyone who may not read it. Each of the `egress` tools,
+/// given by name prefix, sends to the destinations in the argument named along with it, while the
+/// label of the call carries the readers of every piece of data the arguments were derived from.
+/// Calls are only allowed if every destination is among those readers, such that a summary of
+/// confidential emails cannot be sent to a third party whatever the message looks like. Attachments
+/// of emails carry readers of their own, so private attachments cannot be forwarded to whoever was
+/// only copied on their email either. Calls whose destinations cannot be told are denied.
+pub fn policy_no_exfiltration(egress: &[(&str, &str)]) -> Policy {
+    let egress: Vec<(String, String)> = egress
+        .iter()
+        .map(|(tool, arg)| (tool.to_string(), arg.to_string()))
+        .collect();
+    Policy::new(move |trace: &Trace<ActionLabel>| {
+        let (Action::MakeCall(function, args, _), label) = trace.value().last()?.raw_parts() else {
+            return None;
+        };
+        let (_, arg) = egress
+            .iter()
+            .find(|(tool, _)| function.name().starts_with(tool.as_str()))?;
+        let untold = || {
+            Some(PolicyViolation::Standard(format!(
+                "the destinations of `{}` could not be told from its `{arg}` argument",
+                function.name()
+            )))
+        };
+        let Ok(args) = serde_json::from_str::<serde_json::Value>(args.value()) else {
+            return untold();
+        };
+        // Destinations are either a list or a single string separated by commas
+        let destinations: Vec<&str> = match args.get(arg) {
+            Some(serde_json::Value::String(destinations)) => {
+                destinations.split(',').map(str::trim).collect()
+            }
+            Some(serde_json::Value::Array(destinations)) => {
+                match destinations.iter().map(serde_json::Value::as_str).collect() {
+                    Some(destinations) => destinations,
+                    None => return untold(),
+                }
+            }
+            _ => return untold(),
+        };
+        let readers = label.lattice2().inner().subset();
+        let destination = destinations
+            .into_iter()
+            .find(|destination| !readers.contains(*destination))?;
+        Some(PolicyViolation::Standard(format!(
+            "`{}` would send data to {destination}, who may not read it",
+            function.name()
+        )))
+    })
 }
 
-impl Policy {
-    pub fn new(inner: fn(&Trace<ActionLabel>) -> Option<PolicyViolation>) -> Self {
-        Self { inner }
+/// Policy keeping the `write_file` tools from writing data into files of the `sandbox` which
+/// someone who may not read the data could read, such as salaries into a world-readable directory,
+/// and from writing untrusted data into trusted files, which would come back trusted when read.
+/// Who may read a file and whether it is trusted is told by the labels the sandbox maps its path
+/// to. Writes whose arguments or file cannot be made sense of are denied.
+pub fn policy_no_leaky_writes(sandbox: Sandbox) -> Policy {
+    Policy::new(move |trace: &Trace<ActionLabel>| {
+        let (Action::MakeCall(function, args, _), label) = trace.value().last()?.raw_parts() else {
+            return None;
+        };
+        if !function.name().starts_with("write_file") {
+            return None;
+        }
+        let args: WriteFileArgs = match serde_json::from_str(args.value()) {
+            Ok(args) => args,
+            Err(err) => {
+                return Some(PolicyViolation::Standard(format!(
+                    "the file to write could not be told ({err})"
+                )));
+            }
+        };
+        let file = match sandbox.label(args.path()) {
+            Ok(file) => file,
+            Err(err) => return Some(PolicyViolation::Standard(err.to_string())),
+        };
+        if label.lattice1() == &Integrity::Untrusted && file.lattice1() == &Integrity::Trusted {
+            return Some(PolicyViolation::Standard(format!(
+                "`{}` is trusted, while the data written to it is not",
+                args.path()
+            )));
+        }
+        let readers = label.lattice2().inner().subset();
+        let mut leaked: Vec<_> = file
+            .lattice2()
+            .inner()
+            .subset()
+            .iter()
+            .filter(|reader| !readers.contains(*reader))
+            .map(String::as_str)
+            .collect();
+        if leaked.is_empty() {
+            return None;
+        }
+        leaked.sort();
+        Some(PolicyViolation::Standard(format!(
+            "`{}` would let {} read data they may not read",
+            args.path(),
+            leaked.join(", ")
+        )))
+    })
+}
+
+/// Policy flagging messages sent in a language other than the one of the user's `locale`. Users
+/// rarely switch languages, while injected instructions are often written in the attacker's
+/// language, which makes a language switch a common sign of social engineering. The policy does
+/// not look at labels, so it checks runs with any label.
+pub fn policy_locale_language<L: Lattice>(locale: Locale) -> Policy<L> {
+    Policy::new(move |trace| {
+        let Action::MakeCall(function, args, _) = trace.value().last()?.value() else {
+            return None;
+        };
+        if !function.name().starts_with("send_") {
+            return None;
+        }
+        let args: serde_json::Value = serde_json::from_str(args.value()).ok()?;
+        let language = detect_language(args.get("message")?.as_str()?)?;
+        (language != locale.language()).then(|| {
+            PolicyViolation::Standard(format!(
+                "the message was written in `{language}`, while you use `{}`",
+                locale.language()
+            ))
+        })
+    })
+}
+
+/// Check of the traces of a run which may wait on other services, such as a DLP scanner or an
+/// allow-list API, and keep state from one check to the next, such as counters of the calls made
+/// to each tool. Besides the trace, the check is shown the `state` of the planner and the
+/// `datastore` of the run. The loop also checks the variants of denied calls it tries in their
+/// place, so an action being checked does not mean it was taken. The loop waits on the check
+/// before taking any action, queries to the model included, such that a slow check delays the run
+/// rather than letting the data out before it ruled.
+pub trait PolicyCheck<L: Lattice>: Send {
+    fn check(
+        &mut self,
+        trace: &Trace<L>,
+        state: &State,
+        datastore: &Datastore,
+    ) -> impl Future<Output = Option<PolicyViolation>> + Send;
+
+    /// Name the decisions of the check are recorded under in the audit log
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Check run next to [`check`] whose violations are only reported and never block the
+    /// action, such that new rules can be tuned against real traffic before being enforced.
+    /// Checks have nothing in shadow mode unless wrapped in [`Shadowed`].
+    ///
+    /// [`check`]: PolicyCheck::check
+    fn shadow(
+        &mut self,
+        _trace: &Trace<L>,
+        _state: &State,
+        _datastore: &Datastore,
+    ) -> impl Future<Output = Option<PolicyViolation>> + Send {
+        std::future::ready(None)
     }
 
-    pub fn check(&self, trace: &Trace<ActionLabel>) -> Option<PolicyViolation> {
+    /// Name the would-be violations of the [`shadow`] check are recorded under in the audit log
+    ///
+    /// [`shadow`]: PolicyCheck::shadow
+    fn shadow_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The `enforced` check, together with a `shadow` check in dry-run mode. Actions are only blocked
+/// by the enforced check, while the violations of the shadow check are reported to the observers
+/// and recorded in the audit log as what would have been blocked.
+pub struct Shadowed<C, S> {
+    enforced: C,
+    shadow: S,
+}
+
+impl<C, S> Shadowed<C, S> {
+    pub fn new(enforced: C, shadow: S) -> Self {
+        Self { enforced, shadow }
+    }
+}
+
+impl<L: Lattice, S> Shadowed<Policy<L>, S> {
+    /// Only run the `shadow` check, allowing every action
+    pub fn dry_run(shadow: S) -> Self {
+        Self::new(Policy::new(|_| None), shadow)
+    }
+}
+
+impl<L: Lattice, C: PolicyCheck<L>, S: PolicyCheck<L>> PolicyCheck<L> for Shadowed<C, S> {
+    fn check(
+        &mut self,
+        trace: &Trace<L>,
+        state: &State,
+        datastore: &Datastore,
+    ) -> impl Future<Output = Option<PolicyViolation>> + Send {
+        self.enforced.check(trace, state, datastore)
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.enforced.name()
+    }
+
+    fn shadow(
+        &mut self,
+        trace: &Trace<L>,
+        state: &State,
+        datastore: &Datastore,
+    ) -> impl Future<Output = Option<PolicyViolation>> + Send {
+        self.shadow.check(trace, state, datastore)
+    }
+
+    fn shadow_name(&self) -> Option<&str> {
+        self.shadow.name()
+    }
+}
+
+impl<L: Lattice + Sync> PolicyCheck<L> for Policy<L> {
+    async fn check(
+        &mut self,
+        trace: &Trace<L>,
+        _state: &State,
+        _datastore: &Datastore,
+    ) -> Option<PolicyViolation> {
+        Policy::check(self, trace)
+    }
+
+    fn name(&self) -> Option<&str> {
+        Policy::name(self)
+    }
+}
+
+type Check<L> = dyn Fn(&Trace<L>) -> Option<PolicyViolation> + Send + Sync;
+
+/// Check of the traces of a run, labeled with `L`
+#[derive(Clone)]
+pub struct Policy<L: Lattice = ActionLabel> {
+    inner: Arc<Check<L>>,
+    // Name of the policy in the audit log
+    name: Option<String>,
+}
+
+impl<L: Lattice> Policy<L> {
+    pub fn new<F>(inner: F) -> Self
+    where
+        F: Fn(&Trace<L>) -> Option<PolicyViolation> + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(inner),
+            name: None,
+        }
+    }
+
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn check(&self, trace: &Trace<L>) -> Option<PolicyViolation> {
         (self.inner)(trace)
     }
 }
 
-#[derive(Debug)]
+impl<L: Lattice + 'static> Policy<L> {
+    /// Policy violated whenever `self` or `other` is, reporting the violation of `self` first
+    pub fn and(self, other: Self) -> Self {
+        Self::new(move |trace| self.check(trace).or_else(|| other.check(trace)))
+    }
+
+    /// Policy only violated when both `self` and `other` are, reporting the violation of `self`
+    pub fn or(self, other: Self) -> Self {
+        Self::new(move |trace| {
+            let violation = self.check(trace)?;
+            other.check(trace).map(|_| violation)
+        })
+    }
+}
+
+/// How seriously the violation of a policy is taken, from the least to the most serious
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// How the policies of a [`PolicySet`] are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Composition {
+    /// Every policy has to hold, and the most severe violation is reported
+    AllOf,
+    /// At least one policy has to hold, and the most severe violation is reported when none does
+    AnyOf,
+    /// Every policy has to hold, and the policies are checked in order until one is violated,
+    /// which is the one reported
+    FirstViolation,
+}
+
+// Policy of a set, along with what the set knows about it
+#[derive(Clone)]
+struct NamedPolicy<L: Lattice> {
+    name: String,
+    policy: Policy<L>,
+    severity: Severity,
+    enabled: bool,
+}
+
+/// Policies known by name and combined with a [`Composition`], such that policies written on their
+/// own can be layered together and switched on and off. A set is checked like any other policy
+/// once turned into one.
+#[derive(Clone)]
+pub struct PolicySet<L: Lattice = ActionLabel> {
+    composition: Composition,
+    policies: Vec<NamedPolicy<L>>,
+}
+
+impl<L: Lattice> PolicySet<L> {
+    pub fn new(composition: Composition) -> Self {
+        Self {
+            composition,
+            policies: vec![],
+        }
+    }
+
+    /// Add `policy` to the set under `name`, enabled. Policies are checked in the order they are
+    /// added.
+    pub fn with_policy<S: Into<String>>(
+        mut self,
+        name: S,
+        policy: Policy<L>,
+        severity: Severity,
+    ) -> Self {
+        self.policies.push(NamedPolicy {
+            name: name.into(),
+            policy,
+            severity,
+            enabled: true,
+        });
+        self
+    }
+
+    /// Switch the policy called `name` on or off. Returns false if the set has no such policy.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.policies.iter_mut().find(|named| named.name == name) {
+            Some(named) => {
+                named.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> Option<bool> {
+        self.policies
+            .iter()
+            .find(|named| named.name == name)
+            .map(|named| named.enabled)
+    }
+
+    pub fn composition(&self) -> Composition {
+        self.composition
+    }
+
+    /// Names of the policies of the set, in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.policies.iter().map(|named| named.name.as_str())
+    }
+
+    /// Check the `trace` against the enabled policies of the set. Violations name the policy
+    /// they come from. A set with no enabled policy is never violated.
+    pub fn check(&self, trace: &Trace<L>) -> Option<PolicyViolation> {
+        let enabled: Vec<_> = self.policies.iter().filter(|named| named.enabled).collect();
+        let mut violations = enabled.iter().filter_map(|named| {
+            named.policy.check(trace).map(|violation| {
+                PolicyViolation::Named(Box::new(NamedViolation {
+                    name: named.name.clone(),
+                    severity: named.severity,
+                    violation,
+                }))
+            })
+        });
+        match self.composition {
+            Composition::FirstViolation => violations.next(),
+            Composition::AllOf => violations.max_by_key(PolicyViolation::severity),
+            Composition::AnyOf => {
+                let violations: Vec<_> = violations.collect();
+                if enabled.is_empty() || violations.len() < enabled.len() {
+                    return None;
+                }
+                violations.into_iter().max_by_key(PolicyViolation::severity)
+            }
+        }
+    }
+}
+
+impl<L: Lattice + 'static> From<PolicySet<L>> for Policy<L> {
+    fn from(set: PolicySet<L>) -> Self {
+        Policy::new(move |trace| set.check(trace))
+    }
+}
+
+/// Violation of a policy of a [`PolicySet`]
+#[derive(Debug, Clone)]
+pub struct NamedViolation {
+    name: String,
+    severity: Severity,
+    violation: PolicyViolation,
+}
+
+impl NamedViolation {
+    /// Name of the violated policy within its set
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// Violation reported by the policy itself
+    pub fn violation(&self) -> &PolicyViolation {
+        &self.violation
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum PolicyViolation {
     Standard(String),
+    // Violation of a policy of a set
+    Named(Box<NamedViolation>),
+}
+
+impl PolicyViolation {
+    /// Explanation of why the policy was violated
+    pub fn explanation(&self) -> &str {
+        match self {
+            Self::Standard(explanation) => explanation,
+            Self::Named(named) => named.violation.explanation(),
+        }
+    }
+
+    /// Severity the violated policy was given in its set, if it belongs to one. The violations of
+    /// policies outside sets are taken as seriously as they can be.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::Standard(_) => Severity::Critical,
+            Self::Named(named) => named.severity,
+        }
+    }
+}
+
+/// Build a final answer for the user explaining that the `action` could not be taken because it
+/// was denied by a policy with the given `violation`.
+pub fn refusal_message(action: &Action, violation: &PolicyViolation) -> String {
+    let attempted = match action {
+        Action::MakeCall(function, _, _) => format!("call `{}`", function.name()),
+        Action::Query(_, _) => "query the model".to_string(),
+        Action::Finish(_) => "give you the final answer".to_string(),
+        Action::Custom(custom) => format!("take the `{}` action", custom.name()),
+    };
+    format!(
+        "I couldn't complete your request. I was about to {attempted}, but it was blocked by a \
+        security policy because {}.",
+        violation.explanation()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Args, Function, ProductLattice,
+        mime::{Attachment, Sensitivity},
+        registry::ToolEntry,
+        schema::ToolDefinition,
+        tools::{
+            Email, EmailAddressUniverse, EmailLabel, INBOX, MetaValue, label_attachment,
+            label_email, readers_label,
+        },
+    };
+
+    fn send_slack_trace(message: &str, integrity: Integrity) -> Trace<ActionLabel> {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            integrity,
+            readers_label(universe.clone(), universe).expect("Failed to build readers label"),
+        );
+        let args = serde_json::json!({
+            "channel": "bob.sheffield@magnet.com",
+            "message": message,
+            "preview": false,
+        });
+        let mut trace = Trace::default();
+        trace.value_mut().push(MetaValue::new(
+            Action::MakeCall(
+                Function::new("send_slack_message_labeled".to_string()),
+                Args::new(args.to_string()),
+                "call_0".to_string(),
+            ),
+            label,
+        ));
+        trace
+    }
+
+    #[test]
+    fn data_only_flows_to_its_readers() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let send = |channel: &str, readers: &[&str]| {
+            let readers = readers.iter().map(|reader| reader.to_string()).collect();
+            let label = ProductLattice::new(
+                Integrity::trusted(),
+                readers_label(readers, universe.clone()).unwrap(),
+            );
+            let args = serde_json::json!({ "channel": channel, "message": "Hi", "preview": false });
+            let mut trace = Trace::default();
+            trace.value_mut().push(MetaValue::new(
+                Action::MakeCall(
+                    Function::new("send_slack_message_labeled".to_string()),
+                    Args::new(args.to_string()),
+                    "call_0".to_string(),
+                ),
+                label,
+            ));
+            trace
+        };
+        let policy = policy_no_exfiltration(&[("send_slack_message", "channel")]);
+
+        let bob = "bob.sheffield@magnet.com";
+        let alice = "alice.hudson@magnet.com";
+        assert!(policy.check(&send(bob, &[bob, alice])).is_none());
+        let violation = policy.check(&send(alice, &[bob])).unwrap();
+        assert!(violation.explanation().contains(alice));
+        // Destinations which cannot be told are denied rather than let through
+        for args in ["not json", r#"{"message":"Hi"}"#, r#"{"channel":7}"#] {
+            let mut trace = send(bob, &[bob]);
+            let (action, label) = trace.value_mut().pop().unwrap().into_raw_parts();
+            let Action::MakeCall(function, _, id) = action else {
+                unreachable!()
+            };
+            let action = Action::MakeCall(function, Args::new(args.to_string()), id);
+            trace.value_mut().push(MetaValue::new(action, label));
+            assert!(policy.check(&trace).is_some());
+        }
+
+        // Private attachments cannot be forwarded to whoever was only copied on their email
+        let david = "david.bernard@magnet.com";
+        let email = Email::builder(alice)
+            .to(bob)
+            .cc(david)
+            .attachment(
+                Attachment::new("salaries.csv", "text/csv", 7)
+                    .with_sensitivity(Sensitivity::Private),
+            )
+            .build();
+        let email = label_email(email, universe.clone()).unwrap();
+        let attachment = label_attachment(&email.value().attachments()[0], &email).unwrap();
+        fn readers(label: &EmailLabel) -> Vec<&str> {
+            let readers = label.lattice2().inner().subset();
+            readers.iter().map(String::as_str).collect()
+        }
+        assert!(
+            policy
+                .check(&send(david, &readers(email.label())))
+                .is_none()
+        );
+        assert!(
+            policy
+                .check(&send(david, &readers(attachment.label())))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn untrusted_data_is_kept_from_capable_tools() {
+        let schema = ToolDefinition::<serde_json::Value>::new("send_slack_message_labeled", "")
+            .build()
+            .unwrap();
+        let registry = ToolRegistry::new()
+            .with_tool(ToolEntry::new(schema).with_capability(Capability::SendsExternally))
+            .unwrap();
+        let policy = policy_untrusted_capabilities(
+            &registry,
+            &[Capability::Destructive, Capability::SendsExternally],
+        );
+        let violation = policy
+            .check(&send_slack_trace("Hi", Integrity::untrusted()))
+            .unwrap();
+        assert!(violation.explanation().contains("`sends_externally`"));
+        assert!(
+            policy
+                .check(&send_slack_trace("Hi", Integrity::trusted()))
+                .is_none()
+        );
+        let unregistered =
+            policy_untrusted_capabilities(&ToolRegistry::new(), &[Capability::SendsExternally]);
+        assert!(
+            unregistered
+                .check(&send_slack_trace("Hi", Integrity::untrusted()))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn confidential_data_is_not_written_where_anyone_reads() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let bob = "bob.sheffield@magnet.com";
+        let alice = "alice.hudson@magnet.com";
+        let write = |path: &str, readers: &[&str], integrity: Integrity| {
+            let readers = readers.iter().map(|reader| reader.to_string()).collect();
+            let label =
+                ProductLattice::new(integrity, readers_label(readers, universe.clone()).unwrap());
+            let args = serde_json::json!({ "path": path, "content": "Bob: 100" });
+            let mut trace = Trace::default();
+            trace.value_mut().push(MetaValue::new(
+                Action::MakeCall(
+                    Function::new("write_file_labeled".to_string()),
+                    Args::new(args.to_string()),
+                    "call_0".to_string(),
+                ),
+                label,
+            ));
+            trace
+        };
+        let root = std::env::temp_dir().join(format!("gentlemen-writes-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let policy = policy_no_leaky_writes(Sandbox::new(&root).with_readers("hr", &[alice, bob]));
+
+        let trusted = Integrity::trusted;
+        assert!(
+            policy
+                .check(&write("hr/salaries.txt", &[alice, bob], trusted()))
+                .is_none()
+        );
+        // Files outside of `hr` are world-readable
+        let violation = policy
+            .check(&write("public/salaries.txt", &[alice, bob], trusted()))
+            .unwrap();
+        assert!(violation.explanation().contains("david.bernard@magnet.com"));
+        let violation = policy
+            .check(&write("hr/salaries.txt", &[bob], trusted()))
+            .unwrap();
+        assert!(violation.explanation().contains(alice));
+        // Untrusted data would come back trusted when read from `hr`
+        let violation = policy
+            .check(&write(
+                "hr/salaries.txt",
+                &[alice, bob],
+                Integrity::untrusted(),
+            ))
+            .unwrap();
+        assert!(violation.explanation().contains("is trusted"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn finds_urls() {
+        assert_eq!(
+            find_url("Details at https://fides.github.io/summary/QWxp now").unwrap(),
+            Some("https://fides.github.io/summary/QWxp")
+        );
+        assert!(!contains_url("No links in here.").unwrap());
+    }
+
+    #[test]
+    fn untrusted_url_refusal() {
+        let trace = send_slack_trace(
+            "Summary: see https://fides.github.io/summary/QWxp",
+            Integrity::untrusted(),
+        );
+        let violation = policy_no_untrusted_url(&trace).expect("Policy should be violated");
+        let refusal = refusal_message(trace.value()[0].value(), &violation);
+        assert!(refusal.contains("`send_slack_message_labeled`"));
+        assert!(refusal.contains("untrusted link (https://fides.github.io/summary/QWxp)"));
+
+        let trace = send_slack_trace(
+            "Summary: see https://fides.github.io/summary/QWxp",
+            Integrity::trusted(),
+        );
+        assert!(policy_no_untrusted_url(&trace).is_none());
+
+        // Links in emails are caught as well, wherever they are written
+        let mut trace = Trace::default();
+        let args = serde_json::json!({
+            "to": "bob.sheffield@magnet.com",
+            "subject": "Summary",
+            "body": "See https://fides.github.io/summary/QWxp",
+        });
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        trace.value_mut().push(MetaValue::new(
+            Action::MakeCall(
+                Function::new("send_email_labeled".to_string()),
+                Args::new(args.to_string()),
+                "call_0".to_string(),
+            ),
+            ProductLattice::new(
+                Integrity::untrusted(),
+                readers_label(universe.clone(), universe).unwrap(),
+            ),
+        ));
+        assert!(policy_no_untrusted_url(&trace).is_some());
+    }
+
+    #[test]
+    fn unchecked_urls_are_not_sent() {
+        let trace = send_slack_trace(
+            "Notes at https://wiki.magnet.com/policy-test and https://bit.ly/policy-test",
+            Integrity::trusted(),
+        );
+        let violation = policy_checked_urls(&trace).expect("Policy should be violated");
+        assert!(violation.explanation().contains("was not checked"));
+
+        URL_REPUTATION.check("https://wiki.magnet.com/policy-test");
+        URL_REPUTATION.check("https://bit.ly/policy-test");
+        let violation = policy_checked_urls(&trace).expect("Policy should be violated");
+        assert!(violation.explanation().contains("found unknown"));
+
+        URL_REPUTATION.add_expansion(
+            "https://bit.ly/policy-test",
+            "https://magnet.com/policy-test",
+        );
+        URL_REPUTATION.check("https://bit.ly/policy-test");
+        assert!(policy_checked_urls(&trace).is_none());
+    }
+
+    #[test]
+    fn policy_sets_layer_policies() {
+        // Messages to anyone outside of Magnet are denied
+        let internal = Policy::new(|trace: &Trace<ActionLabel>| {
+            let Action::MakeCall(_, args, _) = trace.value().last()?.value() else {
+                return None;
+            };
+            let args: serde_json::Value = serde_json::from_str(args.value()).ok()?;
+            let channel = args.get("channel")?.as_str()?;
+            (!channel.ends_with("@magnet.com"))
+                .then(|| PolicyViolation::Standard(format!("{channel} is not a colleague")))
+        });
+        let never = Policy::new(|_: &Trace<ActionLabel>| {
+            Some(PolicyViolation::Standard("nothing is allowed".to_string()))
+        });
+        let set = |composition| {
+            PolicySet::new(composition)
+                .with_policy("never", never.clone(), Severity::Low)
+                .with_policy("internal", internal.clone(), Severity::Medium)
+                .with_policy(
+                    "no-untrusted-url",
+                    Policy::new(policy_no_untrusted_url),
+                    Severity::High,
+                )
+        };
+        let trace = send_slack_trace("See https://fides.github.io/x", Integrity::untrusted());
+
+        // The most severe violation is the one reported
+        let Some(PolicyViolation::Named(violation)) = set(Composition::AllOf).check(&trace) else {
+            panic!("Policy should be violated");
+        };
+        assert_eq!(violation.name(), "no-untrusted-url");
+        assert_eq!(violation.severity(), Severity::High);
+        // Unless only the first violation is looked for
+        let violation = set(Composition::FirstViolation).check(&trace).unwrap();
+        assert_eq!(violation.explanation(), "nothing is allowed");
+        assert!(set(Composition::AnyOf).check(&trace).is_none());
+
+        // Disabled policies are skipped, and sets are checked like any other policy
+        let mut relaxed = set(Composition::AllOf);
+        assert!(relaxed.set_enabled("never", false));
+        assert!(!relaxed.set_enabled("missing", false));
+        let policy: Policy = relaxed.into();
+        let trusted = send_slack_trace("See https://fides.github.io/x", Integrity::trusted());
+        assert!(policy.check(&trusted).is_none());
+        assert!(policy.clone().or(never.clone()).check(&trace).is_some());
+        assert!(policy.and(never).check(&trusted).is_some());
+    }
+
+    #[test]
+    fn language_switch_is_flagged() {
+        let policy: Policy = policy_locale_language(Locale::parse("en-US"));
+        let trace = send_slack_trace(
+            "Alice confirmed the meeting and the reports are ready for you.",
+            Integrity::trusted(),
+        );
+        assert!(policy.check(&trace).is_none());
+
+        let trace = send_slack_trace(
+            "Votre paiement est disponible, veuillez vous connecter avec le lien.",
+            Integrity::trusted(),
+        );
+        let violation = policy.check(&trace).expect("Policy should be violated");
+        assert!(violation.explanation().contains("`fr`"));
+    }
 }
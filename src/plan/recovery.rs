@@ -0,0 +1,60 @@
+//! Recovery from the actions denied by the policy. By default a denial ends the run with an answer
+//! explaining why the request could not be completed, which makes the policy a kill switch: a
+//! single link in a summary throws away everything the run did so far. A [`ViolationHandler`]
+//! lets the [`PlanningLoop`] act as a guardrail instead, by deciding per denial whether to abort,
+//! to skip the action and let the model plan around it, to ask the [`ApprovalGate`] of the loop or
+//! to take the action with other arguments.
+//!
+//! Skipped actions are not made, so they are left out of the trace the policy checks later
+//! actions against.
+//!
+//! The handler is only consulted once the [`PlanRepair`], if any, found no compliant variant of
+//! the action.
+//!
+//! [`PlanningLoop`]: super::PlanningLoop
+//! [`PlanRepair`]: super::repair::PlanRepair
+//! [`ApprovalGate`]: super::approval::ApprovalGate
+use super::policy::PolicyViolation;
+use crate::{Action, Args};
+
+/// What the loop does with an action denied by the policy
+#[derive(Debug, Clone)]
+pub enum Recovery {
+    /// Finish the run with an answer explaining why the request could not be completed
+    Abort,
+    /// Tell the model the tool call was denied and why, and let it plan around it. Only tool
+    /// calls can be skipped, the loop aborts on every other action.
+    SkipAction,
+    /// Ask the approval gate of the loop whether to make the tool call anyway, whether the gate
+    /// designates the tool or not. Calls the gate denies are skipped, and modified ones are made
+    /// once they comply with the policy. The loop aborts without a gate, if the modified call does
+    /// not comply, or if the action is not a tool call.
+    RequireHumanApproval,
+    /// Make the tool call with these arguments instead, once they comply with the policy. The
+    /// loop aborts if they do not, or if the action is not a tool call.
+    RewriteArgs(Args),
+}
+
+/// Decides how the loop recovers from the actions denied by the policy
+pub trait ViolationHandler: Send {
+    /// How to recover from the `action` being denied with `violation`
+    fn recover(&mut self, action: &Action, violation: &PolicyViolation) -> Recovery;
+}
+
+impl<F> ViolationHandler for F
+where
+    F: FnMut(&Action, &PolicyViolation) -> Recovery + Send,
+{
+    fn recover(&mut self, action: &Action, violation: &PolicyViolation) -> Recovery {
+        self(action, violation)
+    }
+}
+
+/// Message telling the model that the call to the tool called `name` was denied with `violation`
+pub fn skipped_message(name: &str, violation: &PolicyViolation) -> String {
+    format!(
+        "The call to `{name}` was not made, as it was blocked by a security policy because {}. \
+        Do not try to make it again.",
+        violation.explanation()
+    )
+}
@@ -0,0 +1,328 @@
+//! Repair of the tool calls denied by the policy. Under strict policies many denials are about a
+//! detail of the call, such as a link in a message or who the message goes to, while the call
+//! itself is what the user asked for. Instead of refusing the request outright, the
+//! [`PlanningLoop`] can rewrite the call into a compliant variant of it, which is checked against
+//! the policy like any other action before being made.
+//!
+//! Deterministic rewrites are tried first, in the order they were added, and the model is only
+//! asked to fix the call once none of them complies. The model is only shown the arguments it
+//! wrote itself, and keeps those it referred to by variable as they are, such that asking for a
+//! fix does not show the model what the policy denied. The call the model made is rewritten in the
+//! conversation once repaired.
+//!
+//! [`PlanningLoop`]: super::PlanningLoop
+use super::policy::{MESSAGE_ARGS, PolicyViolation, find_urls};
+use crate::{Action, Args};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs,
+};
+use serde_json::{Map, Value, json};
+use std::sync::Arc;
+
+type RewriteFn = dyn Fn(&Action, &PolicyViolation) -> Option<Action> + Send + Sync;
+
+/// Deterministic rewrite of a denied action into a variant which may comply with the policy
+#[derive(Clone)]
+pub struct Rewrite {
+    inner: Arc<RewriteFn>,
+}
+
+impl Rewrite {
+    pub fn new<F>(inner: F) -> Self
+    where
+        F: Fn(&Action, &PolicyViolation) -> Option<Action> + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Rewrite the `action` denied with `violation`, or `None` if the rewrite does not apply
+    pub fn apply(&self, action: &Action, violation: &PolicyViolation) -> Option<Action> {
+        (self.inner)(action, violation)
+    }
+}
+
+// Rewrite the arguments of the calls to tools sending messages, returning `None` when the
+// arguments stay the same
+pub(super) fn rewrite_send_args<F>(action: &Action, rewrite: F) -> Option<Action>
+where
+    F: Fn(&mut serde_json::Map<String, Value>),
+{
+    let Action::MakeCall(function, args, id) = action else {
+        return None;
+    };
+    if !function.name().starts_with("send_") {
+        return None;
+    }
+    let Value::Object(mut fields) = serde_json::from_str(args.value()).ok()? else {
+        return None;
+    };
+    let original = fields.clone();
+    rewrite(&mut fields);
+    (fields != original).then(|| {
+        Action::MakeCall(
+            function.clone(),
+            Args::new(Value::Object(fields).to_string()),
+            id.clone(),
+        )
+    })
+}
+
+/// Rewrite removing the links from the messages sent
+pub fn remove_urls() -> Rewrite {
+    Rewrite::new(|action, _| rewrite_send_args(action, strip_urls))
+}
+
+// Replace the links in what the `fields` of a call send
+pub(super) fn strip_urls(fields: &mut serde_json::Map<String, Value>) {
+    rewrite_messages(fields, |message| {
+        let mut stripped = message.to_string();
+        for url in find_urls(message).unwrap_or_default() {
+            stripped = stripped.replace(url, "[link removed]");
+        }
+        stripped
+    });
+}
+
+// Rewrite each of the `fields` of a call carrying what is sent, such as the message of a Slack
+// message or the subject and body of an email
+pub(super) fn rewrite_messages<F>(fields: &mut serde_json::Map<String, Value>, rewrite: F)
+where
+    F: Fn(&str) -> String,
+{
+    for arg in MESSAGE_ARGS {
+        if let Some(Value::String(message)) = fields.get(*arg) {
+            let rewritten = rewrite(message);
+            fields.insert(arg.to_string(), Value::String(rewritten));
+        }
+    }
+}
+
+/// Rewrite sending the messages to the `recipient` instead, such as the user themselves or a
+/// channel internal to the organization
+pub fn internal_recipient(recipient: &str) -> Rewrite {
+    let recipient = recipient.to_string();
+    Rewrite::new(move |action, _| {
+        rewrite_send_args(action, |fields| {
+            for field in ["channel", "recipient", "to"] {
+                if fields.contains_key(field) {
+                    fields.insert(field.to_string(), Value::String(recipient.clone()));
+                }
+            }
+        })
+    })
+}
+
+/// How the loop repairs the tool calls denied by the policy
+#[derive(Clone, Default)]
+pub struct PlanRepair {
+    rewrites: Vec<Rewrite>,
+    // Number of times the model is asked to fix the call, after the rewrites failed
+    model_attempts: usize,
+}
+
+impl PlanRepair {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Try the `rewrite` after the ones added before it
+    pub fn with_rewrite(mut self, rewrite: Rewrite) -> Self {
+        self.rewrites.push(rewrite);
+        self
+    }
+
+    /// Ask the model to fix the call up to `attempts` times, once no rewrite complies
+    pub fn with_model_attempts(mut self, attempts: usize) -> Self {
+        self.model_attempts = attempts;
+        self
+    }
+
+    /// Variants of the `action` denied with `violation`, produced by the rewrites
+    pub fn rewrites(&self, action: &Action, violation: &PolicyViolation) -> Vec<Action> {
+        self.rewrites
+            .iter()
+            .filter_map(|rewrite| rewrite.apply(action, violation))
+            .collect()
+    }
+
+    pub fn model_attempts(&self) -> usize {
+        self.model_attempts
+    }
+}
+
+/// Request asking the model to fix the arguments of the call `action`, denied with `violation`.
+/// The arguments which are `hidden` from the model are left out, see [`hidden_args`]. The model
+/// answers with the arguments alone, see [`repaired_call`].
+pub fn repair_request(
+    action: &Action,
+    violation: &PolicyViolation,
+    hidden: &[String],
+) -> Option<Vec<ChatCompletionRequestMessage>> {
+    let Action::MakeCall(function, args, _) = action else {
+        return None;
+    };
+    let Value::Object(mut shown) = serde_json::from_str(args.value()).ok()? else {
+        return None;
+    };
+    shown.retain(|arg, _| !hidden.contains(arg));
+    let system = ChatCompletionRequestSystemMessageArgs::default()
+        .content(
+            "A security policy blocked a tool call. Change its arguments as little as possible \
+            such that the call complies with the policy while still doing what it was meant to. \
+            Answer with the JSON object of the new arguments and nothing else.",
+        )
+        .build()
+        .ok()?;
+    let user = ChatCompletionRequestUserMessageArgs::default()
+        .content(format!(
+            "Tool: `{}`\nArguments: {}\nBlocked because {}.",
+            function.name(),
+            Value::Object(shown),
+            violation.explanation()
+        ))
+        .build()
+        .ok()?;
+    Some(vec![system.into(), user.into()])
+}
+
+/// The call `action` with the arguments in the model's `answer` to a [`repair_request`], or `None`
+/// if the answer holds no arguments. The arguments `hidden` from the model keep their values.
+pub fn repaired_call(action: &Action, answer: &str, hidden: &[String]) -> Option<Action> {
+    let Action::MakeCall(function, args, id) = action else {
+        return None;
+    };
+    // Models tend to wrap JSON in code fences
+    let answer = answer
+        .trim()
+        .trim_start_matches("```json")
+        .trim_matches('`')
+        .trim();
+    let Value::Object(mut fields) = serde_json::from_str(answer).ok()? else {
+        return None;
+    };
+    let Value::Object(denied) = serde_json::from_str(args.value()).ok()? else {
+        return None;
+    };
+    for arg in hidden {
+        match denied.get(arg) {
+            Some(value) => fields.insert(arg.clone(), value.clone()),
+            None => fields.remove(arg),
+        };
+    }
+    Some(Action::MakeCall(
+        function.clone(),
+        Args::new(Value::Object(fields).to_string()),
+        id.clone(),
+    ))
+}
+
+// Arguments of the call `id` as the model wrote them in the conversation `messages`, if the call is
+// in it
+fn written_args(messages: &[ChatCompletionRequestMessage], id: &str) -> Option<Map<String, Value>> {
+    messages.iter().rev().find_map(|message| {
+        let ChatCompletionRequestMessage::Assistant(message) = message else {
+            return None;
+        };
+        let call = message
+            .tool_calls
+            .iter()
+            .flatten()
+            .find(|call| call.id == id)?;
+        match serde_json::from_str(&call.function.arguments).ok()? {
+            Value::Object(args) => Some(args),
+            _ => None,
+        }
+    })
+}
+
+// Whether the model wrote the `value` of an argument itself as `written`, either as it is or
+// given by value, rather than referring to data it may not have seen
+fn written_by_model(written: &Value, value: &Value) -> bool {
+    match written.get("kind") {
+        Some(kind) => kind == "value" && written.get("value") == Some(value),
+        None => written == value,
+    }
+}
+
+/// Arguments of the call `action` which the model did not write itself in the conversation
+/// `messages`, such as those it referred to by variable. All of them are hidden if the model did
+/// not make the call.
+pub fn hidden_args(action: &Action, messages: &[ChatCompletionRequestMessage]) -> Vec<String> {
+    let Action::MakeCall(_, args, id) = action else {
+        return vec![];
+    };
+    let Ok(Value::Object(args)) = serde_json::from_str(args.value()) else {
+        return vec![];
+    };
+    let written = written_args(messages, id).unwrap_or_default();
+    args.into_iter()
+        .filter(|(arg, value)| {
+            !written
+                .get(arg)
+                .is_some_and(|written| written_by_model(written, value))
+        })
+        .map(|(arg, _)| arg)
+        .collect()
+}
+
+/// Rewrite the call the model made in the conversation `messages` into the `repaired` variant of
+/// the `denied` call, such that the model goes on knowing which call was made. Only the arguments
+/// the model wrote itself are rewritten, those it referred to by variable keep referring to it.
+pub fn rewrite_tool_call(
+    messages: &mut [ChatCompletionRequestMessage],
+    denied: &Action,
+    repaired: &Action,
+) {
+    let (Action::MakeCall(_, denied, id), Action::MakeCall(_, repaired, _)) = (denied, repaired)
+    else {
+        return;
+    };
+    let parse = |args: &Args| match serde_json::from_str(args.value()) {
+        Ok(Value::Object(args)) => args,
+        _ => Map::new(),
+    };
+    let (denied, repaired) = (parse(denied), parse(repaired));
+    let Some(call) = messages.iter_mut().rev().find_map(|message| match message {
+        ChatCompletionRequestMessage::Assistant(message) => message
+            .tool_calls
+            .iter_mut()
+            .flatten()
+            .find(|call| call.id == *id),
+        _ => None,
+    }) else {
+        return;
+    };
+    let Ok(Value::Object(mut written)) = serde_json::from_str(&call.function.arguments) else {
+        return;
+    };
+    // Arguments are given by value the way the model gave the others
+    let by_value = written
+        .values()
+        .any(|written| written.get("kind").is_some());
+    let args = denied
+        .keys()
+        .chain(repaired.keys())
+        .cloned()
+        .collect::<Vec<_>>();
+    for arg in args {
+        let (before, after) = (denied.get(&arg), repaired.get(&arg));
+        let writable = match (written.get(&arg), before) {
+            (Some(written), Some(before)) => written_by_model(written, before),
+            (written, _) => written.is_none(),
+        };
+        if before == after || !writable {
+            continue;
+        }
+        match after {
+            Some(after) if by_value => {
+                written.insert(arg, json!({ "kind": "value", "value": after }))
+            }
+            Some(after) => written.insert(arg, after.clone()),
+            None => written.remove(&arg),
+        };
+    }
+    call.function.arguments = Value::Object(written).to_string();
+}
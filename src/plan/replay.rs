@@ -0,0 +1,280 @@
+//! Replays of saved conversations, such that a bug report about what an agent did can be
+//! reproduced offline and deterministically. A [`ConversationHistory`] saved with
+//! [`ConversationHistory::save`] holds everything the planner was given during the run: the
+//! requests of the user, the answers of the model and the results of the tools. [`replay`] and
+//! [`replay_labeled`] hand these to a fresh planner in the same order, without querying any model
+//! nor calling any tool, and return the actions the planner takes on them.
+//!
+//! Messages the planners add to the conversation themselves, such as system prompts, are not
+//! handed over again.
+use super::Plan;
+use crate::{
+    ConversationHistory, Image, Message, State, ifc::Lattice, message::TOOL_IMAGE_RESULT,
+    tools::MetaValue,
+};
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestMessage, ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestToolMessageContentPart, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart, ChatCompletionResponseMessage, Role,
+};
+
+/// Labeled conversation, where each message keeps the label it was handed to the planner with
+pub type LabeledHistory<L> = ConversationHistory<MetaValue<ChatCompletionRequestMessage, L>>;
+
+/// Re-drive the `planner` with the messages of the saved `history`, returning the conversation it
+/// ends up with and the actions it took, in order
+pub fn replay<P: Plan<State, Message>>(
+    planner: &mut P,
+    history: &State,
+) -> Result<(State, Vec<P::Action>), P::Error> {
+    let messages = to_messages(history.messages().iter().map(|message| (message, ())));
+    drive(planner, messages.map(|(message, _)| message))
+}
+
+/// Re-drive the `planner` with the messages of the saved `history`, each with its label
+pub fn replay_labeled<L, P>(
+    planner: &mut P,
+    history: &LabeledHistory<L>,
+) -> Result<(State, Vec<P::Action>), P::Error>
+where
+    L: Lattice,
+    P: Plan<State, MetaValue<Message, L>>,
+{
+    let messages = to_messages(history.messages().iter().map(MetaValue::raw_parts));
+    drive(
+        planner,
+        messages.map(|(message, label)| MetaValue::new(message, label.clone())),
+    )
+}
+
+fn drive<M, P: Plan<State, M>>(
+    planner: &mut P,
+    messages: impl Iterator<Item = M>,
+) -> Result<(State, Vec<P::Action>), P::Error> {
+    let mut state = State::default();
+    let mut actions = vec![];
+    for message in messages {
+        let (new_state, action) = planner.plan(state, message)?;
+        state = new_state;
+        actions.push(action);
+    }
+    Ok((state, actions))
+}
+
+// The messages the planner was given for the saved `messages`, each along with its `T`. Images
+// resulting from a tool call are saved as the result of the call followed by the image, which the
+// planner was given as a single message.
+fn to_messages<'a, T>(
+    messages: impl Iterator<Item = (&'a ChatCompletionRequestMessage, T)>,
+) -> impl Iterator<Item = (Message, T)> {
+    // Tool call whose image follows, if any
+    let mut image_call = None;
+    messages.filter_map(move |(message, meta)| match to_message(message)? {
+        Message::ToolResult(content, id) if content == TOOL_IMAGE_RESULT => {
+            image_call = Some(id);
+            None
+        }
+        Message::UserImages(_, images) if image_call.is_some() && images.len() == 1 => {
+            let image = images.into_iter().next()?;
+            Some((Message::ToolImage(image, image_call.take()?), meta))
+        }
+        message => Some((message, meta)),
+    })
+}
+
+// The message the planner was given for the saved `message`, if the planner was given any
+#[allow(deprecated)]
+fn to_message(message: &ChatCompletionRequestMessage) -> Option<Message> {
+    let chat = |role, content, tool_calls| {
+        Message::Chat(ChatCompletionResponseMessage {
+            content,
+            refusal: None,
+            tool_calls,
+            role,
+            function_call: None,
+            audio: None,
+        })
+    };
+    match message {
+        ChatCompletionRequestMessage::User(message) => {
+            let (content, images) = match &message.content {
+                ChatCompletionRequestUserMessageContent::Text(text) => (text.clone(), vec![]),
+                ChatCompletionRequestUserMessageContent::Array(parts) => {
+                    let mut text = vec![];
+                    let mut images = vec![];
+                    for part in parts {
+                        match part {
+                            ChatCompletionRequestUserMessageContentPart::Text(part) => {
+                                text.push(part.text.as_str())
+                            }
+                            ChatCompletionRequestUserMessageContentPart::ImageUrl(part) => {
+                                images.push(Image::from_url(&part.image_url.url))
+                            }
+                            ChatCompletionRequestUserMessageContentPart::InputAudio(_) => {}
+                        }
+                    }
+                    (text.join("\n"), images)
+                }
+            };
+            match images.is_empty() {
+                true => Some(chat(Role::User, Some(content), None)),
+                false => Some(Message::UserImages(content, images)),
+            }
+        }
+        ChatCompletionRequestMessage::Assistant(message) => {
+            let content = message.content.as_ref().map(|content| match content {
+                ChatCompletionRequestAssistantMessageContent::Text(text) => text.clone(),
+                ChatCompletionRequestAssistantMessageContent::Array(parts) => parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ChatCompletionRequestAssistantMessageContentPart::Text(text) => {
+                            Some(text.text.as_str())
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            });
+            Some(chat(Role::Assistant, content, message.tool_calls.clone()))
+        }
+        ChatCompletionRequestMessage::Tool(message) => {
+            let content = match &message.content {
+                ChatCompletionRequestToolMessageContent::Text(text) => text.clone(),
+                ChatCompletionRequestToolMessageContent::Array(parts) => parts
+                    .iter()
+                    .map(|part| match part {
+                        ChatCompletionRequestToolMessageContentPart::Text(text) => {
+                            text.text.as_str()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            };
+            Some(Message::ToolResult(content, message.tool_call_id.clone()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Action, BasicPlanner, Integrity, TaintTrackingPlanner, mock::MockLlm};
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestToolMessageArgs,
+        ChatCompletionRequestUserMessageArgs,
+    };
+    use serde_json::json;
+
+    fn history() -> State {
+        let tool_calls = MockLlm::assistant_tool_call(
+            "call_0",
+            "read_emails",
+            json!({ "count": { "kind": "value", "value": "1" } }),
+        )
+        .tool_calls
+        .unwrap();
+        State::new(vec![
+            ChatCompletionRequestUserMessageArgs::default()
+                .content("Read my latest email.")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .tool_calls(tool_calls)
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestToolMessageArgs::default()
+                .content("Alice: the meeting is at 10.")
+                .tool_call_id("call_0")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content("Alice confirmed the meeting.")
+                .build()
+                .unwrap()
+                .into(),
+        ])
+    }
+
+    #[test]
+    fn saved_conversations_are_replayed_offline() {
+        let json = serde_json::to_string(&history()).unwrap();
+        let saved: State = serde_json::from_str(&json).unwrap();
+
+        let (state, actions) = replay(&mut BasicPlanner::new(vec![]), &saved).unwrap();
+        assert!(matches!(
+            &actions[..],
+            [
+                Action::Query(..),
+                Action::MakeCall(function, _, id),
+                Action::Query(..),
+                Action::Finish(answer),
+            ] if function.name() == "read_emails"
+                && id == "call_0"
+                && answer == "Alice confirmed the meeting."
+        ));
+        assert_eq!(state.messages(), history().messages());
+
+        let labeled: LabeledHistory<Integrity> = ConversationHistory::new(
+            saved
+                .into_inner()
+                .into_iter()
+                .map(|message| MetaValue::new(message, Integrity::untrusted()))
+                .collect(),
+        );
+        let labeled = serde_json::to_string(&labeled).unwrap();
+        let (_, actions) = replay_labeled(
+            &mut TaintTrackingPlanner::<Integrity>::new(vec![]),
+            &serde_json::from_str(&labeled).unwrap(),
+        )
+        .unwrap();
+        let (Action::Finish(_), label) = actions.last().unwrap() else {
+            panic!("Expected the replay to finish");
+        };
+        assert_eq!(label, &Integrity::untrusted());
+    }
+
+    #[test]
+    fn images_are_shown_to_the_model_and_replayed() {
+        let mut planner = BasicPlanner::new(vec![]);
+        let screenshot = Image::base64("image/png", "iVBORw0KGgo=");
+        let (state, _) = planner
+            .plan(
+                State::default(),
+                Message::UserImages(
+                    "What does this email say?".to_string(),
+                    vec![Image::Url("https://magnet.com/logo.png".to_string())],
+                ),
+            )
+            .unwrap();
+        // Tools resulting in an image data URL result in the image
+        let result = Message::tool_result(screenshot.url(), "call_0".to_string());
+        assert!(matches!(&result, Message::ToolImage(image, _) if image == &screenshot));
+        let (state, action) = planner.plan(state, result).unwrap();
+        let Action::Query(conversation, _) = action else {
+            panic!("Expected the model to be queried with the image");
+        };
+        let request = serde_json::to_value(conversation.messages()).unwrap();
+        assert_eq!(request[1]["tool_call_id"], "call_0");
+        assert_eq!(
+            request[2]["content"][1]["image_url"]["url"],
+            "data:image/png;base64,iVBORw0KGgo="
+        );
+
+        // The result of the call and the image following it are replayed as a single message
+        let (_, actions) = replay(&mut BasicPlanner::new(vec![]), &state).unwrap();
+        assert_eq!(actions.len(), 2);
+        let messages: Vec<_> = to_messages(state.messages().iter().map(|message| (message, ())))
+            .map(|(message, _)| message)
+            .collect();
+        assert!(matches!(
+            &messages[..],
+            [Message::UserImages(_, images), Message::ToolImage(image, id)]
+                if images.len() == 1 && image == &screenshot && id == "call_0"
+        ));
+    }
+}
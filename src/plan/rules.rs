@@ -0,0 +1,342 @@
+//! Policies written as data rather than code, such that guardrails can be changed without
+//! rebuilding the agent. A rule set is a JSON document such as
+//!
+//! ```json
+//! {
+//!     "composition": "all_of",
+//!     "rules": [{
+//!         "name": "internal-links",
+//!         "severity": "high",
+//!         "tools": "send_*",
+//!         "integrity": "Trusted",
+//!         "recipients": { "arg": "channel", "allowed": ["*@magnet.com"] },
+//!         "args": [{ "arg": "message", "forbids": "bit\\.ly" }]
+//!     }]
+//! }
+//! ```
+//!
+//! Each rule only looks at calls to the tools matching its `tools` pattern, or at every call when
+//! it has none, and is violated by calls which break any of its conditions. Patterns of tool names
+//! and recipients take `*` as a wildcard, while the constraints on arguments are regexes. All of
+//! them are compiled when the rules are loaded, such that a broken rule is found before any run.
+//! Calls whose arguments cannot be parsed, or which do not name their recipients, break every rule
+//! looking at them.
+use super::{
+    labeled::{ActionLabel, Trace},
+    policy::{Composition, Policy, PolicySet, PolicyViolation, Severity},
+};
+use crate::{Action, Integrity};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{fs, io, path::Path};
+
+/// Error issued while loading rules
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RuleError {
+    Io(io::Error),
+    SerdeJsonError(serde_json::Error),
+    // A pattern of the rule does not compile
+    InvalidPattern { rule: String, error: regex::Error },
+}
+
+impl From<io::Error> for RuleError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for RuleError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::SerdeJsonError(err)
+    }
+}
+
+/// Rules as they are written, before they are compiled into policies
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet {
+    #[serde(default = "all_of")]
+    composition: Composition,
+    rules: Vec<Rule>,
+}
+
+fn all_of() -> Composition {
+    Composition::AllOf
+}
+
+fn high() -> Severity {
+    Severity::High
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Conditions the calls to some tools have to meet
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    name: String,
+    #[serde(default = "high")]
+    severity: Severity,
+    #[serde(default = "enabled")]
+    enabled: bool,
+    // Pattern of the names of the tools the rule applies to, every tool if missing
+    tools: Option<String>,
+    // Integrity the call has to have at least
+    integrity: Option<Integrity>,
+    recipients: Option<Recipients>,
+    #[serde(default)]
+    args: Vec<ArgConstraint>,
+}
+
+/// Recipients a call may send to, given by the argument `arg`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipients {
+    arg: String,
+    // Patterns of the allowed recipients
+    allowed: Vec<String>,
+}
+
+/// Constraint on the string value of the argument `arg`. Calls without the argument meet it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArgConstraint {
+    arg: String,
+    // Regex the value has to match
+    matches: Option<String>,
+    // Regex the value must not match
+    forbids: Option<String>,
+}
+
+impl RuleSet {
+    pub fn from_json(json: &str) -> Result<Self, RuleError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Read the rules from the JSON file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RuleError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Compile the rules into a set of policies named after the rules, which fails if any of their
+    /// patterns does not compile
+    pub fn compile(&self) -> Result<PolicySet, RuleError> {
+        self.rules
+            .iter()
+            .try_fold(PolicySet::new(self.composition), |mut set, rule| {
+                set = set.with_policy(&rule.name, rule.compile()?, rule.severity);
+                set.set_enabled(&rule.name, rule.enabled);
+                Ok(set)
+            })
+    }
+}
+
+impl Rule {
+    fn compile(&self) -> Result<Policy, RuleError> {
+        let regex = |pattern: &str| {
+            Regex::new(pattern).map_err(|error| RuleError::InvalidPattern {
+                rule: self.name.clone(),
+                error,
+            })
+        };
+        let tools = self
+            .tools
+            .as_deref()
+            .map(|tools| regex(&wildcard(tools)))
+            .transpose()?;
+        let recipients = match &self.recipients {
+            Some(recipients) => Some((
+                recipients.arg.clone(),
+                recipients
+                    .allowed
+                    .iter()
+                    .map(|pattern| regex(&wildcard(pattern)))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            None => None,
+        };
+        let args = self
+            .args
+            .iter()
+            .map(|constraint| {
+                Ok((
+                    constraint.arg.clone(),
+                    constraint.matches.as_deref().map(regex).transpose()?,
+                    constraint.forbids.as_deref().map(regex).transpose()?,
+                ))
+            })
+            .collect::<Result<Vec<_>, RuleError>>()?;
+        let integrity = self.integrity.clone();
+        Ok(Policy::new(move |trace: &Trace<ActionLabel>| {
+            let (Action::MakeCall(function, args_json, _), label) =
+                trace.value().last()?.raw_parts()
+            else {
+                return None;
+            };
+            if tools
+                .as_ref()
+                .is_some_and(|tools| !tools.is_match(function.name()))
+            {
+                return None;
+            }
+            let violation = |explanation: String| Some(PolicyViolation::Standard(explanation));
+            if let Some(integrity) = &integrity
+                && label.lattice1() > integrity
+            {
+                return violation(format!(
+                    "`{}` was called with {:?} data, while it takes {integrity:?} data",
+                    function.name(),
+                    label.lattice1()
+                ));
+            }
+            let Ok(call) = serde_json::from_str::<Value>(args_json.value()) else {
+                return violation(format!(
+                    "the arguments of `{}` could not be parsed",
+                    function.name()
+                ));
+            };
+            let arg = |name: &str| {
+                call.get(name).map(|value| match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                })
+            };
+            if let Some((name, allowed)) = &recipients {
+                // Recipients are either a list or a single string separated by commas
+                let named = match call.get(name) {
+                    Some(Value::String(recipients)) => {
+                        Some(recipients.split(',').map(str::trim).collect::<Vec<_>>())
+                    }
+                    Some(Value::Array(recipients)) => {
+                        recipients.iter().map(Value::as_str).collect()
+                    }
+                    _ => None,
+                };
+                let Some(named) = named else {
+                    return violation(format!(
+                        "`{}` does not name its recipients in its `{name}` argument",
+                        function.name()
+                    ));
+                };
+                if let Some(recipient) = named
+                    .iter()
+                    .find(|recipient| !allowed.iter().any(|pattern| pattern.is_match(recipient)))
+                {
+                    return violation(format!("{recipient} is not an allowed recipient"));
+                }
+            }
+            args.iter().find_map(|(name, matches, forbids)| {
+                let value = arg(name)?;
+                if let Some(matches) = matches
+                    && !matches.is_match(&value)
+                {
+                    return violation(format!("the `{name}` argument does not match `{matches}`"));
+                }
+                match forbids.as_ref()?.find(&value) {
+                    Some(found) => violation(format!(
+                        "the `{name}` argument contains `{}`",
+                        found.as_str()
+                    )),
+                    None => None,
+                }
+            })
+        }))
+    }
+}
+
+// Anchored regex of a `pattern` in which `*` stands for any text
+fn wildcard(pattern: &str) -> String {
+    let parts: Vec<_> = pattern.split('*').map(regex::escape).collect();
+    format!("^{}$", parts.join(".*"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Args, Function, ProductLattice,
+        tools::{EmailAddressUniverse, INBOX, MetaValue, readers_label},
+    };
+    use serde_json::json;
+
+    fn call(function: &str, args: impl ToString, integrity: Integrity) -> Trace<ActionLabel> {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            integrity,
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let mut trace = Trace::default();
+        trace.value_mut().push(MetaValue::new(
+            Action::MakeCall(
+                Function::new(function.to_string()),
+                Args::new(args.to_string()),
+                "call_0".to_string(),
+            ),
+            label,
+        ));
+        trace
+    }
+
+    #[test]
+    fn rules_are_compiled_into_policies() {
+        let rules = RuleSet::from_json(
+            r#"{
+                "rules": [{
+                    "name": "internal-links",
+                    "tools": "send_*",
+                    "integrity": "Trusted",
+                    "recipients": { "arg": "channel", "allowed": ["*@magnet.com"] },
+                    "args": [{ "arg": "message", "forbids": "bit\\.ly/\\S+" }]
+                }, {
+                    "name": "small-reads",
+                    "severity": "low",
+                    "enabled": false,
+                    "tools": "read_emails",
+                    "args": [{ "arg": "count", "matches": "^[0-9]$" }]
+                }]
+            }"#,
+        )
+        .unwrap();
+        let policy: Policy = rules.compile().unwrap().into();
+        let send = |channel: &str, message: &str, integrity| {
+            let args = json!({ "channel": channel, "message": message, "preview": false });
+            policy.check(&call("send_slack_message", args, integrity))
+        };
+
+        assert!(send("bob.sheffield@magnet.com", "Lunch?", Integrity::trusted()).is_none());
+        let violation = send("bob.sheffield@magnet.com", "Lunch?", Integrity::untrusted());
+        assert!(violation.unwrap().explanation().contains("Untrusted data"));
+        let violation = send("eve@evil.com", "Lunch?", Integrity::trusted());
+        assert!(violation.unwrap().explanation().contains("eve@evil.com"));
+        let violation = send(
+            "bob.sheffield@magnet.com",
+            "See bit.ly/x",
+            Integrity::trusted(),
+        );
+        assert!(violation.unwrap().explanation().contains("`bit.ly/x`"));
+        // Calls which cannot be made sense of are denied
+        let unparsed = call("send_slack_message", "{", Integrity::trusted());
+        assert!(policy.check(&unparsed).is_some());
+        let anonymous = json!({ "message": "Lunch?", "preview": false });
+        let anonymous = call("send_slack_message", anonymous, Integrity::trusted());
+        assert!(policy.check(&anonymous).is_some());
+        // Disabled rules, and rules about other tools, are not checked
+        let read = call(
+            "read_emails",
+            json!({ "count": 50 }),
+            Integrity::untrusted(),
+        );
+        assert!(policy.check(&read).is_none());
+
+        let broken = RuleSet::from_json(
+            r#"{ "rules": [{ "name": "broken", "args": [{ "arg": "x", "matches": "(" }] }] }"#,
+        )
+        .unwrap()
+        .compile();
+        assert!(matches!(broken, Err(RuleError::InvalidPattern { rule, .. }) if rule == "broken"));
+    }
+}
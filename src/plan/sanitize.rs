@@ -0,0 +1,157 @@
+//! Sanitization of the tool calls before they are made. Repairs only kick in once the policy
+//! denied a call, while sanitizers rewrite every call up front, such as taking the links out of
+//! the messages carrying untrusted data. The sanitized call takes the place of the planned one in
+//! the trace, such that the policy checks the call which is actually made.
+use super::{
+    labeled::ActionLabel,
+    repair::{rewrite_messages, rewrite_send_args, strip_urls},
+};
+use crate::{Action, Integrity};
+use std::sync::Arc;
+
+type SanitizeFn = dyn Fn(&Action, Option<&ActionLabel>) -> Option<Action> + Send + Sync;
+
+/// Rewrite of the tool calls about to be made, given their label if it can be told as an
+/// `EmailLabel`
+#[derive(Clone)]
+pub struct Sanitizer {
+    inner: Arc<SanitizeFn>,
+}
+
+impl Sanitizer {
+    pub fn new<F>(inner: F) -> Self
+    where
+        F: Fn(&Action, Option<&ActionLabel>) -> Option<Action> + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Sanitize the `action` labeled `label`, or `None` if the action stays the same
+    pub fn apply(&self, action: &Action, label: Option<&ActionLabel>) -> Option<Action> {
+        (self.inner)(action, label)
+    }
+}
+
+/// Sanitize the `action` with each of the `sanitizers` in order, each one rewriting the result of
+/// the one before. Returns `None` if none of them changed the action.
+pub fn sanitize(
+    sanitizers: &[Sanitizer],
+    action: &Action,
+    label: Option<&ActionLabel>,
+) -> Option<Action> {
+    sanitizers.iter().fold(None, |sanitized, sanitizer| {
+        sanitizer
+            .apply(sanitized.as_ref().unwrap_or(action), label)
+            .or(sanitized)
+    })
+}
+
+/// Sanitizer taking the links out of the messages sent with untrusted data. Messages whose label
+/// is not known are taken as untrusted.
+pub fn strip_untrusted_urls() -> Sanitizer {
+    Sanitizer::new(|action, label| {
+        if label.is_some_and(|label| label.lattice1() == &Integrity::Trusted) {
+            return None;
+        }
+        rewrite_send_args(action, strip_urls)
+    })
+}
+
+/// Sanitizer redacting the email addresses written in the messages sent, down to the subject and
+/// body of emails. Who the messages are sent to is left as it is.
+pub fn redact_email_addresses() -> Sanitizer {
+    let address = regex::Regex::new(r"[\w.+-]+@[\w-]+(\.[\w-]+)+").expect("Invalid address regex");
+    Sanitizer::new(move |action, _| {
+        rewrite_send_args(action, |fields| {
+            rewrite_messages(fields, |message| {
+                address
+                    .replace_all(message, "[address redacted]")
+                    .into_owned()
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, Function, ProductLattice, tools::readers_label};
+    use serde_json::Value;
+    use std::collections::HashSet;
+
+    #[test]
+    fn sanitizers_rewrite_calls_in_order() {
+        let call = Action::MakeCall(
+            Function::new("send_slack_message".to_string()),
+            Args::new(
+                serde_json::json!({
+                    "channel": "bob.sheffield@magnet.com",
+                    "message": "Ask alice.hudson@magnet.com, see https://fides.github.io/x",
+                })
+                .to_string(),
+            ),
+            "call_0".to_string(),
+        );
+        let universe = HashSet::from(["bob.sheffield@magnet.com".to_string()]);
+        let label = |integrity| {
+            ProductLattice::new(
+                integrity,
+                readers_label(universe.clone(), universe.clone()).unwrap(),
+            )
+        };
+        let sanitizers = [strip_untrusted_urls(), redact_email_addresses()];
+
+        let Some(Action::MakeCall(_, args, _)) =
+            sanitize(&sanitizers, &call, Some(&label(Integrity::untrusted())))
+        else {
+            panic!("The call should be sanitized");
+        };
+        let args: Value = serde_json::from_str(args.value()).unwrap();
+        assert_eq!(args["channel"], "bob.sheffield@magnet.com");
+        assert_eq!(
+            args["message"],
+            "Ask [address redacted], see [link removed]"
+        );
+
+        // Trusted messages keep their links
+        let Some(Action::MakeCall(_, args, _)) =
+            sanitize(&sanitizers, &call, Some(&label(Integrity::trusted())))
+        else {
+            panic!("The call should be sanitized");
+        };
+        assert!(args.value().contains("https://fides.github.io/x"));
+        assert!(
+            sanitize(
+                &[strip_untrusted_urls()],
+                &call,
+                Some(&label(Integrity::trusted()))
+            )
+            .is_none()
+        );
+
+        // Emails are sanitized down to their subject and body
+        let email = Action::MakeCall(
+            Function::new("send_email_labeled".to_string()),
+            Args::new(
+                serde_json::json!({
+                    "to": "bob.sheffield@magnet.com",
+                    "subject": "Ask alice.hudson@magnet.com",
+                    "body": "See https://fides.github.io/x",
+                })
+                .to_string(),
+            ),
+            "call_0".to_string(),
+        );
+        let Some(Action::MakeCall(_, args, _)) =
+            sanitize(&sanitizers, &email, Some(&label(Integrity::untrusted())))
+        else {
+            panic!("The email should be sanitized");
+        };
+        let args: Value = serde_json::from_str(args.value()).unwrap();
+        assert_eq!(args["to"], "bob.sheffield@magnet.com");
+        assert_eq!(args["subject"], "Ask [address redacted]");
+        assert_eq!(args["body"], "See [link removed]");
+    }
+}
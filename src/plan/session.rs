@@ -0,0 +1,181 @@
+//! Conversations of several turns with the same assistant. A [`Session`] owns a [`PlanningLoop`]
+//! along with the conversation and the datastore it carries from one request of the user to the
+//! next, such that each request is answered knowing the ones before it.
+//!
+//! A request which fails leaves the conversation as it was before it, while what its tools wrote
+//! to the datastore stays unless the loop runs [`with_atomic_runs`].
+//!
+//! [`with_atomic_runs`]: PlanningLoop::with_atomic_runs
+use super::{Plan, PlanError, PlanningLoop, RunTrace};
+use crate::{Action, Datastore, Function, Message, State};
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+};
+
+/// Assistant answering the requests of the user one after the other, in the same conversation
+pub struct Session<P: Plan<State, Message>> {
+    planning_loop: PlanningLoop<State, Message, Function, P>,
+    // Conversation up to the latest answer
+    state: State,
+    datastore: Datastore,
+    // Requests answered so far
+    turns: usize,
+}
+
+impl<P: Plan<State, Message, Action = Action>> Session<P> {
+    pub fn new(planning_loop: PlanningLoop<State, Message, Function, P>) -> Self {
+        Self {
+            planning_loop,
+            state: State::default(),
+            datastore: Datastore::default(),
+            turns: 0,
+        }
+    }
+
+    /// Start the conversation with the `prompt` as system message
+    pub fn with_system_prompt(mut self, prompt: &str) -> Result<Self, PlanError> {
+        let system = ChatCompletionRequestSystemMessageArgs::default()
+            .content(prompt)
+            .build()?;
+        self.state = State::new(vec![system.into()]);
+        Ok(self)
+    }
+
+    /// Run the requests with the `datastore`, such as one holding the mailbox of the user
+    pub fn with_datastore(mut self, datastore: Datastore) -> Self {
+        self.datastore = datastore;
+        self
+    }
+
+    /// Answer the `request` of the user, in the conversation of the requests answered before
+    pub async fn ask(&mut self, request: &str) -> Result<String, PlanError> {
+        let (answer, trace) = self
+            .planning_loop
+            .run_traced(
+                self.state.clone(),
+                &mut self.datastore,
+                Message::user(request),
+            )
+            .await;
+        let answer = answer?;
+        self.state = conversation(&self.state, request, &trace, &answer)?;
+        self.turns += 1;
+        Ok(answer)
+    }
+
+    /// The conversation up to the latest answer
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn datastore(&self) -> &Datastore {
+        &self.datastore
+    }
+
+    pub fn datastore_mut(&mut self) -> &mut Datastore {
+        &mut self.datastore
+    }
+
+    pub fn planning_loop_mut(&mut self) -> &mut PlanningLoop<State, Message, Function, P> {
+        &mut self.planning_loop
+    }
+
+    /// Number of requests answered so far
+    pub fn turns(&self) -> usize {
+        self.turns
+    }
+
+    /// Forget the conversation, keeping its system message if it had one
+    pub fn reset(&mut self) {
+        let system = self
+            .state
+            .messages()
+            .first()
+            .filter(|message| matches!(message, ChatCompletionRequestMessage::System(_)))
+            .cloned();
+        self.state = State::new(system.into_iter().collect());
+        self.turns = 0;
+    }
+}
+
+// The conversation after the run answering `request` from `state`: the one the model was last
+// queried with, which holds everything the run added to it, followed by the `answer`
+fn conversation(
+    state: &State,
+    request: &str,
+    trace: &RunTrace,
+    answer: &str,
+) -> Result<State, PlanError> {
+    let queried = trace
+        .steps()
+        .iter()
+        .rev()
+        .find_map(|step| match &step.action {
+            Action::Query(conv_history, _) => Some(conv_history.clone()),
+            _ => None,
+        });
+    let mut conversation = match queried {
+        Some(conversation) => conversation,
+        // The planner answered without querying the model
+        None => state.clone().with_message(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(request)
+                .build()?
+                .into(),
+        ),
+    };
+    conversation.push(
+        ChatCompletionRequestAssistantMessageArgs::default()
+            .content(answer)
+            .build()?
+            .into(),
+    );
+    Ok(conversation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicPlanner, mock::MockLlm, openai::LlmClient};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn later_requests_are_answered_in_the_same_conversation() {
+        let mut session = Session::new(PlanningLoop::new(
+            BasicPlanner::new(vec![]),
+            LlmClient::mock(MockLlm::new(vec![
+                MockLlm::assistant_tool_call(
+                    "call_0",
+                    "read_emails",
+                    json!({ "count": { "kind": "value", "value": "1" } }),
+                ),
+                MockLlm::assistant_text("Alice confirmed the meeting."),
+                MockLlm::assistant_text("It is at 10 AM."),
+            ])),
+            vec![Function::new("read_emails".to_string())],
+        ))
+        .with_system_prompt("You are an email assistant.")
+        .unwrap();
+
+        let answer = session.ask("Read my latest email.").await.unwrap();
+        assert_eq!(answer, "Alice confirmed the meeting.");
+        let answer = session.ask("When is the meeting?").await.unwrap();
+        assert_eq!(answer, "It is at 10 AM.");
+        assert_eq!(session.turns(), 2);
+
+        // The second request is asked with the first one, its tool call and its answer
+        let requests = session
+            .planning_loop_mut()
+            .model()
+            .as_mock()
+            .unwrap()
+            .requests();
+        assert_eq!(requests.last().unwrap().len(), 6);
+        // System message, both requests, the tool call and its result, and both answers
+        assert_eq!(session.state().messages().len(), 7);
+
+        session.reset();
+        assert_eq!(session.state().messages().len(), 1);
+    }
+}
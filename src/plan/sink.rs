@@ -0,0 +1,315 @@
+//! Trace entries are streamed to [`TraceSink`]s as the [`PlanningLoop`] produces them, such that
+//! long-running agents can be monitored live without holding their whole trace in memory.
+//!
+//! Sinks are usually slower than the loop, so entries go through a bounded [`TraceStream`] which
+//! writes them to the sink in the background and either waits for room or drops entries once its
+//! buffer is full, depending on its [`Overflow`] policy.
+//!
+//! [`PlanningLoop`]: super::PlanningLoop
+use super::audit::redacted_action_json;
+use crate::{Action, registry::label_manifest, tools::EmailLabel};
+use serde_json::{Value, json};
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+/// One action taken by the planning loop
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    // Index of the action in the run
+    step: usize,
+    action: Action,
+    // Label of the action, for loops which track labels
+    label: Option<EmailLabel>,
+}
+
+impl TraceEntry {
+    pub fn new(step: usize, action: Action, label: Option<EmailLabel>) -> Self {
+        Self {
+            step,
+            action,
+            label,
+        }
+    }
+
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    pub fn label(&self) -> Option<&EmailLabel> {
+        self.label.as_ref()
+    }
+
+    /// Describe the entry as JSON. Queries only report the size of the conversation, as sending
+    /// the whole conversation at each step is what sinks are supposed to avoid, and the arguments
+    /// and answers which not everyone may read are redacted.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "step": self.step,
+            "action": redacted_action_json(&self.action, self.label.as_ref()),
+            "label": self.label.as_ref().map(label_manifest),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    IoError(std::io::Error),
+    HttpError(reqwest::Error),
+}
+
+impl From<std::io::Error> for SinkError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<reqwest::Error> for SinkError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::HttpError(err)
+    }
+}
+
+/// Destination of the trace entries
+pub trait TraceSink: Send + 'static {
+    fn write(&mut self, entry: &TraceEntry) -> impl Future<Output = Result<(), SinkError>> + Send;
+}
+
+/// Prints each entry as a line of JSON to stdout
+#[derive(Debug, Default)]
+pub struct StdoutSink;
+
+impl TraceSink for StdoutSink {
+    async fn write(&mut self, entry: &TraceEntry) -> Result<(), SinkError> {
+        println!("{}", entry.to_json());
+        Ok(())
+    }
+}
+
+/// Appends each entry as a line of JSON to a file
+#[derive(Debug)]
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    /// Open the file at `path` for appending, creating it if it does not exist
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, SinkError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self { file })
+    }
+}
+
+impl TraceSink for FileSink {
+    async fn write(&mut self, entry: &TraceEntry) -> Result<(), SinkError> {
+        let mut line = entry.to_json().to_string();
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Posts each entry as JSON to a collector listening at `url`
+#[derive(Debug)]
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+        }
+    }
+}
+
+impl TraceSink for HttpSink {
+    async fn write(&mut self, entry: &TraceEntry) -> Result<(), SinkError> {
+        self.client
+            .post(&self.url)
+            .json(&entry.to_json())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// What a [`TraceStream`] does with new entries while its buffer is full
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    // Wait for the sink to catch up, slowing down the planning loop
+    #[default]
+    Block,
+    // Drop the new entry, keeping the planning loop going
+    Drop,
+}
+
+/// Bounded buffer of trace entries which are written to a sink in the background
+#[derive(Debug)]
+pub struct TraceStream {
+    sender: mpsc::Sender<TraceEntry>,
+    overflow: Overflow,
+    // Entries dropped because the buffer was full
+    dropped: Arc<AtomicUsize>,
+    // Entries the sink failed to write
+    failed: Arc<AtomicUsize>,
+    writer: JoinHandle<()>,
+}
+
+impl TraceStream {
+    /// Start writing to the `sink` the entries sent to the stream, buffering at most `capacity`
+    /// of them. Must be called from within a tokio runtime.
+    pub fn new<S: TraceSink>(mut sink: S, capacity: usize, overflow: Overflow) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<TraceEntry>(capacity);
+        let failed = Arc::new(AtomicUsize::new(0));
+        let writer_failed = failed.clone();
+        // A failing sink must not bring the agent down, so its failures are only counted
+        let writer = tokio::spawn(async move {
+            while let Some(entry) = receiver.recv().await {
+                if sink.write(&entry).await.is_err() {
+                    writer_failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        Self {
+            sender,
+            overflow,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            failed,
+            writer,
+        }
+    }
+
+    /// Send the `entry` to the sink, handling a full buffer according to the overflow policy
+    pub async fn send(&self, entry: TraceEntry) {
+        match self.overflow {
+            Overflow::Block => {
+                // The writer only stops once the stream is closed, so this cannot fail
+                let _ = self.sender.send(entry).await;
+            }
+            Overflow::Drop => {
+                if let Err(TrySendError::Full(_)) = self.sender.try_send(entry) {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Number of entries dropped so far because the buffer was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of entries the sink failed to write so far
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Wait for all the buffered entries to be written to the sink
+    pub async fn close(self) {
+        drop(self.sender);
+        let _ = self.writer.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Integrity, tools::readers_label};
+    use std::{collections::HashSet, sync::Mutex};
+
+    // Sink collecting the steps of the entries it writes
+    struct Collect(Arc<Mutex<Vec<usize>>>);
+
+    impl TraceSink for Collect {
+        async fn write(&mut self, entry: &TraceEntry) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push(entry.step());
+            Ok(())
+        }
+    }
+
+    fn entry(step: usize) -> TraceEntry {
+        TraceEntry::new(step, Action::Finish(format!("answer {step}")), None)
+    }
+
+    #[tokio::test]
+    async fn blocking_stream_writes_every_entry() {
+        let steps = Arc::new(Mutex::new(vec![]));
+        let stream = TraceStream::new(Collect(steps.clone()), 1, Overflow::Block);
+        for step in 0..5 {
+            stream.send(entry(step)).await;
+        }
+        assert_eq!(stream.dropped(), 0);
+        stream.close().await;
+        assert_eq!(*steps.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn dropping_stream_keeps_going_when_full() {
+        let steps = Arc::new(Mutex::new(vec![]));
+        let stream = TraceStream::new(Collect(steps.clone()), 2, Overflow::Drop);
+        // The writer does not get to run before the test yields, so only the first entries fit
+        for step in 0..5 {
+            stream.send(entry(step)).await;
+        }
+        assert_eq!(stream.dropped(), 3);
+        stream.close().await;
+        assert_eq!(*steps.lock().unwrap(), vec![0, 1]);
+        assert_eq!(
+            entry(7).to_json(),
+            json!({
+                "step": 7,
+                "action": { "kind": "finish", "answer": "answer 7" },
+                "label": null,
+            })
+        );
+    }
+
+    #[test]
+    fn entries_only_show_what_everyone_may_read() {
+        let universe: HashSet<String> = ["alice@magnet.com", "bob@magnet.com"]
+            .map(String::from)
+            .into();
+        let readable_by = |readers: HashSet<String>| {
+            EmailLabel::new(
+                Integrity::trusted(),
+                readers_label(readers, universe.clone()).unwrap(),
+            )
+        };
+        let answer = |label| {
+            TraceEntry::new(
+                0,
+                Action::Finish("Payroll is due.".to_string()),
+                Some(label),
+            )
+            .to_json()["action"]["answer"]
+                .clone()
+        };
+        assert_eq!(answer(readable_by(universe.clone())), "Payroll is due.");
+        assert_eq!(
+            answer(readable_by(HashSet::from(["alice@magnet.com".to_string()]))),
+            "<redacted:string>"
+        );
+    }
+}
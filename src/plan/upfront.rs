@@ -0,0 +1,381 @@
+//! Module defining `UpfrontPlanner`, which asks the model for the complete plan of a request before
+//! taking any action. The plan is made from the request alone, before any tool result could inject
+//! instructions into it, and is checked against the policy as a whole before its first call is
+//! made. The steps then run without the model, which only sees their results at the end, if at
+//! all. Steps taking the result of an earlier step are checked again with the result in place
+//! before they run.
+use super::{
+    Plan, PlanError,
+    dag::{DagError, DagNode, PlanDag},
+    labeled::Trace,
+    policy::{Policy, refusal_message},
+};
+use crate::{
+    Action, Args, Function, Image, Integrity, Message, ProductLattice, State, TaskType,
+    tools::{EmailLabel, MetaValue},
+};
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionTool, Role,
+};
+use std::sync::Arc;
+
+/// Instructions for the model to answer with the plan as a whole
+pub const UPFRONT_PROMPT: &str = "Plan every tool call needed to complete the request before \
+    any of them is made, as you will not see their results. Answer with a JSON object and nothing \
+    else, of the form {\"nodes\": [{\"function\": <tool name>, \"args\": {<argument>: <value>}}]}. \
+    The calls are made in order. An argument can take the result of an earlier call, counting \
+    from 0, with {\"kind\": \"output\", \"node\": <index of the call>}. The tools are:";
+
+/// A planner which asks the model for a complete plan once, checks it and executes its steps
+pub struct UpfrontPlanner {
+    // Tools the plan may call, described to the model in the prompt
+    tools: Vec<ChatCompletionTool>,
+    // Whether the model writes the final answer from the results of the plan. Data independent
+    // tasks finish with the result of their last step instead, without querying the model again.
+    task_type: TaskType,
+    // Policy checked against every step of the plan before executing any, with the label of the
+    // request the plan was made from
+    policy: Option<(Policy, EmailLabel)>,
+    // The plan being executed, if any, along with the results of its steps done so far
+    plan: Option<(PlanDag, Vec<Option<String>>)>,
+    // Steps of the plan taken so far, as checked against the policy
+    trace: Trace<EmailLabel>,
+}
+
+impl UpfrontPlanner {
+    pub fn new(tools: Vec<ChatCompletionTool>, task_type: TaskType) -> Self {
+        Self {
+            tools,
+            task_type,
+            policy: None,
+            plan: None,
+            trace: Trace::default(),
+        }
+    }
+
+    /// Check every plan against the `policy` before executing it, labeling its steps with the
+    /// `label` of the request, since the model saw nothing else when planning them. Steps taking
+    /// the result of an earlier step are also labeled with the results they take, which are
+    /// untrusted as tool results may carry anything. Such steps are checked with the reference in
+    /// place of the result along with the plan, and again with the result before they run.
+    pub fn with_policy(mut self, policy: Policy, label: EmailLabel) -> Self {
+        self.policy = Some((policy, label));
+        self
+    }
+
+    /// The plan being executed, if any
+    pub fn current_plan(&self) -> Option<&PlanDag> {
+        self.plan.as_ref().map(|(plan, _)| plan)
+    }
+
+    // The instructions describing the plan to answer with and the available tools
+    fn prompt(&self) -> Result<String, PlanError> {
+        let tools = self
+            .tools
+            .iter()
+            .map(|tool| serde_json::to_string(&tool.function))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(format!("{UPFRONT_PROMPT}\n{}", tools.join("\n")))
+    }
+
+    // Check every step of the `plan` against the policy, if any, returning the refusal for the
+    // first step denied
+    fn check(&self, plan: &PlanDag) -> Option<String> {
+        let (policy, label) = self.policy.as_ref()?;
+        let mut trace = Trace::default();
+        for (step, call) in plan.nodes().iter().enumerate() {
+            let action = Action::MakeCall(
+                Function::new(call.function().to_string()),
+                Args::new(serde_json::Value::Object(call.args().clone()).to_string()),
+                step_id(step),
+            );
+            trace
+                .value_mut()
+                .push(MetaValue::new(action.clone(), step_label(label, call)));
+            if let Some(violation) = policy.check(&trace) {
+                return Some(refusal_message(&action, &violation));
+            }
+        }
+        None
+    }
+
+    // Check the `action` taking the step `call` of the plan against the policy, if any, along with
+    // the steps taken before it, returning the refusal if it is denied
+    fn check_step(&mut self, call: &DagNode, action: &Action) -> Option<String> {
+        let (policy, label) = self.policy.as_ref()?;
+        self.trace
+            .value_mut()
+            .push(MetaValue::new(action.clone(), step_label(label, call)));
+        let violation = policy.check(&self.trace)?;
+        Some(refusal_message(action, &violation))
+    }
+
+    // Ask the model for the plan answering the `request` of the user
+    fn request(
+        &mut self,
+        mut state: State,
+        request: ChatCompletionRequestMessage,
+    ) -> Result<(State, Action), PlanError> {
+        // A new request starts a new plan
+        self.plan = None;
+        self.trace = Trace::default();
+        state.push(
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(self.prompt()?)
+                .build()?
+                .into(),
+        );
+        state.push(request);
+        let action = Action::Query(state.clone(), Arc::default());
+        Ok((state, action))
+    }
+
+    // Take the next step of the plan, or finish once all of them are done
+    fn next(&mut self, mut state: State) -> Result<(State, Action), PlanError> {
+        let Some((plan, results)) = &self.plan else {
+            return Err(PlanError::CannotPlan("No plan to execute".to_string()));
+        };
+        if let Some(step) = results.iter().position(Option::is_none) {
+            let call = plan.nodes()[step].clone();
+            let action = Action::MakeCall(
+                Function::new(call.function().to_string()),
+                plan.resolve(step, results),
+                step_id(step),
+            );
+            // The results the step takes were only references when the plan was checked
+            if let Some(refusal) = self.check_step(&call, &action) {
+                self.plan = None;
+                return Ok((state, Action::Finish(refusal)));
+            }
+            return Ok((state, action));
+        }
+        let results: Vec<String> = results.iter().flatten().cloned().collect();
+        match self.task_type {
+            TaskType::DataIndependent => {
+                let answer = results.last().cloned().unwrap_or_default();
+                self.plan = None;
+                Ok((state, Action::Finish(answer)))
+            }
+            // The model writes the answer from the results, without tools to make more calls
+            TaskType::DataDependent => {
+                let results = results
+                    .iter()
+                    .enumerate()
+                    .map(|(step, result)| format!("Result of call {step}: {result}"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                state.push(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(format!("{results}\nAnswer the request from these results."))
+                        .build()?
+                        .into(),
+                );
+                Ok((state.clone(), Action::Query(state, Arc::default())))
+            }
+        }
+    }
+}
+
+// Id of the tool call making the step of the plan
+fn step_id(step: usize) -> String {
+    format!("upfront_{step}")
+}
+
+// Label of the step `call` of a plan made from a request labeled with `label`, which is untrusted
+// if the step takes the result of an earlier step
+fn step_label(label: &EmailLabel, call: &DagNode) -> EmailLabel {
+    if call.references().is_empty() {
+        return label.clone();
+    }
+    ProductLattice::new(Integrity::untrusted(), label.lattice2().clone())
+}
+
+impl Plan<State, Message> for UpfrontPlanner {
+    type Action = Action;
+    type Error = PlanError;
+
+    // The plan is kept along with the results of its steps done so far, such that a resumed run
+    // carries on with the next step instead of asking for a new plan
+    fn memory(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.plan).ok()
+    }
+
+    fn restore_memory(&mut self, memory: serde_json::Value) -> Result<(), serde_json::Error> {
+        self.plan = serde_json::from_value(memory)?;
+        Ok(())
+    }
+
+    fn plan(&mut self, state: State, message: Message) -> Result<(State, Action), PlanError> {
+        let mut new_state = state;
+        match message {
+            Message::Chat(message) if message.role == Role::User => {
+                let request = ChatCompletionRequestUserMessageArgs::default()
+                    .content(message.content.ok_or(PlanError::NoUserContent)?)
+                    .build()?
+                    .into();
+                self.request(new_state, request)
+            }
+            Message::UserImages(content, images) => {
+                let request = Image::user_message(&content, &images)?;
+                self.request(new_state, request)
+            }
+            Message::Chat(message) if message.role == Role::Assistant => {
+                let content = message.content.ok_or(PlanError::NoFunctionCall)?;
+                new_state.push(
+                    ChatCompletionRequestAssistantMessageArgs::default()
+                        .content(content.clone())
+                        .build()?
+                        .into(),
+                );
+                // Once the plan is done, the model's answer is the final one
+                if self.plan.is_some() {
+                    self.plan = None;
+                    return Ok((new_state, Action::Finish(content)));
+                }
+                // Models tend to wrap JSON in code fences
+                let json = content
+                    .trim()
+                    .trim_start_matches("```json")
+                    .trim_matches('`')
+                    .trim();
+                let plan = PlanDag::from_json(json).map_err(|err| match err {
+                    DagError::SerdeJsonError(err) => PlanError::SerdeJsonError(err),
+                    err => PlanError::CannotPlan(format!("{err:?}")),
+                })?;
+                if let Some(refusal) = self.check(&plan) {
+                    return Ok((new_state, Action::Finish(refusal)));
+                }
+                let results = vec![None; plan.len()];
+                self.plan = Some((plan, results));
+                self.next(new_state)
+            }
+            Message::Chat(message) => Err(PlanError::InvalidMessage(format!(
+                "Unexpected message from {:?}",
+                message.role
+            ))),
+            Message::ToolResult(content, id) => {
+                let Some((_, results)) = &mut self.plan else {
+                    return Err(PlanError::InvalidMessage(format!(
+                        "Tool result {id} outside of a plan"
+                    )));
+                };
+                let step = results
+                    .iter()
+                    .position(Option::is_none)
+                    .filter(|&step| step_id(step) == id)
+                    .ok_or_else(|| {
+                        PlanError::InvalidMessage(format!("Unexpected tool call {id}"))
+                    })?;
+                results[step] = Some(content);
+                self.next(new_state)
+            }
+            // Later steps are passed the image as its URL
+            Message::ToolImage(image, id) => {
+                self.plan(new_state, Message::ToolResult(image.url(), id))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Datastore, Integrity, PlanningLoop, ProductLattice,
+        mock::MockLlm,
+        plan::policy::policy_no_untrusted_url,
+        simulation::SimulatedEnvironment,
+        tools::{EmailAddressUniverse, INBOX, readers_label},
+    };
+
+    const PLAN: &str = r#"```json
+        {"nodes": [
+            {"function": "read_emails", "args": {"count": "2"}},
+            {"function": "send_slack_message", "args": {
+                "channel": "bob.sheffield@magnet.com",
+                "message": {"kind": "output", "node": 0},
+                "preview": "false"
+            }}
+        ]}
+        ```"#;
+
+    async fn run(planner: UpfrontPlanner, plan: &str) -> (SimulatedEnvironment, String, usize) {
+        let environment = SimulatedEnvironment::new(
+            "Forward my 2 latest emails to me on Slack.",
+            vec![MockLlm::assistant_text(plan)],
+        );
+        let mut planning_loop = PlanningLoop::new(
+            planner,
+            environment.model(),
+            vec![
+                Function::new("read_emails".to_string()),
+                Function::new("send_slack_message".to_string()),
+            ],
+        );
+        let answer = planning_loop
+            .run(
+                environment.state(),
+                &mut Datastore::default(),
+                environment.request(),
+            )
+            .await
+            .expect("Failed to run");
+        let requests = planning_loop.usage().requests as usize;
+        (environment, answer, requests)
+    }
+
+    #[tokio::test]
+    async fn plans_run_without_querying_the_model_again() {
+        let (environment, answer, requests) =
+            run(UpfrontPlanner::new(vec![], TaskType::DataIndependent), PLAN).await;
+        assert_eq!(requests, 1);
+        assert!(answer.contains("send-"));
+        assert!(
+            environment
+                .sent()
+                .iter()
+                .any(|sent| sent.message().contains("Alice"))
+        );
+    }
+
+    #[tokio::test]
+    async fn plans_are_checked_before_running() {
+        // The model was talked into linking to an attacker's site in the plan itself
+        let plan = PLAN.replace(
+            r#"{"kind": "output", "node": 0}"#,
+            r#""Summary at https://fides.github.io/upfront""#,
+        );
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            Integrity::untrusted(),
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let planner = UpfrontPlanner::new(vec![], TaskType::DataIndependent)
+            .with_policy(Policy::new(policy_no_untrusted_url), label);
+        let (environment, answer, _) = run(planner, &plan).await;
+        assert!(answer.starts_with("I couldn't complete your request"));
+        assert!(
+            environment
+                .sent()
+                .iter()
+                .all(|sent| !sent.message().contains("fides.github.io/upfront"))
+        );
+    }
+
+    #[tokio::test]
+    async fn steps_are_checked_again_with_the_results_they_take() {
+        // The plan only refers to the emails, which turn out to carry an untrusted link
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            Integrity::trusted(),
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let planner = UpfrontPlanner::new(vec![], TaskType::DataIndependent)
+            .with_policy(Policy::new(policy_no_untrusted_url), label);
+        let (environment, answer, _) = run(planner, PLAN).await;
+        assert!(answer.starts_with("I couldn't complete your request"));
+        assert!(environment.sent().is_empty());
+    }
+}
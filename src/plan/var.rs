@@ -1,37 +1,80 @@
 //! Module defining and implementing `VarPlanner` which is an action planner with internal memory
 //! capable of mapping variables to tool call results, allowing for 1 level of indirection between
 //! the LLM tool calling messages and the execution / retrieval of tool results from the caller.
+//! Tool results may themselves reference variables, which are resolved and label checked whenever
+//! a variable is read by the model or passed to a tool.
 use super::{Plan, PlanError};
 use crate::{
-    Action, Args, Function, Message, State,
-    tools::{Memory, Variable},
+    Action, Args, Confidentiality, Function, Image, Integrity, Label, Message, State,
+    message::result_messages,
+    tools::{Variable, VariableMemory},
 };
 use async_openai::types::{
     ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestToolMessageArgs,
     ChatCompletionRequestUserMessageArgs, ChatCompletionTool, FunctionCall, Role,
 };
 use serde_json::{Map, Value};
-use std::collections::HashMap;
+use std::sync::Arc;
 
 /// A planner that takes a set of actions given an array of tools. It does not returns tool results
 /// directly to the LLM, but rather it uses internal `memory` to map tool results to variables and
 /// then when queried about a variable ID, it returns the matching tool result
 pub struct VarPlanner {
     // Set of tools the LLM could choose to call.
-    tools: Vec<ChatCompletionTool>,
+    tools: Arc<Vec<ChatCompletionTool>>,
     // Memory mapping variable names to tool results from tool calls
-    memory: Memory,
+    memory: VariableMemory<Label>,
+    // Label given to the tool results stored in memory
+    result_label: Label,
+    // Most restrictive label the variables read or passed to tools may carry
+    clearance: Label,
 }
 
 impl VarPlanner {
-    /// Create a new [`VarPlanner`] with the given `tools` and empty memory
+    /// Create a new [`VarPlanner`] with the given `tools` and empty memory. Tool results are
+    /// public and trusted, and any variable can be used.
     pub fn new(tools: Vec<ChatCompletionTool>) -> Self {
         Self {
-            tools,
-            memory: HashMap::new(),
+            tools: Arc::new(tools),
+            memory: VariableMemory::default(),
+            result_label: Label::new(Confidentiality::low(), Integrity::trusted()),
+            clearance: Label::new(Confidentiality::high(), Integrity::untrusted()),
         }
     }
 
+    /// Label the tool results stored from now on with `label`
+    pub fn with_result_label(mut self, label: Label) -> Self {
+        self.result_label = label;
+        self
+    }
+
+    /// Only let variables whose resolved label can flow to `clearance` be read or passed to tools
+    pub fn with_clearance(mut self, clearance: Label) -> Self {
+        self.clearance = clearance;
+        self
+    }
+
+    /// Start with the variables of `memory`, such as the ones persisted by an earlier task
+    pub fn with_memory(mut self, memory: VariableMemory<Label>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// The variables stored so far, along with their results and labels, for audits
+    pub fn memory(&self) -> &VariableMemory<Label> {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut VariableMemory<Label> {
+        &mut self.memory
+    }
+
+    // Resolve the references made by `variable` and check the result against the clearance
+    fn resolve(&self, variable: &Variable) -> Result<Value, PlanError> {
+        let resolved = self.memory.resolve_within(variable, &self.clearance)?;
+        Ok(resolved.into_raw_parts().0)
+    }
+
     /// Normalize the arguments passed by the LLM. The LLM is instructed to pass a specific schema
     /// for the function arguments such that it could be distinguished which arguments are
     /// `variables` which have to be queried by internal memory and which are plain variables which
@@ -69,10 +112,15 @@ impl VarPlanner {
                                 .ok_or(PlanError::InvalidObjectKey("value".to_string()))?
                                 .clone(),
                         ),
-                        // If it is a variable, we need to query it in the internal [`Memory`].
-                        // However this is an interesting case as currently the LLM does not listen
-                        // to our instructions and never returns a `kind: variable` value.
-                        Some("variable") => todo!(),
+                        // If it is a variable, the argument is the result it maps to in memory,
+                        // along with the results it references
+                        Some("variable") => {
+                            let name = kind_map
+                                .get("value")
+                                .and_then(Value::as_str)
+                                .ok_or(PlanError::InvalidObjectKey("value".to_string()))?;
+                            new_args.insert(arg_name, self.resolve(&Variable::new(name.into()))?)
+                        }
                         // Any other kind value is an error
                         Some(kind) => return Err(PlanError::InvalidArgumentKind(kind.to_string())),
                         // If the kind field is missing, we return an error
@@ -92,6 +140,17 @@ impl VarPlanner {
 impl Plan<State, Message> for VarPlanner {
     type Action = Action;
     type Error = PlanError;
+
+    // The variables are kept along with their labels, such that a resumed run checks them the same
+    fn memory(&self) -> Option<Value> {
+        serde_json::to_value(&self.memory).ok()
+    }
+
+    fn restore_memory(&mut self, memory: Value) -> Result<(), serde_json::Error> {
+        self.memory = serde_json::from_value(memory)?;
+        Ok(())
+    }
+
     fn plan(
         &mut self,
         state: State,
@@ -118,7 +177,7 @@ impl Plan<State, Message> for VarPlanner {
                             .build()?
                             .into();
                         // Update the state with the new message
-                        new_state.0.push(conv_message);
+                        new_state.push(conv_message);
                         // In this case we query the model with all the updated state and the
                         // tools.
                         let action = Action::Query(new_state.clone(), self.tools.clone());
@@ -130,8 +189,11 @@ impl Plan<State, Message> for VarPlanner {
                         // Generate a new variable
                         let x = Variable::fresh();
                         // Insert the new message's content mapped to the variable
-                        self.memory
-                            .insert(x.clone(), message.content.ok_or(PlanError::NoToolContent)?);
+                        self.memory.insert(
+                            x.clone(),
+                            message.content.ok_or(PlanError::NoToolContent)?,
+                            self.result_label.clone(),
+                        );
                         // Create a tool message with the variable name as the content and the tool
                         // id (matching the requested tool we just called). The model will be
                         // instructed to inspect this variable and will get back the data backing
@@ -146,7 +208,7 @@ impl Plan<State, Message> for VarPlanner {
                             .build()?
                             .into();
                         // Update the state with the new message
-                        new_state.0.push(conv_message);
+                        new_state.push(conv_message);
                         // In this case we query the model with all the updated state and the
                         // tools.
                         let action = Action::Query(new_state.clone(), self.tools.clone());
@@ -171,11 +233,12 @@ impl Plan<State, Message> for VarPlanner {
                                 // which is a variable's name.
                                 let variable = self.normalize_args(arguments)?;
                                 // Get the variable's corresponding tool result from the internal
-                                // memory
-                                let result = self
-                                    .memory
-                                    .get(&serde_json::from_str(&variable)?)
-                                    .ok_or(PlanError::MissingVariable(variable))?;
+                                // memory, with the variables it references resolved
+                                let result =
+                                    match self.resolve(&serde_json::from_str(&variable)?)? {
+                                        Value::String(result) => result,
+                                        result => result.to_string(),
+                                    };
                                 // Convert the tool call message from the assistant to a request
                                 // message with the tool call's contents
                                 let conv_message =
@@ -184,21 +247,21 @@ impl Plan<State, Message> for VarPlanner {
                                         .build()?
                                         .into();
                                 // Update the state with the message
-                                new_state.0.push(conv_message);
+                                new_state.push(conv_message);
                                 // Build another tool role message which contains the tool results
                                 // that were mapped to the variable's name we got as argument. Also
-                                // add the tool call id generated by the LLM.
-                                let conv_message = ChatCompletionRequestToolMessageArgs::default()
-                                    .content(result.clone())
-                                    .tool_call_id(
-                                        message.tool_calls.ok_or(PlanError::NoToolCalls)?[0]
-                                            .id
-                                            .clone(),
-                                    )
-                                    .build()?
-                                    .into();
+                                // add the tool call id generated by the LLM. Images are shown as
+                                // such.
+                                let conv_messages = result_messages(
+                                    result,
+                                    message.tool_calls.ok_or(PlanError::NoToolCalls)?[0]
+                                        .id
+                                        .clone(),
+                                )?;
                                 // Update the state with this tool result message
-                                new_state.0.push(conv_message);
+                                for conv_message in conv_messages {
+                                    new_state.push(conv_message);
+                                }
                                 // In this case we query the LLM with the 2 newly constructed
                                 // messages
                                 Action::Query(new_state.clone(), self.tools.clone())
@@ -212,13 +275,13 @@ impl Plan<State, Message> for VarPlanner {
                                         .build()?
                                         .into();
                                 // Update the state with the new message
-                                new_state.0.push(conv_message);
+                                new_state.push(conv_message);
                                 // Create an `Action` which instructs the caller to call the
                                 // function `name` with the normalized `arguments` and the LLM
                                 // generated tool id.
                                 Action::MakeCall(
                                     Function::new(name),
-                                    Args(self.normalize_args(arguments)?),
+                                    Args::new(self.normalize_args(arguments)?),
                                     tool_calls[0].clone().id,
                                 )
                             };
@@ -232,7 +295,7 @@ impl Plan<State, Message> for VarPlanner {
                                 .build()?
                                 .into();
                             // Update the state with the new message
-                            new_state.0.push(conv_message);
+                            new_state.push(conv_message);
                             // Return a finishing `Action` to the caller, instructing that the
                             // LLM gave the final response.
                             let action = Action::Finish(content);
@@ -244,6 +307,16 @@ impl Plan<State, Message> for VarPlanner {
                     _ => return Err(PlanError::InvalidMessage(format!("{:#?}", message))),
                 }
             }
+            // Images of the user are shown to the model along with the request
+            Message::UserImages(content, images) => {
+                new_state.push(Image::user_message(&content, &images)?);
+                let action = Action::Query(new_state.clone(), self.tools.clone());
+                (new_state, action)
+            }
+            // Images of tools are stored behind a variable like any other result
+            Message::ToolImage(image, id) => {
+                return self.plan(new_state, Message::ToolResult(image.url(), id));
+            }
             // If the message sent by the caller of this function is not a chat message between
             // the user and the assistant, but rather a tool result generated by the caller itself
             // by calling a tool.
@@ -252,7 +325,8 @@ impl Plan<State, Message> for VarPlanner {
                 let x = Variable::fresh();
                 // Insert the contents of the tool result in the internal memory, having the
                 // variable's name as key.
-                self.memory.insert(x.clone(), content);
+                self.memory
+                    .insert(x.clone(), content, self.result_label.clone());
                 // We convert this caller only message into a tool result message to be sent to the
                 // LLM containing the name of the variable mapping this tool result and the tool
                 // id that was generated in a previous assistant's tool call message
@@ -262,7 +336,7 @@ impl Plan<State, Message> for VarPlanner {
                     .build()?
                     .into();
                 // Update the state with the newly generated message
-                new_state.0.push(conv_message);
+                new_state.push(conv_message);
                 // In this case, we query the model with the conversation history which now also
                 // has the variable corresponding to the requested tool call
                 let action = Action::Query(new_state.clone(), self.tools.clone());
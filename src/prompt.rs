@@ -0,0 +1,270 @@
+//! System prompts given to the model at the start of a conversation.
+//!
+//! [`PlannerPrompt`] writes the system prompt of each planner from the tools it offers, such as the
+//! ones of a [`ToolRegistry`], and from the identity of the user. The protocol the model follows to
+//! pass arguments is described along with the tools, and the one of variables only for planners
+//! which show the model variables instead of tool results.
+use crate::{locale::Locale, registry::ToolRegistry};
+use async_openai::types::ChatCompletionTool;
+use serde_json::Value;
+
+const ASSISTANT: &str = "You are a helpful email assistant with the ability to summarize emails and to send Slack \
+    messages.";
+
+const VALUE_PROTOCOL: &str = "Every argument of a tool is an object with a `kind` tag. Pass \
+    literal values as `{\"kind\": \"value\", \"value\": <the value>}`.";
+
+const VARIABLE_PROTOCOL: &str = "Whenever you call a tool, you will not receive the result \
+    directly. Rather, a variable standing in for the result will be appended to the conversation. \
+    Pass a variable to a tool as `{\"kind\": \"variable\", \"value\": <the variable name>}`, \
+    and never a variable name as a value or a value as a variable. Read the contents of a variable \
+    with the `read_variable` tool only if you MUST know them before the next tool call. If you are \
+    not sure about the data the request is about, read the variables or gather it with other \
+    tools: do NOT guess or make up an answer.";
+
+/// Assembles the system prompt from the planner's instructions and the user's preferences
+#[derive(Debug, Clone, Default)]
+pub struct SystemPromptBuilder {
+    instructions: Vec<String>,
+    locale: Option<Locale>,
+}
+
+impl SystemPromptBuilder {
+    pub fn new(base: &str) -> Self {
+        Self {
+            instructions: vec![base.to_string()],
+            locale: None,
+        }
+    }
+
+    pub fn with_instruction(mut self, instruction: &str) -> Self {
+        self.instructions.push(instruction.to_string());
+        self
+    }
+
+    /// Have the model answer in the language and with the formatting conventions of `locale`
+    pub fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = Some(locale);
+        self
+    }
+
+    pub fn locale(&self) -> Option<&Locale> {
+        self.locale.as_ref()
+    }
+
+    pub fn build(&self) -> String {
+        self.instructions
+            .iter()
+            .cloned()
+            .chain(self.locale.as_ref().map(Locale::instruction))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// Planner a [`PlannerPrompt`] is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptTemplate {
+    // `BasicPlanner`, which shows the model the tool results
+    Basic,
+    // `VarPlanner`, which shows the model variables standing for the tool results
+    Variables,
+    // `TaintTrackingPlanner`, which shows the model variables once it is given a memory for them
+    TaintTracking { variables: bool },
+}
+
+impl PromptTemplate {
+    fn uses_variables(&self) -> bool {
+        match self {
+            Self::Basic => false,
+            Self::Variables => true,
+            Self::TaintTracking { variables } => *variables,
+        }
+    }
+}
+
+/// System prompt of a planner, describing its tools and the user they act for
+#[derive(Debug, Clone)]
+pub struct PlannerPrompt {
+    template: PromptTemplate,
+    tools: Vec<ChatCompletionTool>,
+    // Address of the user, which is also their Slack alias
+    user: Option<String>,
+    locale: Option<Locale>,
+}
+
+impl PlannerPrompt {
+    pub fn new(template: PromptTemplate) -> Self {
+        Self {
+            template,
+            tools: vec![],
+            user: None,
+            locale: None,
+        }
+    }
+
+    /// Describe the `tools` to the model, in order
+    pub fn with_tools(mut self, tools: &[ChatCompletionTool]) -> Self {
+        self.tools.extend_from_slice(tools);
+        self
+    }
+
+    /// Describe the tools of the `registry` to the model, in registration order
+    pub fn with_registry(self, registry: &ToolRegistry) -> Self {
+        self.with_tools(&registry.schemas())
+    }
+
+    /// Tell the model the address of the `user` it acts for
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    pub fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = Some(locale);
+        self
+    }
+
+    pub fn template(&self) -> PromptTemplate {
+        self.template
+    }
+
+    /// The builder assembling the prompt, for callers adding instructions of their own
+    pub fn builder(&self) -> SystemPromptBuilder {
+        let tools = self
+            .tools
+            .iter()
+            .enumerate()
+            .map(|(index, tool)| format!("{}. {}", index + 1, describe(tool)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut builder = SystemPromptBuilder::new(ASSISTANT);
+        if !tools.is_empty() {
+            builder = builder
+                .with_instruction(&format!("You have access to the following tools:\n{tools}"))
+                .with_instruction(VALUE_PROTOCOL);
+        }
+        if self.template.uses_variables() {
+            builder = builder.with_instruction(VARIABLE_PROTOCOL);
+        }
+        if let Some(user) = &self.user {
+            builder = builder.with_instruction(&format!("The user's Slack alias is: {user}"));
+        }
+        if let Some(locale) = &self.locale {
+            builder = builder.with_locale(locale.clone());
+        }
+        builder
+    }
+
+    pub fn build(&self) -> String {
+        self.builder().build()
+    }
+}
+
+// One line describing the `tool`, as `name(argument: type, ...)` followed by its description
+fn describe(tool: &ChatCompletionTool) -> String {
+    let function = &tool.function;
+    let parameters = function.parameters.as_ref();
+    let properties = parameters.and_then(|parameters| parameters["properties"].as_object());
+    // Required arguments come in the order the schema lists them, the optional ones after
+    let mut names: Vec<&str> = parameters
+        .and_then(|parameters| parameters["required"].as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    for name in properties
+        .into_iter()
+        .flat_map(|properties| properties.keys())
+    {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    let arguments = names
+        .iter()
+        .map(|name| {
+            let property = properties.and_then(|properties| properties.get(*name));
+            // Arguments taking variables describe the type of their value in their first variant
+            let kind = property
+                .and_then(|property| {
+                    property["type"]
+                        .as_str()
+                        .or_else(|| property["anyOf"][0]["properties"]["value"]["type"].as_str())
+                })
+                .unwrap_or("any");
+            format!("{name}: {kind}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    match &function.description {
+        Some(description) => format!("`{}({arguments})`: {description}", function.name),
+        None => format!("`{}({arguments})`", function.name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{registry::ToolEntry, tools::variable_schema_gen};
+    use async_openai::types::{ChatCompletionToolType, FunctionObject};
+    use serde_json::json;
+
+    fn tool(name: &str, description: &str, arguments: &[&str]) -> ChatCompletionTool {
+        let properties: serde_json::Map<String, Value> = arguments
+            .iter()
+            .map(|argument| (argument.to_string(), json!({ "type": "string" })))
+            .collect();
+        ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                parameters: Some(variable_schema_gen(
+                    json!({
+                        "type": "object",
+                        "properties": properties,
+                        "required": arguments,
+                        "additionalProperties": false,
+                    }),
+                    vec![],
+                )),
+                strict: Some(true),
+            },
+        }
+    }
+
+    #[test]
+    fn prompts_describe_the_tools_and_only_teach_variables_when_needed() {
+        let registry = ToolRegistry::new()
+            .with_tool(ToolEntry::new(tool(
+                "send_slack_message",
+                "Sends a {message} to a slack {channel}",
+                &["channel", "message"],
+            )))
+            .unwrap();
+        let basic = PlannerPrompt::new(PromptTemplate::Basic)
+            .with_registry(&registry)
+            .with_user("bob.sheffield@magnet.com")
+            .build();
+        assert!(basic.contains(
+            "1. `send_slack_message(channel: string, message: string)`: Sends a {message} to a \
+            slack {channel}"
+        ));
+        assert!(basic.contains(VALUE_PROTOCOL));
+        assert!(!basic.contains("read_variable"));
+        assert!(basic.ends_with("The user's Slack alias is: bob.sheffield@magnet.com"));
+
+        let variables = |template| {
+            PlannerPrompt::new(template)
+                .with_registry(&registry)
+                .build()
+                .contains(VARIABLE_PROTOCOL)
+        };
+        assert!(variables(PromptTemplate::Variables));
+        assert!(variables(PromptTemplate::TaintTracking { variables: true }));
+        assert!(!variables(PromptTemplate::TaintTracking {
+            variables: false
+        }));
+    }
+}
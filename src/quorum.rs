@@ -0,0 +1,391 @@
+//! Deciding the integrity of tool results by a vote of several independent signals, such as an
+//! injection scanner and the reputation of the senders.
+//!
+//! No single signal is reliable enough to endorse data on its own, so each of them casts a
+//! verdict and the [`IntegrityQuorum`] only keeps the result trusted when enough of them trust it.
+//! The quorum never makes a result trusted on its own: raising the integrity of an untrusted
+//! result goes through the [`Authority`] of the loop. Every verdict is kept in the
+//! [`Endorsement`], such that audits can tell why a result was, or was not, trusted.
+//!
+//! A data loss prevention scanner finds data which should only reach few readers, which is about
+//! confidentiality rather than integrity. Its findings restrict the readers of the result instead
+//! of casting a verdict on whether it is trusted.
+use crate::{
+    Integrity, ProductLattice,
+    authority::Authority,
+    ifc::{InverseLattice, PowersetLattice},
+    tools::EmailLabel,
+};
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Verdict of one signal about a tool result
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub signal: String,
+    pub integrity: Integrity,
+    // Why the signal did not trust the result
+    pub reason: Option<String>,
+}
+
+impl Verdict {
+    pub fn trusted(signal: &str) -> Self {
+        Self {
+            signal: signal.to_string(),
+            integrity: Integrity::Trusted,
+            reason: None,
+        }
+    }
+
+    pub fn untrusted(signal: &str, reason: String) -> Self {
+        Self {
+            signal: signal.to_string(),
+            integrity: Integrity::Untrusted,
+            reason: Some(reason),
+        }
+    }
+}
+
+/// Source of evidence about the integrity of tool results
+pub trait IntegritySignal: Send + Sync {
+    fn name(&self) -> &str;
+    fn assess(&self, content: &str) -> Verdict;
+}
+
+/// Distrusts content which looks like it is trying to instruct the model
+#[derive(Debug)]
+pub struct InjectionScanner {
+    patterns: Vec<Regex>,
+}
+
+impl Default for InjectionScanner {
+    fn default() -> Self {
+        let patterns = [
+            r"<\|?im_(start|end)\|?>",
+            r"(?i)ignore (all )?(previous|prior) instructions",
+            r"(?i)the assistant has been augmented",
+            r"(?i)\bsystem prompt\b",
+        ];
+        Self {
+            patterns: patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern).expect("Invalid injection pattern"))
+                .collect(),
+        }
+    }
+}
+
+impl IntegritySignal for InjectionScanner {
+    fn name(&self) -> &str {
+        "injection_scanner"
+    }
+
+    fn assess(&self, content: &str) -> Verdict {
+        match self
+            .patterns
+            .iter()
+            .find_map(|pattern| pattern.find(content))
+        {
+            Some(found) => Verdict::untrusted(
+                self.name(),
+                format!("looks like a prompt injection ({:?})", found.as_str()),
+            ),
+            None => Verdict::trusted(self.name()),
+        }
+    }
+}
+
+/// Finds data which should only reach few readers, such as card numbers or credentials, and
+/// restricts the results carrying any to those readers
+#[derive(Debug)]
+pub struct DlpScanner {
+    patterns: Vec<(&'static str, Regex)>,
+    // Who may read the results carrying sensitive data, such as the user alone
+    readers: HashSet<String>,
+}
+
+impl DlpScanner {
+    /// Scanner only letting the `readers` read the results carrying sensitive data
+    pub fn new(readers: &[&str]) -> Self {
+        let patterns = [
+            ("card number", r"\b(?:\d[ -]?){13,16}\b"),
+            ("private key", r"-----BEGIN [A-Z ]*PRIVATE KEY-----"),
+            ("API token", r"\b(?:sk|xox[abp])-[A-Za-z0-9-]{10,}\b"),
+        ];
+        Self {
+            patterns: patterns
+                .iter()
+                .map(|(kind, pattern)| (*kind, Regex::new(pattern).expect("Invalid DLP pattern")))
+                .collect(),
+            readers: readers.iter().map(|reader| reader.to_string()).collect(),
+        }
+    }
+
+    /// Kinds of sensitive data found in `content`
+    pub fn findings(&self, content: &str) -> Vec<&'static str> {
+        self.patterns
+            .iter()
+            .filter(|(_, pattern)| pattern.is_match(content))
+            .map(|(kind, _)| *kind)
+            .collect()
+    }
+
+    /// The `label` of `content`, only readable by the readers of the scanner among its own if any
+    /// sensitive data is found in it. The integrity of the label is kept as it is.
+    pub fn restrict(&self, label: EmailLabel, content: &str) -> EmailLabel {
+        if self.findings(content).is_empty() {
+            return label;
+        }
+        let current = label.lattice2().inner();
+        let readers = current
+            .subset()
+            .intersection(&self.readers)
+            .cloned()
+            .collect();
+        let readers = PowersetLattice::new(readers, current.universe().clone())
+            .expect("Readers of a label are in its universe");
+        ProductLattice::new(label.lattice1().clone(), InverseLattice::new(readers))
+    }
+}
+
+/// Distrusts content mentioning email addresses outside of the trusted domains
+#[derive(Debug)]
+pub struct SenderReputation {
+    trusted_domains: Vec<String>,
+    address: Regex,
+}
+
+impl SenderReputation {
+    pub fn new(trusted_domains: &[&str]) -> Self {
+        Self {
+            trusted_domains: trusted_domains
+                .iter()
+                .map(|domain| domain.to_lowercase())
+                .collect(),
+            address: Regex::new(r"[\w.+-]+@([\w-]+(?:\.[\w-]+)+)").expect("Invalid address regex"),
+        }
+    }
+}
+
+impl IntegritySignal for SenderReputation {
+    fn name(&self) -> &str {
+        "sender_reputation"
+    }
+
+    fn assess(&self, content: &str) -> Verdict {
+        let unknown = self
+            .address
+            .captures_iter(content)
+            .find(|captures| !self.trusted_domains.contains(&captures[1].to_lowercase()));
+        match unknown {
+            Some(captures) => Verdict::untrusted(
+                self.name(),
+                format!("mentions an unknown sender ({})", &captures[0]),
+            ),
+            None => Verdict::trusted(self.name()),
+        }
+    }
+}
+
+/// How many signals have to trust a result for the quorum to endorse it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    // Every signal trusts the result
+    Unanimous,
+    // More than half of the signals trust the result
+    Majority,
+    // At least this many signals trust the result
+    AtLeast(usize),
+}
+
+/// Final decision of the quorum, together with the verdicts it was based on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endorsement {
+    pub integrity: Integrity,
+    pub verdicts: Vec<Verdict>,
+    // Kinds of sensitive data the DLP scanner found, which restricted the readers of the result
+    pub findings: Vec<String>,
+}
+
+/// Combines the verdicts of several signals into the integrity of tool results
+pub struct IntegrityQuorum {
+    signals: Vec<Box<dyn IntegritySignal>>,
+    threshold: Threshold,
+    dlp: Option<DlpScanner>,
+}
+
+impl IntegrityQuorum {
+    pub fn new(threshold: Threshold) -> Self {
+        Self {
+            signals: vec![],
+            threshold,
+            dlp: None,
+        }
+    }
+
+    pub fn with_signal<S: IntegritySignal + 'static>(mut self, signal: S) -> Self {
+        self.signals.push(Box::new(signal));
+        self
+    }
+
+    /// Restrict the readers of the results the `scanner` finds sensitive data in
+    pub fn with_dlp_scanner(mut self, scanner: DlpScanner) -> Self {
+        self.dlp = Some(scanner);
+        self
+    }
+
+    /// Have every signal assess `content` and decide whether it is trusted. Without any signal
+    /// there is no evidence, so nothing gets endorsed.
+    pub fn decide(&self, content: &str) -> Endorsement {
+        let verdicts: Vec<Verdict> = self
+            .signals
+            .iter()
+            .map(|signal| signal.assess(content))
+            .collect();
+        let trusted = verdicts
+            .iter()
+            .filter(|verdict| verdict.integrity == Integrity::Trusted)
+            .count();
+        let required = match self.threshold {
+            Threshold::Unanimous => verdicts.len(),
+            Threshold::Majority => verdicts.len() / 2 + 1,
+            Threshold::AtLeast(count) => count,
+        };
+        let integrity = if !verdicts.is_empty() && trusted >= required {
+            Integrity::Trusted
+        } else {
+            Integrity::Untrusted
+        };
+        let findings = match &self.dlp {
+            Some(dlp) => dlp
+                .findings(content)
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            None => vec![],
+        };
+        Endorsement {
+            integrity,
+            verdicts,
+            findings,
+        }
+    }
+
+    /// The `label` of a tool result, given the decision of the quorum about its `content`. Results
+    /// the quorum distrusts become untrusted, while results it trusts keep the integrity they had,
+    /// unless the `authority` of the loop vouches for them. Results carrying sensitive data are
+    /// restricted to the readers of the DLP scanner, if any.
+    pub fn endorse(
+        &self,
+        label: EmailLabel,
+        content: &str,
+        authority: &Authority,
+    ) -> (EmailLabel, Endorsement) {
+        let endorsement = self.decide(content);
+        let label = match endorsement.integrity {
+            Integrity::Untrusted => {
+                ProductLattice::new(Integrity::untrusted(), label.lattice2().clone())
+            }
+            Integrity::Trusted => authority.endorse(label.clone()).unwrap_or(label),
+        };
+        let label = match &self.dlp {
+            Some(dlp) => dlp.restrict(label, content),
+            None => label,
+        };
+        (label, endorsement)
+    }
+}
+
+impl std::fmt::Debug for IntegrityQuorum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IntegrityQuorum")
+            .field(
+                "signals",
+                &self
+                    .signals
+                    .iter()
+                    .map(|signal| signal.name())
+                    .collect::<Vec<_>>(),
+            )
+            .field("threshold", &self.threshold)
+            .field("dlp", &self.dlp)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        authority::Principal,
+        tools::{EmailAddressUniverse, INBOX, readers_label},
+    };
+
+    #[test]
+    fn quorum_votes_on_integrity() {
+        let quorum = |threshold| {
+            IntegrityQuorum::new(threshold)
+                .with_signal(InjectionScanner::default())
+                .with_signal(SenderReputation::new(&["magnet.com"]))
+        };
+
+        let meeting = format!("{} {}", INBOX[0].sender(), INBOX[0].body());
+        let endorsement = quorum(Threshold::Unanimous).decide(&meeting);
+        assert_eq!(endorsement.integrity, Integrity::Trusted);
+        assert_eq!(endorsement.verdicts.len(), 2);
+
+        // The injected email fails both the injection scanner and the sender reputation
+        let injection = format!("{} {}", INBOX[3].sender(), INBOX[3].body());
+        let endorsement = quorum(Threshold::AtLeast(0)).decide(&injection);
+        assert_eq!(endorsement.integrity, Integrity::Trusted);
+        let endorsement = quorum(Threshold::Majority).decide(&injection);
+        assert_eq!(endorsement.integrity, Integrity::Untrusted);
+        let reasons: Vec<_> = endorsement
+            .verdicts
+            .iter()
+            .filter_map(|verdict| verdict.reason.as_deref())
+            .collect();
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[1].contains("robert@universaltechadvise.biz"));
+
+        assert_eq!(
+            IntegrityQuorum::new(Threshold::AtLeast(0))
+                .decide(&meeting)
+                .integrity,
+            Integrity::Untrusted
+        );
+    }
+
+    #[test]
+    fn quorum_only_raises_integrity_through_the_authority() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            Integrity::untrusted(),
+            readers_label(universe.clone(), universe).unwrap(),
+        );
+        let bob = "bob.sheffield@magnet.com";
+        let quorum = IntegrityQuorum::new(Threshold::Unanimous)
+            .with_signal(InjectionScanner::default())
+            .with_dlp_scanner(DlpScanner::new(&[bob]));
+        let card = "Card: 4111 1111 1111 1111";
+
+        let loop_authority = Authority::new(Principal::new("loop"));
+        let (kept, endorsement) = quorum.endorse(label.clone(), card, &loop_authority);
+        assert_eq!(endorsement.integrity, Integrity::Trusted);
+        assert_eq!(endorsement.findings, ["card number"]);
+        assert_eq!(kept.lattice1(), &Integrity::untrusted());
+        // The card number only reaches Bob, whatever the integrity of the result
+        assert_eq!(
+            kept.lattice2().inner().subset(),
+            &HashSet::from([bob.to_string()])
+        );
+
+        let vouching = Authority::new(Principal::new("scanners")).with_endorsement();
+        let (endorsed, _) = quorum.endorse(label, "See you at 10.", &vouching);
+        assert_eq!(endorsed.lattice1(), &Integrity::trusted());
+        assert_eq!(
+            endorsed.lattice2().inner().subset().len(),
+            EmailAddressUniverse::new(&INBOX).into_inner().len()
+        );
+    }
+}
@@ -0,0 +1,490 @@
+//! Quotas on what users and sessions may spend, enforced across every planning loop serving them
+//! and kept across restarts of the process.
+//!
+//! Spending is counted per scope, a name chosen by the application such as `user:alice` or
+//! `session:42`. A [`Quotas`] handle charges every one of its scopes, each with its own limits, and
+//! is handed to the loops through their [`Datastore`]. The counters live in a [`QuotaStore`] which
+//! is written to disk after every charge, such that a restart does not reset them.
+//!
+//! Spending is reserved before it happens: checking the limits and charging the counters is a
+//! single step, such that loops of the same user running side by side cannot both go through on
+//! the last unit of a quota. What is reserved and not spent is refunded.
+//!
+//! [`Datastore`]: crate::Datastore
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Share of a limit past which the observers are warned that the limit is near
+const WARN_AT: f64 = 0.8;
+
+/// What a quota counts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaKind {
+    Tokens,
+    // In millionths of the currency the prices are given in
+    Cost,
+    // Calls to tools which act on the outside world, such as sending messages
+    SideEffects,
+}
+
+/// Spending counted for one scope
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub tokens: u64,
+    pub cost: u64,
+    pub side_effects: u64,
+}
+
+impl QuotaUsage {
+    pub fn get(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::Tokens => self.tokens,
+            QuotaKind::Cost => self.cost,
+            QuotaKind::SideEffects => self.side_effects,
+        }
+    }
+
+    /// Spending of `self` beyond `other`, counted as nothing where `other` spent more
+    pub fn saturating_sub(&self, other: &Self) -> Self {
+        Self {
+            tokens: self.tokens.saturating_sub(other.tokens),
+            cost: self.cost.saturating_sub(other.cost),
+            side_effects: self.side_effects.saturating_sub(other.side_effects),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Limits of one scope, where a missing limit does not restrict anything
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QuotaLimits {
+    tokens: Option<u64>,
+    cost: Option<u64>,
+    side_effects: Option<u64>,
+}
+
+impl QuotaLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_tokens(mut self, tokens: u64) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Limit the cost, in millionths of the currency the prices are given in
+    pub fn with_cost(mut self, cost: u64) -> Self {
+        self.cost = Some(cost);
+        self
+    }
+
+    pub fn with_side_effects(mut self, side_effects: u64) -> Self {
+        self.side_effects = Some(side_effects);
+        self
+    }
+
+    pub fn get(&self, kind: QuotaKind) -> Option<u64> {
+        match kind {
+            QuotaKind::Tokens => self.tokens,
+            QuotaKind::Cost => self.cost,
+            QuotaKind::SideEffects => self.side_effects,
+        }
+    }
+}
+
+/// Price of the tokens, in millionths of a currency per million tokens, which is the same as the
+/// price in the currency per token
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub prompt: u64,
+    pub completion: u64,
+}
+
+impl Pricing {
+    /// Cost of the tokens, in millionths of the currency
+    pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> u64 {
+        prompt_tokens * self.prompt / 1_000_000 + completion_tokens * self.completion / 1_000_000
+    }
+}
+
+/// Spending which would go over the limit of a scope
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaExceeded {
+    pub scope: String,
+    pub kind: QuotaKind,
+    // What was spent already, and what the limit is
+    pub used: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug)]
+pub enum QuotaError {
+    Exceeded(QuotaExceeded),
+    StoreError(io::Error),
+}
+
+impl From<QuotaExceeded> for QuotaError {
+    fn from(err: QuotaExceeded) -> Self {
+        Self::Exceeded(err)
+    }
+}
+
+impl From<io::Error> for QuotaError {
+    fn from(err: io::Error) -> Self {
+        Self::StoreError(err)
+    }
+}
+
+/// Warning issued when the spending of a scope gets near its limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaWarning {
+    pub scope: String,
+    pub kind: QuotaKind,
+    pub used: u64,
+    pub limit: u64,
+}
+
+/// Counters of all the scopes, optionally backed by a file
+#[derive(Debug)]
+pub struct QuotaStore {
+    path: Option<PathBuf>,
+    counters: Mutex<HashMap<String, QuotaUsage>>,
+    // Held while the counters are written to the file, such that writes land in order
+    writer: AsyncMutex<()>,
+}
+
+impl QuotaStore {
+    /// Create a store which forgets everything once dropped
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            counters: Mutex::new(HashMap::new()),
+            writer: AsyncMutex::new(()),
+        }
+    }
+
+    /// Open the store kept in the JSON file at `path`, which is created on the first charge if it
+    /// does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let counters = match fs::read_to_string(&path) {
+            Ok(counters) => serde_json::from_str(&counters)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path: Some(path),
+            counters: Mutex::new(counters),
+            writer: AsyncMutex::new(()),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, QuotaUsage>> {
+        self.counters.lock().expect("QuotaStore lock poisoned")
+    }
+
+    /// Spending counted for the `scope` so far
+    pub fn usage(&self, scope: &str) -> QuotaUsage {
+        self.lock().get(scope).cloned().unwrap_or_default()
+    }
+
+    // Add `spent` to the counters of every one of the `scopes` unless it goes over the limits of
+    // any of them, returning the counters of each from before and after the charge
+    fn reserve(
+        &self,
+        scopes: &[(String, QuotaLimits)],
+        spent: &QuotaUsage,
+    ) -> Result<Vec<(QuotaUsage, QuotaUsage)>, QuotaExceeded> {
+        let mut counters = self.lock();
+        for (scope, limits) in scopes {
+            let usage = counters.get(scope).cloned().unwrap_or_default();
+            exceeded(scope, limits, &usage, spent)?;
+        }
+        Ok(add(&mut counters, scopes, spent))
+    }
+
+    // Add `spent` to the counters of every one of the `scopes`, returning the counters of each from
+    // before and after the charge
+    fn charge(
+        &self,
+        scopes: &[(String, QuotaLimits)],
+        spent: &QuotaUsage,
+    ) -> Vec<(QuotaUsage, QuotaUsage)> {
+        add(&mut self.lock(), scopes, spent)
+    }
+
+    // Take `refunded` off the counters of every one of the `scopes`
+    fn refund(&self, scopes: &[(String, QuotaLimits)], refunded: &QuotaUsage) {
+        let mut counters = self.lock();
+        for (scope, _) in scopes {
+            if let Some(usage) = counters.get_mut(scope) {
+                *usage = usage.saturating_sub(refunded);
+            }
+        }
+    }
+
+    // Write the counters to the file of the store, if it has one, without blocking the runtime
+    async fn persist(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // The counters are read once the previous writes are done, such that the last write holds
+        // the latest counters
+        let _writer = self.writer.lock().await;
+        let counters = serde_json::to_string(&*self.lock())?;
+        // Written to the side first, such that a crash does not leave a truncated file
+        let temporary = path.with_extension("tmp");
+        tokio::fs::write(&temporary, counters).await?;
+        tokio::fs::rename(temporary, path).await
+    }
+}
+
+fn add(
+    counters: &mut HashMap<String, QuotaUsage>,
+    scopes: &[(String, QuotaLimits)],
+    spent: &QuotaUsage,
+) -> Vec<(QuotaUsage, QuotaUsage)> {
+    scopes
+        .iter()
+        .map(|(scope, _)| {
+            let usage = counters.entry(scope.clone()).or_default();
+            let before = usage.clone();
+            usage.tokens += spent.tokens;
+            usage.cost += spent.cost;
+            usage.side_effects += spent.side_effects;
+            (before, usage.clone())
+        })
+        .collect()
+}
+
+// Fails if spending `spent` more than the `usage` of the `scope` goes over its `limits`
+fn exceeded(
+    scope: &str,
+    limits: &QuotaLimits,
+    usage: &QuotaUsage,
+    spent: &QuotaUsage,
+) -> Result<(), QuotaExceeded> {
+    for kind in [QuotaKind::Tokens, QuotaKind::Cost, QuotaKind::SideEffects] {
+        let Some(limit) = limits.get(kind) else {
+            continue;
+        };
+        let used = usage.get(kind);
+        if used.saturating_add(spent.get(kind)) > limit {
+            return Err(QuotaExceeded {
+                scope: scope.to_string(),
+                kind,
+                used,
+                limit,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Quotas of the scopes a planning loop spends for, shared by all its clones
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    store: Arc<QuotaStore>,
+    scopes: Vec<(String, QuotaLimits)>,
+    pricing: Pricing,
+    // Tools whose calls count as side effects
+    side_effect_tools: Vec<String>,
+}
+
+impl Quotas {
+    pub fn new(store: Arc<QuotaStore>) -> Self {
+        Self {
+            store,
+            scopes: vec![],
+            pricing: Pricing::default(),
+            side_effect_tools: vec![
+                "send_slack_message".to_string(),
+                "send_slack_message_labeled".to_string(),
+            ],
+        }
+    }
+
+    /// Charge the spending to `scope` as well, failing once it would go over `limits`
+    pub fn with_scope(mut self, scope: &str, limits: QuotaLimits) -> Self {
+        self.scopes.push((scope.to_string(), limits));
+        self
+    }
+
+    pub fn with_pricing(mut self, pricing: Pricing) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    /// Count the calls to `tool` as side effects, along with sending Slack messages
+    pub fn with_side_effect_tool(mut self, tool: &str) -> Self {
+        self.side_effect_tools.push(tool.to_string());
+        self
+    }
+
+    pub fn store(&self) -> &QuotaStore {
+        &self.store
+    }
+
+    pub fn is_side_effect(&self, tool: &str) -> bool {
+        self.side_effect_tools.iter().any(|name| name == tool)
+    }
+
+    /// Check that spending `spent` more stays within the limits of every scope. Spending which
+    /// has to stay within the limits is reserved with [`reserve`] instead, as others may spend in
+    /// between the check and the charge.
+    ///
+    /// [`reserve`]: Self::reserve
+    pub fn check(&self, spent: &QuotaUsage) -> Result<(), QuotaExceeded> {
+        for (scope, limits) in self.scopes.iter() {
+            exceeded(scope, limits, &self.store.usage(scope), spent)?;
+        }
+        Ok(())
+    }
+
+    /// Usage of a request of `prompt_tokens` and `completion_tokens`, priced with the pricing of
+    /// the quotas
+    pub fn query_usage(&self, prompt_tokens: u64, completion_tokens: u64) -> QuotaUsage {
+        QuotaUsage {
+            tokens: prompt_tokens + completion_tokens,
+            cost: self.pricing.cost(prompt_tokens, completion_tokens),
+            side_effects: 0,
+        }
+    }
+
+    /// Charge `spent` to every scope if it stays within the limits of all of them, and otherwise
+    /// charge nothing. Returns a warning for each limit the spending got near to.
+    pub async fn reserve(&self, spent: &QuotaUsage) -> Result<Vec<QuotaWarning>, QuotaError> {
+        let charged = self.store.reserve(&self.scopes, spent)?;
+        self.store.persist().await?;
+        Ok(self.warnings(charged))
+    }
+
+    /// Charge `spent` to every scope, whether or not it goes over their limits, as for spending
+    /// which already happened. Returns a warning for each limit the spending got near to.
+    pub async fn charge(&self, spent: &QuotaUsage) -> io::Result<Vec<QuotaWarning>> {
+        let charged = self.store.charge(&self.scopes, spent);
+        self.store.persist().await?;
+        Ok(self.warnings(charged))
+    }
+
+    /// Give back to every scope what was reserved and not spent
+    pub async fn refund(&self, refunded: &QuotaUsage) -> io::Result<()> {
+        if refunded.is_empty() {
+            return Ok(());
+        }
+        self.store.refund(&self.scopes, refunded);
+        self.store.persist().await
+    }
+
+    // Warnings for the limits crossed by a charge, given the counters of each scope from before
+    // and after it
+    fn warnings(&self, charged: Vec<(QuotaUsage, QuotaUsage)>) -> Vec<QuotaWarning> {
+        let mut warnings = vec![];
+        for ((scope, limits), (before, after)) in self.scopes.iter().zip(charged) {
+            for kind in [QuotaKind::Tokens, QuotaKind::Cost, QuotaKind::SideEffects] {
+                let Some(limit) = limits.get(kind) else {
+                    continue;
+                };
+                // Warn once, when the spending crosses the threshold
+                let threshold = limit as f64 * WARN_AT;
+                if (before.get(kind) as f64) < threshold && after.get(kind) as f64 >= threshold {
+                    warnings.push(QuotaWarning {
+                        scope: scope.clone(),
+                        kind,
+                        used: after.get(kind),
+                        limit,
+                    });
+                }
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn quotas_survive_restarts() {
+        let path =
+            std::env::temp_dir().join(format!("gentlemen-quotas-{}.json", std::process::id()));
+        let limits = QuotaLimits::new().with_tokens(1_000).with_side_effects(1);
+        // $2.50 and $10 per million tokens
+        let quotas = |store| {
+            Quotas::new(Arc::new(store))
+                .with_scope("user:alice", limits.clone())
+                .with_pricing(Pricing {
+                    prompt: 2_500_000,
+                    completion: 10_000_000,
+                })
+        };
+
+        let first = quotas(QuotaStore::open(&path).unwrap());
+        let warnings = first.charge(&first.query_usage(700, 100)).await.unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, QuotaKind::Tokens);
+        assert_eq!(first.store().usage("user:alice").cost, 2_750);
+
+        // The counters are read back after a restart
+        let second = quotas(QuotaStore::open(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(second.store().usage("user:alice").tokens, 800);
+        let exceeded = second.check(&second.query_usage(300, 0)).unwrap_err();
+        assert_eq!(exceeded.kind, QuotaKind::Tokens);
+        assert_eq!((exceeded.used, exceeded.limit), (800, 1_000));
+
+        let send = QuotaUsage {
+            side_effects: 1,
+            ..Default::default()
+        };
+        assert!(second.check(&send).is_ok());
+        second.reserve(&send).await.unwrap();
+        assert!(second.check(&send).is_err());
+    }
+
+    #[tokio::test]
+    async fn reservations_never_go_over_the_limits() {
+        let send = QuotaUsage {
+            side_effects: 1,
+            ..Default::default()
+        };
+        let store = Arc::new(QuotaStore::in_memory());
+        // Every loop of the user reserves a send at the same time
+        let reservations: Vec<_> = (0..10)
+            .map(|_| {
+                let quotas = Quotas::new(store.clone())
+                    .with_scope("user:alice", QuotaLimits::new().with_side_effects(3));
+                let send = send.clone();
+                tokio::spawn(async move { quotas.reserve(&send).await.is_ok() })
+            })
+            .collect();
+        let mut reserved = 0;
+        for reservation in reservations {
+            reserved += usize::from(reservation.await.unwrap());
+        }
+        assert_eq!(reserved, 3);
+
+        // Nothing is charged to any scope unless all of them allow it
+        let quotas = Quotas::new(store.clone())
+            .with_scope("session:42", QuotaLimits::new())
+            .with_scope("user:alice", QuotaLimits::new().with_side_effects(3));
+        assert!(matches!(
+            quotas.reserve(&send).await,
+            Err(QuotaError::Exceeded(_))
+        ));
+        assert_eq!(store.usage("session:42"), QuotaUsage::default());
+        quotas.refund(&send).await.unwrap();
+        assert_eq!(store.usage("user:alice").side_effects, 2);
+    }
+}
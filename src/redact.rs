@@ -0,0 +1,101 @@
+//! Previews fit for the model of JSON values labeled node by node, as held by [`LabeledValue`].
+//! Tool results often mix data the model may see with data it may not, such as an email whose
+//! sender is public while its body is confidential. Rather than hiding the whole value,
+//! [`redact_for_model`] replaces what is above the clearance with typed placeholders and keeps the
+//! structure intact, such that the model can still plan against the shape of the data, e.g. by
+//! passing the redacted field on to a tool as a variable.
+use crate::ifc::{LabeledNode, LabeledValue, Lattice};
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+/// Placeholder standing in for a value of the given JSON `kind` the model is not cleared to see
+pub fn placeholder(kind: &str) -> String {
+    format!("<redacted:{kind}>")
+}
+
+/// Preview of the `value` for a model cleared up to `clearance`. Any node whose label is not below
+/// the clearance, or is not comparable with it, is redacted along with everything under it: values
+/// are replaced with placeholders naming their type, like `"<redacted:string>"`, while arrays and
+/// objects keep their length and keys such that the shape of the data stays visible.
+pub fn redact_for_model<L: Lattice>(value: &LabeledValue<L>, clearance: &L) -> Value {
+    redact(value, clearance, false)
+}
+
+// Unlabeled nodes carry the labels above them, so they are only redacted along with those
+fn redact<L: Lattice>(value: &LabeledValue<L>, clearance: &L, redacted: bool) -> Value {
+    let redacted = redacted
+        || value.label().is_some_and(|label| {
+            !matches!(
+                label.partial_cmp(clearance),
+                Some(Ordering::Less | Ordering::Equal)
+            )
+        });
+    match value.node() {
+        LabeledNode::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| redact(value, clearance, redacted))
+                .collect(),
+        ),
+        LabeledNode::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), redact(value, clearance, redacted)))
+                .collect::<Map<_, _>>(),
+        ),
+        node if redacted => Value::String(placeholder(match node {
+            LabeledNode::Null => "null",
+            LabeledNode::Bool(_) => "bool",
+            LabeledNode::Number(_) => "number",
+            _ => "string",
+        })),
+        _ => value.to_value(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ifc::Confidentiality;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn values_above_the_clearance_are_replaced_with_placeholders() {
+        let unlabeled = |value| LabeledValue::new(value, None);
+        let email = LabeledValue::object(BTreeMap::from([
+            ("sender".to_string(), unlabeled(json!("alice@example.com"))),
+            (
+                "body".to_string(),
+                LabeledValue::new(
+                    json!("The launch code is 1234"),
+                    Some(Confidentiality::high()),
+                ),
+            ),
+            (
+                "attachments".to_string(),
+                LabeledValue::array(vec![LabeledValue::new(
+                    json!({ "name": "plan.pdf", "size": 1024 }),
+                    Some(Confidentiality::high()),
+                )]),
+            ),
+            ("read".to_string(), unlabeled(json!(false))),
+        ]))
+        .taint(Confidentiality::low())
+        .unwrap();
+
+        assert_eq!(
+            redact_for_model(&email, &Confidentiality::low()),
+            json!({
+                "sender": "alice@example.com",
+                "body": "<redacted:string>",
+                "attachments": [{ "name": "<redacted:string>", "size": "<redacted:number>" }],
+                "read": false,
+            })
+        );
+        assert_eq!(
+            redact_for_model(&email, &Confidentiality::high()),
+            email.to_value()
+        );
+    }
+}
@@ -0,0 +1,528 @@
+//! Registry of the tools available to the planners, together with the metadata needed to reason
+//! about them: the schema advertised to the model, the label of their results, the clearance
+//! required to pass data to them, how risky calling them is, what they are capable of and the
+//! namespace they belong to.
+//!
+//! The registry is the single source of truth about tools. Its [`manifest`] is meant to be
+//! consumed by external auditing tools and by whatever writes the system prompts, such that
+//! neither has to duplicate tool descriptions.
+//!
+//! Tools implementing [`Tool`] are registered along with their implementation, such that the
+//! [`PlanningLoop`] can call them without them being known to this crate.
+//!
+//! [`manifest`]: ToolRegistry::manifest
+//! [`PlanningLoop`]: crate::PlanningLoop
+use crate::{Args, Datastore, Integrity, ToolError, tools::EmailLabel};
+use async_openai::types::ChatCompletionTool;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::{collections::BTreeSet, fmt, sync::Arc};
+
+// Version of the manifest format, bumped whenever the format changes incompatibly
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// How much damage a tool can do if it is called with the wrong arguments
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Risk {
+    // Only reads data
+    #[default]
+    Low,
+    // Changes data, but the change can be undone
+    Medium,
+    // Sends data outside of the system or changes data irreversibly
+    High,
+}
+
+/// What a tool does with the data it is given, which policies and approval gates key off rather
+/// than off the names of the tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    // Reads data of the user, such as their emails or files
+    ReadsUserData,
+    // Sends data to someone outside of the system, such as a message or an email
+    SendsExternally,
+    // Reaches other hosts on the network
+    NetworkAccess,
+    // Deletes or overwrites data
+    Destructive,
+}
+
+/// Tool defined outside of this crate, which the planning loop calls through the registry
+pub trait Tool: Send + Sync {
+    /// Name the model calls the tool by
+    fn name(&self) -> &str;
+
+    /// Schema of the tool advertised to the model
+    fn schema(&self) -> ChatCompletionTool;
+
+    /// Call the tool with the `args` given by the model, returning the result handed back to it
+    fn execute<'a>(
+        &'a self,
+        args: Args,
+        datastore: &'a mut Datastore,
+    ) -> BoxFuture<'a, Result<String, ToolError>>;
+}
+
+// Implementation of a registered tool
+#[derive(Clone)]
+struct Implementation(Arc<dyn Tool>);
+
+impl fmt::Debug for Implementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Implementation")
+            .field(&self.0.name())
+            .finish()
+    }
+}
+
+/// A tool registered with the [`ToolRegistry`]
+#[derive(Debug, Clone)]
+pub struct ToolEntry {
+    schema: ChatCompletionTool,
+    // How to call the tool, for tools which are not built into the planning loop
+    implementation: Option<Implementation>,
+    namespace: Option<String>,
+    // Label carried by the results of the tool, when known upfront
+    label: Option<EmailLabel>,
+    // Most restrictive label of the data that can be passed as arguments to the tool
+    clearance: Option<EmailLabel>,
+    risk: Risk,
+    capabilities: BTreeSet<Capability>,
+}
+
+impl ToolEntry {
+    pub fn new(schema: ChatCompletionTool) -> Self {
+        Self {
+            schema,
+            implementation: None,
+            namespace: None,
+            label: None,
+            clearance: None,
+            risk: Risk::default(),
+            capabilities: BTreeSet::new(),
+        }
+    }
+
+    /// Entry of the `tool`, described by its own schema, which the planning loop calls through
+    /// the registry
+    pub fn from_tool<T: Tool + 'static>(tool: T) -> Self {
+        Self::new(tool_schema(&tool)).with_implementation(tool)
+    }
+
+    fn with_implementation<T: Tool + 'static>(mut self, tool: T) -> Self {
+        self.implementation = Some(Implementation(Arc::new(tool)));
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    pub fn with_label(mut self, label: EmailLabel) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn with_clearance(mut self, clearance: EmailLabel) -> Self {
+        self.clearance = Some(clearance);
+        self
+    }
+
+    pub fn with_risk(mut self, risk: Risk) -> Self {
+        self.risk = risk;
+        self
+    }
+
+    pub fn with_capability(mut self, capability: Capability) -> Self {
+        self.capabilities.insert(capability);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.schema.function.name
+    }
+
+    pub fn schema(&self) -> &ChatCompletionTool {
+        &self.schema
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    pub fn label(&self) -> Option<&EmailLabel> {
+        self.label.as_ref()
+    }
+
+    pub fn clearance(&self) -> Option<&EmailLabel> {
+        self.clearance.as_ref()
+    }
+
+    pub fn risk(&self) -> Risk {
+        self.risk
+    }
+
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> + '_ {
+        self.capabilities.iter().copied()
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Implementation of the tool, if it was registered with one
+    pub fn tool(&self) -> Option<Arc<dyn Tool>> {
+        self.implementation
+            .as_ref()
+            .map(|implementation| implementation.0.clone())
+    }
+
+    /// Describe the tool as one entry of the registry's manifest
+    pub fn manifest(&self) -> Value {
+        let function = &self.schema.function;
+        json!({
+            "name": function.name,
+            "namespace": self.namespace,
+            "description": function.description,
+            "parameters": function.parameters,
+            "strict": function.strict,
+            "risk": self.risk,
+            "capabilities": self.capabilities,
+            "label": self.label.as_ref().map(label_manifest),
+            "clearance": self.clearance.as_ref().map(label_manifest),
+        })
+    }
+}
+
+// Describe the `label` in the manifest. Readers are sorted such that the manifest of the same
+// registry is always the same.
+pub(crate) fn label_manifest(label: &EmailLabel) -> Value {
+    let integrity = match label.lattice1() {
+        Integrity::Trusted => "trusted",
+        Integrity::Untrusted => "untrusted",
+    };
+    let mut readers: Vec<&String> = label.lattice2().inner().subset().iter().collect();
+    readers.sort();
+    json!({
+        "integrity": integrity,
+        "readers": readers,
+    })
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    // A tool with the same name is already registered
+    DuplicateTool(String),
+}
+
+// Schema of the `tool`, which is registered under its name whatever its schema says
+fn tool_schema<T: Tool>(tool: &T) -> ChatCompletionTool {
+    let mut schema = tool.schema();
+    schema.function.name = tool.name().to_string();
+    schema
+}
+
+/// The tools available to the planners, in registration order
+#[derive(Debug, Default, Clone)]
+pub struct ToolRegistry {
+    tools: Vec<ToolEntry>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the `tool`, refusing tools whose name is already taken since the model only
+    /// refers to tools by name.
+    pub fn register(&mut self, tool: ToolEntry) -> Result<(), RegistryError> {
+        if self.get(tool.name()).is_some() {
+            return Err(RegistryError::DuplicateTool(tool.name().to_string()));
+        }
+        self.tools.push(tool);
+        Ok(())
+    }
+
+    /// Same as [`register`], for chaining on construction
+    ///
+    /// [`register`]: ToolRegistry::register
+    pub fn with_tool(mut self, tool: ToolEntry) -> Result<Self, RegistryError> {
+        self.register(tool)?;
+        Ok(self)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ToolEntry> {
+        self.tools.iter().find(|tool| tool.name() == name)
+    }
+
+    pub fn tools(&self) -> &[ToolEntry] {
+        &self.tools
+    }
+
+    /// Implementation of the tool called `name`, if it was registered with one
+    pub fn tool(&self, name: &str) -> Option<Arc<dyn Tool>> {
+        self.get(name)?.tool()
+    }
+
+    /// Whether the tool called `name` has any of the `capabilities`. Tools which are not
+    /// registered have none.
+    pub fn has_any(&self, name: &str, capabilities: &[Capability]) -> bool {
+        self.get(name).is_some_and(|tool| {
+            capabilities
+                .iter()
+                .any(|capability| tool.has_capability(*capability))
+        })
+    }
+
+    /// Names of the tools with any of the `capabilities`
+    pub fn capable(&self, capabilities: &[Capability]) -> Vec<&str> {
+        self.tools
+            .iter()
+            .filter(|tool| self.has_any(tool.name(), capabilities))
+            .map(ToolEntry::name)
+            .collect()
+    }
+
+    /// Schemas of all the registered tools, as advertised to the model by the planners
+    pub fn schemas(&self) -> Vec<ChatCompletionTool> {
+        self.tools.iter().map(|tool| tool.schema.clone()).collect()
+    }
+
+    /// Machine-readable description of all the registered tools
+    pub fn manifest(&self) -> Value {
+        json!({
+            "version": MANIFEST_VERSION,
+            "tools": self.tools.iter().map(ToolEntry::manifest).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BasicPlanner, Call, ConversationHistory, Function, Message, PlanningLoop, ProductLattice,
+        mock::MockLlm,
+        openai::LlmClient,
+        plan::PlanError,
+        tools::{EmailAddressUniverse, INBOX, readers_label},
+    };
+    use async_openai::types::{
+        ChatCompletionToolArgs, ChatCompletionToolType, FunctionObjectArgs, Role,
+    };
+
+    fn tool(name: &str, description: &str) -> ChatCompletionTool {
+        ChatCompletionToolArgs::default()
+            .r#type(ChatCompletionToolType::Function)
+            .function(
+                FunctionObjectArgs::default()
+                    .name(name)
+                    .description(description)
+                    .parameters(json!({
+                        "type": "object",
+                        "properties": { "count": { "type": "number" } },
+                    }))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    // Tool defined outside of the crate, reporting the weather of any city
+    struct Weather;
+
+    impl Tool for Weather {
+        fn name(&self) -> &str {
+            "get_weather"
+        }
+
+        fn schema(&self) -> ChatCompletionTool {
+            tool("weather", "Get the weather of a city")
+        }
+
+        fn execute<'a>(
+            &'a self,
+            args: Args,
+            _datastore: &'a mut Datastore,
+        ) -> BoxFuture<'a, Result<String, ToolError>> {
+            Box::pin(async move {
+                let args: Value = serde_json::from_str(args.value())?;
+                match args["city"].as_str() {
+                    Some("Atlantis") => Err(ToolError::Failed(
+                        "the weather service does not cover Atlantis".to_string(),
+                    )),
+                    city => Ok(format!("It is sunny in {}", city.unwrap())),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_tools_are_called_by_the_loop() {
+        let registry = ToolRegistry::new()
+            .with_tool(ToolEntry::from_tool(Weather))
+            .unwrap();
+        assert_eq!(registry.schemas()[0].function.name, "get_weather");
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "get_weather",
+                json!({ "city": { "kind": "value", "value": "Paris" } }),
+            ),
+            MockLlm::assistant_text("It is sunny."),
+            MockLlm::assistant_tool_call("call_1", "get_traffic", json!({})),
+        ]));
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(registry.schemas()),
+            model,
+            Vec::<Function>::new(),
+        )
+        .with_registry(registry);
+        let mut request = MockLlm::assistant_text("What is the weather in Paris?");
+        request.role = Role::User;
+
+        let answer = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request.clone()),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "It is sunny.");
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let last_message = serde_json::to_string(requests[1].last().unwrap()).unwrap();
+        assert!(last_message.contains("It is sunny in Paris"));
+
+        // Tools neither built in nor registered fail the run rather than panicking
+        let failed = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request),
+            )
+            .await;
+        assert!(matches!(failed, Err(PlanError::FunctionNotFound(name)) if name == "get_traffic"));
+    }
+
+    #[tokio::test]
+    async fn failed_calls_are_reported_to_the_model() {
+        let registry = ToolRegistry::new()
+            .with_tool(ToolEntry::from_tool(Weather))
+            .unwrap();
+        let model = LlmClient::mock(MockLlm::new(vec![
+            MockLlm::assistant_tool_call(
+                "call_0",
+                "get_weather",
+                json!({ "city": { "kind": "value", "value": "Atlantis" } }),
+            ),
+            MockLlm::assistant_text("I could not get the weather."),
+        ]));
+        let mut planning_loop = PlanningLoop::new(
+            BasicPlanner::new(registry.schemas()),
+            model,
+            Vec::<Function>::new(),
+        )
+        .with_registry(registry);
+        let mut request = MockLlm::assistant_text("What is the weather in Atlantis?");
+        request.role = Role::User;
+
+        let answer = planning_loop
+            .run(
+                ConversationHistory::new(vec![]),
+                &mut Datastore::default(),
+                Message::Chat(request),
+            )
+            .await
+            .expect("Failed to run");
+        assert_eq!(answer, "I could not get the weather.");
+        let requests = planning_loop.model().as_mock().unwrap().requests();
+        let last_message = serde_json::to_string(requests[1].last().unwrap()).unwrap();
+        assert!(last_message.contains("does not cover Atlantis"));
+
+        // Built in tools report arguments and names they do not know instead of panicking
+        let mut datastore = Datastore::default();
+        let called = Function::new("read_emails".to_string())
+            .call(Args::new("{}".to_string()), &mut datastore)
+            .await;
+        assert!(matches!(called, Err(ToolError::InvalidArgs(_))));
+        let called = Function::new("get_traffic".to_string())
+            .call(Args::new("{}".to_string()), &mut datastore)
+            .await;
+        assert_eq!(
+            called.unwrap_err().failure_message("get_traffic"),
+            "The call to `get_traffic` failed because there is no tool called `get_traffic`. \
+            Only call the tools you were given."
+        );
+    }
+
+    #[test]
+    fn manifest_describes_registered_tools() {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let public = ProductLattice::new(
+            Integrity::trusted(),
+            readers_label(universe.clone(), universe.clone()).unwrap(),
+        );
+        let bob = ProductLattice::new(
+            Integrity::untrusted(),
+            readers_label(["bob.sheffield@magnet.com".to_string()].into(), universe).unwrap(),
+        );
+        let registry = ToolRegistry::new()
+            .with_tool(
+                ToolEntry::new(tool("read_emails", "Read the latest emails"))
+                    .with_namespace("email")
+                    .with_label(bob)
+                    .with_capability(Capability::ReadsUserData),
+            )
+            .unwrap()
+            .with_tool(
+                ToolEntry::new(tool("send_slack_message", "Send a message on Slack"))
+                    .with_namespace("slack")
+                    .with_clearance(public)
+                    .with_risk(Risk::High)
+                    .with_capability(Capability::SendsExternally)
+                    .with_capability(Capability::NetworkAccess),
+            )
+            .unwrap();
+        assert!(matches!(
+            registry
+                .clone()
+                .with_tool(ToolEntry::new(tool("read_emails", ""))),
+            Err(RegistryError::DuplicateTool(_))
+        ));
+
+        let manifest = registry.manifest();
+        assert_eq!(manifest["version"], MANIFEST_VERSION);
+        let tools = manifest["tools"].as_array().unwrap();
+        assert_eq!(tools.len(), 2);
+        assert_eq!(tools[0]["name"], "read_emails");
+        assert_eq!(tools[0]["namespace"], "email");
+        assert_eq!(tools[0]["risk"], "low");
+        assert_eq!(
+            tools[0]["label"],
+            json!({ "integrity": "untrusted", "readers": ["bob.sheffield@magnet.com"] })
+        );
+        assert_eq!(tools[0]["clearance"], Value::Null);
+        assert_eq!(
+            tools[0]["parameters"]["properties"]["count"]["type"],
+            "number"
+        );
+        assert_eq!(tools[1]["risk"], "high");
+        assert_eq!(
+            tools[1]["capabilities"],
+            json!(["sends_externally", "network_access"])
+        );
+        assert_eq!(
+            registry.capable(&[Capability::SendsExternally, Capability::Destructive]),
+            vec!["send_slack_message"]
+        );
+        assert!(!registry.has_any("delete_emails", &[Capability::Destructive]));
+        assert_eq!(tools[1]["clearance"]["integrity"], "trusted");
+        assert_eq!(registry.schemas().len(), 2);
+    }
+}
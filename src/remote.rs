@@ -0,0 +1,425 @@
+//! Tools living outside of the agent process, which a [`RemoteTool`] forwards calls to over HTTP.
+//!
+//! Each call is posted as JSON to the endpoint of the tool, with the name of the tool, its
+//! normalized arguments, the authority the loop runs with and the label of the inputs of the call,
+//! in the format of the registry's manifest:
+//!
+//! ```json
+//! {
+//!     "tool": "get_weather",
+//!     "args": { "city": "Paris" },
+//!     "authority": { "principal": "loop", "endorse": true },
+//!     "label": { "integrity": "untrusted", "readers": ["bob.sheffield@magnet.com"] }
+//! }
+//! ```
+//!
+//! The label lets the service tell what the arguments were derived from, such as to refuse calls
+//! made with untrusted data. Calls made outside of a planning loop carry no label. The service
+//! answers with the result, and the label of the result in the same format:
+//!
+//! ```json
+//! { "result": "It is sunny", "label": { "integrity": "trusted", "readers": ["bob.sheffield@magnet.com"] } }
+//! ```
+//!
+//! Services only vouch for their results as far as the authority of the loop goes, and readers
+//! outside of the address universe of the tool are left out, which only makes the label more
+//! restrictive. Results without a label are treated like web content: untrusted, and readable by
+//! anyone. Services refusing a call answer with an error status and `{ "error": "..." }`, and
+//! services taking longer than the timeout of the tool to answer fail the call.
+//!
+//! gRPC services are reached through an HTTP/JSON gateway transcoding the calls, as the crate does
+//! not speak gRPC itself.
+use crate::{
+    Args, Call, Datastore, Integrity, LabeledTool, ProductLattice, ToolError,
+    authority::Authority,
+    ifc::{InverseLattice, Lattice, PowersetLattice},
+    registry::{Tool, label_manifest},
+    secrets::Secret,
+    tools::{EmailAddressUniverse, EmailLabel, INBOX, MetaValue, service_authority},
+};
+use async_openai::types::ChatCompletionTool;
+use futures::future::BoxFuture;
+use serde_json::{Value, json};
+use std::{collections::HashSet, fmt, time::Duration};
+
+// Time a service is given to answer a call by default
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum RemoteError {
+    HttpError(reqwest::Error),
+    // The service refused the call, with the status and the error it gave
+    Refused { status: u16, error: String },
+    // The answer of the service could not be made sense of
+    Malformed(String),
+}
+
+impl From<reqwest::Error> for RemoteError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::HttpError(err)
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HttpError(err) => write!(f, "the tool service could not be reached ({err})"),
+            Self::Refused { status, error } => {
+                write!(f, "the tool service refused the call ({status}: {error})")
+            }
+            Self::Malformed(reason) => write!(f, "the tool service answered oddly ({reason})"),
+        }
+    }
+}
+
+impl From<RemoteError> for ToolError {
+    fn from(err: RemoteError) -> Self {
+        match err {
+            RemoteError::Refused {
+                status: 401 | 403, ..
+            } => Self::PermissionDenied(err.to_string()),
+            _ => Self::Failed(err.to_string()),
+        }
+    }
+}
+
+/// Tool whose calls are forwarded to a service at an HTTP endpoint
+#[derive(Debug, Clone)]
+pub struct RemoteTool {
+    schema: ChatCompletionTool,
+    client: reqwest::Client,
+    endpoint: String,
+    // Bearer token the service is called with, if it needs one
+    token: Option<Secret>,
+    // Addresses the labels of the results range over
+    universe: HashSet<String>,
+}
+
+impl RemoteTool {
+    /// Tool described by the `schema`, whose calls are posted to `endpoint`. Labels range over the
+    /// addresses of the demo `INBOX` until told otherwise.
+    pub fn new(schema: ChatCompletionTool, endpoint: &str) -> Self {
+        Self {
+            schema,
+            client: client(TIMEOUT),
+            endpoint: endpoint.to_string(),
+            token: None,
+            universe: EmailAddressUniverse::new(&INBOX).into_inner(),
+        }
+    }
+
+    /// Fail the calls the service takes longer than `timeout` to answer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = client(timeout);
+        self
+    }
+
+    pub fn with_token(mut self, token: Secret) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Label the results over the addresses of the `universe`
+    pub fn with_universe(mut self, universe: HashSet<String>) -> Self {
+        self.universe = universe;
+        self
+    }
+
+    // Post the call to the service along with the label of its `inputs`, returning the result
+    // along with the label it claims
+    async fn forward(
+        &self,
+        args: &Args,
+        authority: Option<&Authority>,
+        inputs: Option<&EmailLabel>,
+    ) -> Result<(String, Option<Value>), ToolError> {
+        let args: Value = serde_json::from_str(args.value())?;
+        let authority = authority.map(|authority| {
+            json!({
+                "principal": authority.principal().name(),
+                "endorse": authority.can_endorse(),
+            })
+        });
+        let mut request = self.client.post(&self.endpoint).json(&json!({
+            "tool": self.schema.function.name,
+            "args": args,
+            "authority": authority,
+            "label": inputs.map(label_manifest),
+        }));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token.expose());
+        }
+        let response = request.send().await.map_err(RemoteError::from)?;
+        let status = response.status();
+        let body: Value = response.json().await.map_err(RemoteError::from)?;
+        if !status.is_success() {
+            let error = body["error"]
+                .as_str()
+                .unwrap_or("unknown error")
+                .to_string();
+            return Err(RemoteError::Refused {
+                status: status.as_u16(),
+                error,
+            }
+            .into());
+        }
+        let result = match &body["result"] {
+            Value::String(result) => result.clone(),
+            Value::Null => {
+                return Err(RemoteError::Malformed("the answer has no result".to_string()).into());
+            }
+            result => result.to_string(),
+        };
+        Ok((result, body.get("label").cloned()))
+    }
+
+    /// Call the service with the `authority` of the loop and the label of the `inputs` of the call,
+    /// labeling the result with the label the service gave it, as far as the `authority` lets the
+    /// service vouch for it
+    pub async fn call_remote(
+        &self,
+        args: Args,
+        authority: &Authority,
+        inputs: Option<&EmailLabel>,
+    ) -> Result<MetaValue<String, EmailLabel>, ToolError> {
+        let (result, label) = self.forward(&args, Some(authority), inputs).await?;
+        let label = match label {
+            Some(label) => self.label(&label)?,
+            None => ProductLattice::new(
+                Integrity::untrusted(),
+                InverseLattice::new(PowersetLattice::top(self.universe.clone())),
+            ),
+        };
+        let vouched = authority.mint(self.universe.clone()).lattice1().clone();
+        let integrity = label
+            .lattice1()
+            .clone()
+            .join(vouched)
+            .unwrap_or(Integrity::untrusted());
+        Ok(MetaValue::new(
+            result,
+            ProductLattice::new(integrity, label.lattice2().clone()),
+        ))
+    }
+
+    // Label out of its description in the format of the registry's manifest
+    fn label(&self, label: &Value) -> Result<EmailLabel, RemoteError> {
+        let integrity = match label["integrity"].as_str() {
+            Some("trusted") => Integrity::trusted(),
+            Some("untrusted") => Integrity::untrusted(),
+            _ => {
+                return Err(RemoteError::Malformed(
+                    "the label has no integrity".to_string(),
+                ));
+            }
+        };
+        let readers = label["readers"]
+            .as_array()
+            .ok_or(RemoteError::Malformed(
+                "the label has no readers".to_string(),
+            ))?
+            .iter()
+            .filter_map(Value::as_str)
+            .filter(|reader| self.universe.contains(*reader))
+            .map(str::to_string)
+            .collect();
+        let readers = PowersetLattice::new(readers, self.universe.clone())
+            .map_err(|err| RemoteError::Malformed(format!("{err:?}")))?;
+        Ok(ProductLattice::new(integrity, InverseLattice::new(readers)))
+    }
+}
+
+// Client of the services, failing the requests left unanswered for longer than `timeout`
+fn client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("Failed to build the HTTP client")
+}
+
+// Calls made through the registry carry no authority, and their label is dropped
+impl Tool for RemoteTool {
+    fn name(&self) -> &str {
+        &self.schema.function.name
+    }
+
+    fn schema(&self) -> ChatCompletionTool {
+        self.schema.clone()
+    }
+
+    fn execute<'a>(
+        &'a self,
+        args: Args,
+        _datastore: &'a mut Datastore,
+    ) -> BoxFuture<'a, Result<String, ToolError>> {
+        Box::pin(async move { Ok(self.forward(&args, None, None).await?.0) })
+    }
+}
+
+impl Call for RemoteTool {
+    type Args = Args;
+    type Output = (String, EmailLabel);
+
+    async fn call(
+        &self,
+        args: Self::Args,
+        _datastore: &mut Datastore,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        Ok(self
+            .call_remote(args, &service_authority(), None)
+            .await?
+            .into_raw_parts())
+    }
+}
+
+impl LabeledTool<EmailLabel> for RemoteTool {
+    fn name(&self) -> &str {
+        &self.schema.function.name
+    }
+
+    async fn call_with_authority(
+        &self,
+        args: Args,
+        _datastore: &mut Datastore,
+        authority: &Authority,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        Ok(self
+            .call_remote(args, authority, None)
+            .await?
+            .into_raw_parts())
+    }
+
+    async fn call_with_inputs(
+        &self,
+        args: Args,
+        _datastore: &mut Datastore,
+        authority: &Authority,
+        inputs: &EmailLabel,
+    ) -> Result<(String, EmailLabel), ToolError> {
+        Ok(self
+            .call_remote(args, authority, Some(inputs))
+            .await?
+            .into_raw_parts())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{authority::Principal, schema::ToolDefinition};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Service answering each request with the next of the `responses` and its status, handing
+    // back the bodies of the requests
+    fn service(responses: Vec<(u16, Value)>) -> (String, thread::JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/tools", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|(status, response)| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    let response = response.to_string();
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 {status} Status\r\ncontent-type: application/json\r\n\
+                        content-length: {}\r\nconnection: close\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .unwrap();
+                    serde_json::from_slice(&body).unwrap()
+                })
+                .collect()
+        });
+        (endpoint, handle)
+    }
+
+    #[tokio::test]
+    async fn remote_labels_are_only_trusted_as_far_as_the_authority_goes() {
+        let (endpoint, requests) = service(vec![
+            (
+                200,
+                json!({
+                    "result": { "salary": 100 },
+                    "label": {
+                        "integrity": "trusted",
+                        "readers": ["bob.sheffield@magnet.com", "eve@evil.com"],
+                    },
+                }),
+            ),
+            (200, json!({ "result": "It is sunny" })),
+            (403, json!({ "error": "salaries are confidential" })),
+        ]);
+        let schema = ToolDefinition::<Value>::new("get_salary", "Get the salary of an employee")
+            .param::<String>("name", "The name of the employee")
+            .build()
+            .unwrap();
+        let tool = RemoteTool::new(schema, &endpoint);
+        let mut datastore = Datastore::default();
+        let args = Args::new(json!({ "name": "Bob" }).to_string());
+
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let inputs = ProductLattice::new(
+            Integrity::untrusted(),
+            InverseLattice::new(
+                PowersetLattice::new(
+                    HashSet::from(["bob.sheffield@magnet.com".to_string()]),
+                    universe,
+                )
+                .unwrap(),
+            ),
+        );
+        let (result, label) = tool
+            .call_with_inputs(
+                args.clone(),
+                &mut datastore,
+                &Authority::new(Principal::new("planner")),
+                &inputs,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, r#"{"salary":100}"#);
+        // The planner may not vouch for anything, and Eve is unknown
+        assert_eq!(label.lattice1(), &Integrity::untrusted());
+        assert_eq!(
+            label.lattice2().inner().subset(),
+            &HashSet::from(["bob.sheffield@magnet.com".to_string()])
+        );
+
+        let (_, label) = tool.call(args.clone(), &mut datastore).await.unwrap();
+        assert_eq!(label.lattice1(), &Integrity::untrusted());
+        let refused = tool.execute(args, &mut datastore).await.unwrap_err();
+        assert!(matches!(refused, ToolError::PermissionDenied(_)));
+
+        let requests = requests.join().unwrap();
+        assert_eq!(
+            requests[0],
+            json!({
+                "tool": "get_salary",
+                "args": { "name": "Bob" },
+                "authority": { "principal": "planner", "endorse": false },
+                "label": { "integrity": "untrusted", "readers": ["bob.sheffield@magnet.com"] },
+            })
+        );
+        assert_eq!(requests[2]["authority"], Value::Null);
+        assert_eq!(requests[2]["label"], Value::Null);
+    }
+}
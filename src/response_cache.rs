@@ -0,0 +1,128 @@
+//! Caching of the model's completions, such that running the same planner over and over during
+//! development replays the completions it already paid for instead of billing the API again.
+//!
+//! Completions are keyed on everything which shapes them: the messages, the tools and the options
+//! of the client. The cache is kept in memory, and optionally in a directory holding one JSON file
+//! per completion, such that it survives restarts and can be checked in along with the tests using
+//! it. Completions are replayed as they were, so the cache is meant for development and not for
+//! serving users, whose conversations rarely repeat anyway.
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionTool, CreateChatCompletionResponse,
+};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Request a cached completion answers, as it is compared when looking the completion up
+pub fn cache_key(
+    messages: &[ChatCompletionRequestMessage],
+    tools: &[ChatCompletionTool],
+    options: Value,
+) -> Value {
+    json!({ "messages": messages, "tools": tools, "options": options })
+}
+
+// 64-bit FNV-1a of the serialized key. Unlike the hashers of the standard library it is stable
+// across releases of Rust, which the names of the files on disk rely on.
+fn hash(key: &Value) -> u64 {
+    key.to_string()
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // Completions by the hash of their key, along with the key guarding against collisions
+    entries: HashMap<u64, (Value, CreateChatCompletionResponse)>,
+    hits: u32,
+    misses: u32,
+}
+
+/// Cache of completions, shared by all its clones
+#[derive(Debug, Clone, Default)]
+pub struct ResponseCache {
+    // Directory the completions are kept in, if any
+    dir: Option<PathBuf>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ResponseCache {
+    /// Create a cache which forgets everything once dropped
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Create a cache kept in the directory at `dir`, created if it does not exist
+    pub fn on_disk<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: Some(dir.as_ref().to_path_buf()),
+            inner: Arc::default(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("ResponseCache lock poisoned")
+    }
+
+    fn path(&self, hash: u64) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{hash:016x}.json")))
+    }
+
+    /// Returns the completion cached for the `key`, if any
+    pub fn get(&self, key: &Value) -> Option<CreateChatCompletionResponse> {
+        let hash = hash(key);
+        let mut inner = self.lock();
+        let cached = match inner.entries.get(&hash) {
+            Some((cached_key, response)) => (cached_key == key).then(|| response.clone()),
+            None => self.load(hash, key),
+        };
+        match &cached {
+            Some(response) => {
+                inner.hits += 1;
+                inner.entries.insert(hash, (key.clone(), response.clone()));
+            }
+            None => inner.misses += 1,
+        }
+        cached
+    }
+
+    // Read the completion for the `key` from the directory of the cache. Unreadable files are
+    // treated as missing, such that they are written again.
+    fn load(&self, hash: u64, key: &Value) -> Option<CreateChatCompletionResponse> {
+        let entry: Value =
+            serde_json::from_str(&fs::read_to_string(self.path(hash)?).ok()?).ok()?;
+        if entry["request"] != *key {
+            return None;
+        }
+        serde_json::from_value(entry["response"].clone()).ok()
+    }
+
+    /// Cache the `response` for the `key`. Failing to write it to disk only costs a request later
+    /// on, so the error is returned for the caller to decide what to make of it.
+    pub fn insert(&self, key: Value, response: CreateChatCompletionResponse) -> io::Result<()> {
+        let hash = hash(&key);
+        if let Some(path) = self.path(hash) {
+            let entry = json!({ "request": key, "response": response });
+            fs::write(path, serde_json::to_string_pretty(&entry)?)?;
+        }
+        self.lock().entries.insert(hash, (key, response));
+        Ok(())
+    }
+
+    /// Number of lookups answered by the cache
+    pub fn hits(&self) -> u32 {
+        self.lock().hits
+    }
+
+    /// Number of lookups which had to go to the model
+    pub fn misses(&self) -> u32 {
+        self.lock().misses
+    }
+}
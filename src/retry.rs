@@ -0,0 +1,186 @@
+//! Retrying the requests to the model which fail for reasons that go away on their own, such as
+//! rate limits and overloaded servers. Long agent runs send many requests, so without retries a
+//! single transient error is enough to lose the whole run.
+use async_openai::error::OpenAIError;
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    time::Duration,
+};
+
+// Error types reported by the OpenAI and Anthropic APIs for transient failures
+const RETRYABLE_TYPES: &[&str] = &[
+    "rate_limit_exceeded",
+    "rate_limit_error",
+    "server_error",
+    "api_error",
+    "overloaded_error",
+];
+
+/// How often and how long to wait before retrying a failed request. The delays grow exponentially
+/// from `base_delay` up to `max_delay`, and are shortened by a random fraction of at most `jitter`
+/// such that clients rate limited together do not all retry at the same time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // Attempts in total, including the first one
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Shorten the delays by a random fraction of at most `jitter`, clamped between 0 and 1
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Whether the request which failed with `error` on its `attempt`, counting from 1, should be
+    /// sent again
+    pub fn should_retry(&self, attempt: u32, error: &OpenAIError) -> bool {
+        attempt < self.max_attempts && is_retryable(error)
+    }
+
+    /// How long to wait before retrying the request which failed on its `attempt`, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        // A hasher with fresh random keys is as good a source of randomness as jitter needs
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 - self.jitter * random)
+    }
+
+    /// Wait before retrying the request which failed on its `attempt`
+    pub async fn backoff(&self, attempt: u32) {
+        tokio::time::sleep(self.delay(attempt)).await;
+    }
+}
+
+/// Whether the request which failed with `error` may succeed if sent again. Rate limits, server
+/// errors and dropped connections are transient, while invalid requests and exhausted quotas are
+/// not.
+pub fn is_retryable(error: &OpenAIError) -> bool {
+    match error {
+        OpenAIError::Reqwest(error) => {
+            error.is_timeout()
+                || error.is_connect()
+                || error
+                    .status()
+                    .is_some_and(|status| is_retryable_status(status.as_u16()))
+        }
+        OpenAIError::ApiError(error) => {
+            let status = error.code.as_deref().and_then(|code| code.parse().ok());
+            match (error.r#type.as_deref(), error.code.as_deref()) {
+                // Also sent with a 429, but waiting does not raise the quota
+                (Some("insufficient_quota"), _) => false,
+                (Some(kind), _) if RETRYABLE_TYPES.contains(&kind) => true,
+                (_, Some("rate_limit_exceeded")) => true,
+                _ if status.is_some_and(is_retryable_status) => true,
+                // Server errors are not guaranteed to come as JSON, so the OpenAI client reports
+                // them with nothing but the body as the message
+                (None, None) => error.param.is_none(),
+                _ => false,
+            }
+        }
+        OpenAIError::StreamError(_) => true,
+        OpenAIError::JSONDeserialize(_)
+        | OpenAIError::FileSaveError(_)
+        | OpenAIError::FileReadError(_)
+        | OpenAIError::InvalidArgument(_) => false,
+    }
+}
+
+// Too many requests, request timeout and server errors
+fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 408 | 429) || status >= 500
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+
+    fn api_error(r#type: Option<&str>, code: Option<&str>) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: "Request failed".to_string(),
+            r#type: r#type.map(str::to_string),
+            param: None,
+            code: code.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        assert!(is_retryable(&api_error(Some("rate_limit_exceeded"), None)));
+        assert!(is_retryable(&api_error(
+            Some("overloaded_error"),
+            Some("529")
+        )));
+        assert!(is_retryable(&api_error(None, None)));
+        assert!(!is_retryable(&api_error(
+            Some("insufficient_quota"),
+            Some("429")
+        )));
+        assert!(!is_retryable(&api_error(
+            Some("invalid_request_error"),
+            Some("400")
+        )));
+        assert!(!is_retryable(&OpenAIError::InvalidArgument(
+            "No messages".to_string()
+        )));
+
+        let policy = RetryPolicy::default().with_max_attempts(3);
+        let error = api_error(Some("server_error"), None);
+        assert!(policy.should_retry(2, &error));
+        assert!(!policy.should_retry(3, &error));
+    }
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_limit() {
+        let policy = RetryPolicy::default()
+            .with_base_delay(Duration::from_millis(100))
+            .with_max_delay(Duration::from_millis(300))
+            .with_jitter(0.0);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(300));
+
+        let policy = policy.with_jitter(0.5);
+        for attempt in 1..10 {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(300));
+        }
+    }
+}
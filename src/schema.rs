@@ -0,0 +1,359 @@
+//! Typed definitions of tools, building the schema advertised to the model from the struct the
+//! arguments are parsed into, rather than from hand-written JSON.
+//!
+//! Each parameter is declared with the Rust type of its value, from which its JSON type is
+//! derived, and every parameter is wrapped in the `anyOf` of a literal value or a variable name
+//! the planners expect. When the tool is built, an example call made of the declared parameters
+//! is parsed into the argument struct, such that a schema out of sync with the struct is caught
+//! before the model ever sees it.
+//!
+//! Final answers are declared the same way with an [`OutputSchema`], which the model is asked to
+//! answer with instead of free text, and which the answer is parsed with.
+use crate::{
+    Args,
+    tools::{Variable, variable_schema_gen},
+};
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        ChatCompletionTool, ChatCompletionToolArgs, ChatCompletionToolType, FunctionObject,
+        ResponseFormat, ResponseFormatJsonSchema,
+    },
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value, json};
+use std::marker::PhantomData;
+
+/// Rust type of a tool parameter, as told to the model
+pub trait ParamType {
+    /// JSON schema type of the values
+    fn json_type() -> &'static str;
+    /// Value used in the example call checking the definition
+    fn example() -> Value;
+}
+
+impl ParamType for String {
+    fn json_type() -> &'static str {
+        "string"
+    }
+
+    fn example() -> Value {
+        json!("")
+    }
+}
+
+impl ParamType for bool {
+    fn json_type() -> &'static str {
+        "boolean"
+    }
+
+    fn example() -> Value {
+        json!(false)
+    }
+}
+
+impl ParamType for f64 {
+    fn json_type() -> &'static str {
+        "number"
+    }
+
+    fn example() -> Value {
+        json!(0.0)
+    }
+}
+
+macro_rules! integer_param {
+    ($($integer:ty),*) => {
+        $(impl ParamType for $integer {
+            fn json_type() -> &'static str {
+                "integer"
+            }
+
+            fn example() -> Value {
+                json!(0)
+            }
+        })*
+    };
+}
+
+integer_param!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+#[derive(Debug)]
+pub enum SchemaError {
+    // The example call built from the parameters does not parse into the argument struct
+    OutOfSync(serde_json::Error),
+    OpenAIError(OpenAIError),
+}
+
+impl From<OpenAIError> for SchemaError {
+    fn from(err: OpenAIError) -> Self {
+        Self::OpenAIError(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Param {
+    name: String,
+    description: String,
+    json_type: &'static str,
+    // Whether the model may pass `null` to leave the parameter out
+    nullable: bool,
+    example: Value,
+}
+
+/// Definition of the tool called `name`, whose arguments are parsed into `A`
+#[derive(Debug, Clone)]
+pub struct ToolDefinition<A> {
+    name: String,
+    description: String,
+    params: Vec<Param>,
+    // Variables the model may pass instead of literal values
+    variables: Vec<Variable>,
+    phantom_args: PhantomData<A>,
+}
+
+impl<A: DeserializeOwned> ToolDefinition<A> {
+    pub fn new(name: &str, description: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            params: vec![],
+            variables: vec![],
+            phantom_args: PhantomData,
+        }
+    }
+
+    /// Declare the required parameter `name`, whose values are of type `T`
+    pub fn param<T: ParamType>(mut self, name: &str, description: &str) -> Self {
+        self.params.push(Param {
+            name: name.to_string(),
+            description: description.to_string(),
+            json_type: T::json_type(),
+            nullable: false,
+            example: T::example(),
+        });
+        self
+    }
+
+    /// Declare the optional parameter `name`, whose values are of type `T`. Strict schemas require
+    /// every parameter, so the model leaves it out by passing `null`, which the example call does.
+    pub fn optional<T: ParamType>(mut self, name: &str, description: &str) -> Self {
+        self.params.push(Param {
+            name: name.to_string(),
+            description: description.to_string(),
+            json_type: T::json_type(),
+            nullable: true,
+            example: Value::Null,
+        });
+        self
+    }
+
+    /// Use `example` as the value of the parameter declared last in the example call, for
+    /// parameters whose values are checked further when parsed, such as numbers passed as strings
+    pub fn example<V: Into<Value>>(mut self, example: V) -> Self {
+        if let Some(param) = self.params.last_mut() {
+            param.example = example.into();
+        }
+        self
+    }
+
+    /// Let the model pass any of the `variables` instead of a literal value
+    pub fn with_variables(mut self, variables: Vec<Variable>) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// JSON schema of the parameters, before their wrapping into literal values or variables
+    pub fn parameters(&self) -> Value {
+        object_schema(&self.params)
+    }
+
+    /// Parse the `args` of a call to the tool
+    pub fn parse(&self, args: &Args) -> Result<A, serde_json::Error> {
+        serde_json::from_str(args.value())
+    }
+
+    /// Build the schema advertised to the model, failing with `SchemaError::OutOfSync` if a call
+    /// made of the declared parameters does not parse into `A`
+    pub fn build(&self) -> Result<ChatCompletionTool, SchemaError> {
+        check_example::<A>(&self.params)?;
+        Ok(ChatCompletionToolArgs::default()
+            .function(FunctionObject {
+                name: self.name.clone(),
+                description: Some(self.description.clone()),
+                parameters: Some(variable_schema_gen(
+                    self.parameters(),
+                    self.variables.clone(),
+                )),
+                strict: Some(true),
+            })
+            .r#type(ChatCompletionToolType::Function)
+            .build()?)
+    }
+}
+
+/// Definition of the final answers called `name`, which are parsed into `T`
+#[derive(Debug, Clone)]
+pub struct OutputSchema<T> {
+    name: String,
+    description: String,
+    fields: Vec<Param>,
+    phantom_output: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> OutputSchema<T> {
+    pub fn new(name: &str, description: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            fields: vec![],
+            phantom_output: PhantomData,
+        }
+    }
+
+    /// Declare the required field `name`, whose values are of type `V`
+    pub fn field<V: ParamType>(mut self, name: &str, description: &str) -> Self {
+        self.fields.push(Param {
+            name: name.to_string(),
+            description: description.to_string(),
+            json_type: V::json_type(),
+            nullable: false,
+            example: V::example(),
+        });
+        self
+    }
+
+    /// Declare the optional field `name`, which the model leaves out by answering `null`
+    pub fn optional<V: ParamType>(mut self, name: &str, description: &str) -> Self {
+        self.fields.push(Param {
+            name: name.to_string(),
+            description: description.to_string(),
+            json_type: V::json_type(),
+            nullable: true,
+            example: Value::Null,
+        });
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// JSON schema of the answers
+    pub fn schema(&self) -> Value {
+        object_schema(&self.fields)
+    }
+
+    /// Parse the final `answer` of the model
+    pub fn parse(&self, answer: &str) -> Result<T, serde_json::Error> {
+        serde_json::from_str(answer)
+    }
+
+    /// Build the response format asking the model for answers of this schema, failing with
+    /// `SchemaError::OutOfSync` if an answer made of the declared fields does not parse into `T`
+    pub fn build(&self) -> Result<ResponseFormat, SchemaError> {
+        check_example::<T>(&self.fields)?;
+        Ok(ResponseFormat::JsonSchema {
+            json_schema: ResponseFormatJsonSchema {
+                description: Some(self.description.clone()),
+                name: self.name.clone(),
+                schema: Some(self.schema()),
+                strict: Some(true),
+            },
+        })
+    }
+}
+
+// Strict JSON schema of an object made of the `params`
+fn object_schema(params: &[Param]) -> Value {
+    let properties: Map<String, Value> = params
+        .iter()
+        .map(|param| {
+            let json_type = match param.nullable {
+                true => json!([param.json_type, "null"]),
+                false => json!(param.json_type),
+            };
+            let property = json!({
+                "type": json_type,
+                "description": param.description,
+            });
+            (param.name.clone(), property)
+        })
+        .collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": params.iter().map(|param| &param.name).collect::<Vec<_>>(),
+        "additionalProperties": false,
+    })
+}
+
+// Check that an object made of the examples of the `params` parses into `T`
+fn check_example<T: DeserializeOwned>(params: &[Param]) -> Result<(), SchemaError> {
+    let example: Map<String, Value> = params
+        .iter()
+        .map(|param| (param.name.clone(), param.example.clone()))
+        .collect();
+    serde_json::from_value::<T>(Value::Object(example)).map_err(SchemaError::OutOfSync)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{ReadEmailsArgs, SendSlackMessageArgs};
+
+    #[test]
+    fn schemas_are_built_from_the_arguments() {
+        let send = ToolDefinition::<SendSlackMessageArgs>::new(
+            "send_slack_message",
+            "Sends a {message} to a slack {channel} with an optional {preview}",
+        )
+        .param::<String>("channel", "The channel where the message should be sent")
+        .param::<String>("message", "The message to be sent")
+        .param::<bool>("preview", "Whether or not to include the link preview");
+        let tool = send.build().unwrap();
+        let parameters = tool.function.parameters.unwrap();
+        assert_eq!(
+            parameters["required"],
+            json!(["channel", "message", "preview"])
+        );
+        let preview = &parameters["properties"]["preview"];
+        assert_eq!(
+            preview["anyOf"][0]["properties"]["value"]["type"],
+            "boolean"
+        );
+        assert_eq!(
+            preview["anyOf"][1]["properties"]["kind"]["const"],
+            "variable_name"
+        );
+        let args =
+            Args::new(json!({ "channel": "c", "message": "m", "preview": true }).to_string());
+        assert_eq!(send.parse(&args).unwrap().message(), "m");
+
+        // Counts are passed as strings holding a number
+        let read = ToolDefinition::<ReadEmailsArgs>::new("read_emails", "Read emails")
+            .param::<String>("count", "The number of emails to read");
+        assert!(matches!(read.build(), Err(SchemaError::OutOfSync(_))));
+        assert!(read.clone().example("5").build().is_ok());
+        // Filters may be left out with `null`
+        let filtered = read
+            .example("5")
+            .optional::<String>("sender", "Part of the address of the sender")
+            .optional::<String>("after", "First day the emails were sent on, as YYYY-MM-DD")
+            .optional::<bool>("unread", "Whether the emails are unread");
+        assert!(filtered.build().is_ok());
+        assert_eq!(
+            filtered.parameters()["properties"]["after"]["type"],
+            json!(["string", "null"])
+        );
+        // Parameters the arguments need cannot be left out
+        let missing = ToolDefinition::<SendSlackMessageArgs>::new("send_slack_message", "")
+            .param::<String>("channel", "");
+        assert!(matches!(missing.build(), Err(SchemaError::OutOfSync(_))));
+    }
+}
@@ -0,0 +1,5 @@
+//! Sealed traits are public such that they can be used in bounds, but can only be implemented
+//! inside this crate, which leaves room to extend them without breaking downstream users.
+
+/// Supertrait of the traits which are not meant to be implemented outside of this crate
+pub trait Sealed {}
@@ -0,0 +1,256 @@
+//! Secrets used by tools to talk to external services (API tokens, passwords). Secrets are handed
+//! to tools when they are registered, such that they never have to be passed as tool arguments
+//! visible to the model, and every secret handed out is scrubbed from the tool results before they
+//! reach the conversation, the trace or the logs.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Placeholder replacing secret values in redacted text
+pub const REDACTED_SECRET: &str = "<redacted:secret>";
+
+/// A secret value which is never printed by its `Debug` implementation
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    /// Returns the actual value of the secret
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({REDACTED_SECRET})")
+    }
+}
+
+#[derive(Debug)]
+pub enum SecretsError {
+    // The requested secret does not exist in the provider
+    Missing(String),
+    IoError(std::io::Error),
+    #[cfg(feature = "keyring")]
+    KeyringError(keyring::Error),
+}
+
+impl From<std::io::Error> for SecretsError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl From<keyring::Error> for SecretsError {
+    fn from(err: keyring::Error) -> Self {
+        Self::KeyringError(err)
+    }
+}
+
+/// Source of secrets for the tools
+pub trait SecretsProvider: Send + Sync {
+    /// Returns the secret identified by `name`
+    fn secret(&self, name: &str) -> Result<Secret, SecretsError>;
+}
+
+/// Reads secrets from environment variables, optionally prefixed with `prefix`
+#[derive(Debug, Default)]
+pub struct EnvSecretsProvider {
+    prefix: String,
+}
+
+impl EnvSecretsProvider {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+impl SecretsProvider for EnvSecretsProvider {
+    fn secret(&self, name: &str) -> Result<Secret, SecretsError> {
+        let variable = format!("{}{}", self.prefix, name);
+        std::env::var(&variable)
+            .map(Secret::new)
+            .map_err(|_| SecretsError::Missing(variable))
+    }
+}
+
+/// Reads each secret from a file named after the secret inside the `dir` directory, as done by
+/// Docker and Kubernetes secret mounts.
+#[derive(Debug)]
+pub struct FileSecretsProvider {
+    dir: PathBuf,
+}
+
+impl FileSecretsProvider {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl SecretsProvider for FileSecretsProvider {
+    fn secret(&self, name: &str) -> Result<Secret, SecretsError> {
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Err(SecretsError::Missing(name.to_string()));
+        }
+        // Files usually end with a new line which is not part of the secret
+        let value = std::fs::read_to_string(path)?;
+        Ok(Secret::new(
+            value.trim_end_matches(['\r', '\n']).to_string(),
+        ))
+    }
+}
+
+/// Reads secrets from the platform's keyring, where each secret is stored under the `service`
+/// with the secret's name as user.
+#[cfg(feature = "keyring")]
+#[derive(Debug)]
+pub struct KeyringSecretsProvider {
+    service: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringSecretsProvider {
+    pub fn new(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl SecretsProvider for KeyringSecretsProvider {
+    fn secret(&self, name: &str) -> Result<Secret, SecretsError> {
+        match keyring::Entry::new(&self.service, name)?.get_password() {
+            Ok(password) => Ok(Secret::new(password)),
+            Err(keyring::Error::NoEntry) => Err(SecretsError::Missing(name.to_string())),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Keeps secrets in memory. Mostly useful for tests and for secrets computed at runtime.
+#[derive(Debug, Default)]
+pub struct MemorySecretsProvider {
+    secrets: HashMap<String, Secret>,
+}
+
+impl MemorySecretsProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_secret(mut self, name: &str, value: &str) -> Self {
+        self.secrets
+            .insert(name.to_string(), Secret::new(value.to_string()));
+        self
+    }
+}
+
+impl SecretsProvider for MemorySecretsProvider {
+    fn secret(&self, name: &str) -> Result<Secret, SecretsError> {
+        self.secrets
+            .get(name)
+            .cloned()
+            .ok_or(SecretsError::Missing(name.to_string()))
+    }
+}
+
+/// Handle to a [`SecretsProvider`] shared by the tools it was injected into. It remembers every
+/// secret it handed out such that they can be redacted from anything the tools produce.
+#[derive(Clone)]
+pub struct Secrets {
+    provider: Arc<dyn SecretsProvider>,
+    // Values of the secrets handed out so far
+    revealed: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Secrets {
+    pub fn new<P: SecretsProvider + 'static>(provider: P) -> Self {
+        Self {
+            provider: Arc::new(provider),
+            revealed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Get the secret identified by `name` from the provider
+    pub fn get(&self, name: &str) -> Result<Secret, SecretsError> {
+        let secret = self.provider.secret(name)?;
+        // Empty secrets would redact everything, so there is no point in remembering them
+        if !secret.expose().is_empty() {
+            self.revealed
+                .lock()
+                .expect("Secrets lock poisoned")
+                .insert(secret.expose().to_string());
+        }
+        Ok(secret)
+    }
+
+    /// Replace every secret handed out so far that appears in `text` with a placeholder
+    pub fn redact(&self, text: &str) -> String {
+        let revealed = self.revealed.lock().expect("Secrets lock poisoned");
+        revealed.iter().fold(text.to_string(), |text, secret| {
+            text.replace(secret.as_str(), REDACTED_SECRET)
+        })
+    }
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revealed_secrets_are_redacted() {
+        let secrets =
+            Secrets::new(MemorySecretsProvider::new().with_secret("SLACK_TOKEN", "xoxb-1"));
+        // Nothing was handed out yet, so there is nothing to redact
+        assert_eq!(secrets.redact("token xoxb-1"), "token xoxb-1");
+
+        let token = secrets.get("SLACK_TOKEN").expect("Missing secret");
+        assert_eq!(token.expose(), "xoxb-1");
+        assert!(!format!("{token:?}").contains("xoxb-1"));
+        assert_eq!(
+            secrets.redact("token xoxb-1"),
+            format!("token {REDACTED_SECRET}")
+        );
+        assert!(matches!(
+            secrets.get("IMAP_PASSWORD"),
+            Err(SecretsError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn file_secrets() {
+        let dir = std::env::temp_dir().join(format!("gentlemen-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("WEBHOOK_TOKEN"), "hunter2\n").unwrap();
+
+        let provider = FileSecretsProvider::new(&dir);
+        assert_eq!(
+            provider.secret("WEBHOOK_TOKEN").unwrap().expose(),
+            "hunter2"
+        );
+        assert!(matches!(
+            provider.secret("MISSING"),
+            Err(SecretsError::Missing(_))
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
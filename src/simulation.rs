@@ -0,0 +1,78 @@
+//! A simulated environment to run the planners in without any API key: the model replays a
+//! scripted transcript, the tools read the demo inbox and whatever the tools send lands in the
+//! demo outbox, where it can be checked once the run is over.
+use crate::{
+    ConversationHistory, Integrity, Message, ProductLattice, State,
+    mock::MockLlm,
+    openai::LlmClient,
+    tools::{
+        EmailAddressUniverse, EmailLabel, INBOX, MetaValue, OUTBOX, SentMessage, readers_label,
+    },
+};
+use async_openai::types::{ChatCompletionRequestSystemMessageArgs, ChatCompletionResponseMessage};
+
+/// System prompt describing the demo tools to the model
+pub const SYSTEM_PROMPT: &str = "You are a helpful email assistant with the ability to summarize \
+    emails and to send Slack messages. All arguments to tools have an `anyOf` schema, with a \
+    `kind` tag indicating whether the value is a literal value (`value`) or a variable name \
+    (`variable`). The user's Slack alias is: bob.sheffield@magnet.com";
+
+/// The user's request, the model's scripted answers to it and the messages sent while running it
+pub struct SimulatedEnvironment {
+    request: String,
+    transcript: Vec<ChatCompletionResponseMessage>,
+    system_prompt: String,
+    // Messages already in the outbox when the environment was set up, which belong to other runs
+    already_sent: usize,
+}
+
+impl SimulatedEnvironment {
+    /// Simulate the user making the `request`, answered by the model with the `transcript`
+    pub fn new(request: &str, transcript: Vec<ChatCompletionResponseMessage>) -> Self {
+        Self {
+            request: request.to_string(),
+            transcript,
+            system_prompt: SYSTEM_PROMPT.to_string(),
+            already_sent: OUTBOX.sent().len(),
+        }
+    }
+
+    pub fn with_system_prompt(mut self, system_prompt: &str) -> Self {
+        self.system_prompt = system_prompt.to_string();
+        self
+    }
+
+    /// A model replaying the transcript from the start
+    pub fn model(&self) -> LlmClient {
+        LlmClient::mock(MockLlm::new(self.transcript.clone()))
+    }
+
+    /// The conversation before the request, holding the system prompt
+    pub fn state(&self) -> State {
+        let system = ChatCompletionRequestSystemMessageArgs::default()
+            .content(self.system_prompt.clone())
+            .build()
+            .expect("Invalid system prompt");
+        ConversationHistory::new(vec![system.into()])
+    }
+
+    /// The request, as the message starting the run
+    pub fn request(&self) -> Message {
+        Message::user(&self.request)
+    }
+
+    /// The request, labeled as coming from the user: trusted and readable by everyone in the inbox
+    pub fn labeled_request(&self) -> MetaValue<Message, EmailLabel> {
+        let universe = EmailAddressUniverse::new(&INBOX).into_inner();
+        let label = ProductLattice::new(
+            Integrity::trusted(),
+            readers_label(universe.clone(), universe).expect("Invalid readers"),
+        );
+        MetaValue::new(self.request(), label)
+    }
+
+    /// Messages sent by the tools since the environment was set up
+    pub fn sent(&self) -> Vec<SentMessage> {
+        OUTBOX.sent().into_iter().skip(self.already_sent).collect()
+    }
+}
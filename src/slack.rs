@@ -0,0 +1,361 @@
+//! Delivery of the messages of the `send_slack_message` tools through the Slack Web API. Without a
+//! [`SlackClient`] in the [`Datastore`], the tools only record the messages in the `OUTBOX`.
+//!
+//! The channel of a message is any of a channel id, a channel name with or without its `#`, or the
+//! email address of a user, which the message is sent to directly. Names and addresses are looked
+//! up once and remembered. Messages delivered through Slack are recorded in the `OUTBOX` as well,
+//! such that their status can be asked for like the status of any other send, and replies can be
+//! threaded under them.
+//!
+//! [`Datastore`]: crate::Datastore
+use crate::{
+    secrets::Secret,
+    tools::{DeliveryStatus, OUTBOX, SendSlackMessageArgs},
+};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug)]
+pub enum SlackError {
+    HttpError(reqwest::Error),
+    // Slack refused the call to the method, with the error it gave
+    Api { method: String, error: String },
+    UnknownChannel(String),
+    // The send to reply to was not delivered through Slack
+    UnknownThread(String),
+}
+
+impl From<reqwest::Error> for SlackError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::HttpError(err)
+    }
+}
+
+impl fmt::Display for SlackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HttpError(err) => write!(f, "Slack could not be reached ({err})"),
+            Self::Api { method, error } => write!(f, "Slack refused `{method}` ({error})"),
+            Self::UnknownChannel(channel) => write!(f, "there is no Slack channel `{channel}`"),
+            Self::UnknownThread(send_id) => {
+                write!(
+                    f,
+                    "`{send_id}` was not sent through Slack, so it has no thread"
+                )
+            }
+        }
+    }
+}
+
+// Where a message was posted, which replies are threaded under
+#[derive(Debug, Clone)]
+struct Posted {
+    channel: String,
+    ts: String,
+}
+
+/// Client of the Slack Web API, posting as the bot the token was issued for
+#[derive(Debug, Clone)]
+pub struct SlackClient {
+    client: reqwest::Client,
+    token: Secret,
+    // Address of the API, which only changes to point at a fake of it
+    endpoint: String,
+    // Ids of the channels looked up by name or address
+    channels: Arc<Mutex<HashMap<String, String>>>,
+    // Where each send delivered through Slack was posted
+    posted: Arc<Mutex<HashMap<String, Posted>>>,
+}
+
+impl SlackClient {
+    pub fn new(token: Secret) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            token,
+            endpoint: "https://slack.com/api".to_string(),
+            channels: Arc::default(),
+            posted: Arc::default(),
+        }
+    }
+
+    /// Talk to the API at `endpoint` rather than to Slack
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    // Call the API `method`, with a JSON `body` if it is given, failing unless Slack says it is ok
+    async fn call(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> Result<Value, SlackError> {
+        let url = format!("{}/{method}", self.endpoint);
+        let request = match body {
+            Some(body) => self.client.post(url).json(&body),
+            None => self.client.get(url).query(query),
+        };
+        let response: Value = request
+            .bearer_auth(self.token.expose())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match response["ok"].as_bool() {
+            Some(true) => Ok(response),
+            _ => Err(SlackError::Api {
+                method: method.to_string(),
+                error: response["error"]
+                    .as_str()
+                    .unwrap_or("unknown_error")
+                    .to_string(),
+            }),
+        }
+    }
+
+    /// Id of the `channel`, which is either an id, the name of a channel or the address of a user
+    pub async fn channel_id(&self, channel: &str) -> Result<String, SlackError> {
+        if is_channel_id(channel) {
+            return Ok(channel.to_string());
+        }
+        if let Some(id) = self
+            .channels
+            .lock()
+            .expect("Slack lock poisoned")
+            .get(channel)
+        {
+            return Ok(id.clone());
+        }
+        let id = match channel.contains('@') {
+            true => self.direct_channel(channel).await?,
+            false => self.named_channel(channel.trim_start_matches('#')).await?,
+        }
+        .ok_or_else(|| SlackError::UnknownChannel(channel.to_string()))?;
+        self.channels
+            .lock()
+            .expect("Slack lock poisoned")
+            .insert(channel.to_string(), id.clone());
+        Ok(id)
+    }
+
+    // Id of the direct messages with the user of the `address`
+    async fn direct_channel(&self, address: &str) -> Result<Option<String>, SlackError> {
+        let user = match self
+            .call("users.lookupByEmail", &[("email", address)], None)
+            .await
+        {
+            Ok(user) => user,
+            Err(SlackError::Api { error, .. }) if error == "users_not_found" => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let Some(user) = user["user"]["id"].as_str() else {
+            return Ok(None);
+        };
+        let opened = self
+            .call("conversations.open", &[], Some(json!({ "users": user })))
+            .await?;
+        Ok(opened["channel"]["id"].as_str().map(str::to_string))
+    }
+
+    // Id of the channel called `name`, going through the pages of channels until it is found
+    async fn named_channel(&self, name: &str) -> Result<Option<String>, SlackError> {
+        let mut cursor = String::new();
+        loop {
+            let page = self
+                .call(
+                    "conversations.list",
+                    &[
+                        ("types", "public_channel,private_channel"),
+                        ("exclude_archived", "true"),
+                        ("limit", "200"),
+                        ("cursor", &cursor),
+                    ],
+                    None,
+                )
+                .await?;
+            let found = page["channels"].as_array().and_then(|channels| {
+                channels
+                    .iter()
+                    .find(|channel| channel["name"] == name)
+                    .and_then(|channel| channel["id"].as_str())
+            });
+            if let Some(id) = found {
+                return Ok(Some(id.to_string()));
+            }
+            match page["response_metadata"]["next_cursor"].as_str() {
+                Some(next) if !next.is_empty() => cursor = next.to_string(),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Post the message of the `args`, recording it in the `OUTBOX`, and return the id of the send
+    pub async fn send(&self, args: &SendSlackMessageArgs) -> Result<String, SlackError> {
+        let thread = match args.thread() {
+            Some(send_id) => Some(
+                self.posted
+                    .lock()
+                    .expect("Slack lock poisoned")
+                    .get(send_id)
+                    .cloned()
+                    .ok_or_else(|| SlackError::UnknownThread(send_id.to_string()))?,
+            ),
+            None => None,
+        };
+        // Replies go where the message they reply to was posted
+        let channel = match &thread {
+            Some(thread) => thread.channel.clone(),
+            None => self.channel_id(args.channel()).await?,
+        };
+        let mut message = json!({
+            "channel": channel,
+            "text": args.message(),
+            "unfurl_links": args.preview(),
+            "unfurl_media": args.preview(),
+        });
+        if let Some(thread) = &thread {
+            message["thread_ts"] = json!(thread.ts);
+        }
+        let posted = self.call("chat.postMessage", &[], Some(message)).await?;
+        let ts = posted["ts"].as_str().unwrap_or_default().to_string();
+        let send_id = OUTBOX.record(args.channel(), args.message());
+        // Slack only says it is ok once the message is in the channel
+        OUTBOX.set_status(&send_id, DeliveryStatus::Delivered);
+        self.posted
+            .lock()
+            .expect("Slack lock poisoned")
+            .insert(send_id.clone(), Posted { channel, ts });
+        Ok(send_id)
+    }
+}
+
+// Whether the `channel` is the id of a public, private or direct channel, such as `C024BE91L`
+fn is_channel_id(channel: &str) -> bool {
+    channel.len() >= 9
+        && channel.starts_with(['C', 'G', 'D'])
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, Call, Datastore, Function};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Slack answering each request with the next of the `responses`, handing back the request
+    // lines along with their bodies
+    fn slack(responses: Vec<Value>) -> (String, thread::JoinHandle<Vec<(String, Value)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    let mut request = String::new();
+                    reader.read_line(&mut request).unwrap();
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    let response = response.to_string();
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                        content-length: {}\r\nconnection: close\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .unwrap();
+                    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                    (request.trim().to_string(), body)
+                })
+                .collect()
+        });
+        (endpoint, handle)
+    }
+
+    #[tokio::test]
+    async fn messages_are_posted_to_slack() {
+        let (endpoint, requests) = slack(vec![
+            json!({ "ok": true, "user": { "id": "U012AB3CD" } }),
+            json!({ "ok": true, "channel": { "id": "D024BE91L" } }),
+            json!({ "ok": true, "channel": "D024BE91L", "ts": "1503435956.000247" }),
+            json!({ "ok": true, "channel": "D024BE91L", "ts": "1503435957.000248" }),
+            json!({ "ok": true, "channels": [], "response_metadata": { "next_cursor": "" } }),
+        ]);
+        let client = SlackClient::new(Secret::new("xoxb".to_string())).with_endpoint(&endpoint);
+        let mut datastore = Datastore::default().with_slack(client.clone());
+
+        let args = json!({
+            "channel": "bob.sheffield@magnet.com",
+            "message": "See https://roma.com/nextsteps",
+            "preview": "false",
+        });
+        let result = Function::new("send_slack_message".to_string())
+            .call(Args::new(args.to_string()), &mut datastore)
+            .await
+            .unwrap();
+        let send_id = serde_json::from_str::<Value>(&result).unwrap()["send_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(OUTBOX.status(&send_id), Some(DeliveryStatus::Delivered));
+
+        // Replies are threaded under the message, in the channel it was posted to
+        let mut from_model = args.clone();
+        from_model["thread"] = json!(send_id);
+        let reply: SendSlackMessageArgs = serde_json::from_value(from_model).unwrap();
+        assert_eq!(reply.thread(), None);
+        client
+            .send(&reply.clone().in_thread(&send_id))
+            .await
+            .unwrap();
+        let unknown = client
+            .send(&reply.in_thread("send-unknown"))
+            .await
+            .unwrap_err();
+        assert!(matches!(unknown, SlackError::UnknownThread(_)));
+        let missing = client.channel_id("#roma").await.unwrap_err();
+        assert!(matches!(missing, SlackError::UnknownChannel(_)));
+
+        let requests = requests.join().unwrap();
+        assert_eq!(
+            requests[0].0,
+            "GET /users.lookupByEmail?email=bob.sheffield%40magnet.com HTTP/1.1"
+        );
+        assert_eq!(requests[1].1, json!({ "users": "U012AB3CD" }));
+        assert_eq!(
+            requests[2].1,
+            json!({
+                "channel": "D024BE91L",
+                "text": "See https://roma.com/nextsteps",
+                "unfurl_links": false,
+                "unfurl_media": false,
+            })
+        );
+        assert_eq!(requests[3].1["thread_ts"], "1503435956.000247");
+        assert!(requests[4].0.starts_with("GET /conversations.list?"));
+    }
+}
@@ -1,12 +1,83 @@
 use crate::Label;
 use async_openai::types::ChatCompletionRequestMessage;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::DeserializeOwned};
+use std::{fs, io, path::Path, sync::Arc};
 
-// Comprises all the messages in the conversation up to the current point
-#[derive(Debug, Clone)]
-pub struct ConversationHistory<T>(pub Vec<T>);
+// Comprises all the messages in the conversation up to the current point. The messages are shared
+// between the clones of the history, such that planners hand their state over to the actions they
+// take without copying it, and are only copied once a clone which shares them is changed.
+#[derive(Debug)]
+pub struct ConversationHistory<T>(Arc<Vec<T>>);
+
+impl<T> ConversationHistory<T> {
+    pub fn new(messages: Vec<T>) -> Self {
+        Self(Arc::new(messages))
+    }
+
+    pub fn messages(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T: Clone> ConversationHistory<T> {
+    /// Append `message` to the conversation
+    pub fn with_message(mut self, message: T) -> Self {
+        self.push(message);
+        self
+    }
+
+    pub fn push(&mut self, message: T) {
+        Arc::make_mut(&mut self.0).push(message);
+    }
+
+    pub fn messages_mut(&mut self) -> &mut Vec<T> {
+        Arc::make_mut(&mut self.0)
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        Arc::unwrap_or_clone(self.0)
+    }
+}
+
+impl<T> Clone for ConversationHistory<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T: Serialize> Serialize for ConversationHistory<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for ConversationHistory<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> ConversationHistory<T> {
+    /// Read the conversation from the JSON file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Write the conversation to the JSON file at `path`, such that it can be attached to a bug
+    /// report and replayed
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl<T> Default for ConversationHistory<T> {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
 pub type State = ConversationHistory<ChatCompletionRequestMessage>;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct LabeledConversationHistory<M> {
     conv: Vec<M>,
     label: Label,
@@ -0,0 +1,130 @@
+//! Token accounting for the requests sent to the model. Models report the tokens they used in
+//! their responses, but some backends do not, and a request has to be priced before it is sent to
+//! know whether it still fits in a budget. Token counts are therefore estimated the way tiktoken
+//! splits text, without its vocabulary: the text is cut into the same pieces and long pieces are
+//! counted as several tokens. The estimates err on the side of too many tokens.
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionTool, CreateChatCompletionResponse,
+};
+use regex::Regex;
+use std::sync::LazyLock;
+
+// Pieces tiktoken's cl100k encoding splits text into before encoding them, minus the lookahead
+// keeping the last space of a run of whitespace for the following word
+static PIECES: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+",
+    )
+    .expect("Invalid token regex")
+});
+
+// Characters of a piece which make up a single token. Common words are a single token.
+const CHARS_PER_TOKEN: usize = 6;
+// Tokens wrapping each message of a conversation, and priming the answer of the model
+const TOKENS_PER_MESSAGE: u32 = 3;
+
+/// Estimate the number of tokens the `text` is encoded into
+pub fn estimate_tokens(text: &str) -> u32 {
+    PIECES
+        .find_iter(text)
+        .map(|piece| piece.as_str().chars().count().div_ceil(CHARS_PER_TOKEN) as u32)
+        .sum()
+}
+
+/// Estimate the number of prompt tokens of a request sending the `messages` with the `tools`
+pub fn estimate_prompt_tokens(
+    messages: &[ChatCompletionRequestMessage],
+    tools: &[ChatCompletionTool],
+) -> u32 {
+    // The serialized messages and tools are a bit longer than what the model sees, which keeps the
+    // estimate on the safe side
+    let serialized =
+        |value: serde_json::Result<String>| estimate_tokens(&value.unwrap_or_default());
+    let messages: u32 = messages
+        .iter()
+        .map(|message| TOKENS_PER_MESSAGE + serialized(serde_json::to_string(message)))
+        .sum();
+    let tools: u32 = tools
+        .iter()
+        .map(|tool| serialized(serde_json::to_string(&tool.function)))
+        .sum();
+    messages + tools + TOKENS_PER_MESSAGE
+}
+
+/// Tokens spent on the request answered by `response`, as reported by the model. Backends which do
+/// not report their usage are accounted for with the `prompt_estimate` and the estimated length of
+/// the answer.
+pub fn spent_tokens(response: &CreateChatCompletionResponse, prompt_estimate: u32) -> u32 {
+    if let Some(usage) = &response.usage {
+        return usage.total_tokens;
+    }
+    let completion: u32 = response
+        .choices
+        .iter()
+        .map(|choice| {
+            let message = &choice.message;
+            let tool_calls: u32 = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|tool_call| {
+                    estimate_tokens(&tool_call.function.name)
+                        + estimate_tokens(&tool_call.function.arguments)
+                })
+                .sum();
+            estimate_tokens(message.content.as_deref().unwrap_or_default()) + tool_calls
+        })
+        .sum();
+    prompt_estimate + completion
+}
+
+/// Tokens a task is allowed to spend, and how many it spent so far
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBudget {
+    limit: u32,
+    spent: u32,
+}
+
+impl TokenBudget {
+    pub fn new(limit: u32) -> Self {
+        Self { limit, spent: 0 }
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    pub fn spent(&self) -> u32 {
+        self.spent
+    }
+
+    /// Whether `tokens` more can be spent without going over the limit
+    pub fn allows(&self, tokens: u32) -> bool {
+        self.spent.saturating_add(tokens) <= self.limit
+    }
+
+    pub fn spend(&mut self, tokens: u32) {
+        self.spent = self.spent.saturating_add(tokens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_estimated_like_tiktoken_splits_text() {
+        // "Hello", ",", " world", "!"
+        assert_eq!(estimate_tokens("Hello, world!"), 4);
+        // Numbers are split every three digits
+        assert_eq!(estimate_tokens("1234567"), 3);
+        assert_eq!(estimate_tokens(""), 0);
+        // Long words are several tokens
+        assert!(estimate_tokens("Pneumonoultramicroscopicsilicovolcanoconiosis") > 1);
+
+        let mut budget = TokenBudget::new(10);
+        budget.spend(8);
+        assert!(budget.allows(2));
+        assert!(!budget.allows(3));
+    }
+}
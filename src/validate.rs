@@ -0,0 +1,195 @@
+//! Semantic validation of tool arguments, checking what a JSON schema cannot express: that a
+//! channel exists, that an email address is internal or that a count stays within bounds.
+//!
+//! Arguments failing validation are never passed to the tool. Instead, the planning loop answers
+//! the tool call with a corrective message, such that the model can fix its arguments and call the
+//! tool again.
+use crate::Args;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::{fmt, sync::Arc};
+
+/// Why the arguments of a tool call were rejected
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError(String);
+
+impl ValidationError {
+    pub fn new(reason: String) -> Self {
+        Self(reason)
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.0
+    }
+
+    /// Tool result telling the model what to fix in its call to `function`
+    pub fn corrective_message(&self, function: &str) -> String {
+        format!(
+            "The call to `{function}` was rejected because {}. Fix the arguments and call \
+            `{function}` again.",
+            self.0
+        )
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+type Check = dyn Fn(&Map<String, Value>) -> Result<(), ValidationError> + Send + Sync;
+
+/// Check run on the arguments of a tool before calling it
+#[derive(Clone)]
+pub struct Validator(Arc<Check>);
+
+impl Validator {
+    pub fn new<F>(check: F) -> Self
+    where
+        F: Fn(&Map<String, Value>) -> Result<(), ValidationError> + Send + Sync + 'static,
+    {
+        Self(Arc::new(check))
+    }
+
+    /// Check the JSON object `args`
+    pub fn validate(&self, args: &Args) -> Result<(), ValidationError> {
+        match serde_json::from_str(args.value()) {
+            Ok(Value::Object(map)) => (self.0)(&map),
+            _ => Err(ValidationError::new(
+                "the arguments are not a JSON object".to_string(),
+            )),
+        }
+    }
+
+    /// The numeric argument `field` must be at most `max`
+    pub fn max_count(field: &str, max: u64) -> Self {
+        let field = field.to_string();
+        Self::new(move |args| {
+            // Models pass numbers as strings about as often as they pass them as numbers
+            let count = match args.get(&field) {
+                Some(Value::Number(count)) => count.as_u64(),
+                Some(Value::String(count)) => count.parse().ok(),
+                _ => None,
+            }
+            .ok_or(ValidationError::new(format!(
+                "`{field}` must be a positive number"
+            )))?;
+            if count > max {
+                return Err(ValidationError::new(format!(
+                    "`{field}` is {count}, but it must be at most {max}"
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    /// The argument `field` must be a well-formed email address, belonging to `domain` if given
+    pub fn email_address(field: &str, domain: Option<&str>) -> Self {
+        let field = field.to_string();
+        let domain = domain.map(|domain| format!("@{domain}"));
+        let address = Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").expect("Invalid email regex");
+        Self::new(move |args| {
+            let value = string_arg(args, &field)?;
+            if !address.is_match(value) {
+                return Err(ValidationError::new(format!(
+                    "`{field}` is not a valid email address: {value:?}"
+                )));
+            }
+            match &domain {
+                Some(domain) if !value.ends_with(domain.as_str()) => Err(ValidationError::new(
+                    format!("`{field}` must be an address ending in {domain}, not {value:?}"),
+                )),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    /// The argument `field` must be one of the `allowed` values, such as existing channels
+    pub fn one_of(field: &str, allowed: &[&str]) -> Self {
+        let field = field.to_string();
+        let allowed: Vec<String> = allowed.iter().map(|value| value.to_string()).collect();
+        Self::new(move |args| {
+            let value = string_arg(args, &field)?;
+            if !allowed.iter().any(|allowed| allowed == value) {
+                return Err(ValidationError::new(format!(
+                    "`{field}` is {value:?}, but it must be one of {allowed:?}"
+                )));
+            }
+            Ok(())
+        })
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validator").finish_non_exhaustive()
+    }
+}
+
+// Returns the string argument `field`
+fn string_arg<'a>(args: &'a Map<String, Value>, field: &str) -> Result<&'a str, ValidationError> {
+    args.get(field)
+        .and_then(Value::as_str)
+        .ok_or(ValidationError::new(format!("`{field}` must be a string")))
+}
+
+/// Run all the `validators` on `args`, stopping at the first failure
+pub fn validate_all(validators: &[Validator], args: &Args) -> Result<(), ValidationError> {
+    validators
+        .iter()
+        .try_for_each(|validator| validator.validate(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validators_reject_bad_arguments() {
+        let validators = [
+            Validator::max_count("count", 50),
+            Validator::email_address("channel", Some("magnet.com")),
+        ];
+        let args = |args: &str| Args::new(args.to_string());
+
+        assert_eq!(
+            validate_all(
+                &validators,
+                &args(r#"{"count": "5", "channel": "bob.sheffield@magnet.com"}"#)
+            ),
+            Ok(())
+        );
+        let error = validate_all(
+            &validators,
+            &args(r#"{"count": 51, "channel": "bob.sheffield@magnet.com"}"#),
+        )
+        .unwrap_err();
+        assert_eq!(error.reason(), "`count` is 51, but it must be at most 50");
+        assert!(
+            validate_all(
+                &validators,
+                &args(r#"{"count": 5, "channel": "robert@universaltechadvise.biz"}"#)
+            )
+            .is_err()
+        );
+        assert!(validate_all(&validators, &args(r#"{"count": 5, "channel": "bob"}"#)).is_err());
+
+        let channels = Validator::one_of("channel", &["general", "random"]);
+        assert!(
+            channels
+                .validate(&args(r#"{"channel": "general"}"#))
+                .is_ok()
+        );
+        assert!(
+            error
+                .corrective_message("read_emails")
+                .contains("call `read_emails` again")
+        );
+        assert!(
+            channels
+                .validate(&args(r#"{"channel": "secret"}"#))
+                .is_err()
+        );
+    }
+}